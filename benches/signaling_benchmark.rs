@@ -0,0 +1,92 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use carapace::signaling::{
+    CreateOptions, JoinOptions, PeerId, RoomManagerHandle, SignalingAddr, SignalingConfig,
+    SignalingServer, outbound_channel,
+};
+use tokio::runtime::Runtime;
+
+const ROOM_SIZE: usize = 500;
+
+/// spawn a server and fill one room with ROOM_SIZE peers whose queues are drained
+fn setup_room(
+    rt: &Runtime,
+    offload_threshold: usize,
+) -> (SignalingServer, RoomManagerHandle, PeerId) {
+    rt.block_on(async {
+        let server = SignalingServer::with_config(SignalingConfig {
+            fanout_offload_threshold: offload_threshold,
+            ..SignalingConfig::default()
+        });
+        let handle = server.handle();
+        let addr = SignalingAddr::from("127.0.0.1:5000".parse::<SocketAddr>().unwrap());
+
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        tokio::spawn(async move { while owner_rx.recv().await.is_some() {} });
+        let (code, owner, _) = handle
+            .create_room(addr, owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+
+        for _ in 1..ROOM_SIZE {
+            let (tx, mut rx) = outbound_channel();
+            tokio::spawn(async move { while rx.recv().await.is_some() {} });
+            handle
+                .join_room(code, addr, tx, JoinOptions::default())
+                .await
+                .unwrap();
+        }
+
+        (server, handle, owner)
+    })
+}
+
+/// latency of an unrelated actor command while the 500-peer room is being
+/// broadcast to continuously: with inline fan-out every broadcast holds the
+/// actor for ROOM_SIZE channel sends before the next command is served
+fn bench_fanout(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let addr = SignalingAddr::from("127.0.0.1:5000".parse::<SocketAddr>().unwrap());
+
+    let mut group = c.benchmark_group("Fanout");
+    group.measurement_time(Duration::from_secs(5));
+
+    for (name, threshold) in [("inline", usize::MAX), ("offload", 0)] {
+        let (_server, handle, owner) = setup_room(&rt, threshold);
+
+        let broadcaster = {
+            let handle = handle.clone();
+            rt.spawn(async move {
+                for i in 0.. {
+                    handle.set_locked(&owner, i % 2 == 0).await.unwrap();
+                }
+            })
+        };
+
+        group.bench_function(BenchmarkId::new(name, ROOM_SIZE), |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        let (tx, _rx) = outbound_channel();
+                        let (_, peer_id, _) = handle
+                            .create_room(addr, tx, CreateOptions::default())
+                            .await
+                            .unwrap();
+                        handle.leave_room(&peer_id).await;
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+
+        broadcaster.abort();
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_fanout);
+criterion_main!(benches);
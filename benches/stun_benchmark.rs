@@ -1,7 +1,14 @@
-use criterion::{Criterion, Throughput, black_box, criterion_group, criterion_main};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
 
-use carapace::protocol::{MAGIC_COOKIE, StunRequest, StunResponse};
+use carapace::protocol::{MAGIC_COOKIE, StunRequest, StunResponse, TransactionId};
+use carapace::server::StunServer;
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+
+/// requests a client sends before reading the responses
+const BURST: usize = 256;
 
 /// create a test binding request
 fn create_binding_request() -> [u8; 20] {
@@ -43,8 +50,9 @@ fn bench_parsing(c: &mut Criterion) {
 
 /// response creation benchmark
 fn bench_response(c: &mut Criterion) {
-    let transaction_id = *b"BENCHMARK123";
-    let client_addr_v4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 100), 12345);
+    let transaction_id = TransactionId::new(*b"BENCHMARK123");
+    let client_addr_v4 =
+        SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 100), 12345));
 
     let mut group = c.benchmark_group("Response");
     group.throughput(Throughput::Elements(1));
@@ -52,7 +60,7 @@ fn bench_response(c: &mut Criterion) {
     group.bench_function("StunResponse", |b| {
         b.iter(|| {
             let response = StunResponse::binding_response(
-                black_box(&transaction_id),
+                black_box(transaction_id),
                 black_box(client_addr_v4),
             );
             black_box(&response);
@@ -65,7 +73,8 @@ fn bench_response(c: &mut Criterion) {
 /// full request-response cycle benchmark
 fn bench_full_cycle(c: &mut Criterion) {
     let request_data = create_binding_request();
-    let client_addr_v4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 100), 12345);
+    let client_addr_v4 =
+        SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 100), 12345));
 
     let mut group = c.benchmark_group("FullCycle");
     group.throughput(Throughput::Elements(1));
@@ -84,5 +93,98 @@ fn bench_full_cycle(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_parsing, bench_response, bench_full_cycle);
+/// hand a request from a receive task to a worker the way `run` used to: a
+/// copy into an inline buffer, moved through the queue
+fn bench_work_queue(c: &mut Criterion) {
+    const SIZE: usize = 256;
+    let request = create_binding_request();
+    let (tx, rx) = async_channel::bounded::<([u8; SIZE], usize)>(1024);
+    let (pooled_tx, pooled_rx) = async_channel::bounded::<(Box<[u8; SIZE]>, usize)>(1024);
+    let pool = crossbeam_queue::ArrayQueue::new(1024);
+
+    let mut group = c.benchmark_group("WorkQueue");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("inline_copy", |b| {
+        b.iter(|| {
+            let mut data = [0u8; SIZE];
+            data[..request.len()].copy_from_slice(black_box(&request));
+            tx.try_send((data, request.len())).unwrap();
+            let (data, len) = rx.try_recv().unwrap();
+            black_box(&data[..len]);
+        })
+    });
+
+    // The current path: a recycled buffer the datagram is received into
+    group.bench_function("pooled", |b| {
+        b.iter(|| {
+            let mut data = pool.pop().unwrap_or_else(|| Box::new([0u8; SIZE]));
+            data[..request.len()].copy_from_slice(black_box(&request));
+            pooled_tx.try_send((data, request.len())).unwrap();
+            let (data, len) = pooled_rx.try_recv().unwrap();
+            black_box(&data[..len]);
+            let _ = pool.push(data);
+        })
+    });
+
+    group.finish();
+}
+
+/// send a burst of requests and wait for every response; one lost to a full
+/// queue ends the burst after a short timeout instead of hanging
+async fn burst(client: &UdpSocket, server: SocketAddr, request: &[u8]) {
+    let mut buf = [0u8; 128];
+    for _ in 0..BURST {
+        client.send_to(request, server).await.unwrap();
+    }
+    for _ in 0..BURST {
+        let response = tokio::time::timeout(Duration::from_millis(100), client.recv_from(&mut buf));
+        if response.await.is_err() {
+            break;
+        }
+    }
+}
+
+/// end-to-end throughput over loopback, one datagram per receive syscall
+/// against batched `recvmmsg` receives
+fn bench_throughput(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let request = create_binding_request();
+    let batches: &[usize] = if cfg!(feature = "recvmmsg") {
+        &[1, 32]
+    } else {
+        &[1]
+    };
+
+    let mut group = c.benchmark_group("Throughput");
+    group.throughput(Throughput::Elements(BURST as u64));
+
+    for &batch in batches {
+        let (server, client) = rt.block_on(async {
+            let server = StunServer::builder()
+                .recv_batch(batch)
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addrs()[0];
+            tokio::spawn(server.run());
+            (addr, UdpSocket::bind("127.0.0.1:0").await.unwrap())
+        });
+
+        group.bench_with_input(BenchmarkId::new("recv_batch", batch), &batch, |b, _| {
+            b.iter(|| rt.block_on(burst(&client, server, &request)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_parsing,
+    bench_response,
+    bench_full_cycle,
+    bench_work_queue,
+    bench_throughput
+);
 criterion_main!(benches);
@@ -0,0 +1,194 @@
+//! STUN client: asks a STUN server which public address this host's UDP
+//! traffic appears to come from
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::protocol::{
+    ATTR_ERROR_CODE, MessageType, StunError, StunRequest, StunResponse, TransactionId,
+};
+
+/// Time `discover` waits for an answer by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait before the first retransmission; it doubles after each one
+/// (RFC 5389 section 7.2.1)
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+
+/// receive buffer size: other servers may add SOFTWARE, FINGERPRINT and the
+/// like, but a response never outgrows an Ethernet MTU
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// Sends Binding Requests from one UDP socket, so every server it asks
+/// reports the mapping of that same socket
+#[derive(Debug)]
+pub struct StunClient {
+    socket: UdpSocket,
+    timeout: Duration,
+}
+
+impl StunClient {
+    /// bind the socket requests are sent from
+    pub async fn bind(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// give up on a server that hasn't answered within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// ask `server` for the address this client's socket is seen from
+    ///
+    /// The request goes out under a fresh random transaction id and is
+    /// retransmitted at doubling intervals until the timeout. Datagrams from
+    /// other sources or for other transactions are dropped, so queries on one
+    /// client shouldn't overlap.
+    ///
+    /// # Errors
+    /// - `StunError::Timeout` - if no response arrives in time
+    /// - `StunError::ErrorResponse` - if the server answers with an error
+    /// - `StunError::MissingAttribute` - if the response lacks
+    ///   XOR-MAPPED-ADDRESS (or an error response lacks ERROR-CODE)
+    /// - `StunError::MalformedAttribute` - if the response's attributes don't
+    ///   decode
+    /// - `StunError::Io` - if the socket fails
+    pub async fn discover(&self, server: SocketAddr) -> Result<SocketAddr, StunError> {
+        let transaction_id = TransactionId::generate();
+        let request = StunRequest::binding(transaction_id);
+        let deadline = Instant::now() + self.timeout;
+        let mut rto = INITIAL_RTO;
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            self.socket.send_to(&request, server).await?;
+            let retransmit_at = deadline.min(Instant::now() + rto);
+            rto *= 2;
+
+            while let Ok(received) =
+                tokio::time::timeout_at(retransmit_at, self.socket.recv_from(&mut buf)).await
+            {
+                let (len, from) = received?;
+                if from != server {
+                    continue;
+                }
+                let Ok(response) = StunRequest::parse(&buf[..len]) else {
+                    continue;
+                };
+                if response.transaction_id != transaction_id {
+                    debug!("Dropping response to another transaction from {}", from);
+                    continue;
+                }
+                match response.msg_type {
+                    MessageType::BindingResponse => {
+                        return StunResponse::parse(&buf[..len]).map(|r| r.mapped_addr);
+                    }
+                    MessageType::BindingErrorResponse => {
+                        let (code, reason) = response
+                            .error_code()?
+                            .ok_or(StunError::MissingAttribute(ATTR_ERROR_CODE))?;
+                        return Err(StunError::ErrorResponse {
+                            code,
+                            reason: reason.to_string(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(StunError::Timeout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::StunServer;
+
+    #[tokio::test]
+    async fn discovers_own_address_from_local_server() {
+        let server = StunServer::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addrs()[0];
+        tokio::spawn(server.run());
+
+        let client = StunClient::bind("127.0.0.1:0").await.unwrap();
+        let mapped = client.discover(server_addr).await.unwrap();
+        assert_eq!(mapped, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn responses_to_other_transactions_are_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = StunClient::bind("127.0.0.1:0").await.unwrap();
+        let mapped: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            let request = StunRequest::parse(&buf[..len]).unwrap();
+            assert!(request.is_binding_request());
+
+            let stale = StunResponse::binding_response(TransactionId::generate(), from);
+            server.send_to(stale.as_bytes(), from).await.unwrap();
+            let response = StunResponse::binding_response(request.transaction_id, mapped);
+            server.send_to(response.as_bytes(), from).await.unwrap();
+        });
+
+        assert_eq!(client.discover(server_addr).await.unwrap(), mapped);
+    }
+
+    #[tokio::test]
+    async fn only_spoofed_responses_time_out() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = StunClient::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (_, from) = server.recv_from(&mut buf).await.unwrap();
+            // Neither a success nor an error for another transaction ends the wait
+            let spoofed_error =
+                StunResponse::binding_error_response(TransactionId::generate(), 4, 0, "Bad");
+            server
+                .send_to(spoofed_error.as_bytes(), from)
+                .await
+                .unwrap();
+            let spoofed = StunResponse::binding_response(TransactionId::generate(), from);
+            server.send_to(spoofed.as_bytes(), from).await.unwrap();
+        });
+
+        let result = client.discover(server_addr).await;
+        assert!(matches!(result, Err(StunError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn silent_server_times_out() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = StunClient::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(50));
+
+        let result = client.discover(server.local_addr().unwrap()).await;
+        assert!(matches!(result, Err(StunError::Timeout)));
+    }
+}
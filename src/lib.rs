@@ -1,3 +1,8 @@
+pub mod client;
+pub mod metrics;
+pub mod pcap;
 pub mod protocol;
+pub mod rate_limit;
+pub mod redact;
 pub mod server;
 pub mod signaling;
@@ -1,30 +1,243 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use carapace::pcap::{DEFAULT_PCAP_MAX_BYTES, spawn_capture};
+use carapace::redact::AddrRedaction;
 use carapace::server::{DEFAULT_PORT, StunServer};
-use carapace::signaling::{DEFAULT_SIGNALING_PORT, SignalingServer};
+use carapace::signaling::{DEFAULT_SIGNALING_PORT, SignalingServer, spawn_event_log};
 use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+
+/// Environment variable naming the JSON event log sink ("-" for stdout)
+const EVENT_LOG_ENV: &str = "CARAPACE_EVENT_LOG";
+
+/// Environment variable naming a pcap file to capture STUN traffic into
+const PCAP_ENV: &str = "CARAPACE_STUN_PCAP";
+
+/// Environment variable naming a second `ip:port` to answer from, enabling
+/// RFC 5780 NAT behavior discovery
+const ALTERNATE_ENV: &str = "CARAPACE_STUN_ALTERNATE";
+
+/// Environment variable naming an `ip:port` to also serve STUN over TCP on
+const STUN_TCP_ENV: &str = "CARAPACE_STUN_TCP";
+
+/// Environment variable overriding the STUN port (3478 by default)
+const STUN_PORT_ENV: &str = "CARAPACE_STUN_PORT";
+
+/// Environment variable overriding the number of STUN worker tasks
+const STUN_WORKERS_ENV: &str = "CARAPACE_STUN_WORKERS";
+
+/// Environment variables naming the PEM certificate chain and private key to
+/// serve signaling over TLS (wss://) with; needs the `tls` feature
+#[cfg(feature = "tls")]
+const TLS_CERT_ENV: &str = "CARAPACE_TLS_CERT";
+#[cfg(feature = "tls")]
+const TLS_KEY_ENV: &str = "CARAPACE_TLS_KEY";
+
+/// Environment variable naming the local IP to bind UDP relays on, enabling
+/// `RequestRelay` for peers that can't connect directly
+const RELAY_IP_ENV: &str = "CARAPACE_RELAY_IP";
+
+/// Environment variable selecting client address redaction in logs
+/// ("full", "truncate" or "hash")
+const REDACT_ENV: &str = "CARAPACE_REDACT_ADDRS";
+
+/// Log output format, chosen with `--log-format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the enclosing spans, for log aggregators
+    Json,
+}
+
+/// Logging options from the command line
+#[derive(Debug, Default, PartialEq, Eq)]
+struct LogArgs {
+    /// Filter directives (`info`, `carapace::signaling=debug,warn`, ...);
+    /// overrides `RUST_LOG`
+    level: Option<String>,
+    format: LogFormat,
+}
+
+/// Parse `--log-level <filter>` and `--log-format text|json`, in either the
+/// `--flag value` or `--flag=value` form
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<LogArgs, String> {
+    let mut parsed = LogArgs::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} requires a value", flag))
+        };
+        match flag.as_str() {
+            "--log-level" => parsed.level = Some(value()?),
+            "--log-format" => {
+                parsed.format = match value()?.as_str() {
+                    "text" => LogFormat::Text,
+                    "json" => LogFormat::Json,
+                    other => {
+                        return Err(format!(
+                            "unknown log format {:?} (expected text or json)",
+                            other
+                        ));
+                    }
+                }
+            }
+            _ => return Err(format!("unknown argument {:?}", flag)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Install the global subscriber: `--log-level` if given, else `RUST_LOG`,
+/// else `info`
+fn init_tracing(args: &LogArgs) -> Result<(), String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("invalid log filter: {}", e);
+    let filter = match &args.level {
+        Some(directives) => EnvFilter::try_new(directives).map_err(|e| invalid(&e))?,
+        None => EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env()
+            .map_err(|e| invalid(&e))?,
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match args.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt::init();
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let log_args = parse_args(std::env::args().skip(1)).map_err(invalid)?;
+    init_tracing(&log_args).map_err(invalid)?;
 
-    let stun_addr = format!("0.0.0.0:{}", DEFAULT_PORT);
+    let stun_port = match std::env::var(STUN_PORT_ENV) {
+        Ok(port) => port
+            .parse::<u16>()
+            .map_err(|e| invalid(format!("invalid {}: {}", STUN_PORT_ENV, e)))?,
+        Err(_) => DEFAULT_PORT,
+    };
+    let stun_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), stun_port);
     let signaling_addr = format!("0.0.0.0:{}", DEFAULT_SIGNALING_PORT);
 
     info!("Carapace P2P Server starting...");
     info!("STUN:      {}", stun_addr);
     info!("Signaling: {} (WebSocket)", signaling_addr);
 
-    let stun_server = StunServer::bind(&stun_addr).await?;
-    let signaling_server = SignalingServer::new();
+    let redaction = match std::env::var(REDACT_ENV) {
+        Ok(mode) => mode.parse::<AddrRedaction>().map_err(invalid)?,
+        Err(_) => AddrRedaction::default(),
+    };
+
+    let mut stun_builder = StunServer::builder().redaction(redaction);
+    if let Ok(workers) = std::env::var(STUN_WORKERS_ENV) {
+        let workers = workers
+            .parse()
+            .map_err(|e| invalid(format!("invalid {}: {}", STUN_WORKERS_ENV, e)))?;
+        stun_builder = stun_builder.workers(workers);
+    }
+    if let Ok(path) = std::env::var(PCAP_ENV) {
+        let (capture, _) = spawn_capture(File::create(&path)?, DEFAULT_PCAP_MAX_BYTES)?;
+        stun_builder = stun_builder.capture(capture);
+        info!("STUN capture: {}", path);
+    }
+    if let Ok(alternate) = std::env::var(ALTERNATE_ENV) {
+        let alternate = alternate
+            .parse()
+            .map_err(|e| invalid(format!("invalid {}: {}", ALTERNATE_ENV, e)))?;
+        stun_builder = stun_builder.alternate(alternate);
+        info!("STUN alternate: {}", alternate);
+    }
+    if let Ok(tcp) = std::env::var(STUN_TCP_ENV) {
+        let tcp = tcp
+            .parse()
+            .map_err(|e| invalid(format!("invalid {}: {}", STUN_TCP_ENV, e)))?;
+        stun_builder = stun_builder.tcp(tcp);
+    }
+    let stun_server = stun_builder.bind_addr(stun_addr).await?;
+    let mut signaling_builder = SignalingServer::builder().addr_redaction(redaction);
+    if let Ok(ip) = std::env::var(RELAY_IP_ENV) {
+        let ip = ip
+            .parse()
+            .map_err(|e| invalid(format!("invalid {}: {}", RELAY_IP_ENV, e)))?;
+        signaling_builder = signaling_builder.relay_ip(ip);
+        info!("Relay IP: {}", ip);
+    }
+    let signaling_server = signaling_builder.build();
+
+    if let Ok(target) = std::env::var(EVENT_LOG_ENV) {
+        let sink: Box<dyn Write + Send> = if target == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(&target)?)
+        };
+        spawn_event_log(signaling_server.subscribe_events(), sink);
+        info!("Event log: {}", target);
+    }
 
+    let (stop_stun, stun_stopped) = tokio::sync::oneshot::channel::<()>();
     let stun_handle = tokio::spawn(async move {
-        if let Err(e) = stun_server.run().await {
+        let shutdown = async {
+            let _ = stun_stopped.await;
+        };
+        if let Err(e) = stun_server.run_until(shutdown).await {
             error!("STUN server error: {}", e);
         }
     });
 
+    #[cfg(feature = "tls")]
+    let tls = match (std::env::var(TLS_CERT_ENV), std::env::var(TLS_KEY_ENV)) {
+        (Ok(cert), Ok(key)) => Some(carapace::signaling::TlsConfig::new(cert, key)),
+        (Err(_), Err(_)) => None,
+        _ => {
+            return Err(invalid(format!(
+                "{} and {} must be set together",
+                TLS_CERT_ENV, TLS_KEY_ENV
+            )));
+        }
+    };
+
+    let (stop_signaling, signaling_stopped) = tokio::sync::oneshot::channel::<()>();
     let signaling_addr_clone = signaling_addr.clone();
     let signaling_handle = tokio::spawn(async move {
-        if let Err(e) = signaling_server.run(&signaling_addr_clone).await {
+        let shutdown = async {
+            let _ = signaling_stopped.await;
+        };
+        #[cfg(feature = "tls")]
+        let result = match &tls {
+            Some(tls) => {
+                signaling_server
+                    .run_tls_until(&signaling_addr_clone, tls, shutdown)
+                    .await
+            }
+            None => {
+                signaling_server
+                    .run_until(&signaling_addr_clone, shutdown)
+                    .await
+            }
+        };
+        #[cfg(not(feature = "tls"))]
+        let result = signaling_server
+            .run_until(&signaling_addr_clone, shutdown)
+            .await;
+        if let Err(e) = result {
             error!("Signaling server error: {}", e);
         }
     });
@@ -32,9 +245,40 @@ async fn main() -> std::io::Result<()> {
     tokio::signal::ctrl_c().await?;
     info!("Shutdown signal received, stopping servers...");
 
-    stun_handle.abort();
-    signaling_handle.abort();
+    // The STUN server answers what it has already queued before stopping,
+    // and signaling peers are told the server is going away
+    let _ = stop_stun.send(());
+    let _ = stop_signaling.send(());
+    let _ = tokio::join!(stun_handle, signaling_handle);
 
     info!("Servers stopped. Goodbye!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Result<LogArgs, String> {
+        parse_args(list.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn parses_log_flags_in_both_forms() {
+        assert_eq!(args(&[]), Ok(LogArgs::default()));
+        assert_eq!(
+            args(&["--log-level", "carapace=debug,warn", "--log-format=json"]),
+            Ok(LogArgs {
+                level: Some("carapace=debug,warn".to_string()),
+                format: LogFormat::Json,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_log_flags() {
+        assert!(args(&["--log-format", "xml"]).is_err());
+        assert!(args(&["--log-level"]).is_err());
+        assert!(args(&["--verbose"]).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Lock-free metrics shared between the server's tasks
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in microseconds; anything slower
+/// lands in a final overflow bucket
+pub const LATENCY_BUCKETS_US: [u64; 10] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// Histogram of request processing latency
+///
+/// Recording is a couple of relaxed atomic adds, so workers can share one
+/// without contending on a lock.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    sum_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one observation
+    #[inline]
+    pub fn record(&self, latency: Duration) {
+        let us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_US.partition_point(|&bound| bound < us);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    /// Current counts; concurrent recordings may be partially included
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            counts: self
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a [`LatencyHistogram`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Observations per bucket, in the order of `LATENCY_BUCKETS_US`, plus
+    /// the overflow bucket last
+    pub counts: Vec<u64>,
+    /// Total of all observations, in microseconds
+    pub sum_us: u64,
+}
+
+impl HistogramSnapshot {
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Buckets as `(upper bound in µs, count)`, `None` for the overflow bucket
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<u64>, u64)> + '_ {
+        LATENCY_BUCKETS_US
+            .iter()
+            .map(|&bound| Some(bound))
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+}
+
+/// Packet counters for a STUN server
+///
+/// Like the histogram, every update is a relaxed atomic add, so the receive
+/// tasks and workers share one and another task can read it at any time.
+#[derive(Debug, Default)]
+pub struct StunMetrics {
+    received: AtomicU64,
+    responses_sent: AtomicU64,
+    parse_errors: AtomicU64,
+    dropped: AtomicU64,
+    rate_limited: AtomicU64,
+    indications: AtomicU64,
+}
+
+impl StunMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a datagram read from a socket
+    #[inline]
+    pub fn record_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a response (success or error) handed to a socket
+    #[inline]
+    pub fn record_response_sent(&self) {
+        self.responses_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request that couldn't be answered with a success response
+    #[inline]
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a datagram discarded because the worker queue was full
+    #[inline]
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a datagram discarded because its source was over its rate limit
+    #[inline]
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a Binding Indication, which is accepted without a response
+    #[inline]
+    pub fn record_indication(&self) {
+        self.indications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current counts; each is read separately, so concurrent updates may be
+    /// reflected in some and not others
+    pub fn snapshot(&self) -> StunMetricsSnapshot {
+        StunMetricsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            responses_sent: self.responses_sent.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            indications: self.indications.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of [`StunMetrics`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StunMetricsSnapshot {
+    /// Datagrams read from the server's sockets
+    pub received: u64,
+    /// Responses sent, error responses included
+    pub responses_sent: u64,
+    /// Requests rejected as malformed, unsupported or unauthenticated
+    pub parse_errors: u64,
+    /// Datagrams discarded because every worker was busy
+    pub dropped: u64,
+    /// Datagrams discarded because their source exceeded its rate limit
+    pub rate_limited: u64,
+    /// Binding Indications received, which are never answered
+    pub indications: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observations_land_in_their_bucket() {
+        let histogram = LatencyHistogram::new();
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_micros(10));
+        histogram.record(Duration::from_micros(11));
+        histogram.record(Duration::from_secs(1));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 4);
+        assert_eq!(snapshot.sum_us, 1_000_024);
+
+        let buckets: Vec<_> = snapshot.buckets().filter(|&(_, n)| n > 0).collect();
+        assert_eq!(buckets, [(Some(10), 2), (Some(25), 1), (None, 1)]);
+    }
+}
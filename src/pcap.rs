@@ -0,0 +1,341 @@
+//! Capture of STUN traffic to a pcap file, for inspection in Wireshark
+//!
+//! Datagrams are written with synthesized IP and UDP headers (LINKTYPE_RAW),
+//! so Wireshark's STUN dissector picks them up by port.
+
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Default cap on the capture file size
+pub const DEFAULT_PCAP_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Datagrams queued for the writer; beyond this, captures are dropped rather
+/// than slowing the workers down
+const CAPTURE_QUEUE_CAPACITY: usize = 4096;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_GLOBAL_HEADER_SIZE: u64 = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+const SNAPLEN: u32 = 65535;
+/// raw IP packets, version taken from the first nibble
+const LINKTYPE_RAW: u32 = 101;
+
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+/// Writes datagrams as pcap records, up to a size limit
+pub struct PcapWriter<W: Write> {
+    sink: W,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the pcap global header; records are accepted until the file
+    /// would exceed `max_bytes`
+    pub fn new(mut sink: W, max_bytes: u64) -> std::io::Result<Self> {
+        let mut header = [0u8; PCAP_GLOBAL_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        // thiszone and sigfigs stay zero
+        header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        sink.write_all(&header)?;
+
+        Ok(Self {
+            sink,
+            written: PCAP_GLOBAL_HEADER_SIZE,
+            max_bytes,
+        })
+    }
+
+    /// Append one UDP datagram
+    ///
+    /// Returns `Ok(false)` without writing once the size limit is reached.
+    pub fn write_datagram(
+        &mut self,
+        ts: SystemTime,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> std::io::Result<bool> {
+        let packet = ip_udp_packet(src, dst, payload);
+        let record_len = (PCAP_RECORD_HEADER_SIZE + packet.len()) as u64;
+        if self.written + record_len > self.max_bytes {
+            return Ok(false);
+        }
+
+        let since_epoch = ts.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut header = [0u8; PCAP_RECORD_HEADER_SIZE];
+        header[0..4].copy_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+
+        self.sink.write_all(&header)?;
+        self.sink.write_all(&packet)?;
+        self.sink.flush()?;
+        self.written += record_len;
+        Ok(true)
+    }
+}
+
+/// Build an IP packet carrying `payload` in a UDP datagram
+///
+/// Mixed families (an IPv4 client on a dual-stack socket) are written as IPv6
+/// with the IPv4 address mapped.
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (UDP_HEADER_SIZE + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo.extend_from_slice(&udp_len.to_be_bytes());
+            set_udp_checksum(&mut udp, &pseudo);
+
+            let total_len = (IPV4_HEADER_SIZE + udp.len()) as u16;
+            let mut packet = Vec::with_capacity(total_len as usize);
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            // identification 0, don't fragment
+            packet.extend_from_slice(&[0, 0, 0x40, 0, TTL, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let checksum = internet_checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&udp);
+            packet
+        }
+        (s, d) => {
+            let s = to_v6(s).octets();
+            let d = to_v6(d).octets();
+
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&s);
+            pseudo.extend_from_slice(&d);
+            pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            set_udp_checksum(&mut udp, &pseudo);
+
+            let mut packet = Vec::with_capacity(IPV6_HEADER_SIZE + udp.len());
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[IPPROTO_UDP, TTL]);
+            packet.extend_from_slice(&s);
+            packet.extend_from_slice(&d);
+            packet.extend_from_slice(&udp);
+            packet
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let checksum = match internet_checksum(&[pseudo_header, udp]) {
+        // 0 means "no checksum" in UDP, so a computed 0 is sent as all ones
+        0 => 0xffff,
+        c => c,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// RFC 1071 ones' complement sum over the concatenation of `parts`
+///
+/// Every part except the last must have even length.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            let word = match chunk {
+                [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+                [hi] => u16::from_be_bytes([*hi, 0]),
+                _ => unreachable!(),
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A datagram waiting to be written
+struct Captured {
+    ts: SystemTime,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: Vec<u8>,
+}
+
+/// Cheap handle for recording datagrams from the server's tasks
+#[derive(Debug, Clone)]
+pub struct PacketCapture {
+    tx: mpsc::Sender<Captured>,
+}
+
+impl PacketCapture {
+    /// Queue a datagram for the writer, dropping it if the writer is behind
+    pub fn record(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        let _ = self.tx.try_send(Captured {
+            ts: SystemTime::now(),
+            src,
+            dst,
+            payload: payload.to_vec(),
+        });
+    }
+}
+
+/// Start writing captured datagrams to `sink`
+///
+/// Runs on a blocking thread. The task ends once every [`PacketCapture`] is
+/// dropped, the size limit is reached, or the sink fails.
+pub fn spawn_capture<W>(sink: W, max_bytes: u64) -> std::io::Result<(PacketCapture, JoinHandle<()>)>
+where
+    W: Write + Send + 'static,
+{
+    let mut writer = PcapWriter::new(sink, max_bytes)?;
+    let (tx, mut rx) = mpsc::channel::<Captured>(CAPTURE_QUEUE_CAPACITY);
+
+    let task = tokio::task::spawn_blocking(move || {
+        while let Some(c) = rx.blocking_recv() {
+            match writer.write_datagram(c.ts, c.src, c.dst, &c.payload) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("Packet capture reached {} bytes, stopping", max_bytes);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Packet capture write failed, stopping: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok((PacketCapture { tx }, task))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    /// Parse records back into (src, dst, payload), checking every checksum
+    fn read_datagrams(file: &[u8]) -> Vec<(SocketAddr, SocketAddr, Vec<u8>)> {
+        assert_eq!(&file[0..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&file[20..24], &LINKTYPE_RAW.to_le_bytes());
+
+        let mut datagrams = Vec::new();
+        let mut rest = &file[PCAP_GLOBAL_HEADER_SIZE as usize..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            let packet = &rest[PCAP_RECORD_HEADER_SIZE..PCAP_RECORD_HEADER_SIZE + len];
+            rest = &rest[PCAP_RECORD_HEADER_SIZE + len..];
+
+            let (src_ip, dst_ip, udp): (IpAddr, IpAddr, &[u8]) = match packet[0] >> 4 {
+                4 => {
+                    assert_eq!(internet_checksum(&[&packet[..IPV4_HEADER_SIZE]]), 0);
+                    let src: [u8; 4] = packet[12..16].try_into().unwrap();
+                    let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+                    (src.into(), dst.into(), &packet[IPV4_HEADER_SIZE..])
+                }
+                6 => {
+                    let src: [u8; 16] = packet[8..24].try_into().unwrap();
+                    let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+                    (src.into(), dst.into(), &packet[IPV6_HEADER_SIZE..])
+                }
+                v => panic!("bad IP version {}", v),
+            };
+            let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+            let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+            datagrams.push((
+                SocketAddr::new(src_ip, src_port),
+                SocketAddr::new(dst_ip, dst_port),
+                udp[UDP_HEADER_SIZE..].to_vec(),
+            ));
+        }
+        datagrams
+    }
+
+    #[test]
+    fn records_parse_back_to_original_datagrams() {
+        let server: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let client: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let client6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 40001);
+        let request = [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 1, 2, 3];
+        let response = [0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42];
+
+        let mut writer = PcapWriter::new(Vec::new(), DEFAULT_PCAP_MAX_BYTES).unwrap();
+        let now = SystemTime::now();
+        assert!(
+            writer
+                .write_datagram(now, client, server, &request)
+                .unwrap()
+        );
+        assert!(
+            writer
+                .write_datagram(now, server, client, &response)
+                .unwrap()
+        );
+        assert!(
+            writer
+                .write_datagram(now, client6, server, &request)
+                .unwrap()
+        );
+        let file = writer.sink;
+
+        let mapped = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().into(), 3478);
+        assert_eq!(
+            read_datagrams(&file),
+            [
+                (client, server, request.to_vec()),
+                (server, client, response.to_vec()),
+                (client6, mapped, request.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_size_limit() {
+        let addr: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let mut writer = PcapWriter::new(Vec::new(), 100).unwrap();
+        // 24 header + 16 record + 28 IP/UDP + 20 payload = 88
+        assert!(
+            writer
+                .write_datagram(SystemTime::now(), addr, addr, &[0; 20])
+                .unwrap()
+        );
+        assert!(
+            !writer
+                .write_datagram(SystemTime::now(), addr, addr, &[0; 20])
+                .unwrap()
+        );
+        assert_eq!(writer.sink.len(), 88);
+    }
+}
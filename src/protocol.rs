@@ -1,5 +1,9 @@
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::Rng;
+use sha1::Sha1;
 use thiserror::Error;
 
 /// STUN protocol errors
@@ -16,14 +20,50 @@ pub enum StunError {
     #[error("invalid magic cookie: expected 0x{expected:08X}, got 0x{actual:08X}")]
     InvalidMagicCookie { expected: u32, actual: u32 },
 
+    #[error("not a STUN message: leading bits of type 0x{0:04X} are set")]
+    NotStun(u16),
+
     #[error("unknown message type: 0x{0:04X}")]
     UnknownMessageType(u16),
 
     #[error("unsupported message type: {0:?}")]
     UnsupportedMessageType(MessageType),
 
-    #[error("IPv6 is not supported yet")]
-    Ipv6NotSupported,
+    #[error("message length {declared} exceeds the {available} bytes after the header")]
+    LengthMismatch { declared: usize, available: usize },
+
+    #[error("malformed attribute 0x{0:04X}")]
+    MalformedAttribute(u16),
+
+    #[error("CHANGE-REQUEST received but no alternate address is configured")]
+    AlternateNotConfigured,
+
+    #[error("CHANGE-REQUEST received over TCP")]
+    ChangeRequestOverTcp,
+
+    #[error("unknown comprehension-required attributes: {0:04X?}")]
+    UnknownAttributes(Vec<u16>),
+
+    #[error("missing or invalid credentials")]
+    Unauthorized,
+
+    #[error("nonce is no longer valid")]
+    StaleNonce,
+
+    #[error("transaction id must be {TRANSACTION_ID_SIZE} bytes, got {0}")]
+    InvalidTransactionId(usize),
+
+    #[error("response lacks attribute 0x{0:04X}")]
+    MissingAttribute(u16),
+
+    #[error("server answered with error {code}: {reason}")]
+    ErrorResponse { code: u16, reason: String },
+
+    #[error("no response from the server")]
+    Timeout,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
 /// STUN Magic Cookie (RFC 5389)
@@ -32,23 +72,194 @@ pub const MAGIC_COOKIE: u32 = 0x2112A442;
 /// STUN Header size in bytes
 pub const HEADER_SIZE: usize = 20;
 
-/// Binding Response size: 20 (header) + 12 (XOR-MAPPED-ADDRESS for IPv4)
-pub const BINDING_RESPONSE_SIZE: usize = 32;
+/// Transaction id size in bytes
+pub const TRANSACTION_ID_SIZE: usize = 12;
+
+/// Binding Response size for an IPv4 client: 20 (header) + 12 (XOR-MAPPED-ADDRESS)
+pub const BINDING_RESPONSE_SIZE_V4: usize = 32;
+
+/// Binding Response size for an IPv6 client: 20 (header) + 24 (XOR-MAPPED-ADDRESS)
+pub const BINDING_RESPONSE_SIZE_V6: usize = 44;
+
+/// Largest response we build: fits the 576-byte IPv4 minimum MTU (RFC 5389 section 7.1)
+pub const MAX_RESPONSE_SIZE: usize = 548;
+
+/// Attribute header size in bytes (type + length)
+pub const ATTR_HEADER_SIZE: usize = 4;
+
+/// MAPPED-ADDRESS attribute (RFC 3489), the client address without XOR
+pub const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+
+/// CHANGE-REQUEST attribute (RFC 5780)
+pub const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+
+/// XOR-MAPPED-ADDRESS attribute (RFC 5389)
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// RESPONSE-ORIGIN attribute (RFC 5780)
+pub const ATTR_RESPONSE_ORIGIN: u16 = 0x802B;
+
+/// OTHER-ADDRESS attribute (RFC 5780)
+pub const ATTR_OTHER_ADDRESS: u16 = 0x802C;
+
+/// USERNAME attribute (RFC 5389)
+pub const ATTR_USERNAME: u16 = 0x0006;
+
+/// MESSAGE-INTEGRITY attribute (RFC 5389): HMAC-SHA1 of the preceding message
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+
+/// Size of the MESSAGE-INTEGRITY attribute, header included
+pub const MESSAGE_INTEGRITY_SIZE: usize = 24;
+
+/// REALM attribute (RFC 5389)
+pub const ATTR_REALM: u16 = 0x0014;
+
+/// NONCE attribute (RFC 5389)
+pub const ATTR_NONCE: u16 = 0x0015;
+
+/// SOFTWARE attribute (RFC 8489): free-form description of the server
+pub const ATTR_SOFTWARE: u16 = 0x8022;
+
+/// ALTERNATE-SERVER attribute (RFC 5389): where a 300 sends the client
+pub const ATTR_ALTERNATE_SERVER: u16 = 0x8023;
+
+/// ERROR-CODE attribute (RFC 5389)
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+
+/// UNKNOWN-ATTRIBUTES attribute (RFC 5389)
+pub const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+
+/// FINGERPRINT attribute (RFC 5389): CRC-32 of the message, always last
+pub const ATTR_FINGERPRINT: u16 = 0x8028;
+
+/// Size of the FINGERPRINT attribute, header included
+pub const FINGERPRINT_SIZE: usize = 8;
+
+/// XORed into the CRC-32 so FINGERPRINT can't match another protocol's CRC
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+/// PADDING attribute (RFC 5780)
+pub const ATTR_PADDING: u16 = 0x0026;
+
+/// RESPONSE-SIZE attribute: desired total size of the binding response, as a
+/// 16-bit value. Private to this server, in the comprehension-optional range
+/// so other servers ignore it.
+pub const ATTR_RESPONSE_SIZE: u16 = 0xC001;
+
+/// CHANGE-REQUEST flag: respond from the alternate IP
+const CHANGE_IP_FLAG: u32 = 0x04;
+
+/// CHANGE-REQUEST flag: respond from the alternate port
+const CHANGE_PORT_FLAG: u32 = 0x02;
+
+/// PRIORITY attribute (RFC 8445)
+pub const ATTR_PRIORITY: u16 = 0x0024;
+
+/// USE-CANDIDATE attribute (RFC 8445)
+pub const ATTR_USE_CANDIDATE: u16 = 0x0025;
+
+/// ICE-CONTROLLED attribute (RFC 8445)
+pub const ATTR_ICE_CONTROLLED: u16 = 0x8029;
+
+/// ICE-CONTROLLING attribute (RFC 8445)
+pub const ATTR_ICE_CONTROLLING: u16 = 0x802A;
+
+/// Comprehension-required attributes (below 0x8000) this server understands
+/// or knowingly ignores; any other in a request is answered with a 420
+const KNOWN_REQUIRED_ATTRIBUTES: &[u16] = &[
+    ATTR_MAPPED_ADDRESS,
+    ATTR_CHANGE_REQUEST,
+    ATTR_USERNAME,
+    ATTR_MESSAGE_INTEGRITY,
+    ATTR_ERROR_CODE,
+    ATTR_UNKNOWN_ATTRIBUTES,
+    ATTR_REALM,
+    ATTR_NONCE,
+    0x001C, // MESSAGE-INTEGRITY-SHA256
+    0x001D, // PASSWORD-ALGORITHM
+    0x001E, // USERHASH
+    ATTR_XOR_MAPPED_ADDRESS,
+    ATTR_PRIORITY,
+    ATTR_USE_CANDIDATE,
+    ATTR_PADDING,
+    0x0027, // RESPONSE-PORT
+];
+
+/// Most attribute types listed in one UNKNOWN-ATTRIBUTES
+const MAX_UNKNOWN_ATTRIBUTES: usize = 16;
+
+/// STUN transaction id: 96 bits a client picks to match responses to its
+/// requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransactionId([u8; TRANSACTION_ID_SIZE]);
+
+impl TransactionId {
+    pub const fn new(bytes: [u8; TRANSACTION_ID_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    /// A random id, for requests this side originates
+    ///
+    /// Drawn from a cryptographically secure generator: the id is all that
+    /// ties a response to its request, so an off-path attacker must not be
+    /// able to predict it.
+    pub fn generate() -> Self {
+        Self(rand::rng().random())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; TRANSACTION_ID_SIZE] {
+        &self.0
+    }
+}
+
+impl From<[u8; TRANSACTION_ID_SIZE]> for TransactionId {
+    fn from(bytes: [u8; TRANSACTION_ID_SIZE]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for TransactionId {
+    type Error = StunError;
+
+    /// # Errors
+    /// `StunError::InvalidTransactionId` unless `bytes` is exactly 12 bytes
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| StunError::InvalidTransactionId(bytes.len()))
+    }
+}
 
 /// STUN Request
 #[derive(Debug)]
 pub struct StunRequest<'a> {
     pub msg_type: MessageType,
-    pub transaction_id: &'a [u8],
+    pub transaction_id: TransactionId,
+    /// header and body, up to the declared length
+    message: &'a [u8],
 }
 
 impl<'a> StunRequest<'a> {
+    /// encode a Binding Request without attributes, as a client sends it
+    pub fn binding(transaction_id: TransactionId) -> [u8; HEADER_SIZE] {
+        let mut data = [0u8; HEADER_SIZE];
+        data[0..2].copy_from_slice(&MessageType::BindingRequest.to_u16().to_be_bytes());
+        data[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        data[8..HEADER_SIZE].copy_from_slice(transaction_id.as_bytes());
+        data
+    }
+
     /// Parse a STUN request from raw bytes
     ///
     /// # Errors
     /// - `StunError::MessageTooShort` - if data is less than 20 bytes
     /// - `StunError::InvalidMagicCookie` - if magic cookie doesn't match
+    /// - `StunError::NotStun` - if the two leading bits of the type are set
+    ///   (RFC 8489 section 5), i.e. the datagram belongs to another protocol
     /// - `StunError::UnknownMessageType` - if message type is not recognized
+    /// - `StunError::LengthMismatch` - if the header declares a longer body
+    ///   than the datagram carries
     #[inline]
     pub fn parse(data: &'a [u8]) -> Result<Self, StunError> {
         if data.len() < HEADER_SIZE {
@@ -59,6 +270,9 @@ impl<'a> StunRequest<'a> {
         }
 
         let msg_type_raw = u16::from_be_bytes([data[0], data[1]]);
+        if msg_type_raw & 0xC000 != 0 {
+            return Err(StunError::NotStun(msg_type_raw));
+        }
         let msg_type = MessageType::from_u16(msg_type_raw)
             .ok_or(StunError::UnknownMessageType(msg_type_raw))?;
 
@@ -70,11 +284,20 @@ impl<'a> StunRequest<'a> {
             });
         }
 
-        let transaction_id = &data[8..20];
+        let transaction_id = TransactionId::try_from(&data[8..HEADER_SIZE])?;
 
+        let declared = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let available = data.len() - HEADER_SIZE;
+        if declared > available {
+            return Err(StunError::LengthMismatch {
+                declared,
+                available,
+            });
+        }
         Ok(Self {
             msg_type,
             transaction_id,
+            message: &data[..HEADER_SIZE + declared],
         })
     }
 
@@ -82,51 +305,602 @@ impl<'a> StunRequest<'a> {
     pub fn is_binding_request(&self) -> bool {
         self.msg_type == MessageType::BindingRequest
     }
+
+    #[inline]
+    pub fn is_binding_indication(&self) -> bool {
+        self.msg_type == MessageType::BindingIndication
+    }
+
+    /// iterate over the attributes following the header
+    ///
+    /// Yields `(type, value)` pairs, skipping each value's padding, up to the
+    /// declared message length. An attribute running past the end, or whose
+    /// padding is cut off, yields `StunError::MalformedAttribute` and ends the
+    /// iteration.
+    #[inline]
+    pub fn attributes(&self) -> StunAttributeIter<'a> {
+        StunAttributeIter {
+            remaining: &self.message[HEADER_SIZE..],
+        }
+    }
+
+    /// value of the first attribute of `attr_type`, if present
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute before it is truncated
+    pub fn attribute(&self, attr_type: u16) -> Result<Option<&'a [u8]>, StunError> {
+        for attr in self.attributes() {
+            let (t, value) = attr?;
+            if t == attr_type {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// check MESSAGE-INTEGRITY against `key`
+    ///
+    /// The HMAC covers the message up to the attribute, with the header length
+    /// rewritten to end just after it (RFC 5389 section 15.4). Returns `false`
+    /// if the request carries no MESSAGE-INTEGRITY or it doesn't match.
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated or
+    ///   MESSAGE-INTEGRITY isn't 20 bytes
+    pub fn verify_integrity(&self, key: &[u8]) -> Result<bool, StunError> {
+        let mut offset = HEADER_SIZE;
+        for attr in self.attributes() {
+            let (attr_type, value) = attr?;
+            if attr_type == ATTR_MESSAGE_INTEGRITY {
+                if value.len() != 20 {
+                    return Err(StunError::MalformedAttribute(attr_type));
+                }
+                let len = (offset + MESSAGE_INTEGRITY_SIZE - HEADER_SIZE) as u16;
+                let mut mac = hmac_sha1(key);
+                mac.update(&self.message[..2]);
+                mac.update(&len.to_be_bytes());
+                mac.update(&self.message[4..offset]);
+                return Ok(mac.verify_slice(value).is_ok());
+            }
+            offset += ATTR_HEADER_SIZE + ((value.len() + 3) & !3);
+        }
+        Ok(false)
+    }
+
+    /// collect the ICE connectivity-check attributes carried by the request
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated or an
+    ///   ICE attribute has the wrong length
+    pub fn ice_attributes(&self) -> Result<IceAttributes, StunError> {
+        let mut ice = IceAttributes::default();
+
+        for attr in self.attributes() {
+            let (attr_type, value) = attr?;
+            match attr_type {
+                ATTR_PRIORITY => {
+                    let bytes: [u8; 4] = value
+                        .try_into()
+                        .map_err(|_| StunError::MalformedAttribute(attr_type))?;
+                    ice.priority = Some(u32::from_be_bytes(bytes));
+                }
+                ATTR_USE_CANDIDATE => {
+                    if !value.is_empty() {
+                        return Err(StunError::MalformedAttribute(attr_type));
+                    }
+                    ice.use_candidate = true;
+                }
+                ATTR_ICE_CONTROLLED | ATTR_ICE_CONTROLLING => {
+                    let bytes: [u8; 8] = value
+                        .try_into()
+                        .map_err(|_| StunError::MalformedAttribute(attr_type))?;
+                    let tie_breaker = u64::from_be_bytes(bytes);
+                    ice.role = Some(if attr_type == ATTR_ICE_CONTROLLING {
+                        IceRole::Controlling(tie_breaker)
+                    } else {
+                        IceRole::Controlled(tie_breaker)
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(ice)
+    }
+
+    /// the CHANGE-REQUEST flags, if the request carries the attribute
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated or
+    ///   CHANGE-REQUEST isn't 4 bytes
+    pub fn change_request(&self) -> Result<Option<ChangeRequest>, StunError> {
+        for attr in self.attributes() {
+            let (attr_type, value) = attr?;
+            if attr_type == ATTR_CHANGE_REQUEST {
+                let bytes: [u8; 4] = value
+                    .try_into()
+                    .map_err(|_| StunError::MalformedAttribute(attr_type))?;
+                let flags = u32::from_be_bytes(bytes);
+                return Ok(Some(ChangeRequest {
+                    change_ip: flags & CHANGE_IP_FLAG != 0,
+                    change_port: flags & CHANGE_PORT_FLAG != 0,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// the address XOR-MAPPED-ADDRESS reports, if the response carries it
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated or
+    ///   XOR-MAPPED-ADDRESS has an unknown family or the wrong length
+    pub fn xor_mapped_address(&self) -> Result<Option<SocketAddr>, StunError> {
+        let Some(value) = self.attribute(ATTR_XOR_MAPPED_ADDRESS)? else {
+            return Ok(None);
+        };
+        let malformed = || StunError::MalformedAttribute(ATTR_XOR_MAPPED_ADDRESS);
+        let (header, xored) = value.split_at_checked(4).ok_or_else(malformed)?;
+        let port = u16::from_be_bytes([header[2], header[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+
+        // IPv4 is keyed by the cookie alone, IPv6 by the cookie and the
+        // transaction id
+        let mut key = [0u8; 16];
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(self.transaction_id.as_bytes());
+        let mut ip = [0u8; 16];
+        for ((out, b), k) in ip.iter_mut().zip(xored).zip(key) {
+            *out = b ^ k;
+        }
+
+        match (header[1], xored.len()) {
+            (0x01, 4) => Ok(Some(SocketAddr::from(([ip[0], ip[1], ip[2], ip[3]], port)))),
+            (0x02, 16) => Ok(Some(SocketAddr::from((ip, port)))),
+            _ => Err(malformed()),
+        }
+    }
+
+    /// the code and reason phrase of ERROR-CODE, if the response carries it
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated or
+    ///   ERROR-CODE is shorter than 4 bytes or its reason isn't UTF-8
+    pub fn error_code(&self) -> Result<Option<(u16, &'a str)>, StunError> {
+        let Some(value) = self.attribute(ATTR_ERROR_CODE)? else {
+            return Ok(None);
+        };
+        let malformed = || StunError::MalformedAttribute(ATTR_ERROR_CODE);
+        let (header, reason) = value.split_at_checked(4).ok_or_else(malformed)?;
+        let code = (header[2] & 0x07) as u16 * 100 + header[3] as u16;
+        let reason = std::str::from_utf8(reason).map_err(|_| malformed())?;
+        Ok(Some((code, reason)))
+    }
+
+    /// the desired response size from RESPONSE-SIZE, if the request carries it
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated or
+    ///   RESPONSE-SIZE isn't 2 bytes
+    pub fn response_size(&self) -> Result<Option<u16>, StunError> {
+        for attr in self.attributes() {
+            let (attr_type, value) = attr?;
+            if attr_type == ATTR_RESPONSE_SIZE {
+                let bytes: [u8; 2] = value
+                    .try_into()
+                    .map_err(|_| StunError::MalformedAttribute(attr_type))?;
+                return Ok(Some(u16::from_be_bytes(bytes)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// check the attribute list is well formed and only carries
+    /// comprehension-required attributes this server knows
+    ///
+    /// # Errors
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated
+    /// - `StunError::UnknownAttributes` - listing the unknown types (at most
+    ///   16, each once)
+    pub fn check_attributes(&self) -> Result<(), StunError> {
+        let mut unknown = Vec::new();
+        for attr in self.attributes() {
+            let (attr_type, _) = attr?;
+            if attr_type < 0x8000
+                && !KNOWN_REQUIRED_ATTRIBUTES.contains(&attr_type)
+                && !unknown.contains(&attr_type)
+                && unknown.len() < MAX_UNKNOWN_ATTRIBUTES
+            {
+                unknown.push(attr_type);
+            }
+        }
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(StunError::UnknownAttributes(unknown))
+        }
+    }
+}
+
+/// long-term credential key: MD5 of `username:realm:password` (RFC 5389 section 15.4)
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+    let mut md5 = Md5::new();
+    md5.update(username.as_bytes());
+    md5.update(b":");
+    md5.update(realm.as_bytes());
+    md5.update(b":");
+    md5.update(password.as_bytes());
+    md5.finalize().into()
+}
+
+fn hmac_sha1(key: &[u8]) -> Hmac<Sha1> {
+    Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+/// CRC-32 lookup table (IEEE, reflected polynomial 0xEDB88320)
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// FINGERPRINT value of a message: its CRC-32 XORed with 0x5354554E
+fn fingerprint(message: &[u8]) -> u32 {
+    let crc = message.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc ^ FINGERPRINT_XOR
+}
+
+/// CHANGE-REQUEST flags (RFC 5780 section 7.2)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeRequest {
+    pub change_ip: bool,
+    pub change_port: bool,
+}
+
+impl ChangeRequest {
+    /// encode as the 4-byte attribute value
+    pub fn to_bytes(self) -> [u8; 4] {
+        let mut flags = 0;
+        if self.change_ip {
+            flags |= CHANGE_IP_FLAG;
+        }
+        if self.change_port {
+            flags |= CHANGE_PORT_FLAG;
+        }
+        flags.to_be_bytes()
+    }
+}
+
+/// Iterator over the `(type, value)` attribute pairs of a STUN message
+///
+/// Values are yielded without their 4-byte alignment padding. Iteration stops
+/// after the first error.
+#[derive(Debug, Clone)]
+pub struct StunAttributeIter<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Iterator for StunAttributeIter<'a> {
+    type Item = Result<(u16, &'a [u8]), StunError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+
+        let data = std::mem::take(&mut self.remaining);
+        if data.len() < ATTR_HEADER_SIZE {
+            let attr_type = data
+                .get(..2)
+                .map_or(0, |b| u16::from_be_bytes([b[0], b[1]]));
+            return Some(Err(StunError::MalformedAttribute(attr_type)));
+        }
+
+        let attr_type = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let padded_len = (len + 3) & !3;
+
+        let body = &data[ATTR_HEADER_SIZE..];
+        if body.len() < padded_len {
+            return Some(Err(StunError::MalformedAttribute(attr_type)));
+        }
+
+        self.remaining = &body[padded_len..];
+        Some(Ok((attr_type, &body[..len])))
+    }
+}
+
+/// ICE agent role signalled by ICE-CONTROLLED / ICE-CONTROLLING, with its tie-breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceRole {
+    Controlled(u64),
+    Controlling(u64),
+}
+
+/// ICE attributes carried by a connectivity check (RFC 8445 section 7.1)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IceAttributes {
+    pub priority: Option<u32>,
+    pub use_candidate: bool,
+    pub role: Option<IceRole>,
+}
+
+impl IceAttributes {
+    /// whether any ICE attribute was present
+    pub fn is_connectivity_check(&self) -> bool {
+        self.priority.is_some() || self.use_candidate || self.role.is_some()
+    }
+}
+
+/// A binding success response, as read back by a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedResponse {
+    pub transaction_id: TransactionId,
+    /// where the server saw the request come from, from XOR-MAPPED-ADDRESS
+    pub mapped_addr: SocketAddr,
 }
 
 /// STUN Response
 #[derive(Debug)]
 pub struct StunResponse {
-    buffer: [u8; BINDING_RESPONSE_SIZE],
+    buffer: [u8; MAX_RESPONSE_SIZE],
+    len: usize,
 }
 
 impl StunResponse {
+    /// parse a binding success response
+    ///
+    /// # Errors
+    /// - any header error of `StunRequest::parse`
+    /// - `StunError::UnsupportedMessageType` - if it isn't a Binding Response
+    /// - `StunError::MissingAttribute` - if it lacks XOR-MAPPED-ADDRESS
+    /// - `StunError::MalformedAttribute` - if an attribute is truncated or
+    ///   XOR-MAPPED-ADDRESS doesn't decode
+    pub fn parse(data: &[u8]) -> Result<ParsedResponse, StunError> {
+        let message = StunRequest::parse(data)?;
+        if message.msg_type != MessageType::BindingResponse {
+            return Err(StunError::UnsupportedMessageType(message.msg_type));
+        }
+        let mapped_addr = message
+            .xor_mapped_address()?
+            .ok_or(StunError::MissingAttribute(ATTR_XOR_MAPPED_ADDRESS))?;
+        Ok(ParsedResponse {
+            transaction_id: message.transaction_id,
+            mapped_addr,
+        })
+    }
+
     /// create a binding response
+    ///
+    /// IPv6 clients get a family 0x02 XOR-MAPPED-ADDRESS, with the address
+    /// XORed against the magic cookie followed by the transaction id.
     #[inline]
-    pub fn binding_response(transaction_id: &[u8], client_addr: SocketAddrV4) -> Self {
-        let mut buffer = [0u8; BINDING_RESPONSE_SIZE];
+    pub fn binding_response(transaction_id: TransactionId, client_addr: SocketAddr) -> Self {
+        let mut buffer = [0u8; MAX_RESPONSE_SIZE];
 
         buffer[0] = 0x01;
         buffer[1] = 0x01;
-        buffer[2] = 0x00;
-        buffer[3] = 0x0C;
         buffer[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
-        buffer[8..20].copy_from_slice(transaction_id);
+        buffer[8..20].copy_from_slice(transaction_id.as_bytes());
 
         buffer[20] = 0x00;
         buffer[21] = 0x20;
-        buffer[22] = 0x00;
-        buffer[23] = 0x08;
-        buffer[24] = 0x00;
-        buffer[25] = 0x01;
 
         let xor_port = client_addr.port() ^ ((MAGIC_COOKIE >> 16) as u16);
         buffer[26..28].copy_from_slice(&xor_port.to_be_bytes());
 
-        let ip_bytes = client_addr.ip().octets();
         let magic_bytes = MAGIC_COOKIE.to_be_bytes();
-        buffer[28] = ip_bytes[0] ^ magic_bytes[0];
-        buffer[29] = ip_bytes[1] ^ magic_bytes[1];
-        buffer[30] = ip_bytes[2] ^ magic_bytes[2];
-        buffer[31] = ip_bytes[3] ^ magic_bytes[3];
+        let len = match client_addr {
+            SocketAddr::V4(v4) => {
+                buffer[25] = 0x01;
+                let ip_bytes = v4.ip().octets();
+                buffer[28] = ip_bytes[0] ^ magic_bytes[0];
+                buffer[29] = ip_bytes[1] ^ magic_bytes[1];
+                buffer[30] = ip_bytes[2] ^ magic_bytes[2];
+                buffer[31] = ip_bytes[3] ^ magic_bytes[3];
+                BINDING_RESPONSE_SIZE_V4
+            }
+            SocketAddr::V6(v6) => {
+                buffer[25] = 0x02;
+                let ip_bytes = v6.ip().octets();
+                let key = magic_bytes.iter().chain(transaction_id.as_bytes());
+                for ((out, ip), k) in buffer[28..44].iter_mut().zip(ip_bytes).zip(key) {
+                    *out = ip ^ k;
+                }
+                BINDING_RESPONSE_SIZE_V6
+            }
+        };
 
-        Self { buffer }
+        let body_len = (len - HEADER_SIZE) as u16;
+        buffer[2..4].copy_from_slice(&body_len.to_be_bytes());
+        buffer[23] = (body_len - ATTR_HEADER_SIZE as u16) as u8;
+
+        Self { buffer, len }
+    }
+
+    /// create a binding error response carrying an ERROR-CODE attribute
+    ///
+    /// The code is `class * 100 + number` (e.g. 4 and 20 for 420); `reason`
+    /// is truncated to 127 bytes at a character boundary.
+    pub fn binding_error_response(
+        transaction_id: TransactionId,
+        class: u8,
+        number: u8,
+        reason: &str,
+    ) -> Self {
+        let mut buffer = [0u8; MAX_RESPONSE_SIZE];
+        buffer[0..2].copy_from_slice(&MessageType::BindingErrorResponse.to_u16().to_be_bytes());
+        buffer[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buffer[8..20].copy_from_slice(transaction_id.as_bytes());
+        let mut response = Self {
+            buffer,
+            len: HEADER_SIZE,
+        };
+
+        let mut end = reason.len().min(127);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        // 21 reserved bits, 3 bits of class, 8 bits of number
+        let mut value = [0u8; 4 + 127];
+        value[2] = class & 0x07;
+        value[3] = number;
+        value[4..4 + end].copy_from_slice(&reason.as_bytes()[..end]);
+        response.push_attribute(ATTR_ERROR_CODE, &value[..4 + end]);
+        response
+    }
+
+    /// append an UNKNOWN-ATTRIBUTES attribute listing `types` (for a 420)
+    pub fn with_unknown_attributes(mut self, types: &[u16]) -> Self {
+        let mut value = [0u8; 2 * MAX_UNKNOWN_ATTRIBUTES];
+        let count = types.len().min(MAX_UNKNOWN_ATTRIBUTES);
+        for (chunk, attr_type) in value.chunks_exact_mut(2).zip(&types[..count]) {
+            chunk.copy_from_slice(&attr_type.to_be_bytes());
+        }
+        self.push_attribute(ATTR_UNKNOWN_ATTRIBUTES, &value[..2 * count]);
+        self
+    }
+
+    /// append a MAPPED-ADDRESS attribute, for RFC 3489 clients that don't
+    /// understand XOR-MAPPED-ADDRESS
+    pub fn with_mapped_address(mut self, addr: SocketAddr) -> Self {
+        self.push_address(ATTR_MAPPED_ADDRESS, addr);
+        self
+    }
+
+    /// append a RESPONSE-ORIGIN attribute (the address the response is sent from)
+    pub fn with_response_origin(mut self, addr: SocketAddr) -> Self {
+        self.push_address(ATTR_RESPONSE_ORIGIN, addr);
+        self
+    }
+
+    /// append an OTHER-ADDRESS attribute (the alternate IP and port, RFC 5780)
+    pub fn with_other_address(mut self, addr: SocketAddr) -> Self {
+        self.push_address(ATTR_OTHER_ADDRESS, addr);
+        self
+    }
+
+    /// append an ALTERNATE-SERVER attribute (the server to retry at, for a
+    /// 300)
+    pub fn with_alternate_server(mut self, addr: SocketAddr) -> Self {
+        self.push_address(ATTR_ALTERNATE_SERVER, addr);
+        self
+    }
+
+    /// append a SOFTWARE attribute describing the server
+    pub fn with_software(mut self, software: &str) -> Self {
+        self.push_attribute(ATTR_SOFTWARE, software.as_bytes());
+        self
+    }
+
+    /// append a REALM attribute
+    pub fn with_realm(mut self, realm: &str) -> Self {
+        self.push_attribute(ATTR_REALM, realm.as_bytes());
+        self
+    }
+
+    /// append a NONCE attribute
+    pub fn with_nonce(mut self, nonce: &str) -> Self {
+        self.push_attribute(ATTR_NONCE, nonce.as_bytes());
+        self
+    }
+
+    /// append a MESSAGE-INTEGRITY attribute keyed with `key`
+    ///
+    /// Only FINGERPRINT may follow it.
+    pub fn with_message_integrity(mut self, key: &[u8]) -> Self {
+        let body_len = (self.len + MESSAGE_INTEGRITY_SIZE - HEADER_SIZE) as u16;
+        self.buffer[2..4].copy_from_slice(&body_len.to_be_bytes());
+        let mut mac = hmac_sha1(key);
+        mac.update(&self.buffer[..self.len]);
+        let digest = mac.finalize().into_bytes();
+        self.push_attribute(ATTR_MESSAGE_INTEGRITY, &digest);
+        self
+    }
+
+    /// append a FINGERPRINT attribute, letting clients demultiplex STUN from
+    /// other protocols on the same port
+    ///
+    /// Must be the last attribute added.
+    pub fn with_fingerprint(mut self) -> Self {
+        // the CRC covers the header with its length already including FINGERPRINT
+        let body_len = (self.len + FINGERPRINT_SIZE - HEADER_SIZE) as u16;
+        self.buffer[2..4].copy_from_slice(&body_len.to_be_bytes());
+        let crc = fingerprint(&self.buffer[..self.len]);
+        self.push_attribute(ATTR_FINGERPRINT, &crc.to_be_bytes());
+        self
+    }
+
+    /// append a PADDING attribute so the response is `size` bytes long
+    ///
+    /// `size` is capped at `MAX_RESPONSE_SIZE`, which also bounds how much a
+    /// small request can amplify, and rounded down to a multiple of 4. No
+    /// padding is added if the response is already too long to reach it.
+    pub fn with_padding_to(mut self, size: usize) -> Self {
+        const ZEROS: [u8; MAX_RESPONSE_SIZE] = [0; MAX_RESPONSE_SIZE];
+
+        let target = size.min(MAX_RESPONSE_SIZE) & !3;
+        if target >= self.len + ATTR_HEADER_SIZE {
+            self.push_attribute(ATTR_PADDING, &ZEROS[..target - self.len - ATTR_HEADER_SIZE]);
+        }
+        self
     }
 
     /// return the response bytes slice
     #[inline]
     pub fn as_bytes(&self) -> &[u8] {
-        &self.buffer
+        &self.buffer[..self.len]
+    }
+
+    /// append a plain (non-XOR) address attribute, MAPPED-ADDRESS style
+    fn push_address(&mut self, attr_type: u16, addr: SocketAddr) {
+        let mut value = [0u8; 20];
+        value[2..4].copy_from_slice(&addr.port().to_be_bytes());
+        let len = match addr {
+            SocketAddr::V4(v4) => {
+                value[1] = 0x01;
+                value[4..8].copy_from_slice(&v4.ip().octets());
+                8
+            }
+            SocketAddr::V6(v6) => {
+                value[1] = 0x02;
+                value[4..20].copy_from_slice(&v6.ip().octets());
+                20
+            }
+        };
+        self.push_attribute(attr_type, &value[..len]);
+    }
+
+    /// append an attribute, padding it to 4 bytes and growing the header length
+    fn push_attribute(&mut self, attr_type: u16, value: &[u8]) {
+        let start = self.len;
+        let end = start + ATTR_HEADER_SIZE + ((value.len() + 3) & !3);
+        assert!(end <= MAX_RESPONSE_SIZE, "STUN response exceeds buffer");
+
+        self.buffer[start..start + 2].copy_from_slice(&attr_type.to_be_bytes());
+        self.buffer[start + 2..start + 4].copy_from_slice(&(value.len() as u16).to_be_bytes());
+        self.buffer[start + ATTR_HEADER_SIZE..start + ATTR_HEADER_SIZE + value.len()]
+            .copy_from_slice(value);
+        self.buffer[start + ATTR_HEADER_SIZE + value.len()..end].fill(0);
+        self.len = end;
+
+        let body_len = (self.len - HEADER_SIZE) as u16;
+        self.buffer[2..4].copy_from_slice(&body_len.to_be_bytes());
     }
 }
 
@@ -134,6 +908,8 @@ impl StunResponse {
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MessageType {
     BindingRequest,
+    /// Sent to keep a NAT mapping alive; never answered
+    BindingIndication,
     BindingResponse,
     BindingErrorResponse,
 }
@@ -142,6 +918,7 @@ impl MessageType {
     pub fn from_u16(value: u16) -> Option<Self> {
         match value {
             0x0001 => Some(MessageType::BindingRequest),
+            0x0011 => Some(MessageType::BindingIndication),
             0x0101 => Some(MessageType::BindingResponse),
             0x0111 => Some(MessageType::BindingErrorResponse),
             _ => None,
@@ -151,8 +928,561 @@ impl MessageType {
     pub fn to_u16(self) -> u16 {
         match self {
             MessageType::BindingRequest => 0x0001,
+            MessageType::BindingIndication => 0x0011,
             MessageType::BindingResponse => 0x0101,
             MessageType::BindingErrorResponse => 0x0111,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+    use super::*;
+
+    const TID: TransactionId = TransactionId::new(*b"TRANSACTION1");
+
+    fn request_with_attributes(attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0x00, 0x01, 0x00, 0x00];
+        data.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        data.extend_from_slice(b"TRANSACTION1");
+
+        for (attr_type, value) in attrs {
+            data.extend_from_slice(&attr_type.to_be_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            data.extend_from_slice(value);
+            data.resize((data.len() + 3) & !3, 0);
+        }
+
+        let body_len = (data.len() - HEADER_SIZE) as u16;
+        data[2..4].copy_from_slice(&body_len.to_be_bytes());
+        data
+    }
+
+    /// decode an ERROR-CODE value into (code, reason)
+    fn error_code(value: &[u8]) -> (u16, &str) {
+        let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+        (code, std::str::from_utf8(&value[4..]).unwrap())
+    }
+
+    #[test]
+    fn error_response_round_trips_code_and_reason() {
+        // 11 bytes: the attribute needs a byte of padding
+        let response = StunResponse::binding_error_response(TID, 4, 20, "Unknown Bar")
+            .with_unknown_attributes(&[0x0042, 0x7001, 0x0003]);
+        let bytes = response.as_bytes();
+        assert_eq!(bytes.len() % 4, 0);
+
+        let parsed = StunRequest::parse(bytes).unwrap();
+        assert_eq!(parsed.msg_type, MessageType::BindingErrorResponse);
+        assert_eq!(parsed.transaction_id, TID);
+
+        let attrs: Vec<_> = parsed.attributes().map(Result::unwrap).collect();
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs[0].0, ATTR_ERROR_CODE);
+        assert_eq!(error_code(attrs[0].1), (420, "Unknown Bar"));
+        assert_eq!(parsed.error_code().unwrap(), Some((420, "Unknown Bar")));
+        assert_eq!(attrs[1].0, ATTR_UNKNOWN_ATTRIBUTES);
+        assert_eq!(attrs[1].1, [0x00, 0x42, 0x70, 0x01, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn try_alternate_carries_a_plain_alternate_server() {
+        let alternate: SocketAddr = "198.51.100.7:3478".parse().unwrap();
+        let response = StunResponse::binding_error_response(TID, 3, 0, "Try Alternate")
+            .with_alternate_server(alternate);
+        let parsed = StunRequest::parse(response.as_bytes()).unwrap();
+        assert_eq!(parsed.error_code().unwrap(), Some((300, "Try Alternate")));
+        // Like MAPPED-ADDRESS, not XORed
+        assert_eq!(
+            parsed.attribute(ATTR_ALTERNATE_SERVER).unwrap().unwrap(),
+            [0x00, 0x01, 0x0D, 0x96, 198, 51, 100, 7]
+        );
+
+        let alternate: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+        let response = StunResponse::binding_error_response(TID, 3, 0, "Try Alternate")
+            .with_alternate_server(alternate);
+        let parsed = StunRequest::parse(response.as_bytes()).unwrap();
+        let value = parsed.attribute(ATTR_ALTERNATE_SERVER).unwrap().unwrap();
+        assert_eq!(value.len(), 20);
+        assert_eq!(value[1], 0x02);
+        assert_eq!(
+            value[4..],
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets()
+        );
+    }
+
+    #[test]
+    fn check_attributes_reports_unknown_required_types() {
+        let data = request_with_attributes(&[
+            (0x0006, b"user"),
+            (0x0042, &[1, 2]),
+            (0x8123, &[]),
+            (0x0042, &[]),
+        ]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert!(matches!(
+            request.check_attributes(),
+            Err(StunError::UnknownAttributes(ref types)) if *types == [0x0042]
+        ));
+
+        let mut truncated = request_with_attributes(&[(ATTR_PRIORITY, &[0; 4])]);
+        truncated[HEADER_SIZE + 3] = 40;
+        let request = StunRequest::parse(&truncated).unwrap();
+        assert!(matches!(
+            request.check_attributes(),
+            Err(StunError::MalformedAttribute(_))
+        ));
+    }
+
+    #[test]
+    fn transaction_id_must_be_12_bytes() {
+        assert_eq!(TransactionId::try_from(&b"TRANSACTION1"[..]).unwrap(), TID);
+        for len in [0, 11, 13, 16] {
+            assert!(matches!(
+                TransactionId::try_from(&vec![0u8; len][..]),
+                Err(StunError::InvalidTransactionId(l)) if l == len
+            ));
+        }
+    }
+
+    #[test]
+    fn generated_transaction_ids_are_unique() {
+        let ids: std::collections::HashSet<_> =
+            (0..1000).map(|_| TransactionId::generate()).collect();
+        assert_eq!(ids.len(), 1000);
+    }
+
+    #[test]
+    fn parse_binding_indication() {
+        let mut data = vec![0x00, 0x11, 0x00, 0x00];
+        data.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        data.extend_from_slice(b"TRANSACTION1");
+
+        let parsed = StunRequest::parse(&data).unwrap();
+        assert_eq!(parsed.msg_type, MessageType::BindingIndication);
+        assert!(parsed.is_binding_indication());
+        assert!(!parsed.is_binding_request());
+        assert_eq!(MessageType::BindingIndication.to_u16(), 0x0011);
+    }
+
+    #[test]
+    fn parse_checks_declared_length() {
+        // zero-length body
+        let data = request_with_attributes(&[]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert_eq!(request.attributes().count(), 0);
+
+        // exact length
+        let data = request_with_attributes(&[(ATTR_PRIORITY, &[0; 4])]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert_eq!(request.attributes().count(), 1);
+
+        // over-declared: the body was cut short
+        assert!(matches!(
+            StunRequest::parse(&data[..data.len() - 4]),
+            Err(StunError::LengthMismatch {
+                declared: 8,
+                available: 4
+            })
+        ));
+
+        // bytes past the declared length are not attributes
+        let mut padded = request_with_attributes(&[]);
+        padded.extend_from_slice(&[0x00, 0x24, 0x00, 0x04, 0, 0, 0, 1]);
+        let request = StunRequest::parse(&padded).unwrap();
+        assert_eq!(request.attributes().count(), 0);
+    }
+
+    #[test]
+    fn parse_rejects_leading_bits_set() {
+        for first in [0x40, 0x80, 0xC0] {
+            let mut data = request_with_attributes(&[]);
+            data[0] |= first;
+            let raw = u16::from_be_bytes([data[0], data[1]]);
+            assert!(matches!(
+                StunRequest::parse(&data),
+                Err(StunError::NotStun(t)) if t == raw
+            ));
+        }
+    }
+
+    #[test]
+    fn attributes_walk_mixed_tlvs() {
+        let data = request_with_attributes(&[
+            (ATTR_SOFTWARE, b"carapace 1.0"),
+            (ATTR_USERNAME, b"alice:bob"),
+            (0x7F31, &[0xAB]),
+        ]);
+        let request = StunRequest::parse(&data).unwrap();
+        let attrs: Vec<_> = request.attributes().map(Result::unwrap).collect();
+        assert_eq!(
+            attrs,
+            [
+                (ATTR_SOFTWARE, &b"carapace 1.0"[..]),
+                (ATTR_USERNAME, &b"alice:bob"[..]),
+                (0x7F31, &[0xAB][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn attribute_overrunning_the_message_is_malformed() {
+        // the last value is intact but its padding was cut off
+        let mut data = request_with_attributes(&[(ATTR_SOFTWARE, b"ok"), (ATTR_USERNAME, b"bob")]);
+        data.truncate(data.len() - 1);
+        let body_len = (data.len() - HEADER_SIZE) as u16;
+        data[2..4].copy_from_slice(&body_len.to_be_bytes());
+
+        let request = StunRequest::parse(&data).unwrap();
+        let mut attrs = request.attributes();
+        assert_eq!(attrs.next().unwrap().unwrap(), (ATTR_SOFTWARE, &b"ok"[..]));
+        assert!(matches!(
+            attrs.next(),
+            Some(Err(StunError::MalformedAttribute(ATTR_USERNAME)))
+        ));
+        assert!(attrs.next().is_none());
+
+        // a length claiming more than remains
+        let mut data = request_with_attributes(&[(ATTR_USERNAME, b"bob")]);
+        data[HEADER_SIZE + 3] = 64;
+        let request = StunRequest::parse(&data).unwrap();
+        assert!(matches!(
+            request.attributes().next(),
+            Some(Err(StunError::MalformedAttribute(ATTR_USERNAME)))
+        ));
+    }
+
+    #[test]
+    fn parse_plain_binding_request_has_no_ice_attributes() {
+        let data = request_with_attributes(&[]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert_eq!(request.attributes().count(), 0);
+
+        let ice = request.ice_attributes().unwrap();
+        assert!(!ice.is_connectivity_check());
+    }
+
+    #[test]
+    fn parse_connectivity_check_attributes() {
+        let data = request_with_attributes(&[
+            (0x0006, b"remote:local"),
+            (ATTR_PRIORITY, &0x6E7F_00FFu32.to_be_bytes()),
+            (ATTR_USE_CANDIDATE, &[]),
+            (
+                ATTR_ICE_CONTROLLING,
+                &0x0102_0304_0506_0708u64.to_be_bytes(),
+            ),
+        ]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert!(request.is_binding_request());
+
+        let ice = request.ice_attributes().unwrap();
+        assert_eq!(ice.priority, Some(0x6E7F_00FF));
+        assert!(ice.use_candidate);
+        assert_eq!(ice.role, Some(IceRole::Controlling(0x0102_0304_0506_0708)));
+        assert!(ice.is_connectivity_check());
+    }
+
+    #[test]
+    fn parse_ice_controlled_role() {
+        let data = request_with_attributes(&[
+            (ATTR_PRIORITY, &1u32.to_be_bytes()),
+            (ATTR_ICE_CONTROLLED, &42u64.to_be_bytes()),
+        ]);
+        let request = StunRequest::parse(&data).unwrap();
+
+        let ice = request.ice_attributes().unwrap();
+        assert_eq!(ice.role, Some(IceRole::Controlled(42)));
+        assert!(!ice.use_candidate);
+    }
+
+    #[test]
+    fn parse_change_request_flags() {
+        let both = ChangeRequest {
+            change_ip: true,
+            change_port: true,
+        };
+        let data = request_with_attributes(&[(ATTR_CHANGE_REQUEST, &both.to_bytes())]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert_eq!(request.change_request().unwrap(), Some(both));
+
+        let data = request_with_attributes(&[(ATTR_CHANGE_REQUEST, &[0, 0, 0, 0x02])]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert_eq!(
+            request.change_request().unwrap(),
+            Some(ChangeRequest {
+                change_ip: false,
+                change_port: true,
+            })
+        );
+
+        let data = request_with_attributes(&[]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert_eq!(request.change_request().unwrap(), None);
+    }
+
+    #[test]
+    fn response_carries_origin_and_other_address() {
+        let client = SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 40000));
+        let origin: SocketAddr = "198.51.100.1:3479".parse().unwrap();
+        let other: SocketAddr = "198.51.100.2:3479".parse().unwrap();
+
+        let response = StunResponse::binding_response(TID, client)
+            .with_response_origin(origin)
+            .with_other_address(other);
+        let bytes = response.as_bytes();
+
+        assert_eq!(bytes.len(), BINDING_RESPONSE_SIZE_V4 + 24);
+        assert_eq!(
+            u16::from_be_bytes([bytes[2], bytes[3]]) as usize,
+            bytes.len() - HEADER_SIZE
+        );
+
+        assert_eq!(&bytes[32..36], &[0x80, 0x2B, 0x00, 0x08]);
+        assert_eq!(&bytes[36..44], &[0x00, 0x01, 0x0D, 0x97, 198, 51, 100, 1]);
+        assert_eq!(&bytes[44..48], &[0x80, 0x2C, 0x00, 0x08]);
+        assert_eq!(&bytes[48..56], &[0x00, 0x01, 0x0D, 0x97, 198, 51, 100, 2]);
+    }
+
+    /// decode the XOR-MAPPED-ADDRESS of a binding response
+    fn xor_mapped_address(bytes: &[u8]) -> SocketAddr {
+        StunResponse::parse(bytes).unwrap().mapped_addr
+    }
+
+    #[test]
+    fn legacy_mapped_address_is_not_xored() {
+        let client: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let response = StunResponse::binding_response(TID, client).with_mapped_address(client);
+        let bytes = response.as_bytes();
+
+        assert_eq!(bytes.len(), BINDING_RESPONSE_SIZE_V4 + 12);
+        assert_eq!(
+            u16::from_be_bytes([bytes[2], bytes[3]]) as usize,
+            bytes.len() - HEADER_SIZE
+        );
+        let parsed = StunRequest::parse(bytes).unwrap();
+        let (_, mapped) = parsed
+            .attributes()
+            .map(Result::unwrap)
+            .find(|(t, _)| *t == ATTR_MAPPED_ADDRESS)
+            .unwrap();
+        assert_eq!(mapped, &[0x00, 0x01, 0x80, 0x55, 192, 0, 2, 1]);
+        assert_eq!(xor_mapped_address(bytes), client);
+        // the same address, XORed
+        assert_eq!(
+            &bytes[24..32],
+            &[0x00, 0x01, 0xA1, 0x47, 0xE1, 0x12, 0xA6, 0x43]
+        );
+    }
+
+    #[test]
+    fn ipv6_client_gets_family_2_xor_mapped_address() {
+        let client = SocketAddr::from((
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0x1234, 0x5678, 0x9abc, 0xdef0),
+            40000,
+        ));
+        let response = StunResponse::binding_response(TID, client);
+        let bytes = response.as_bytes();
+
+        assert_eq!(bytes.len(), BINDING_RESPONSE_SIZE_V6);
+        assert_eq!(u16::from_be_bytes([bytes[2], bytes[3]]), 24);
+        assert_eq!(&bytes[20..26], &[0x00, 0x20, 0x00, 0x14, 0x00, 0x02]);
+        assert_eq!(xor_mapped_address(bytes), client);
+
+        // the first 4 bytes are keyed by the cookie, the rest by the transaction id
+        let ip = &bytes[28..44];
+        assert_eq!(
+            &ip[..4],
+            &[0x20 ^ 0x21, 0x01 ^ 0x12, 0x0d ^ 0xA4, 0xb8 ^ 0x42]
+        );
+        let expected: Vec<u8> = [0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0]
+            .iter()
+            .zip(b"TRANSACTION1")
+            .map(|(a, b)| a ^ b)
+            .collect();
+        assert_eq!(&ip[4..], expected.as_slice());
+    }
+
+    #[test]
+    fn parsed_response_round_trips_v4_and_v6() {
+        for client in [
+            SocketAddr::from((Ipv4Addr::new(198, 51, 100, 20), 61000)),
+            SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x42), 3478)),
+        ] {
+            let response = StunResponse::binding_response(TID, client);
+            let parsed = StunResponse::parse(response.as_bytes()).unwrap();
+            assert_eq!(
+                parsed,
+                ParsedResponse {
+                    transaction_id: TID,
+                    mapped_addr: client,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn response_parser_rejects_requests_and_bare_responses() {
+        let request = StunRequest::binding(TID);
+        assert!(matches!(
+            StunResponse::parse(&request),
+            Err(StunError::UnsupportedMessageType(
+                MessageType::BindingRequest
+            ))
+        ));
+
+        // a success header with no attributes
+        let mut bare = request;
+        bare[0..2].copy_from_slice(&MessageType::BindingResponse.to_u16().to_be_bytes());
+        assert!(matches!(
+            StunResponse::parse(&bare),
+            Err(StunError::MissingAttribute(ATTR_XOR_MAPPED_ADDRESS))
+        ));
+    }
+
+    #[test]
+    fn ipv4_client_round_trips() {
+        let client = SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 40000));
+        let response = StunResponse::binding_response(TID, client);
+        assert_eq!(response.as_bytes().len(), BINDING_RESPONSE_SIZE_V4);
+        assert_eq!(xor_mapped_address(response.as_bytes()), client);
+    }
+
+    #[test]
+    fn fingerprint_matches_rfc_5769_vector() {
+        assert_eq!(fingerprint(b"123456789") ^ FINGERPRINT_XOR, 0xCBF4_3926);
+
+        // RFC 5769 section 2.2: the sample IPv4 response
+        let vector: [u8; 80] = [
+            0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34,
+            0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74,
+            0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01,
+            0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99,
+            0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b,
+            0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+        ];
+        assert_eq!(fingerprint(&vector[..72]), 0xc07d_4c96);
+    }
+
+    #[test]
+    fn integrity_matches_rfc_5769_request() {
+        // RFC 5769 section 2.1: sample request with short-term credentials
+        let mut vector = vec![
+            0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34,
+            0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10,
+        ];
+        vector.extend_from_slice(b"STUN test client");
+        vector.extend_from_slice(&[
+            0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f,
+            0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36, 0x00, 0x06, 0x00, 0x09,
+        ]);
+        vector.extend_from_slice(b"evtj:h6vY   ");
+        vector.extend_from_slice(&[
+            0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e,
+            0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04,
+            0xe5, 0x7a, 0x3b, 0xcf,
+        ]);
+
+        let request = StunRequest::parse(&vector).unwrap();
+        assert_eq!(
+            request.attribute(ATTR_USERNAME).unwrap(),
+            Some(&b"evtj:h6vY"[..])
+        );
+        assert!(request.verify_integrity(b"VOkJxbRl1RmTxUk/WvJxBt").unwrap());
+        assert!(!request.verify_integrity(b"wrong password").unwrap());
+        assert_eq!(fingerprint(&vector[..vector.len() - 8]), 0xe57a_3bcf);
+    }
+
+    #[test]
+    fn response_integrity_verifies_with_its_key() {
+        let key = long_term_key("user", "example.org", "secret");
+        let client = SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 40000));
+        let response = StunResponse::binding_response(TID, client)
+            .with_message_integrity(&key)
+            .with_fingerprint();
+        let bytes = response.as_bytes();
+        assert_eq!(
+            bytes.len(),
+            BINDING_RESPONSE_SIZE_V4 + MESSAGE_INTEGRITY_SIZE + FINGERPRINT_SIZE
+        );
+
+        // FINGERPRINT after the HMAC doesn't disturb it
+        let parsed = StunRequest::parse(bytes).unwrap();
+        assert!(parsed.verify_integrity(&key).unwrap());
+
+        let mut tampered = bytes.to_vec();
+        tampered[30] ^= 1;
+        let parsed = StunRequest::parse(&tampered).unwrap();
+        assert!(!parsed.verify_integrity(&key).unwrap());
+
+        let unsigned = StunResponse::binding_response(TID, client);
+        let parsed = StunRequest::parse(unsigned.as_bytes()).unwrap();
+        assert!(!parsed.verify_integrity(&key).unwrap());
+    }
+
+    #[test]
+    fn fingerprint_is_appended_last() {
+        let client = SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 40000));
+        let response = StunResponse::binding_response(TID, client)
+            .with_software("carapace")
+            .with_fingerprint();
+        let bytes = response.as_bytes();
+
+        assert_eq!(
+            bytes.len(),
+            BINDING_RESPONSE_SIZE_V4 + 12 + FINGERPRINT_SIZE
+        );
+        assert_eq!(
+            u16::from_be_bytes([bytes[2], bytes[3]]) as usize,
+            bytes.len() - HEADER_SIZE
+        );
+        let (trailer, value) = bytes.split_at(bytes.len() - 4);
+        assert_eq!(&trailer[trailer.len() - 4..], &[0x80, 0x28, 0x00, 0x04]);
+        assert_eq!(
+            u32::from_be_bytes(value.try_into().unwrap()),
+            fingerprint(&bytes[..bytes.len() - FINGERPRINT_SIZE])
+        );
+    }
+
+    #[test]
+    fn padding_reaches_requested_size() {
+        let client = SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 40000));
+        let response = StunResponse::binding_response(TID, client).with_padding_to(200);
+        let bytes = response.as_bytes();
+
+        assert_eq!(bytes.len(), 200);
+        assert_eq!(u16::from_be_bytes([bytes[2], bytes[3]]), 180);
+        assert_eq!(&bytes[32..36], &[0x00, 0x26, 0x00, 164]);
+        assert!(bytes[36..].iter().all(|&b| b == 0));
+
+        let capped = StunResponse::binding_response(TID, client).with_padding_to(9000);
+        assert_eq!(capped.as_bytes().len(), MAX_RESPONSE_SIZE);
+
+        let too_small = StunResponse::binding_response(TID, client).with_padding_to(34);
+        assert_eq!(too_small.as_bytes().len(), BINDING_RESPONSE_SIZE_V4);
+    }
+
+    #[test]
+    fn parse_response_size() {
+        let data = request_with_attributes(&[(ATTR_RESPONSE_SIZE, &[0x01, 0x00])]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert_eq!(request.response_size().unwrap(), Some(256));
+
+        let data = request_with_attributes(&[(ATTR_RESPONSE_SIZE, &[0x01])]);
+        let request = StunRequest::parse(&data).unwrap();
+        assert!(request.response_size().is_err());
+    }
+
+    #[test]
+    fn ice_attribute_with_wrong_length_is_malformed() {
+        let data = request_with_attributes(&[(ATTR_PRIORITY, &[0x01, 0x02])]);
+        let request = StunRequest::parse(&data).unwrap();
+
+        assert!(matches!(
+            request.ice_attributes(),
+            Err(StunError::MalformedAttribute(ATTR_PRIORITY))
+        ));
+    }
+}
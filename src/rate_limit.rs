@@ -0,0 +1,106 @@
+//! Token-bucket rate limiting shared by the STUN and signaling servers
+
+use tokio::time::Instant;
+
+/// Sustained rate and burst allowance for a token bucket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// tokens refilled per second
+    pub per_second: f64,
+    /// bucket capacity, i.e. how many operations may happen back to back
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+/// Classic token bucket: starts full, refills continuously, one token per operation
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self::new_at(limit, Instant::now())
+    }
+
+    pub fn new_at(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Take a token if one is available
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    /// Take a token if one is available at `now`
+    pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket will have refilled completely by `now`, making it
+    /// indistinguishable from a new one
+    pub fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens + elapsed * self.limit.per_second >= self.limit.burst as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn burst_then_exhausted() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(RateLimit::new(1.0, 3), start);
+
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(!bucket.try_acquire_at(start));
+    }
+
+    #[test]
+    fn refills_over_time_up_to_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(RateLimit::new(2.0, 2), start);
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(!bucket.try_acquire_at(start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_acquire_at(later));
+        assert!(!bucket.try_acquire_at(later));
+
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.try_acquire_at(much_later));
+        assert!(bucket.try_acquire_at(much_later));
+        assert!(!bucket.try_acquire_at(much_later));
+    }
+}
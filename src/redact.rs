@@ -0,0 +1,137 @@
+//! Redaction of client addresses in log output
+
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// How client addresses appear in logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddrRedaction {
+    /// Log addresses as-is
+    #[default]
+    Full,
+    /// Zero the host part: the last octet of IPv4, all but the /48 of IPv6
+    Truncate,
+    /// Replace the IP with a keyed hash, stable for the life of the process
+    Hash,
+}
+
+impl AddrRedaction {
+    /// Wrap `addr` for display under this policy
+    #[inline]
+    pub fn redact(self, addr: SocketAddr) -> Redacted {
+        Redacted { addr, mode: self }
+    }
+}
+
+impl FromStr for AddrRedaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "truncate" => Ok(Self::Truncate),
+            "hash" => Ok(Self::Hash),
+            other => Err(format!(
+                "unknown redaction mode {:?} (expected full, truncate or hash)",
+                other
+            )),
+        }
+    }
+}
+
+/// A client address formatted according to an [`AddrRedaction`] policy
+///
+/// Formatting is deferred, so disabled log levels cost nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct Redacted {
+    addr: SocketAddr,
+    mode: AddrRedaction,
+}
+
+impl fmt::Display for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            AddrRedaction::Full => write!(f, "{}", self.addr),
+            AddrRedaction::Truncate => {
+                let masked = match self.addr.ip() {
+                    IpAddr::V4(v4) => {
+                        let [a, b, c, _] = v4.octets();
+                        IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+                    }
+                    IpAddr::V6(v6) => {
+                        let [a, b, c, ..] = v6.segments();
+                        IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+                    }
+                };
+                write!(f, "{}", SocketAddr::new(masked, self.addr.port()))
+            }
+            // Port is kept: it tells concurrent flows from one client apart
+            AddrRedaction::Hash => write!(
+                f,
+                "ip-{:016x}:{}",
+                hash_key().hash_one(self.addr.ip()),
+                self.addr.port()
+            ),
+        }
+    }
+}
+
+/// Per-process key: SipHash with random keys, so hashes can't be reversed by
+/// enumerating the address space offline
+fn hash_key() -> &'static RandomState {
+    static KEY: OnceLock<RandomState> = OnceLock::new();
+    KEY.get_or_init(RandomState::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_is_unchanged() {
+        let addr: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        assert_eq!(
+            AddrRedaction::Full.redact(addr).to_string(),
+            "203.0.113.7:5000"
+        );
+    }
+
+    #[test]
+    fn truncate_zeroes_host_bits() {
+        let v4: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        assert_eq!(
+            AddrRedaction::Truncate.redact(v4).to_string(),
+            "203.0.113.0:5000"
+        );
+
+        let v6: SocketAddr = "[2001:db8:1234:5678::1]:5000".parse().unwrap();
+        assert_eq!(
+            AddrRedaction::Truncate.redact(v6).to_string(),
+            "[2001:db8:1234::]:5000"
+        );
+    }
+
+    #[test]
+    fn hash_hides_ip_but_is_stable() {
+        let a: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        let b: SocketAddr = "203.0.113.7:6000".parse().unwrap();
+
+        let shown = AddrRedaction::Hash.redact(a).to_string();
+        assert!(!shown.contains("203.0.113"));
+        let (hash, port) = shown.rsplit_once(':').unwrap();
+        assert_eq!(port, "5000");
+        assert!(hash.starts_with("ip-") && hash.len() == 19);
+
+        let other = AddrRedaction::Hash.redact(b).to_string();
+        assert_eq!(other.rsplit_once(':').unwrap().0, hash);
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!("hash".parse(), Ok(AddrRedaction::Hash));
+        assert!("partial".parse::<AddrRedaction>().is_err());
+    }
+}
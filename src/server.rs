@@ -1,153 +1,2562 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use async_channel::{Receiver, Sender};
-use tokio::net::UdpSocket;
+use futures_util::future::try_join_all;
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, ToSocketAddrs, UdpSocket, lookup_host};
 use tracing::{debug, info, warn};
 
-use crate::protocol::{BINDING_RESPONSE_SIZE, StunError, StunRequest, StunResponse};
+use crate::metrics::{LatencyHistogram, StunMetrics};
+use crate::pcap::PacketCapture;
+use crate::protocol::{
+    ATTR_CHANGE_REQUEST, ATTR_NONCE, ATTR_REALM, ATTR_USERNAME, ChangeRequest, FINGERPRINT_SIZE,
+    MAX_RESPONSE_SIZE, MESSAGE_INTEGRITY_SIZE, StunError, StunRequest, StunResponse, long_term_key,
+};
+use crate::rate_limit::RateLimit;
+use crate::redact::AddrRedaction;
+use limiter::IpRateLimiter;
+use pool::{BufferPool, PooledBuf};
+
+#[cfg(all(target_os = "linux", feature = "recvmmsg"))]
+mod batch;
+mod limiter;
+mod pool;
+mod sockopt;
+mod tcp;
 
 pub const DEFAULT_PORT: u16 = 3478;
 
+/// Datagrams the receive tasks can queue for the workers by default
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// receive buffer size: a plain Binding Request is 20-48 bytes, but ICE
+/// connectivity checks (USERNAME, PRIORITY, MESSAGE-INTEGRITY, ...) run ~100 bytes
+const MAX_REQUEST_SIZE: usize = 256;
+
+/// socket slot bit: bound to the alternate port
+const ALT_PORT: usize = 0b01;
+
+/// socket slot bit: bound to the alternate IP
+const ALT_IP: usize = 0b10;
+
 /// work item to be sent to the worker
 struct WorkItem {
-    data: [u8; 64], // STUN request is usually 20-48 bytes
+    data: PooledBuf,
     len: usize,
     client_addr: SocketAddr,
+    /// slot of the socket the request arrived on
+    local: usize,
+}
+
+/// The server's bound sockets: a single one, or four for RFC 5780
+///
+/// With an alternate address configured, slot `i` is bound to the alternate IP
+/// if `i & ALT_IP` and to the alternate port if `i & ALT_PORT`, so flipping
+/// bits of a slot index follows the CHANGE-REQUEST flags.
+struct SocketSet {
+    sockets: Vec<Arc<UdpSocket>>,
+    addrs: Vec<SocketAddr>,
+    /// dedicated socket that every response is sent from, if configured
+    send: Option<(UdpSocket, SocketAddr)>,
+}
+
+impl SocketSet {
+    /// socket to send a response from when answering from `slot`
+    #[inline]
+    fn reply_socket(&self, slot: usize) -> &UdpSocket {
+        match &self.send {
+            Some((socket, _)) => socket,
+            None => &self.sockets[slot],
+        }
+    }
+
+    /// source address of a response answered from `slot`
+    #[inline]
+    fn reply_addr(&self, slot: usize) -> SocketAddr {
+        match &self.send {
+            Some((_, addr)) => *addr,
+            None => self.addrs[slot],
+        }
+    }
+}
+
+/// Most datagrams read by one batched receive
+pub const MAX_RECV_BATCH: usize = 64;
+
+/// Longest SOFTWARE description accepted, in bytes (RFC 8489 allows fewer
+/// than 128 characters; bytes keep the response within its buffer)
+pub const MAX_SOFTWARE_LEN: usize = 127;
+
+/// Longest REALM accepted, in bytes (RFC 5389 allows fewer than 128 characters)
+pub const MAX_REALM_LEN: usize = 127;
+
+/// Long-term credentials requests must be signed with (RFC 5389 section 10.2)
+///
+/// Only the derived key is kept, not the password.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub realm: String,
+    key: [u8; 16],
+    /// Issued in 401s; requests carrying another get a 438 with this one
+    nonce: String,
+}
+
+impl Credentials {
+    pub fn new(username: impl Into<String>, realm: impl Into<String>, password: &str) -> Self {
+        let username = username.into();
+        let realm = realm.into();
+        let key = long_term_key(&username, &realm, password);
+        let mut rng = rand::rng();
+        let nonce = (0..16)
+            .map(|_| char::from(b"0123456789abcdef"[rng.random_range(0..16)]))
+            .collect();
+        Self {
+            username,
+            realm,
+            key,
+            nonce,
+        }
+    }
+
+    /// nonce handed out with challenges
+    pub fn nonce(&self) -> &str {
+        &self.nonce
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("realm", &self.realm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// STUN server configuration
+#[derive(Debug, Clone)]
+pub struct StunConfig {
+    /// number of worker tasks processing requests (defaults to the CPU count)
+    pub workers: usize,
+    /// datagrams queued between the receive tasks and the workers; beyond
+    /// it receiving waits for a worker to catch up
+    pub queue_capacity: usize,
+    /// datagrams read per receive syscall (1 = one `recv_from` each); larger
+    /// batches use `recvmmsg`, which needs Linux and the `recvmmsg` feature
+    pub recv_batch: usize,
+    /// how client addresses appear in logs
+    pub redaction: AddrRedaction,
+    /// SOFTWARE description added to every response (`None` = omitted)
+    pub software: Option<String>,
+    /// alternate address for RFC 5780 NAT behavior discovery; must differ
+    /// from the primary in both IP and port
+    pub alternate: Option<SocketAddr>,
+    /// address of a separate socket that every response is sent from
+    pub send_addr: Option<SocketAddr>,
+    /// end every response with a FINGERPRINT attribute
+    pub fingerprint: bool,
+    /// also answer with a plain MAPPED-ADDRESS, for RFC 3489 clients
+    pub legacy_mapped: bool,
+    /// require requests to carry MESSAGE-INTEGRITY under these credentials
+    pub credentials: Option<Credentials>,
+    /// also accept STUN over TCP on this address, for clients whose network
+    /// blocks UDP (not captured to pcap)
+    pub tcp: Option<SocketAddr>,
+    /// budget of UDP requests per client IP; requests beyond it are dropped
+    /// unanswered (`None` = unlimited)
+    pub rate_limit: Option<RateLimit>,
+    /// set the Don't Fragment bit on responses, so one too big for the path
+    /// is dropped rather than fragmented (Linux only)
+    pub dont_fragment: bool,
+    /// IP TTL (IPv6 hop limit) of responses (`None` = the system default)
+    pub ttl: Option<u32>,
+    /// another instance to send clients to with a 300 (Try Alternate) while
+    /// the worker queue is backed up
+    pub alternate_server: Option<SocketAddr>,
+    /// queued requests at which clients are redirected to `alternate_server`
+    /// (`None` = half the queue capacity)
+    pub redirect_depth: Option<usize>,
+}
+
+impl StunConfig {
+    /// where to redirect a request dequeued with `queued` more behind it,
+    /// if the queue is deep enough to shed load
+    fn redirect_target(&self, queued: usize) -> Option<SocketAddr> {
+        let depth = self
+            .redirect_depth
+            .unwrap_or((self.queue_capacity / 2).max(1));
+        self.alternate_server.filter(|_| queued >= depth)
+    }
+}
+
+impl Default for StunConfig {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            recv_batch: 1,
+            redaction: AddrRedaction::default(),
+            software: None,
+            alternate: None,
+            send_addr: None,
+            fingerprint: false,
+            legacy_mapped: false,
+            credentials: None,
+            tcp: None,
+            rate_limit: None,
+            dont_fragment: false,
+            ttl: None,
+            alternate_server: None,
+            redirect_depth: None,
+        }
+    }
+}
+
+/// Fluent construction of a [`StunServer`]
+///
+/// Each setter corresponds to a [`StunConfig`] field; `bind` validates the
+/// configuration and binds the sockets.
+#[derive(Debug, Clone, Default)]
+pub struct StunServerBuilder {
+    config: StunConfig,
+    capture: Option<PacketCapture>,
+}
+
+impl StunServerBuilder {
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.config.queue_capacity = capacity;
+        self
+    }
+
+    pub fn recv_batch(mut self, datagrams: usize) -> Self {
+        self.config.recv_batch = datagrams;
+        self
+    }
+
+    pub fn redaction(mut self, redaction: AddrRedaction) -> Self {
+        self.config.redaction = redaction;
+        self
+    }
+
+    pub fn software(mut self, software: impl Into<String>) -> Self {
+        self.config.software = Some(software.into());
+        self
+    }
+
+    pub fn alternate(mut self, alternate: SocketAddr) -> Self {
+        self.config.alternate = Some(alternate);
+        self
+    }
+
+    pub fn send_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.send_addr = Some(addr);
+        self
+    }
+
+    pub fn fingerprint(mut self, fingerprint: bool) -> Self {
+        self.config.fingerprint = fingerprint;
+        self
+    }
+
+    pub fn legacy_mapped(mut self, legacy_mapped: bool) -> Self {
+        self.config.legacy_mapped = legacy_mapped;
+        self
+    }
+
+    pub fn credentials(mut self, username: &str, realm: &str, password: &str) -> Self {
+        self.config.credentials = Some(Credentials::new(username, realm, password));
+        self
+    }
+
+    pub fn capture(mut self, capture: PacketCapture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn tcp(mut self, addr: SocketAddr) -> Self {
+        self.config.tcp = Some(addr);
+        self
+    }
+
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.config.rate_limit = Some(limit);
+        self
+    }
+
+    pub fn dont_fragment(mut self, dont_fragment: bool) -> Self {
+        self.config.dont_fragment = dont_fragment;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.config.ttl = Some(ttl);
+        self
+    }
+
+    pub fn alternate_server(mut self, addr: SocketAddr) -> Self {
+        self.config.alternate_server = Some(addr);
+        self
+    }
+
+    pub fn redirect_depth(mut self, queued: usize) -> Self {
+        self.config.redirect_depth = Some(queued);
+        self
+    }
+
+    /// bind the server's sockets, with the first address `addr` resolves to
+    /// as the primary address
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> std::io::Result<StunServer> {
+        let primary = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| invalid_input("no address to bind"))?;
+        self.bind_addr(primary).await
+    }
+
+    /// bind the server's sockets, with `primary` as the primary address
+    ///
+    /// An unspecified IPv6 address (`[::]`) binds dual-stack, so IPv4 clients
+    /// are answered too.
+    pub async fn bind_addr(self, primary: SocketAddr) -> std::io::Result<StunServer> {
+        let config = self.config;
+        if config.workers == 0 {
+            return Err(invalid_input("at least one worker is required"));
+        }
+        if config.queue_capacity == 0 {
+            return Err(invalid_input("queue capacity must be at least 1"));
+        }
+        check_recv_batch(config.recv_batch)?;
+        if let Some(limit) = config.rate_limit
+            && (limit.burst == 0 || !limit.per_second.is_finite() || limit.per_second < 0.0)
+        {
+            return Err(invalid_input(
+                "rate limit needs a burst of at least 1 and a finite, non-negative rate",
+            ));
+        }
+        if let Some(software) = &config.software {
+            check_software(software)?;
+        }
+        if config.ttl.is_some_and(|ttl| !(1..=255).contains(&ttl)) {
+            return Err(invalid_input("TTL must be between 1 and 255"));
+        }
+        if config
+            .redirect_depth
+            .is_some_and(|depth| depth == 0 || depth > config.queue_capacity)
+        {
+            return Err(invalid_input(
+                "redirect depth must be between 1 and the queue capacity",
+            ));
+        }
+        if let Some(credentials) = &config.credentials {
+            check_credentials(credentials)?;
+        }
+
+        let mut sockets = match config.alternate {
+            Some(alternate) => bind_alternate(primary, alternate)?,
+            None => {
+                let socket = bind_udp(primary)?;
+                let local_addr = socket.local_addr()?;
+                info!("STUN server listening on {}", local_addr);
+                SocketSet {
+                    sockets: vec![Arc::new(socket)],
+                    addrs: vec![local_addr],
+                    send: None,
+                }
+            }
+        };
+        if let Some(send_addr) = config.send_addr {
+            bind_send_socket(&mut sockets, send_addr)?;
+        }
+        let send_socket = sockets.send.as_ref().map(|(socket, _)| socket);
+        for socket in sockets.sockets.iter().map(|s| &**s).chain(send_socket) {
+            sockopt::apply(socket, config.dont_fragment, config.ttl)?;
+        }
+        let tcp = match config.tcp {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                info!("STUN over TCP listening on {}", listener.local_addr()?);
+                Some(listener)
+            }
+            None => None,
+        };
+        info!("Using {} worker tasks", config.workers);
+        // Enough idle buffers for a full queue plus one request per worker
+        // and per receive task
+        let pool = BufferPool::new(config.queue_capacity + config.workers + sockets.addrs.len());
+
+        let limiter = config
+            .rate_limit
+            .map(|limit| Arc::new(IpRateLimiter::new(limit)));
+
+        Ok(StunServer {
+            sockets,
+            limiter,
+            config,
+            capture: self.capture,
+            latency: Arc::new(LatencyHistogram::new()),
+            metrics: Arc::new(StunMetrics::new()),
+            pool,
+            tcp,
+        })
+    }
+}
+
+/// bind a UDP socket in `addr`'s family; `[::]` also accepts IPv4 clients
+/// as mapped addresses
+fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn invalid_input(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+/// reject batch sizes this build can't receive
+fn check_recv_batch(batch: usize) -> std::io::Result<()> {
+    if batch == 0 || batch > MAX_RECV_BATCH {
+        return Err(invalid_input("receive batch must be between 1 and 64"));
+    }
+    if batch > 1 && !cfg!(all(target_os = "linux", feature = "recvmmsg")) {
+        return Err(invalid_input(
+            "batched receive needs Linux and the recvmmsg feature",
+        ));
+    }
+    Ok(())
+}
+
+/// reject realms that wouldn't fit in a 401
+fn check_credentials(credentials: &Credentials) -> std::io::Result<()> {
+    if credentials.realm.len() > MAX_REALM_LEN {
+        return Err(invalid_input("REALM is too long"));
+    }
+    Ok(())
+}
+
+/// reject SOFTWARE descriptions that wouldn't fit in a response
+fn check_software(software: &str) -> std::io::Result<()> {
+    if software.len() > MAX_SOFTWARE_LEN {
+        return Err(invalid_input("SOFTWARE description is too long"));
+    }
+    Ok(())
+}
+
+/// bind all four combinations of the primary and alternate IP and port
+///
+/// A port of 0 picks an ephemeral port, shared by both IPs.
+fn bind_alternate(primary: SocketAddr, alternate: SocketAddr) -> std::io::Result<SocketSet> {
+    if primary.ip() == alternate.ip() || (primary.port() != 0 && primary.port() == alternate.port())
+    {
+        return Err(invalid_input(
+            "alternate address must differ from the primary in both IP and port",
+        ));
+    }
+
+    let primary_socket = bind_udp(primary)?;
+    let primary_port = primary_socket.local_addr()?.port();
+    let alt_port_socket = bind_udp(SocketAddr::new(primary.ip(), alternate.port()))?;
+    let alternate_port = alt_port_socket.local_addr()?.port();
+
+    let sockets = vec![
+        primary_socket,
+        alt_port_socket,
+        bind_udp(SocketAddr::new(alternate.ip(), primary_port))?,
+        bind_udp(SocketAddr::new(alternate.ip(), alternate_port))?,
+    ];
+    let addrs = sockets
+        .iter()
+        .map(|s| s.local_addr())
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    for addr in &addrs {
+        info!("STUN server listening on {}", addr);
+    }
+
+    Ok(SocketSet {
+        sockets: sockets.into_iter().map(Arc::new).collect(),
+        addrs,
+        send: None,
+    })
+}
+
+/// bind the socket every response is sent from
+///
+/// Not available with an alternate address, where the response source is
+/// dictated by CHANGE-REQUEST.
+fn bind_send_socket(sockets: &mut SocketSet, addr: SocketAddr) -> std::io::Result<()> {
+    if sockets.sockets.len() > 1 {
+        return Err(invalid_input(
+            "a separate send socket can't be combined with an alternate address",
+        ));
+    }
+
+    let socket = bind_udp(addr)?;
+    let local_addr = socket.local_addr()?;
+    info!("STUN responses sent from {}", local_addr);
+    sockets.send = Some((socket, local_addr));
+    Ok(())
 }
 
 pub struct StunServer {
-    socket: Arc<UdpSocket>,
-    num_workers: usize,
+    sockets: SocketSet,
+    config: StunConfig,
+    capture: Option<PacketCapture>,
+    latency: Arc<LatencyHistogram>,
+    metrics: Arc<StunMetrics>,
+    pool: Arc<BufferPool>,
+    limiter: Option<Arc<IpRateLimiter>>,
+    tcp: Option<TcpListener>,
 }
 
 impl StunServer {
+    /// start configuring a server; unset options keep their defaults
+    pub fn builder() -> StunServerBuilder {
+        StunServerBuilder::default()
+    }
+
     /// create and bind the server to the port
     pub async fn bind(addr: &str) -> std::io::Result<Self> {
-        let socket = UdpSocket::bind(addr).await?;
-        let num_workers = std::thread::available_parallelism()
-            .map(|n| n.get())
-            .unwrap_or(4);
+        Self::builder().bind(addr).await
+    }
 
-        info!("STUN server listening on {}", socket.local_addr()?);
-        info!("Using {} worker tasks", num_workers);
+    /// create and bind the server to an explicit address, IPv4 or IPv6
+    pub async fn bind_addr(addr: SocketAddr) -> std::io::Result<Self> {
+        Self::builder().bind_addr(addr).await
+    }
 
-        Ok(Self {
-            socket: Arc::new(socket),
-            num_workers,
-        })
+    /// create and bind the server on two IPs and two ports for RFC 5780 NAT
+    /// behavior discovery
+    ///
+    /// Binds all four combinations of the primary and alternate IP and port, so
+    /// CHANGE-REQUEST can be answered from any of them. A port of 0 picks an
+    /// ephemeral port, shared by both IPs.
+    pub async fn bind_with_alternate(
+        primary: SocketAddr,
+        alternate: SocketAddr,
+    ) -> std::io::Result<Self> {
+        Self::builder().alternate(alternate).bind(primary).await
+    }
+
+    /// the configuration the server was built with
+    pub fn config(&self) -> &StunConfig {
+        &self.config
+    }
+
+    /// histogram of worker processing latency, from dequeuing a request to
+    /// having sent its response
+    ///
+    /// Take it before `run`, which consumes the server.
+    pub fn latency(&self) -> Arc<LatencyHistogram> {
+        self.latency.clone()
+    }
+
+    /// packet counters: received, responses sent, parse errors and drops;
+    /// call `snapshot` on it to read them
+    ///
+    /// Take it before `run`, which consumes the server.
+    pub fn metrics(&self) -> Arc<StunMetrics> {
+        self.metrics.clone()
+    }
+
+    /// set how client addresses appear in logs (full addresses by default)
+    pub fn with_redaction(mut self, redaction: AddrRedaction) -> Self {
+        self.config.redaction = redaction;
+        self
     }
 
-    /// run the multi-task server
+    /// tag every response with a SOFTWARE attribute naming this server
     ///
-    /// - Main task: receives UDP packets and dispatches to workers
+    /// # Errors
+    /// `InvalidInput` if `software` is longer than `MAX_SOFTWARE_LEN` bytes
+    pub fn with_software(mut self, software: impl Into<String>) -> std::io::Result<Self> {
+        let software = software.into();
+        check_software(&software)?;
+        self.config.software = Some(software);
+        Ok(self)
+    }
+
+    /// add a plain MAPPED-ADDRESS to every binding response, next to the
+    /// XOR-MAPPED-ADDRESS, for clients that predate RFC 5389
+    pub fn with_legacy_mapped(mut self, legacy_mapped: bool) -> Self {
+        self.config.legacy_mapped = legacy_mapped;
+        self
+    }
+
+    /// only answer requests signed with long-term credentials
+    ///
+    /// Unsigned or badly signed requests get a 401 carrying the realm and a
+    /// nonce to sign with.
+    ///
+    /// # Errors
+    /// `InvalidInput` if `realm` is longer than `MAX_REALM_LEN` bytes
+    pub fn with_credentials(
+        mut self,
+        username: &str,
+        realm: &str,
+        password: &str,
+    ) -> std::io::Result<Self> {
+        let credentials = Credentials::new(username, realm, password);
+        check_credentials(&credentials)?;
+        self.config.credentials = Some(credentials);
+        Ok(self)
+    }
+
+    /// answer binding requests with a 300 (Try Alternate) pointing at `addr`
+    /// while the worker queue holds `redirect_depth` requests or more
+    ///
+    /// With credentials configured only signed requests are redirected, and
+    /// the 300 is signed too.
+    pub fn with_alternate_server(mut self, addr: SocketAddr) -> Self {
+        self.config.alternate_server = Some(addr);
+        self
+    }
+
+    /// record every request and response datagram to a pcap capture
+    pub fn with_capture(mut self, capture: PacketCapture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// send every response from a separately bound socket instead of the one
+    /// the request arrived on
+    ///
+    /// Clients then see responses come from `addr` rather than the listen
+    /// address. Not available with an alternate address, where the response
+    /// source is dictated by CHANGE-REQUEST.
+    pub async fn with_send_socket(mut self, addr: SocketAddr) -> std::io::Result<Self> {
+        bind_send_socket(&mut self.sockets, addr)?;
+        self.config.send_addr = Some(addr);
+        Ok(self)
+    }
+
+    /// addresses the server is listening on (primary first)
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.sockets.addrs
+    }
+
+    /// address accepting STUN over TCP, if enabled
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        self.tcp
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// address responses are sent from, if a separate send socket is configured
+    pub fn send_addr(&self) -> Option<SocketAddr> {
+        self.sockets.send.as_ref().map(|(_, addr)| *addr)
+    }
+
+    /// run the multi-task server until a socket fails
+    ///
+    /// - Receive tasks: one per socket, receive UDP packets and dispatch to workers
     /// - Worker tasks: process STUN requests and send responses
     pub async fn run(self) -> std::io::Result<()> {
-        let (tx, rx): (Sender<WorkItem>, Receiver<WorkItem>) = async_channel::bounded(1024);
+        self.run_until(std::future::pending()).await
+    }
 
-        for worker_id in 0..self.num_workers {
-            let socket = self.socket.clone();
-            let rx = rx.clone();
+    /// run the multi-task server until `shutdown` completes
+    ///
+    /// On shutdown the receive tasks stop reading, the workers answer every
+    /// request already queued, and only then does this return.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> std::io::Result<()> {
+        let (tx, rx): (Sender<WorkItem>, Receiver<WorkItem>) =
+            async_channel::bounded(self.config.queue_capacity);
+        let sockets = Arc::new(self.sockets);
+        let config = Arc::new(self.config);
 
-            tokio::spawn(async move {
-                worker_loop(worker_id, socket, rx).await;
-            });
+        let workers: Vec<_> = (0..config.workers)
+            .map(|worker_id| {
+                let sockets = sockets.clone();
+                let rx = rx.clone();
+                let config = config.clone();
+                let capture = self.capture.clone();
+                let latency = self.latency.clone();
+                let metrics = self.metrics.clone();
+
+                tokio::spawn(async move {
+                    worker_loop(worker_id, sockets, rx, config, capture, latency, metrics).await;
+                })
+            })
+            .collect();
+        drop(rx);
+
+        let receivers = sockets.sockets.iter().enumerate().map(|(local, socket)| {
+            recv_loop(
+                local,
+                socket.clone(),
+                tx.clone(),
+                config.clone(),
+                self.metrics.clone(),
+                self.pool.clone(),
+                self.limiter.clone(),
+            )
+        });
+        let tcp = async {
+            match self.tcp {
+                Some(listener) => {
+                    tcp::accept_loop(
+                        listener,
+                        sockets.clone(),
+                        config.clone(),
+                        self.metrics.clone(),
+                    )
+                    .await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        // Dropping the receive tasks and `tx` closes the queue; workers
+        // finish what's in it and then see the close
+        let result = tokio::select! {
+            result = try_join_all(receivers) => result.map(|_| ()),
+            result = tcp => result,
+            () = shutdown => {
+                info!("STUN server shutting down, draining queued requests");
+                Ok(())
+            }
+        };
+        drop(tx);
+        for worker in workers {
+            let _ = worker.await;
         }
+        debug!(
+            "Request buffers: {} allocated, {} idle",
+            self.pool.allocated(),
+            self.pool.available()
+        );
 
-        let mut buf = [0u8; 64];
-        loop {
-            let (len, client_addr) = self.socket.recv_from(&mut buf).await?;
+        result
+    }
 
-            debug!("Received {} bytes from {}", len, client_addr);
+    /// single-threaded STUN server (for debugging/testing), primary socket only
+    pub async fn run_simple(&self) -> std::io::Result<()> {
+        let mut buf = [0u8; MAX_REQUEST_SIZE];
+        let mut response_buf = [0u8; MAX_RESPONSE_SIZE];
+        let sockets = &self.sockets;
 
-            let mut work_data = [0u8; 64];
-            work_data[..len].copy_from_slice(&buf[..len]);
+        loop {
+            let (len, client_addr) = sockets.sockets[0].recv_from(&mut buf).await?;
+            self.metrics.record_received();
+            if let Some(capture) = &self.capture {
+                capture.record(client_addr, sockets.addrs[0], &buf[..len]);
+            }
 
-            let work_item = WorkItem {
-                data: work_data,
-                len,
+            let Some(reply) = respond(
+                &buf[..len],
                 client_addr,
+                0,
+                Transport::Udp,
+                &sockets.addrs,
+                &self.config,
+                None,
+                &self.metrics,
+                &mut response_buf,
+            ) else {
+                continue;
             };
 
-            if tx.try_send(work_item).is_err() {
-                warn!("Worker queue full, dropping packet");
+            sockets
+                .reply_socket(reply.from)
+                .send_to(&response_buf[..reply.len], client_addr)
+                .await?;
+            self.metrics.record_response_sent();
+            if let Some(capture) = &self.capture {
+                capture.record(
+                    sockets.reply_addr(reply.from),
+                    client_addr,
+                    &response_buf[..reply.len],
+                );
             }
         }
     }
+}
 
-    /// single-threaded STUN server (for debugging/testing)
-    pub async fn run_simple(&self) -> std::io::Result<()> {
-        let mut buf = [0u8; 64];
-        let mut response_buf = [0u8; BINDING_RESPONSE_SIZE];
-
+/// receive loop for one socket: copy each datagram into a work item for the workers
+async fn recv_loop(
+    local: usize,
+    socket: Arc<UdpSocket>,
+    tx: Sender<WorkItem>,
+    config: Arc<StunConfig>,
+    metrics: Arc<StunMetrics>,
+    pool: Arc<BufferPool>,
+    limiter: Option<Arc<IpRateLimiter>>,
+) -> std::io::Result<()> {
+    let limiter = limiter.as_deref();
+    #[cfg(all(target_os = "linux", feature = "recvmmsg"))]
+    if config.recv_batch > 1 {
+        let mut batch = batch::RecvBatch::new(config.recv_batch);
         loop {
-            let (len, client_addr) = self.socket.recv_from(&mut buf).await?;
-
-            match handle_request(&buf[..len], client_addr, &mut response_buf) {
-                Ok(response_len) => {
-                    self.socket
-                        .send_to(&response_buf[..response_len], client_addr)
-                        .await?;
-                }
-                Err(e) => {
-                    debug!("Request error: {}", e);
+            let received = batch.recv(&socket).await?;
+            for i in 0..received {
+                if let Some((data, client_addr)) = batch.get(i) {
+                    let mut buf = pool.take();
+                    buf[..data.len()].copy_from_slice(data);
+                    let item = WorkItem {
+                        data: buf,
+                        len: data.len(),
+                        client_addr,
+                        local,
+                    };
+                    enqueue(item, &tx, &config, &metrics, limiter);
                 }
             }
         }
     }
+
+    loop {
+        // Received straight into the buffer the worker will read
+        let mut buf = pool.take();
+        let (len, client_addr) = socket.recv_from(&mut buf[..]).await?;
+        let item = WorkItem {
+            data: buf,
+            len,
+            client_addr,
+            local,
+        };
+        enqueue(item, &tx, &config, &metrics, limiter);
+    }
+}
+
+/// hand a received datagram to the workers, dropping it if its source is
+/// over its rate limit or the workers are all busy
+fn enqueue(
+    work_item: WorkItem,
+    tx: &Sender<WorkItem>,
+    config: &StunConfig,
+    metrics: &StunMetrics,
+    limiter: Option<&IpRateLimiter>,
+) {
+    metrics.record_received();
+    debug!(
+        "Received {} bytes from {}",
+        work_item.len,
+        config.redaction.redact(work_item.client_addr)
+    );
+
+    // Checked before queueing, so a flood can't crowd out other clients
+    if let Some(limiter) = limiter
+        && !limiter.try_acquire(work_item.client_addr.ip())
+    {
+        metrics.record_rate_limited();
+        debug!(
+            "Rate limited {}",
+            config.redaction.redact(work_item.client_addr)
+        );
+        return;
+    }
+
+    if tx.try_send(work_item).is_err() {
+        metrics.record_dropped();
+        warn!("Worker queue full, dropping packet");
+    }
 }
 
 /// worker loop: receive work items from the channel and process them
 ///
 /// With async-channel, multiple workers can call `rx.recv()` concurrently
 /// without any Mutex. The channel internally handles fair distribution.
-async fn worker_loop(_worker_id: usize, socket: Arc<UdpSocket>, rx: Receiver<WorkItem>) {
-    let mut response_buf = [0u8; BINDING_RESPONSE_SIZE];
+async fn worker_loop(
+    _worker_id: usize,
+    sockets: Arc<SocketSet>,
+    rx: Receiver<WorkItem>,
+    config: Arc<StunConfig>,
+    capture: Option<PacketCapture>,
+    latency: Arc<LatencyHistogram>,
+    metrics: Arc<StunMetrics>,
+) {
+    let mut response_buf = [0u8; MAX_RESPONSE_SIZE];
 
     while let Ok(work_item) = rx.recv().await {
-        match handle_request(
+        let dequeued = Instant::now();
+        if let Some(capture) = &capture {
+            capture.record(
+                work_item.client_addr,
+                sockets.addrs[work_item.local],
+                &work_item.data[..work_item.len],
+            );
+        }
+
+        let Some(reply) = respond(
             &work_item.data[..work_item.len],
             work_item.client_addr,
+            work_item.local,
+            Transport::Udp,
+            &sockets.addrs,
+            &config,
+            config.redirect_target(rx.len()),
+            &metrics,
             &mut response_buf,
-        ) {
-            Ok(response_len) => {
-                if let Err(e) = socket
-                    .send_to(&response_buf[..response_len], work_item.client_addr)
-                    .await
-                {
-                    warn!("Failed to send response: {}", e);
-                }
-            }
-            Err(e) => {
-                debug!("Request error from {}: {}", work_item.client_addr, e);
-            }
+        ) else {
+            continue;
+        };
+
+        if let Err(e) = sockets
+            .reply_socket(reply.from)
+            .send_to(&response_buf[..reply.len], work_item.client_addr)
+            .await
+        {
+            warn!("Failed to send response: {}", e);
+            continue;
         }
+        metrics.record_response_sent();
+        latency.record(dequeued.elapsed());
+
+        if let Some(capture) = &capture {
+            capture.record(
+                sockets.reply_addr(reply.from),
+                work_item.client_addr,
+                &response_buf[..reply.len],
+            );
+        }
+    }
+}
+
+/// answer one datagram with a success response, an error response or
+/// nothing, counting the failures
+// Everything here is per-datagram or per-server state the three callers
+// already hold separately
+#[allow(clippy::too_many_arguments)]
+fn respond(
+    data: &[u8],
+    client_addr: SocketAddr,
+    local: usize,
+    transport: Transport,
+    addrs: &[SocketAddr],
+    config: &StunConfig,
+    redirect: Option<SocketAddr>,
+    metrics: &StunMetrics,
+    response_buf: &mut [u8; MAX_RESPONSE_SIZE],
+) -> Option<Reply> {
+    match handle_request(
+        data,
+        client_addr,
+        local,
+        transport,
+        addrs,
+        config,
+        redirect,
+        response_buf,
+    ) {
+        Ok(Reply { len: 0, .. }) => {
+            metrics.record_indication();
+            None
+        }
+        Ok(reply) => Some(reply),
+        Err(e) => {
+            metrics.record_parse_error();
+            debug!(
+                "Request error from {}: {}",
+                config.redaction.redact(client_addr),
+                e
+            );
+            error_reply(data, &e, local, config, response_buf)
+        }
+    }
+}
+
+/// what a request arrived over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    /// a connection the response goes back on, so there is no other socket
+    /// to answer a CHANGE-REQUEST from and no UDP socket address to report
+    Tcp,
+}
+
+/// a response written to the response buffer; empty for an indication,
+/// which gets no response
+#[derive(Debug, PartialEq, Eq)]
+struct Reply {
+    len: usize,
+    /// slot of the socket the response must be sent from
+    from: usize,
+}
+
+/// slot to answer from when a request arrived on `local` with `change` flags
+#[inline]
+fn response_slot(local: usize, change: ChangeRequest) -> usize {
+    let mut slot = local;
+    if change.change_ip {
+        slot ^= ALT_IP;
     }
+    if change.change_port {
+        slot ^= ALT_PORT;
+    }
+    slot
 }
 
 /// handle the STUN request
 ///
+/// `addrs` are the server's socket addresses indexed by slot, `local` the slot
+/// the request arrived on. With a `redirect`, a binding request is answered
+/// with a 300 sending the client there instead.
+///
 /// # Errors
 /// Returns `StunError` if parsing fails or the request is not supported
 #[inline]
+#[allow(clippy::too_many_arguments)]
 fn handle_request(
     data: &[u8],
     client_addr: SocketAddr,
-    response_buf: &mut [u8; BINDING_RESPONSE_SIZE],
-) -> Result<usize, StunError> {
+    local: usize,
+    transport: Transport,
+    addrs: &[SocketAddr],
+    config: &StunConfig,
+    redirect: Option<SocketAddr>,
+    response_buf: &mut [u8; MAX_RESPONSE_SIZE],
+) -> Result<Reply, StunError> {
+    let redaction = config.redaction;
     let request = StunRequest::parse(data)?;
 
+    // Indications only keep the client's NAT mapping open
+    if request.is_binding_indication() {
+        request.check_attributes()?;
+        return Ok(Reply {
+            len: 0,
+            from: local,
+        });
+    }
     if !request.is_binding_request() {
         return Err(StunError::UnsupportedMessageType(request.msg_type));
     }
+    request.check_attributes()?;
+    if let Some(credentials) = &config.credentials {
+        authenticate(&request, credentials)?;
+    }
 
-    let addr_v4 = match client_addr {
-        SocketAddr::V4(v4) => v4,
-        SocketAddr::V6(_) => return Err(StunError::Ipv6NotSupported),
+    if let Some(alternate) = redirect {
+        debug!(
+            "Redirecting {} to {}",
+            redaction.redact(client_addr),
+            alternate
+        );
+        let mut response =
+            StunResponse::binding_error_response(request.transaction_id, 3, 0, "Try Alternate")
+                .with_alternate_server(alternate);
+        if let Some(credentials) = &config.credentials {
+            response = response.with_message_integrity(&credentials.key);
+        }
+        if config.fingerprint {
+            response = response.with_fingerprint();
+        }
+        let bytes = response.as_bytes();
+        response_buf[..bytes.len()].copy_from_slice(bytes);
+        return Ok(Reply {
+            len: bytes.len(),
+            from: local,
+        });
+    }
+
+    // ICE attributes are informational here; a malformed one doesn't fail the binding
+    match request.ice_attributes() {
+        Ok(ice) if ice.is_connectivity_check() => {
+            debug!(
+                "ICE connectivity check from {}: {:?}",
+                redaction.redact(client_addr),
+                ice
+            );
+        }
+        Ok(_) => {}
+        Err(e) => debug!(
+            "Ignoring attributes from {}: {}",
+            redaction.redact(client_addr),
+            e
+        ),
+    }
+
+    // Over TCP the addresses are the UDP sockets', not the listener's
+    let has_alternate = transport == Transport::Udp && addrs.len() == 4;
+    let from = match request.change_request()? {
+        // RFC 5780 section 7.2: a response can't move to another connection
+        Some(_) if transport == Transport::Tcp => return Err(StunError::ChangeRequestOverTcp),
+        Some(change) if change.change_ip || change.change_port => {
+            if !has_alternate {
+                return Err(StunError::AlternateNotConfigured);
+            }
+            response_slot(local, change)
+        }
+        _ => local,
     };
 
-    let response = StunResponse::binding_response(request.transaction_id, addr_v4);
-    response_buf.copy_from_slice(response.as_bytes());
+    // a dual-stack socket sees IPv4 clients as ::ffff:a.b.c.d; report them as IPv4
+    let mapped = SocketAddr::new(client_addr.ip().to_canonical(), client_addr.port());
+
+    let mut response = StunResponse::binding_response(request.transaction_id, mapped);
+    if config.legacy_mapped {
+        response = response.with_mapped_address(mapped);
+    }
+    if has_alternate {
+        response = response
+            .with_response_origin(addrs[from])
+            .with_other_address(addrs[local ^ (ALT_IP | ALT_PORT)]);
+    }
+    if let Some(software) = &config.software {
+        response = response.with_software(software);
+    }
+    // A malformed RESPONSE-SIZE is ignored, like the ICE attributes
+    if let Ok(Some(size)) = request.response_size() {
+        let mut reserve = 0;
+        if config.credentials.is_some() {
+            reserve += MESSAGE_INTEGRITY_SIZE;
+        }
+        if config.fingerprint {
+            reserve += FINGERPRINT_SIZE;
+        }
+        // A size too small to hold even the trailing attributes asks for
+        // no padding at all
+        let target = (size as usize)
+            .min(MAX_RESPONSE_SIZE)
+            .saturating_sub(reserve);
+        response = response.with_padding_to(target);
+    }
+    if let Some(credentials) = &config.credentials {
+        response = response.with_message_integrity(&credentials.key);
+    }
+    if config.fingerprint {
+        response = response.with_fingerprint();
+    }
+
+    let bytes = response.as_bytes();
+    response_buf[..bytes.len()].copy_from_slice(bytes);
+
+    Ok(Reply {
+        len: bytes.len(),
+        from,
+    })
+}
+
+/// check a request is signed with `credentials`
+///
+/// # Errors
+/// - `StunError::Unauthorized` - if USERNAME, REALM, NONCE or
+///   MESSAGE-INTEGRITY is missing, or the username, realm or HMAC is wrong
+/// - `StunError::StaleNonce` - if the nonce isn't the one this server issued
+fn authenticate(request: &StunRequest, credentials: &Credentials) -> Result<(), StunError> {
+    let (Some(username), Some(realm), Some(nonce)) = (
+        request.attribute(ATTR_USERNAME)?,
+        request.attribute(ATTR_REALM)?,
+        request.attribute(ATTR_NONCE)?,
+    ) else {
+        return Err(StunError::Unauthorized);
+    };
+    if nonce != credentials.nonce.as_bytes() {
+        return Err(StunError::StaleNonce);
+    }
+    if username != credentials.username.as_bytes()
+        || realm != credentials.realm.as_bytes()
+        || !request.verify_integrity(&credentials.key)?
+    {
+        return Err(StunError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// write the error response for a binding request `handle_request` rejected
+///
+/// Only answers when the request parses as a binding request, so the
+/// transaction id can be echoed; other failures are dropped silently.
+fn error_reply(
+    data: &[u8],
+    err: &StunError,
+    local: usize,
+    config: &StunConfig,
+    response_buf: &mut [u8; MAX_RESPONSE_SIZE],
+) -> Option<Reply> {
+    let request = StunRequest::parse(data).ok()?;
+    if !request.is_binding_request() {
+        return None;
+    }
+
+    let tid = request.transaction_id;
+    let mut response = match err {
+        StunError::MalformedAttribute(_) => {
+            StunResponse::binding_error_response(tid, 4, 0, "Bad Request")
+        }
+        StunError::UnknownAttributes(types) => {
+            StunResponse::binding_error_response(tid, 4, 20, "Unknown Attribute")
+                .with_unknown_attributes(types)
+        }
+        StunError::ChangeRequestOverTcp => {
+            StunResponse::binding_error_response(tid, 4, 0, "Bad Request")
+        }
+        // without an alternate address CHANGE-REQUEST can't be honoured
+        StunError::AlternateNotConfigured => {
+            StunResponse::binding_error_response(tid, 4, 20, "Unknown Attribute")
+                .with_unknown_attributes(&[ATTR_CHANGE_REQUEST])
+        }
+        StunError::Unauthorized => {
+            let credentials = config.credentials.as_ref()?;
+            StunResponse::binding_error_response(tid, 4, 1, "Unauthorized")
+                .with_realm(&credentials.realm)
+                .with_nonce(&credentials.nonce)
+        }
+        StunError::StaleNonce => {
+            let credentials = config.credentials.as_ref()?;
+            StunResponse::binding_error_response(tid, 4, 38, "Stale Nonce")
+                .with_realm(&credentials.realm)
+                .with_nonce(&credentials.nonce)
+        }
+        _ => return None,
+    };
+    if config.fingerprint {
+        response = response.with_fingerprint();
+    }
+
+    let bytes = response.as_bytes();
+    response_buf[..bytes.len()].copy_from_slice(bytes);
+    Some(Reply {
+        len: bytes.len(),
+        from: local,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::protocol::{
+        ATTR_ALTERNATE_SERVER, ATTR_CHANGE_REQUEST, ATTR_ERROR_CODE, ATTR_FINGERPRINT,
+        ATTR_MAPPED_ADDRESS, ATTR_MESSAGE_INTEGRITY, ATTR_OTHER_ADDRESS, ATTR_PADDING,
+        ATTR_RESPONSE_ORIGIN, ATTR_RESPONSE_SIZE, ATTR_SOFTWARE, ATTR_UNKNOWN_ATTRIBUTES,
+        ATTR_XOR_MAPPED_ADDRESS, BINDING_RESPONSE_SIZE_V4, HEADER_SIZE, MAGIC_COOKIE, MessageType,
+    };
+
+    const FLAG_COMBINATIONS: [(bool, bool); 4] =
+        [(false, false), (false, true), (true, false), (true, true)];
+
+    fn binding_request(change: Option<ChangeRequest>) -> Vec<u8> {
+        let mut data = vec![0x00, 0x01, 0x00, 0x00];
+        data.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        data.extend_from_slice(b"TRANSACTION1");
+        if let Some(change) = change {
+            data[3] = 8;
+            data.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+            data.extend_from_slice(&4u16.to_be_bytes());
+            data.extend_from_slice(&change.to_bytes());
+        }
+        data
+    }
+
+    fn address_attribute(response: &[u8], attr_type: u16) -> SocketAddr {
+        let parsed = StunRequest::parse(response).unwrap();
+        let value = parsed
+            .attributes()
+            .map(Result::unwrap)
+            .find(|(t, _)| *t == attr_type)
+            .map(|(_, v)| v)
+            .expect("attribute missing");
+        let port = u16::from_be_bytes([value[2], value[3]]);
+        let ip: [u8; 4] = value[4..8].try_into().unwrap();
+        SocketAddr::from((ip, port))
+    }
+
+    #[test]
+    fn response_slot_follows_change_flags() {
+        for (change_ip, change_port) in FLAG_COMBINATIONS {
+            let change = ChangeRequest {
+                change_ip,
+                change_port,
+            };
+            let expected = (change_ip as usize) << 1 | change_port as usize;
+            assert_eq!(response_slot(0, change), expected);
+            assert_eq!(response_slot(0b11, change), 0b11 ^ expected);
+        }
+    }
+
+    #[test]
+    fn change_request_without_alternate_is_rejected() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let request = binding_request(Some(ChangeRequest {
+            change_ip: true,
+            change_port: false,
+        }));
+        assert!(matches!(
+            handle_request(
+                &request,
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &StunConfig::default(),
+                None,
+                &mut buf
+            ),
+            Err(StunError::AlternateNotConfigured)
+        ));
+
+        let request = binding_request(Some(ChangeRequest::default()));
+        let reply = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &StunConfig::default(),
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(reply, Reply { len: 32, from: 0 });
+    }
+
+    /// run a request through `handle_request`, falling back to `error_reply`
+    fn error_response(request: &[u8]) -> Vec<u8> {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        let err = handle_request(
+            request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &StunConfig::default(),
+            None,
+            &mut buf,
+        )
+        .unwrap_err();
+        let reply = error_reply(request, &err, 0, &StunConfig::default(), &mut buf)
+            .expect("no error response");
+        buf[..reply.len].to_vec()
+    }
+
+    /// (code, reason, UNKNOWN-ATTRIBUTES value) of an error response
+    fn error_attributes(response: &[u8]) -> (u16, String, Option<Vec<u8>>) {
+        let parsed = StunRequest::parse(response).unwrap();
+        assert_eq!(parsed.msg_type, MessageType::BindingErrorResponse);
+        assert_eq!(parsed.transaction_id.as_bytes(), b"TRANSACTION1");
+        let attrs: Vec<_> = parsed.attributes().map(Result::unwrap).collect();
+        let (_, value) = attrs.iter().find(|(t, _)| *t == ATTR_ERROR_CODE).unwrap();
+        let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+        let reason = String::from_utf8(value[4..].to_vec()).unwrap();
+        let unknown = attrs
+            .iter()
+            .find(|(t, _)| *t == ATTR_UNKNOWN_ATTRIBUTES)
+            .map(|(_, v)| v.to_vec());
+        (code, reason, unknown)
+    }
+
+    #[test]
+    fn rejected_binding_requests_get_error_responses() {
+        // truncated attribute: 400
+        let mut request = binding_request(None);
+        request[3] = 8;
+        request.extend_from_slice(&[0x00, 0x24, 0x00, 0x10, 0, 0, 0, 0]);
+        assert_eq!(
+            error_attributes(&error_response(&request)),
+            (400, "Bad Request".to_string(), None)
+        );
+
+        // unknown comprehension-required attribute: 420 listing it
+        let mut request = binding_request(None);
+        request[3] = 4;
+        request.extend_from_slice(&[0x00, 0x42, 0x00, 0x00]);
+        assert_eq!(
+            error_attributes(&error_response(&request)),
+            (420, "Unknown Attribute".to_string(), Some(vec![0x00, 0x42]))
+        );
+
+        // CHANGE-REQUEST with no alternate address: 420 listing it
+        let request = binding_request(Some(ChangeRequest {
+            change_ip: false,
+            change_port: true,
+        }));
+        let (code, _, unknown) = error_attributes(&error_response(&request));
+        assert_eq!(code, 420);
+        assert_eq!(unknown, Some(ATTR_CHANGE_REQUEST.to_be_bytes().to_vec()));
+
+        // not a binding request: nothing to answer
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        let mut request = binding_request(None);
+        request[1] = 0x11;
+        assert_eq!(
+            error_reply(
+                &request,
+                &StunError::MalformedAttribute(0x0024),
+                0,
+                &StunConfig::default(),
+                &mut buf
+            ),
+            None
+        );
+    }
+
+    /// binding request carrying extra attributes, each padded to 4 bytes
+    fn request_with(attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut request = binding_request(None);
+        for (attr_type, value) in attrs {
+            request.extend_from_slice(&attr_type.to_be_bytes());
+            request.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            request.extend_from_slice(value);
+            request.resize((request.len() + 3) & !3, 0);
+        }
+        let len = (request.len() - 20) as u16;
+        request[2..4].copy_from_slice(&len.to_be_bytes());
+        request
+    }
+
+    #[test]
+    fn unknown_required_attributes_are_listed_in_a_420() {
+        // One type: two bytes of list, padded on the wire
+        let response = error_response(&request_with(&[(0x0042, &[1, 2, 3])]));
+        let (code, _, unknown) = error_attributes(&response);
+        assert_eq!(code, 420);
+        assert_eq!(unknown, Some(vec![0x00, 0x42]));
+        assert_eq!(response.len() % 4, 0);
+        assert_eq!(response[response.len() - 2..], [0, 0]);
+
+        // Two types, in the order they appear, around an optional one
+        let response = error_response(&request_with(&[
+            (0x0042, &[]),
+            (0x8123, &[9]),
+            (0x7001, &[1, 2, 3, 4]),
+        ]));
+        let (code, _, unknown) = error_attributes(&response);
+        assert_eq!(code, 420);
+        assert_eq!(unknown, Some(vec![0x00, 0x42, 0x70, 0x01]));
+
+        // Only comprehension-optional ones: answered as usual
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        let request = request_with(&[(0x8123, &[9]), (0xBEEF, &[])]);
+        let reply = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &StunConfig::default(),
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::BindingResponse);
+    }
+
+    #[test]
+    fn response_size_pads_response() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let mut request = binding_request(None);
+        request[3] = 8;
+        request.extend_from_slice(&ATTR_RESPONSE_SIZE.to_be_bytes());
+        request.extend_from_slice(&2u16.to_be_bytes());
+        request.extend_from_slice(&[0x01, 0x90, 0x00, 0x00]);
+
+        let reply = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &StunConfig::default(),
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(reply.len, 400);
+
+        let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+        let types: Vec<u16> = response.attributes().map(|a| a.unwrap().0).collect();
+        assert_eq!(types, [0x0020, ATTR_PADDING]);
+    }
+
+    #[test]
+    fn response_size_below_the_trailer_pads_nothing() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let config = StunConfig {
+            fingerprint: true,
+            ..StunConfig::default()
+        };
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        for size in [0u16, 4, 7] {
+            let request =
+                request_with(&[(ATTR_RESPONSE_SIZE, &[(size >> 8) as u8, size as u8, 0, 0])]);
+            let reply = handle_request(
+                &request,
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &config,
+                None,
+                &mut buf,
+            )
+            .unwrap();
+            let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+            let types: Vec<u16> = response.attributes().map(|a| a.unwrap().0).collect();
+            assert_eq!(types, [ATTR_XOR_MAPPED_ADDRESS, ATTR_FINGERPRINT]);
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_addr_keeps_the_address_family() {
+        for addr in ["127.0.0.1:0", "[::1]:0"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let server = StunServer::bind_addr(addr).await.unwrap();
+            let local = server.local_addrs()[0];
+            assert_eq!(local.ip(), addr.ip());
+            assert_ne!(local.port(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn unspecified_ipv6_answers_ipv4_clients() {
+        let server = StunServer::bind_addr("[::]:0".parse().unwrap())
+            .await
+            .unwrap();
+        let port = server.local_addrs()[0].port();
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&binding_request(None), ("127.0.0.1", port))
+            .await
+            .unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .expect("no response")
+            .unwrap();
+        // Reported as the IPv4 address, not ::ffff:127.0.0.1
+        let parsed = StunRequest::parse(&buf[..len]).unwrap();
+        let value = parsed.attribute(ATTR_XOR_MAPPED_ADDRESS).unwrap().unwrap();
+        assert_eq!(value[1], 0x01);
+        let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+        assert_eq!(port, client.local_addr().unwrap().port());
+    }
+
+    #[tokio::test]
+    async fn change_request_selects_source_socket() {
+        let server = StunServer::bind_with_alternate(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let addrs = server.local_addrs().to_vec();
+        assert_eq!(addrs.len(), 4);
+        assert_eq!(addrs[0].port(), addrs[2].port());
+        assert_eq!(addrs[1].port(), addrs[3].port());
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        for (change_ip, change_port) in FLAG_COMBINATIONS {
+            let change = ChangeRequest {
+                change_ip,
+                change_port,
+            };
+            client
+                .send_to(&binding_request(Some(change)), addrs[0])
+                .await
+                .unwrap();
+            let (len, from) =
+                tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                    .await
+                    .expect("no response")
+                    .unwrap();
+
+            let expected = addrs[response_slot(0, change)];
+            assert_eq!(
+                from, expected,
+                "flags ip={} port={}",
+                change_ip, change_port
+            );
+            assert_eq!(
+                address_attribute(&buf[..len], ATTR_RESPONSE_ORIGIN),
+                expected
+            );
+            assert_eq!(address_attribute(&buf[..len], ATTR_OTHER_ADDRESS), addrs[3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_egress_from_send_socket() {
+        let server = StunServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_send_socket("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let listen = server.local_addrs()[0];
+        let send = server.send_addr().unwrap();
+        assert_ne!(listen, send);
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&binding_request(None), listen)
+            .await
+            .unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .expect("no response")
+            .unwrap();
+        assert_eq!(from, send);
+        assert_eq!(len, 32);
+    }
+
+    #[tokio::test]
+    async fn builder_applies_workers_and_software() {
+        let server = StunServer::builder()
+            .workers(2)
+            .queue_capacity(16)
+            .software("carapace-test")
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        assert_eq!(server.config().workers, 2);
+        assert_eq!(server.config().queue_capacity, 16);
+        assert_eq!(server.config().software.as_deref(), Some("carapace-test"));
+        let listen = server.local_addrs()[0];
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&binding_request(None), listen)
+            .await
+            .unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .expect("no response")
+            .unwrap();
+
+        let response = StunRequest::parse(&buf[..len]).unwrap();
+        let software = response
+            .attributes()
+            .map(Result::unwrap)
+            .find(|(t, _)| *t == ATTR_SOFTWARE)
+            .map(|(_, v)| v)
+            .expect("SOFTWARE missing");
+        assert_eq!(software, b"carapace-test");
+    }
+
+    #[test]
+    fn fingerprint_option_keeps_requested_size() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let config = StunConfig {
+            fingerprint: true,
+            ..StunConfig::default()
+        };
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let reply = handle_request(
+            &binding_request(None),
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(reply.len, 32 + FINGERPRINT_SIZE);
+
+        // padding leaves room for the fingerprint, even at the size cap
+        let mut request = binding_request(None);
+        request[3] = 8;
+        request.extend_from_slice(&ATTR_RESPONSE_SIZE.to_be_bytes());
+        request.extend_from_slice(&2u16.to_be_bytes());
+        request.extend_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+        let reply = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(reply.len, MAX_RESPONSE_SIZE);
+        let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+        let last = response.attributes().map(|a| a.unwrap().0).last();
+        assert_eq!(last, Some(ATTR_FINGERPRINT));
+    }
+
+    #[test]
+    fn legacy_mapped_adds_plain_mapped_address() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let config = StunServer::builder().legacy_mapped(true).config;
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let reply = handle_request(
+            &binding_request(None),
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(reply.len, 32 + 12);
+        assert_eq!(
+            address_attribute(&buf[..reply.len], ATTR_MAPPED_ADDRESS),
+            client
+        );
+    }
+
+    #[test]
+    fn software_follows_xor_mapped_address() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let reply = handle_request(
+            &binding_request(None),
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &StunConfig::default(),
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(reply.len, 32);
+
+        let config = StunConfig {
+            software: Some("carapace/0.1".to_string()),
+            ..StunConfig::default()
+        };
+        let reply = handle_request(
+            &binding_request(None),
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        // 12 bytes of text: no padding needed
+        assert_eq!(reply.len, 32 + 4 + 12);
+        assert_eq!(
+            u16::from_be_bytes([buf[2], buf[3]]) as usize,
+            reply.len - 20
+        );
+
+        let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+        let attrs: Vec<_> = response.attributes().map(Result::unwrap).collect();
+        assert_eq!(
+            attrs.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            [0x0020, ATTR_SOFTWARE]
+        );
+        assert_eq!(attrs[1].1, b"carapace/0.1");
+    }
+
+    #[tokio::test]
+    async fn with_software_tags_responses() {
+        let server = StunServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_software("carapace")
+            .unwrap();
+        assert_eq!(server.config().software.as_deref(), Some("carapace"));
 
-    Ok(BINDING_RESPONSE_SIZE)
+        let server = server.with_software("x".repeat(MAX_SOFTWARE_LEN + 1));
+        assert!(server.is_err());
+    }
+
+    /// binding request signed with `password`, carrying `nonce`
+    fn signed_request(credentials: &Credentials, nonce: &str, password: &str) -> Vec<u8> {
+        signed_request_with(credentials, nonce, password, &[])
+    }
+
+    /// A signed request carrying `attrs` ahead of the credentials
+    fn signed_request_with(
+        credentials: &Credentials,
+        nonce: &str,
+        password: &str,
+        attrs: &[(u16, &[u8])],
+    ) -> Vec<u8> {
+        use hmac::{Hmac, Mac};
+
+        let mut data = binding_request(None);
+        for &(attr_type, value) in attrs.iter().chain(&[
+            (ATTR_USERNAME, credentials.username.as_bytes()),
+            (ATTR_REALM, credentials.realm.as_bytes()),
+            (ATTR_NONCE, nonce.as_bytes()),
+        ]) {
+            data.extend_from_slice(&attr_type.to_be_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            data.extend_from_slice(value);
+            data.resize((data.len() + 3) & !3, 0);
+        }
+        let len = (data.len() + MESSAGE_INTEGRITY_SIZE - 20) as u16;
+        data[2..4].copy_from_slice(&len.to_be_bytes());
+
+        let key = long_term_key(&credentials.username, &credentials.realm, password);
+        let mut mac = Hmac::<sha1::Sha1>::new_from_slice(&key).unwrap();
+        mac.update(&data);
+        data.extend_from_slice(&[0x00, 0x08, 0x00, 0x14]);
+        data.extend_from_slice(&mac.finalize().into_bytes());
+        data
+    }
+
+    #[test]
+    fn credentials_gate_binding_requests() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let config = StunConfig {
+            credentials: Some(Credentials::new("alice", "example.org", "secret")),
+            ..StunConfig::default()
+        };
+        let credentials = config.credentials.clone().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        // correctly signed: answered, and the answer is signed too
+        let request = signed_request(&credentials, credentials.nonce(), "secret");
+        let reply = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::BindingResponse);
+        assert!(response.verify_integrity(&credentials.key).unwrap());
+
+        // tampered after signing, wrong password, or unsigned: 401 with a challenge
+        let mut tampered = request.clone();
+        tampered[27] ^= 0x01;
+        for request in [
+            tampered,
+            signed_request(&credentials, credentials.nonce(), "guess"),
+            binding_request(None),
+        ] {
+            let err = handle_request(
+                &request,
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &config,
+                None,
+                &mut buf,
+            )
+            .unwrap_err();
+            assert!(matches!(err, StunError::Unauthorized));
+            let reply = error_reply(&request, &err, 0, &config, &mut buf).unwrap();
+            let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+            assert_eq!(
+                response.attribute(ATTR_REALM).unwrap(),
+                Some(&b"example.org"[..])
+            );
+            assert_eq!(
+                response.attribute(ATTR_NONCE).unwrap(),
+                Some(credentials.nonce().as_bytes())
+            );
+            assert_eq!(error_attributes(&buf[..reply.len]).0, 401);
+        }
+
+        // signed against an old nonce: 438
+        let request = signed_request(&credentials, "0000000000000000", "secret");
+        let err = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            None,
+            &mut buf,
+        )
+        .unwrap_err();
+        assert!(matches!(err, StunError::StaleNonce));
+        let reply = error_reply(&request, &err, 0, &config, &mut buf).unwrap();
+        assert_eq!(error_attributes(&buf[..reply.len]).0, 438);
+    }
+
+    #[test]
+    fn response_size_below_the_signed_trailer_pads_nothing() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let config = StunConfig {
+            credentials: Some(Credentials::new("alice", "example.org", "secret")),
+            fingerprint: true,
+            ..StunConfig::default()
+        };
+        let credentials = config.credentials.clone().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        // MESSAGE-INTEGRITY and FINGERPRINT take 32 bytes between them
+        for size in [4u16, 24, 31] {
+            let value = [(size >> 8) as u8, size as u8, 0, 0];
+            let request = signed_request_with(
+                &credentials,
+                credentials.nonce(),
+                "secret",
+                &[(ATTR_RESPONSE_SIZE, &value)],
+            );
+            let reply = handle_request(
+                &request,
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &config,
+                None,
+                &mut buf,
+            )
+            .unwrap();
+            let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+            let types: Vec<u16> = response.attributes().map(|a| a.unwrap().0).collect();
+            assert_eq!(
+                types,
+                [
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    ATTR_MESSAGE_INTEGRITY,
+                    ATTR_FINGERPRINT
+                ]
+            );
+            assert!(response.verify_integrity(&credentials.key).unwrap());
+        }
+    }
+
+    #[test]
+    fn redirect_starts_at_the_configured_queue_depth() {
+        let alternate: SocketAddr = "198.51.100.7:3478".parse().unwrap();
+        let config = StunConfig {
+            queue_capacity: 8,
+            alternate_server: Some(alternate),
+            ..StunConfig::default()
+        };
+        // Half the queue by default
+        assert_eq!(config.redirect_target(3), None);
+        assert_eq!(config.redirect_target(4), Some(alternate));
+
+        let config = StunConfig {
+            redirect_depth: Some(2),
+            ..config
+        };
+        assert_eq!(config.redirect_target(1), None);
+        assert_eq!(config.redirect_target(2), Some(alternate));
+
+        let config = StunConfig {
+            alternate_server: None,
+            ..config
+        };
+        assert_eq!(config.redirect_target(8), None);
+    }
+
+    #[test]
+    fn redirected_requests_get_try_alternate() {
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let alternate = "198.51.100.7:3478".parse().unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let config = StunConfig {
+            fingerprint: true,
+            ..StunConfig::default()
+        };
+        let request = binding_request(None);
+        let reply = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            Some(alternate),
+            &mut buf,
+        )
+        .unwrap();
+        let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::BindingErrorResponse);
+        assert_eq!(response.error_code().unwrap(), Some((300, "Try Alternate")));
+        assert_eq!(
+            response.attribute(ATTR_ALTERNATE_SERVER).unwrap(),
+            Some(&[0x00, 0x01, 0x0D, 0x96, 198, 51, 100, 7][..])
+        );
+        assert!(response.attribute(ATTR_FINGERPRINT).unwrap().is_some());
+
+        // With credentials, only a signed request learns the alternate
+        let config = StunConfig {
+            credentials: Some(Credentials::new("alice", "example.org", "secret")),
+            ..StunConfig::default()
+        };
+        let credentials = config.credentials.clone().unwrap();
+        let err = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            Some(alternate),
+            &mut buf,
+        )
+        .unwrap_err();
+        assert!(matches!(err, StunError::Unauthorized));
+
+        let request = signed_request(&credentials, credentials.nonce(), "secret");
+        let reply = handle_request(
+            &request,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            Some(alternate),
+            &mut buf,
+        )
+        .unwrap();
+        let response = StunRequest::parse(&buf[..reply.len]).unwrap();
+        assert_eq!(response.error_code().unwrap(), Some((300, "Try Alternate")));
+        assert!(response.verify_integrity(&credentials.key).unwrap());
+    }
+
+    #[tokio::test]
+    async fn builder_rejects_invalid_config() {
+        let no_workers = StunServer::builder().workers(0).bind("127.0.0.1:0").await;
+        assert!(no_workers.is_err());
+        let no_queue = StunServer::builder()
+            .queue_capacity(0)
+            .bind("127.0.0.1:0")
+            .await;
+        assert!(no_queue.is_err());
+        for batch in [0, MAX_RECV_BATCH + 1] {
+            let result = StunServer::builder()
+                .recv_batch(batch)
+                .bind("127.0.0.1:0")
+                .await;
+            assert!(result.is_err(), "batch of {}", batch);
+        }
+
+        let long = "x".repeat(MAX_SOFTWARE_LEN + 1);
+        let result = StunServer::builder()
+            .software(long)
+            .bind("127.0.0.1:0")
+            .await;
+        assert!(result.is_err());
+
+        let realm = "r".repeat(MAX_REALM_LEN + 1);
+        let result = StunServer::builder()
+            .credentials("alice", &realm, "secret")
+            .bind("127.0.0.1:0")
+            .await;
+        assert!(result.is_err());
+
+        for depth in [0, DEFAULT_QUEUE_CAPACITY + 1] {
+            let result = StunServer::builder()
+                .redirect_depth(depth)
+                .bind("127.0.0.1:0")
+                .await;
+            assert!(result.is_err(), "redirect depth of {}", depth);
+        }
+
+        for ttl in [0, 256] {
+            let result = StunServer::builder().ttl(ttl).bind("127.0.0.1:0").await;
+            assert!(result.is_err(), "TTL of {}", ttl);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn dont_fragment_and_ttl_apply_to_dual_stack_sockets() {
+        let server = StunServer::builder()
+            .dont_fragment(true)
+            .ttl(17)
+            .bind("[::]:0")
+            .await
+            .unwrap();
+        let port = server.local_addrs()[0].port();
+        let sock = socket2::SockRef::from(&*server.sockets.sockets[0]);
+        assert_eq!(sock.unicast_hops_v6().unwrap(), 17);
+        assert_eq!(sock.ttl_v4().unwrap(), 17);
+        tokio::spawn(server.run());
+
+        let client = crate::client::StunClient::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mapped = client
+            .discover(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .unwrap();
+        assert_eq!(mapped, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn processed_requests_populate_latency_histogram() {
+        let server = StunServer::bind("127.0.0.1:0").await.unwrap();
+        let listen = server.local_addrs()[0];
+        let latency = server.latency();
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        for _ in 0..5 {
+            client
+                .send_to(&binding_request(None), listen)
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                .await
+                .expect("no response")
+                .unwrap();
+        }
+
+        // The last observation is recorded just after its response goes out
+        let snapshot = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let snapshot = latency.snapshot();
+                if snapshot.count() >= 5 {
+                    return snapshot;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("histogram not populated");
+        assert_eq!(snapshot.count(), 5);
+    }
+
+    #[test]
+    fn respond_counts_rejected_requests() {
+        let config = StunConfig::default();
+        let metrics = StunMetrics::new();
+        let client: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let valid = binding_request(None);
+        assert!(
+            respond(
+                &valid,
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &config,
+                None,
+                &metrics,
+                &mut buf
+            )
+            .is_some()
+        );
+        assert!(
+            respond(
+                &[0xFF; 8],
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &config,
+                None,
+                &metrics,
+                &mut buf
+            )
+            .is_none()
+        );
+        // A CHANGE-REQUEST without an alternate gets a 420 but still counts
+        let change = binding_request(Some(ChangeRequest {
+            change_ip: true,
+            change_port: false,
+        }));
+        assert!(
+            respond(
+                &change,
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &config,
+                None,
+                &metrics,
+                &mut buf
+            )
+            .is_some()
+        );
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.parse_errors, 2);
+        assert_eq!(snapshot.received, 0, "receiving is counted by the caller");
+    }
+
+    #[test]
+    fn binding_indication_gets_no_response() {
+        let config = StunConfig::default();
+        let metrics = StunMetrics::new();
+        let client: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let addrs = ["127.0.0.1:3478".parse().unwrap()];
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        let mut indication = binding_request(None);
+        indication[1] = 0x11;
+        let reply = handle_request(
+            &indication,
+            client,
+            0,
+            Transport::Udp,
+            &addrs,
+            &config,
+            None,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(reply.len, 0);
+        assert!(
+            respond(
+                &indication,
+                client,
+                0,
+                Transport::Udp,
+                &addrs,
+                &config,
+                None,
+                &metrics,
+                &mut buf
+            )
+            .is_none()
+        );
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.indications, 1);
+        assert_eq!(snapshot.parse_errors, 0);
+    }
+
+    #[tokio::test]
+    async fn run_counts_received_and_sent_packets() {
+        let server = StunServer::bind("127.0.0.1:0").await.unwrap();
+        let listen = server.local_addrs()[0];
+        let metrics = server.metrics();
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"not stun", listen).await.unwrap();
+        client
+            .send_to(&binding_request(None), listen)
+            .await
+            .unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .expect("no response")
+            .unwrap();
+
+        let snapshot = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let snapshot = metrics.snapshot();
+                if snapshot.received >= 2 && snapshot.responses_sent >= 1 {
+                    return snapshot;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("metrics not updated");
+        assert_eq!(snapshot.responses_sent, 1);
+        assert_eq!(snapshot.parse_errors, 1);
+        assert_eq!(snapshot.dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_applies_per_source_ip() {
+        let server = StunServer::builder()
+            .rate_limit(RateLimit::new(0.001, 3))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let listen = server.local_addrs()[0];
+        let metrics = server.metrics();
+        tokio::spawn(server.run());
+
+        let noisy = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..10 {
+            noisy.send_to(&binding_request(None), listen).await.unwrap();
+        }
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        for _ in 0..3 {
+            tokio::time::timeout(Duration::from_secs(2), noisy.recv_from(&mut buf))
+                .await
+                .expect("no response")
+                .unwrap();
+        }
+
+        // Another IP, sent after the burst, still has its full budget
+        let quiet = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        quiet.send_to(&binding_request(None), listen).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), quiet.recv_from(&mut buf))
+            .await
+            .expect("second IP was limited")
+            .unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.received, 11);
+        assert_eq!(snapshot.rate_limited, 7);
+        assert_eq!(snapshot.responses_sent, 4);
+        let extra = tokio::time::timeout(Duration::from_millis(50), noisy.recv_from(&mut buf));
+        assert!(extra.await.is_err(), "limited requests were answered");
+    }
+
+    #[tokio::test]
+    async fn batched_receive_answers_every_request() {
+        let result = StunServer::builder()
+            .recv_batch(16)
+            .bind("127.0.0.1:0")
+            .await;
+        if !cfg!(all(target_os = "linux", feature = "recvmmsg")) {
+            assert!(result.is_err(), "batching needs the recvmmsg feature");
+            return;
+        }
+        let server = result.unwrap();
+        let listen = server.local_addrs()[0];
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..20 {
+            client
+                .send_to(&binding_request(None), listen)
+                .await
+                .unwrap();
+        }
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        for _ in 0..20 {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                .await
+                .expect("missing response")
+                .unwrap();
+            assert_eq!(len, BINDING_RESPONSE_SIZE_V4);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_buffers_are_recycled_under_load() {
+        let server = StunServer::builder()
+            .workers(2)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let listen = server.local_addrs()[0];
+        let pool = server.pool.clone();
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        for _ in 0..10 {
+            for _ in 0..50 {
+                client
+                    .send_to(&binding_request(None), listen)
+                    .await
+                    .unwrap();
+            }
+            for _ in 0..50 {
+                tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+                    .await
+                    .expect("missing response")
+                    .unwrap();
+            }
+        }
+
+        // 500 requests, but never more than a burst's worth of buffers
+        assert!(pool.allocated() <= 50 + 2 + 1, "{}", pool.allocated());
+        // Everything comes back except the one the receive task is reading into
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while pool.available() + 1 < pool.allocated() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("buffers not returned");
+    }
+
+    #[tokio::test]
+    async fn tcp_request_gets_xor_mapped_address() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = StunServer::builder()
+            .tcp("127.0.0.1:0".parse().unwrap())
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let tcp_addr = server.tcp_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let mut stream = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+        let client = stream.local_addr().unwrap();
+        let mut response = [0u8; BINDING_RESPONSE_SIZE_V4];
+        // Two requests on one connection, each answered in turn
+        for _ in 0..2 {
+            stream.write_all(&binding_request(None)).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(2), stream.read_exact(&mut response))
+                .await
+                .expect("no response")
+                .unwrap();
+
+            let parsed = StunRequest::parse(&response).unwrap();
+            assert_eq!(parsed.msg_type, MessageType::BindingResponse);
+            let value = parsed.attribute(ATTR_XOR_MAPPED_ADDRESS).unwrap().unwrap();
+            let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+            let ip = u32::from_be_bytes(value[4..8].try_into().unwrap()) ^ MAGIC_COOKIE;
+            assert_eq!(
+                SocketAddr::from((std::net::Ipv4Addr::from(ip), port)),
+                client
+            );
+        }
+
+        // Garbage can't be framed, so the server hangs up
+        stream.write_all(&[0xFF; 20]).await.unwrap();
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest))
+            .await
+            .expect("connection left open")
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tcp_request_to_four_socket_server_stays_on_its_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = StunServer::builder()
+            .alternate("127.0.0.2:0".parse().unwrap())
+            .tcp("127.0.0.1:0".parse().unwrap())
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        assert_eq!(server.local_addrs().len(), 4);
+        let tcp_addr = server.tcp_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let mut stream = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+        let mut exchange = async |request: Vec<u8>| {
+            stream.write_all(&request).await.unwrap();
+            let mut response = vec![0u8; HEADER_SIZE];
+            let read = async {
+                stream.read_exact(&mut response).await.unwrap();
+                let len = u16::from_be_bytes([response[2], response[3]]) as usize;
+                response.resize(HEADER_SIZE + len, 0);
+                stream
+                    .read_exact(&mut response[HEADER_SIZE..])
+                    .await
+                    .unwrap();
+            };
+            tokio::time::timeout(Duration::from_secs(2), read)
+                .await
+                .expect("no response");
+            response
+        };
+
+        // The UDP sockets' addresses say nothing about this connection
+        let response = exchange(binding_request(None)).await;
+        let parsed = StunRequest::parse(&response).unwrap();
+        assert_eq!(parsed.msg_type, MessageType::BindingResponse);
+        assert!(parsed.attribute(ATTR_RESPONSE_ORIGIN).unwrap().is_none());
+        assert!(parsed.attribute(ATTR_OTHER_ADDRESS).unwrap().is_none());
+
+        // RFC 5780 section 7.2: CHANGE-REQUEST over TCP is a bad request
+        for (change_ip, change_port) in FLAG_COMBINATIONS {
+            let change = ChangeRequest {
+                change_ip,
+                change_port,
+            };
+            let response = exchange(binding_request(Some(change))).await;
+            let (code, _, _) = error_attributes(&response);
+            assert_eq!(code, 400, "flags ip={} port={}", change_ip, change_port);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_queued_requests() {
+        let server = StunServer::builder()
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let listen = server.local_addrs()[0];
+        let metrics = server.metrics();
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = stop_rx.await;
+        }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..8 {
+            client
+                .send_to(&binding_request(None), listen)
+                .await
+                .unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while metrics.snapshot().received < 8 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("requests not received");
+
+        stop_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), running)
+            .await
+            .expect("run didn't return after shutdown")
+            .unwrap()
+            .unwrap();
+        assert_eq!(metrics.snapshot().responses_sent, 8);
+
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        for _ in 0..8 {
+            tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+                .await
+                .expect("missing response")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn send_socket_rejected_with_alternate() {
+        let server = StunServer::bind_with_alternate(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let result = server
+            .with_send_socket("127.0.0.1:0".parse().unwrap())
+            .await;
+        assert!(result.is_err());
+    }
 }
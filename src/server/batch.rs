@@ -0,0 +1,149 @@
+//! Batched UDP receive with `recvmmsg(2)`: one syscall drains every datagram
+//! queued on the socket, up to the batch size, where `recv_from` takes one
+//! syscall per datagram
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+use super::MAX_REQUEST_SIZE;
+
+/// Receive buffers and sender addresses for one `recvmmsg` call
+pub(super) struct RecvBatch {
+    bufs: Vec<[u8; MAX_REQUEST_SIZE]>,
+    addrs: Vec<libc::sockaddr_storage>,
+    lens: Vec<usize>,
+}
+
+impl RecvBatch {
+    pub fn new(size: usize) -> Self {
+        Self {
+            bufs: vec![[0u8; MAX_REQUEST_SIZE]; size],
+            // SAFETY: sockaddr_storage is plain old data; all zeroes is valid
+            addrs: vec![unsafe { std::mem::zeroed() }; size],
+            lens: vec![0; size],
+        }
+    }
+
+    /// Wait until the socket is readable, then receive as many datagrams as
+    /// are queued (at least one, at most the batch size). Returns the count.
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || self.recvmmsg(socket)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Datagram `i` of the last batch and its sender, `None` past the end
+    /// or for an address family other than IPv4/IPv6
+    pub fn get(&self, i: usize) -> Option<(&[u8], SocketAddr)> {
+        let addr = socket_addr(self.addrs.get(i)?)?;
+        Some((&self.bufs[i][..self.lens[i]], addr))
+    }
+
+    fn recvmmsg(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        // Rebuilt on every call: they point into `bufs` and `addrs`, and
+        // holding raw pointers across an await would make the future !Send
+        let mut iovecs: Vec<libc::iovec> = self
+            .bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(self.addrs.iter_mut())
+            .map(|(iov, addr)| {
+                // SAFETY: mmsghdr is plain old data; the fields that matter
+                // are filled in below
+                let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+                header.msg_hdr.msg_namelen =
+                    std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                header.msg_hdr.msg_iov = iov;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+
+        // SAFETY: every header points at a live iovec, buffer and address
+        // slot owned by this call or by `self`, each sized as declared
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let received = received as usize;
+        for (len, header) in self.lens.iter_mut().zip(&headers[..received]) {
+            *len = header.msg_len as usize;
+        }
+        Ok(received)
+    }
+}
+
+/// Convert a sender address filled in by the kernel
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a sockaddr_in
+            let addr =
+                unsafe { &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says the storage holds a sockaddr_in6
+            let addr = unsafe {
+                &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>()
+            };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drains_queued_datagrams_in_one_batch() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap();
+        for payload in [&b"one"[..], b"two", b"three"] {
+            client.send_to(payload, target).await.unwrap();
+        }
+        // Let all three land before the first read
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        let mut batch = RecvBatch::new(8);
+        assert_eq!(batch.recv(&server).await.unwrap(), 3);
+        let sender = client.local_addr().unwrap();
+        assert_eq!(batch.get(0), Some((&b"one"[..], sender)));
+        assert_eq!(batch.get(2), Some((&b"three"[..], sender)));
+    }
+}
@@ -0,0 +1,101 @@
+//! Per-source rate limiting: one token bucket per client IP, so a client
+//! looping requests can't take the server's whole capacity
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use tokio::time::Instant;
+
+use crate::rate_limit::{RateLimit, TokenBucket};
+
+/// Independently locked slices of the bucket map, so receive tasks rarely
+/// wait on each other
+const SHARDS: usize = 16;
+
+/// Buckets a shard holds before it first forgets the ones that have refilled
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Token buckets keyed by client IP, shared by the receive tasks
+pub(super) struct IpRateLimiter {
+    limit: RateLimit,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl IpRateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+        }
+    }
+
+    /// Take a token from `ip`'s bucket if it has one
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        let shard = self.hasher.hash_one(ip) as usize % SHARDS;
+        self.shards[shard]
+            .lock()
+            .unwrap()
+            .try_acquire(ip, self.limit, Instant::now())
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /// size at which the next prune happens
+    prune_at: usize,
+}
+
+impl Shard {
+    fn try_acquire(&mut self, ip: IpAddr, limit: RateLimit, now: Instant) -> bool {
+        if self.buckets.len() >= self.prune_at.max(PRUNE_THRESHOLD)
+            && !self.buckets.contains_key(&ip)
+        {
+            // A refilled bucket behaves like a new one, so dropping it is
+            // free. Waiting for the map to double again before the next
+            // prune keeps a flood of fresh sources from paying for a scan
+            // each.
+            self.buckets.retain(|_, bucket| !bucket.is_full_at(now));
+            self.prune_at = self.buckets.len() * 2;
+        }
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new_at(limit, now))
+            .try_acquire_at(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn refilled_buckets_are_pruned() {
+        let limit = RateLimit::new(10.0, 1);
+        let mut shard = Shard::default();
+        let start = Instant::now();
+        for i in 0..PRUNE_THRESHOLD as u32 {
+            assert!(shard.try_acquire(IpAddr::V4(Ipv4Addr::from(i)), limit, start));
+        }
+
+        // Still draining at first, so nothing can go yet
+        let soon = start + Duration::from_millis(10);
+        shard.try_acquire(IpAddr::V4(Ipv4Addr::BROADCAST), limit, soon);
+        assert_eq!(shard.buckets.len(), PRUNE_THRESHOLD + 1);
+
+        let later = start + Duration::from_secs(1);
+        for i in 0..PRUNE_THRESHOLD as u32 {
+            shard.try_acquire(IpAddr::V4(Ipv4Addr::from(u32::MAX - 1 - i)), limit, later);
+        }
+        // The last one found the map doubled and swept out every refilled bucket
+        assert_eq!(shard.buckets.len(), PRUNE_THRESHOLD);
+        assert!(!shard.buckets.contains_key(&IpAddr::V4(Ipv4Addr::from(0))));
+    }
+}
@@ -0,0 +1,111 @@
+//! Recycled request buffers: a datagram is received straight into the buffer
+//! its worker reads, which goes back to the pool once the work item is done
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_queue::ArrayQueue;
+
+use super::MAX_REQUEST_SIZE;
+
+type RequestBuf = [u8; MAX_REQUEST_SIZE];
+
+/// Free request buffers shared by the receive tasks and the workers
+pub(super) struct BufferPool {
+    free: ArrayQueue<Box<RequestBuf>>,
+    allocated: AtomicUsize,
+}
+
+impl BufferPool {
+    /// A pool keeping up to `capacity` idle buffers; any beyond that are
+    /// freed when returned
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            free: ArrayQueue::new(capacity),
+            allocated: AtomicUsize::new(0),
+        })
+    }
+
+    /// An idle buffer, or a fresh one if every buffer is in use
+    pub fn take(self: &Arc<Self>) -> PooledBuf {
+        let buf = self.free.pop().unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            Box::new([0u8; MAX_REQUEST_SIZE])
+        });
+        PooledBuf {
+            buf: Some(buf),
+            pool: self.clone(),
+        }
+    }
+
+    /// Buffers allocated so far
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Buffers waiting to be reused
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+}
+
+/// A buffer on loan from a [`BufferPool`], returned to it on drop
+pub(super) struct PooledBuf {
+    buf: Option<Box<RequestBuf>>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = RequestBuf;
+
+    fn deref(&self) -> &RequestBuf {
+        self.buf.as_deref().expect("buffer present until drop")
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut RequestBuf {
+        self.buf.as_deref_mut().expect("buffer present until drop")
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take()
+            && self.pool.free.push(buf).is_err()
+        {
+            // Pool full: this one is freed instead
+            self.pool.allocated.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returned_buffers_are_reused() {
+        let pool = BufferPool::new(2);
+        let first = pool.take();
+        let addr = first.as_ptr();
+        drop(first);
+        assert_eq!(pool.available(), 1);
+
+        let again = pool.take();
+        assert_eq!(again.as_ptr(), addr);
+        assert_eq!(pool.allocated(), 1);
+    }
+
+    #[test]
+    fn buffers_beyond_capacity_are_freed() {
+        let pool = BufferPool::new(2);
+        let loaned: Vec<_> = (0..5).map(|_| pool.take()).collect();
+        assert_eq!(pool.allocated(), 5);
+
+        drop(loaned);
+        assert_eq!(pool.available(), 2);
+        assert_eq!(pool.allocated(), 2);
+    }
+}
@@ -0,0 +1,80 @@
+//! IP-level options on the UDP sockets responses leave from: the Don't
+//! Fragment bit and the TTL (hop limit on IPv6)
+
+use std::io;
+
+use socket2::SockRef;
+use tokio::net::UdpSocket;
+
+/// Apply the configured options to one socket
+///
+/// A dual-stack IPv6 socket also gets the IPv4 options, since its responses
+/// to IPv4 clients go out as IPv4.
+pub(super) fn apply(socket: &UdpSocket, dont_fragment: bool, ttl: Option<u32>) -> io::Result<()> {
+    let sock = SockRef::from(socket);
+    let v6 = socket.local_addr()?.is_ipv6();
+    let v4 = !v6 || !sock.only_v6()?;
+    if let Some(ttl) = ttl {
+        if v6 {
+            sock.set_unicast_hops_v6(ttl)?;
+        }
+        if v4 {
+            sock.set_ttl_v4(ttl)?;
+        }
+    }
+    if dont_fragment {
+        set_dont_fragment(socket, v4, v6)?;
+    }
+    Ok(())
+}
+
+/// Refuse to fragment outgoing datagrams; one too big for the path is
+/// dropped with an ICMP error instead
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, v4: bool, v6: bool) -> io::Result<()> {
+    if v6 {
+        set_int(socket, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1)?;
+    }
+    if v4 {
+        set_int(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &UdpSocket, _v4: bool, _v6: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Don't Fragment is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_int(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the pointer and length describe `value`, which outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! STUN over TCP (RFC 5389 section 7.2.2) for clients on networks that
+//! block UDP: messages are framed by the length in their header and each
+//! response goes back on the connection its request came in on
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::metrics::StunMetrics;
+use crate::protocol::{HEADER_SIZE, MAX_RESPONSE_SIZE, StunRequest};
+
+use super::{MAX_REQUEST_SIZE, SocketSet, StunConfig, Transport, respond};
+
+/// connections that don't complete a message within this long are closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// accept connections until the listener fails, serving each on its own task
+pub(super) async fn accept_loop(
+    listener: TcpListener,
+    sockets: Arc<SocketSet>,
+    config: Arc<StunConfig>,
+    metrics: Arc<StunMetrics>,
+) -> io::Result<()> {
+    loop {
+        let (stream, client_addr) = listener.accept().await?;
+        let sockets = sockets.clone();
+        let config = config.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let shown = config.redaction.redact(client_addr);
+            match serve(stream, client_addr, &sockets, &config, &metrics).await {
+                Ok(()) => debug!("TCP connection from {} closed", shown),
+                Err(e) => warn!("TCP connection from {} failed: {}", shown, e),
+            }
+        });
+    }
+}
+
+/// answer requests on one connection until the client closes it, goes idle
+/// or sends something that isn't STUN
+async fn serve(
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    sockets: &SocketSet,
+    config: &StunConfig,
+    metrics: &StunMetrics,
+) -> io::Result<()> {
+    let mut buf = [0u8; MAX_REQUEST_SIZE];
+    let mut response_buf = [0u8; MAX_RESPONSE_SIZE];
+
+    loop {
+        let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, read_message(&mut stream, &mut buf))
+            .await
+        {
+            Ok(Ok(Some(len))) => len,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+        };
+        metrics.record_received();
+
+        let data = &buf[..len];
+        match respond(
+            data,
+            client_addr,
+            0,
+            Transport::Tcp,
+            &sockets.addrs,
+            config,
+            // Connections aren't queued, so there's no backlog to shed
+            None,
+            metrics,
+            &mut response_buf,
+        ) {
+            Some(reply) => {
+                stream.write_all(&response_buf[..reply.len]).await?;
+                metrics.record_response_sent();
+            }
+            // Past a message that isn't STUN there are no frames to find
+            None if StunRequest::parse(data).is_err() => return Ok(()),
+            // An indication, or a request that gets no answer
+            None => {}
+        }
+    }
+}
+
+/// read one framed message into `buf`, returning its length, or `None` at a
+/// clean end of stream or for a message too large to buffer
+async fn read_message(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    match stream.read_exact(&mut buf[..HEADER_SIZE]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = HEADER_SIZE + u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if len > buf.len() {
+        debug!("Dropping TCP connection: {} byte message", len);
+        return Ok(None);
+    }
+    stream.read_exact(&mut buf[HEADER_SIZE..len]).await?;
+    Ok(Some(len))
+}
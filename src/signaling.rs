@@ -1,11 +1,33 @@
 //! WebSocket signaling server for P2P coordination
 
 mod actor;
+mod codec;
+mod config;
+mod events;
+mod health;
 mod messages;
+mod outbound;
+mod relay;
+mod room;
 mod server;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
 mod types;
 
-pub use actor::RoomManagerHandle;
-pub use messages::{ClientMessage, ServerMessage};
-pub use server::{DEFAULT_SIGNALING_PORT, SignalingServer};
-pub use types::{OutboundMessage, PeerId, PeerInfo, RoomCode, SignalingError};
+pub use actor::{CreateOptions, JoinOptions, QuickMatched, RoomManagerHandle};
+pub use codec::{CodecError, Encoding};
+pub use config::SignalingConfig;
+pub use events::{RoomEvent, spawn_event_log};
+pub use messages::{ClientMessage, RelayedBroadcast, ServerMessage};
+pub use outbound::{
+    DEFAULT_OUTBOUND_CAPACITY, OutboundMessage, OutboundReceiver, OutboundSender, Priority,
+    outbound_channel, outbound_channel_with_capacity,
+};
+pub use server::{DEFAULT_SIGNALING_PORT, SignalingServer, SignalingServerBuilder};
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+pub use types::{
+    PeerId, PeerInfo, ReflexiveAddr, RoomCode, RoomCodeAlphabet, RoomSummary, ServerStats,
+    SessionToken, SignalingAddr, SignalingError, validate_global_peer_addr, validate_peer_addr,
+};
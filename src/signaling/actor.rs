@@ -1,175 +1,3433 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use tokio::sync::{mpsc, oneshot};
-use tracing::info;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{Instant, Interval};
+use tracing::{Instrument, info, info_span, warn};
 
-use super::messages::ServerMessage;
-use super::types::{OutboundMessage, PeerId, PeerInfo, PeerState, Room, RoomCode, SignalingError};
+use crate::rate_limit::TokenBucket;
+
+use super::config::SignalingConfig;
+use super::events::{EVENT_CHANNEL_CAPACITY, RoomEvent};
+use super::messages::{RelayedBroadcast, ServerMessage};
+use super::outbound::{OutboundMessage, OutboundSender, Priority};
+use super::relay::Relay;
+use super::room::{PeerState, Room};
+use super::types::{
+    PeerId, PeerInfo, ReflexiveAddr, RoomCode, RoomPassword, RoomSummary, ServerStats,
+    SessionToken, SignalingAddr, SignalingError, constant_time_eq, is_global,
+    validate_global_peer_addr,
+};
+
+/// Reply channel for a command the caller awaits
+type Reply<T> = oneshot::Sender<Result<T, SignalingError>>;
+
+/// Where `QuickMatch` put a peer
+#[derive(Debug)]
+pub enum QuickMatched {
+    /// No open room had a seat, so the peer opened one. As with
+    /// `create_room`, sending `RoomCreated` is up to the caller.
+    Created(RoomCode, PeerId, SessionToken),
+    /// The peer took a seat in an open room; the actor has queued
+    /// `RoomJoined`
+    Joined(RoomCode, PeerId, SessionToken, Vec<PeerInfo>),
+}
+
+/// How [`RoomManagerHandle::create_room`] sets up a room
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Code to create the room under if it is free (`None` = generated)
+    pub code: Option<RoomCode>,
+    /// Password joiners need to enter (`None` = open room)
+    pub password: Option<String>,
+    /// Shown in the creator's `PeerInfo` from the start
+    pub metadata: Option<serde_json::Value>,
+    /// Remembers the creator's peer id under this key, so it gets the same
+    /// id if it joins again after losing its connection
+    pub session_key: Option<String>,
+}
+
+/// How [`RoomManagerHandle::join_room`] enters a room
+#[derive(Debug, Clone, Default)]
+pub struct JoinOptions {
+    /// The room's password, if it has one; ignored for an open room
+    pub password: Option<String>,
+    /// Shown in this peer's `PeerInfo` from the start, so the `PeerJoined`
+    /// the room gets already carries it
+    pub metadata: Option<serde_json::Value>,
+    /// Joins under the peer id this key was given in the room before, or
+    /// under a new one remembered for it. Only the id carries over: the
+    /// lock and password apply as to any join. A key is forgotten when its
+    /// peer is kicked or the room closes.
+    pub session_key: Option<String>,
+}
 
 /// Commands sent to the room manager actor
-pub(crate) enum RoomCommand {
+enum RoomCommand {
     Create {
-        addr: SocketAddr,
-        peer_tx: mpsc::UnboundedSender<OutboundMessage>,
-        reply: oneshot::Sender<(RoomCode, PeerId)>,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        password: Option<RoomPassword>,
+        /// Code the creator asked for (`None` = generate one)
+        code: Option<RoomCode>,
+        metadata: Option<serde_json::Value>,
+        session_key: Option<String>,
+        reply: Reply<(RoomCode, PeerId, SessionToken)>,
     },
     Join {
         code: RoomCode,
-        addr: SocketAddr,
-        peer_tx: mpsc::UnboundedSender<OutboundMessage>,
-        reply: oneshot::Sender<Result<(PeerId, Vec<PeerInfo>), SignalingError>>,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        password: Option<String>,
+        metadata: Option<serde_json::Value>,
+        /// Key this peer's id is remembered under, in this room only
+        session_key: Option<String>,
+        reply: Reply<(PeerId, SessionToken, Vec<PeerInfo>)>,
+    },
+    QuickMatch {
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        metadata: Option<serde_json::Value>,
+        reply: Reply<QuickMatched>,
+    },
+    Rejoin {
+        code: RoomCode,
+        peer_id: PeerId,
+        token: SessionToken,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        /// Whether a stale id or token still gets an ordinary join
+        fallback: bool,
+        reply: Reply<(PeerId, SessionToken, Vec<PeerInfo>)>,
     },
     Leave {
         peer_id: PeerId,
+        /// Only leave if the peer is still bound to this connection
+        conn: Option<OutboundSender>,
+    },
+    Rebind {
+        peer_id: PeerId,
+        token: SessionToken,
+        new_tx: OutboundSender,
+        reply: Reply<(RoomCode, Vec<PeerInfo>)>,
+    },
+    Stats {
+        reply: Reply<ServerStats>,
+    },
+    List {
+        admin_token: Option<String>,
+        reply: Reply<Vec<RoomSummary>>,
+    },
+    Resync {
+        code: RoomCode,
+        reply: Reply<()>,
+    },
+    SetLocked {
+        peer_id: PeerId,
+        locked: bool,
+        reply: Reply<()>,
+    },
+    Kick {
+        peer_id: PeerId,
+        target: PeerId,
+        reply: Reply<()>,
+    },
+    Multicast {
+        from: PeerId,
+        to: Vec<PeerId>,
+        payload: serde_json::Value,
+        reply: Reply<(Vec<PeerId>, Vec<PeerId>)>,
+    },
+    Relay {
+        from: PeerId,
+        to: PeerId,
+        /// `Signal`, `Offer`, `Answer` or `IceCandidate`, already stamped
+        /// with `from`
+        msg: ServerMessage,
+        reply: Reply<()>,
+    },
+    SetReflexiveAddr {
+        peer_id: PeerId,
+        addr: ReflexiveAddr,
+        reply: Reply<()>,
+    },
+    SetMetadata {
+        peer_id: PeerId,
+        metadata: Option<serde_json::Value>,
+        reply: Reply<()>,
+    },
+    Peers {
+        peer_id: PeerId,
+        reply: Reply<Vec<PeerInfo>>,
+    },
+    RequestRelay {
+        peer_id: PeerId,
+        reply: Reply<SocketAddr>,
     },
+    Migrate {
+        code: RoomCode,
+        url: String,
+        reply: Reply<()>,
+    },
+    Shutdown {
+        reply: Reply<usize>,
+    },
+    AddAlias {
+        peer_id: PeerId,
+        alias: RoomCode,
+        reply: Reply<RoomCode>,
+    },
+    Broadcast {
+        from: PeerId,
+        payload: serde_json::Value,
+        reply: Reply<()>,
+    },
+}
+
+/// Serialize a message addressed to a single peer
+fn direct_message(msg: &ServerMessage) -> OutboundMessage {
+    let json = serde_json::to_string(msg).expect("ServerMessage serialization should never fail");
+    OutboundMessage::from(json)
+}
+
+/// Serialize a server push once, for fan-out to many peers
+fn bulk_message(msg: &ServerMessage) -> OutboundMessage {
+    direct_message(msg).with_priority(Priority::Bulk)
+}
+
+/// Give a two-peer room its UDP relay, or return the one it already has
+fn allocate_relay(room: &mut Room, config: &SignalingConfig) -> Result<SocketAddr, SignalingError> {
+    if let Some(relay) = &room.relay {
+        return Ok(relay.addr());
+    }
+    let Some(ip) = config.relay_ip else {
+        return Err(SignalingError::RelayUnavailable(
+            "relaying is disabled".into(),
+        ));
+    };
+    // One allocation per room, between exactly two peers
+    let ends: Vec<_> = room
+        .peers()
+        .map(|p| (p.info.id, p.info.reflexive_addr.map(|addr| addr.addr())))
+        .collect();
+    let [(a, Some(a_addr)), (b, Some(b_addr))] = ends[..] else {
+        return Err(SignalingError::RelayUnavailable(
+            "needs two peers that have reported their reflexive address".into(),
+        ));
+    };
+    // A relay on a public address only serves public ends, so peers can't
+    // point it at the server's own loopback or private network
+    if is_global(ip) {
+        for addr in [a_addr, b_addr] {
+            validate_global_peer_addr(addr).map_err(SignalingError::RelayUnavailable)?;
+        }
+    }
+    let relay = Relay::bind(ip, (a, a_addr), (b, b_addr))
+        .map_err(|e| SignalingError::RelayUnavailable(e.to_string()))?;
+    let addr = relay.addr();
+    room.relay = Some(relay);
+    room.broadcast(&direct_message(&ServerMessage::RelayAllocated { addr }));
+    info!("Relay {} allocated for peers {} and {}", addr, a, b);
+    Ok(addr)
+}
+
+/// Keep offers and answers in step: one open offer per pair of peers, and
+/// answers only to an offer that's open
+fn check_negotiation(
+    room: &Room,
+    from: PeerId,
+    to: PeerId,
+    msg: &ServerMessage,
+) -> Result<(), SignalingError> {
+    match msg {
+        ServerMessage::Offer { .. } if room.negotiating(from, to) => {
+            Err(SignalingError::OfferPending(to))
+        }
+        ServerMessage::Answer { .. } if !room.offer_pending(to, from) => {
+            Err(SignalingError::NoOfferPending(to))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Refuse to relay a push larger than clients may send themselves
+fn check_relay_size(msg: &OutboundMessage, config: &SignalingConfig) -> Result<(), SignalingError> {
+    if msg.payload_len() > config.max_message_size {
+        return Err(SignalingError::PayloadTooLarge {
+            limit: config.max_message_size,
+        });
+    }
+    Ok(())
+}
+
+/// Codes drawn for a new room before giving up on finding a free one
+const MAX_CODE_ATTEMPTS: usize = 16;
+
+/// Draw codes from `generate` until one isn't `taken`, within
+/// `MAX_CODE_ATTEMPTS` draws
+fn fresh_code(
+    taken: impl Fn(&RoomCode) -> bool,
+    mut generate: impl FnMut() -> RoomCode,
+) -> Option<RoomCode> {
+    (0..MAX_CODE_ATTEMPTS)
+        .map(|_| generate())
+        .find(|code| !taken(code))
+}
+
+/// Refuse a new room when the server is full or creation is rate limited
+fn check_creation(
+    at_capacity: bool,
+    limiter: Option<&mut TokenBucket>,
+    events: &broadcast::Sender<RoomEvent>,
+) -> Result<(), SignalingError> {
+    if at_capacity {
+        warn!("Peer limit reached, rejecting room creation");
+        return Err(SignalingError::ServerAtCapacity);
+    }
+    if let Some(limiter) = limiter
+        && !limiter.try_acquire()
+    {
+        warn!("Room creation rate limited");
+        let err = SignalingError::CreationRateLimited;
+        let _ = events.send(RoomEvent::Error {
+            message: err.to_string(),
+        });
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Pick the code for a new room: the one asked for if it's free, else a
+/// generated one
+fn new_room_code(
+    requested: Option<RoomCode>,
+    rooms: &HashMap<RoomCode, Room>,
+    aliases: &HashMap<RoomCode, RoomCode>,
+    config: &SignalingConfig,
+) -> Result<RoomCode, SignalingError> {
+    // An existing code would replace its room and orphan the peers
+    let taken = |code: &RoomCode| rooms.contains_key(code) || aliases.contains_key(code);
+    match requested {
+        Some(code) if taken(&code) => Err(SignalingError::CodeTaken(code)),
+        Some(code) => Ok(code),
+        None => {
+            let generate = || RoomCode::generate_from(config.room_code_alphabet);
+            fresh_code(taken, generate).ok_or_else(|| {
+                warn!("No free room code after {} attempts", MAX_CODE_ATTEMPTS);
+                SignalingError::Internal("no free room code".to_string())
+            })
+        }
+    }
+}
+
+/// A room with its creator as the only peer
+fn found_room(
+    addr: SignalingAddr,
+    peer_tx: OutboundSender,
+    metadata: Option<serde_json::Value>,
+    config: &SignalingConfig,
+) -> (Room, PeerId, SessionToken) {
+    let peer_id = PeerId::generate();
+    let token = SessionToken::generate();
+    let peer_state = PeerState {
+        info: new_peer_info(peer_id, addr, metadata),
+        tx: peer_tx,
+        token,
+        relay_limiter: config.relay_rate.map(TokenBucket::new),
+    };
+    let room = Room::new(peer_id, peer_state, config.fanout_offload_threshold)
+        .with_replay(config.broadcast_replay);
+    (room, peer_id, token)
+}
+
+/// Whether `QuickMatch` may put another peer in this room
+fn has_open_seat(room: &Room, config: &SignalingConfig) -> bool {
+    room.quick_match
+        && !room.locked
+        && room.len() < config.quick_match_size
+        && config.max_peers_per_room.is_none_or(|max| room.len() < max)
+}
+
+/// List a quick-match room as open again after it lost a peer or was
+/// unlocked
+fn reopen(
+    code: RoomCode,
+    room: &Room,
+    open_rooms: &mut VecDeque<RoomCode>,
+    config: &SignalingConfig,
+) {
+    if has_open_seat(room, config) && !open_rooms.contains(&code) {
+        open_rooms.push_back(code);
+    }
+}
+
+/// Reject a join the room or server can't take. Peers coming back under
+/// their old id (`returning`) aren't new, so neither a lock nor the room
+/// password stops them.
+fn check_admission(
+    room: &Room,
+    code: RoomCode,
+    config: &SignalingConfig,
+    at_capacity: bool,
+    returning: bool,
+    password: Option<&str>,
+) -> Result<(), SignalingError> {
+    let password_ok = |expected: &RoomPassword| password.is_some_and(|p| expected.verify(p));
+    if room.locked && !returning {
+        Err(SignalingError::RoomLocked(code))
+    } else if !returning && room.password.as_ref().is_some_and(|p| !password_ok(p)) {
+        Err(SignalingError::Unauthorized)
+    } else if config
+        .max_peers_per_room
+        .is_some_and(|max| room.len() >= max)
+    {
+        Err(SignalingError::RoomFull(code))
+    } else if at_capacity {
+        Err(SignalingError::ServerAtCapacity)
+    } else {
+        Ok(())
+    }
+}
+
+/// Add a peer to a room, announcing it to the others and queueing
+/// `RoomJoined` (plus any replay) on its own channel. Returns the roster the
+/// peer was shown.
+fn admit_peer(
+    room: &mut Room,
+    code: RoomCode,
+    info: PeerInfo,
+    token: SessionToken,
+    peer_tx: OutboundSender,
+    config: &SignalingConfig,
+) -> Vec<PeerInfo> {
+    let peer_id = info.id;
+
+    // Snapshot, broadcast and insert all happen within one command, so they
+    // share a single view of membership
+    let existing_peers: Vec<PeerInfo> = room.peers().map(|p| p.info.clone()).collect();
+    room.broadcast(&bulk_message(&ServerMessage::PeerJoined {
+        peer: info.clone(),
+    }));
+
+    // Queued here rather than by the connection after the reply, so it
+    // precedes every push caused by later commands
+    let _ = peer_tx.send(direct_message(&ServerMessage::RoomJoined {
+        code,
+        your_id: peer_id,
+        session_token: token,
+        peers: existing_peers.clone(),
+    }));
+    if room.recent_broadcasts().len() > 0 {
+        let messages: Vec<RelayedBroadcast> = room.recent_broadcasts().cloned().collect();
+        let _ = peer_tx.send(direct_message(&ServerMessage::BroadcastReplay { messages }));
+    }
+
+    room.insert_peer(
+        peer_id,
+        PeerState {
+            info,
+            tx: peer_tx,
+            token,
+            relay_limiter: config.relay_rate.map(TokenBucket::new),
+        },
+    );
+    existing_peers
+}
+
+/// Info for a peer that just arrived and hasn't reported a UDP address yet
+fn new_peer_info(id: PeerId, addr: SignalingAddr, metadata: Option<serde_json::Value>) -> PeerInfo {
+    PeerInfo {
+        id,
+        signaling_addr: Some(addr),
+        reflexive_addr: None,
+        metadata,
+    }
+}
+
+/// Longest gap between sweeps for idle rooms
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Wait for the next idle sweep, or forever if rooms never expire
+async fn next_sweep(sweep: &mut Option<Interval>) {
+    match sweep {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Close every room idle for longer than `ttl`, telling its peers first
+fn expire_idle_rooms(
+    ttl: Duration,
+    rooms: &mut HashMap<RoomCode, Room>,
+    peer_rooms: &mut HashMap<PeerId, RoomCode>,
+    aliases: &mut HashMap<RoomCode, RoomCode>,
+    events: &broadcast::Sender<RoomEvent>,
+) {
+    let expired: Vec<RoomCode> = rooms
+        .iter()
+        .filter(|(_, room)| room.idle_for() > ttl)
+        .map(|(code, _)| *code)
+        .collect();
+
+    for code in expired {
+        if let Some(mut room) = remove_room(&code, rooms, peer_rooms, aliases) {
+            room.broadcast(&direct_message(&ServerMessage::Error {
+                message: "room expired".to_string(),
+            }));
+            info!("Room {} expired after {:?} idle", code, room.idle_for());
+            let _ = events.send(RoomEvent::RoomRemoved { code });
+        }
+    }
 }
 
-pub(crate) async fn room_manager_actor(mut rx: mpsc::Receiver<RoomCommand>) {
+/// Drop a room along with its aliases and its peers' memberships
+fn remove_room(
+    code: &RoomCode,
+    rooms: &mut HashMap<RoomCode, Room>,
+    peer_rooms: &mut HashMap<PeerId, RoomCode>,
+    aliases: &mut HashMap<RoomCode, RoomCode>,
+) -> Option<Room> {
+    let room = rooms.remove(code)?;
+    for alias in &room.aliases {
+        aliases.remove(alias);
+    }
+    for peer in room.peers() {
+        peer_rooms.remove(&peer.info.id);
+    }
+    Some(room)
+}
+
+async fn room_manager_actor(
+    mut rx: mpsc::Receiver<RoomCommand>,
+    events: broadcast::Sender<RoomEvent>,
+    shutdown: watch::Sender<bool>,
+    config: SignalingConfig,
+) {
     let mut rooms: HashMap<RoomCode, Room> = HashMap::new();
     let mut peer_rooms: HashMap<PeerId, RoomCode> = HashMap::new();
+    // alias -> canonical code; every alias is also listed on its room
+    let mut aliases: HashMap<RoomCode, RoomCode> = HashMap::new();
+    // Quick-match rooms that may have a seat, oldest first. Rooms that closed
+    // or filled are dropped when `QuickMatch` comes across them.
+    let mut open_rooms: VecDeque<RoomCode> = VecDeque::new();
+    // Running totals; `rooms` and `peers` are filled in when asked for
+    let mut totals = ServerStats::default();
+    let mut creation_limiter = config.room_creation_rate.map(TokenBucket::new);
+    // Every peer is in exactly one room, so `peer_rooms` doubles as the peer count
+    let at_capacity = |peer_rooms: &HashMap<PeerId, RoomCode>| {
+        config.max_peers.is_some_and(|max| peer_rooms.len() >= max)
+    };
+
+    let mut sweep = config.room_ttl.map(|ttl| {
+        let period = ttl.min(ROOM_SWEEP_INTERVAL);
+        tokio::time::interval_at(Instant::now() + period, period)
+    });
+
+    loop {
+        let cmd = tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = next_sweep(&mut sweep) => {
+                if let Some(ttl) = config.room_ttl {
+                    expire_idle_rooms(ttl, &mut rooms, &mut peer_rooms, &mut aliases, &events);
+                }
+                continue;
+            }
+        };
 
-    while let Some(cmd) = rx.recv().await {
         match cmd {
             RoomCommand::Create {
                 addr,
                 peer_tx,
+                password,
+                code,
+                metadata,
+                session_key,
                 reply,
             } => {
-                let code = RoomCode::generate();
-                let peer_id = PeerId::generate();
-
-                let peer_state = PeerState {
-                    info: PeerInfo {
-                        id: peer_id,
-                        public_addr: Some(addr),
-                    },
-                    tx: peer_tx,
-                };
-
-                let room = Room {
-                    peers: HashMap::from([(peer_id, peer_state)]),
+                let code =
+                    check_creation(at_capacity(&peer_rooms), creation_limiter.as_mut(), &events)
+                        .and_then(|()| new_room_code(code, &rooms, &aliases, &config));
+                let code = match code {
+                    Ok(code) => code,
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                        continue;
+                    }
                 };
-
+                let (room, peer_id, token) = found_room(addr, peer_tx, metadata, &config);
+                let mut room = room.with_password(password);
+                if let Some(key) = session_key {
+                    room.session_keys.insert(key, peer_id);
+                }
                 rooms.insert(code, room);
                 peer_rooms.insert(peer_id, code);
+                totals.rooms_created += 1;
 
                 info!("Room created: {} by peer {}", code, peer_id);
-                let _ = reply.send((code, peer_id));
+                let _ = events.send(RoomEvent::RoomCreated { code, peer_id });
+                let _ = reply.send(Ok((code, peer_id, token)));
             }
 
             RoomCommand::Join {
                 code,
                 addr,
                 peer_tx,
+                password,
+                metadata,
+                session_key,
                 reply,
             } => {
-                let result = if let Some(room) = rooms.get_mut(&code) {
-                    let peer_id = PeerId::generate();
-
-                    let existing_peers: Vec<PeerInfo> =
-                        room.peers.values().map(|p| p.info).collect();
-
-                    let join_msg = ServerMessage::PeerJoined {
-                        peer: PeerInfo {
-                            id: peer_id,
-                            public_addr: Some(addr),
-                        },
-                    };
-                    let join_json = serde_json::to_string(&join_msg)
-                        .expect("ServerMessage serialization should never fail");
-                    let msg = OutboundMessage::from(join_json);
-                    for peer in room.peers.values() {
-                        let _ = peer.tx.send(msg.clone());
-                    }
-
-                    let peer_state = PeerState {
-                        info: PeerInfo {
-                            id: peer_id,
-                            public_addr: Some(addr),
-                        },
-                        tx: peer_tx,
-                    };
-                    room.peers.insert(peer_id, peer_state);
-                    peer_rooms.insert(peer_id, code);
-
-                    info!("Peer {} joined room {}", peer_id, code);
-                    Ok((peer_id, existing_peers))
-                } else {
-                    Err(SignalingError::RoomNotFound(code))
+                let requested = code;
+                let code = aliases.get(&requested).copied().unwrap_or(requested);
+                let result = match rooms.get_mut(&code) {
+                    None => Err(SignalingError::RoomNotFound(requested)),
+                    Some(room) => {
+                        // A known key brings back its peer id, but admission is
+                        // a new join's: unlike `Rejoin`, nothing proves the
+                        // key's holder is who left
+                        let known = session_key
+                            .as_ref()
+                            .and_then(|key| room.session_keys.get(key))
+                            .copied();
+                        if known.is_some_and(|id| room.peer(&id).is_some()) {
+                            Err(SignalingError::SessionKeyInUse(code))
+                        } else {
+                            check_admission(
+                                room,
+                                code,
+                                &config,
+                                at_capacity(&peer_rooms),
+                                false,
+                                password.as_deref(),
+                            )
+                            .map(|()| {
+                                let peer_id = known.unwrap_or_else(PeerId::generate);
+                                if let Some(key) = session_key {
+                                    room.session_keys.insert(key, peer_id);
+                                }
+                                let token = SessionToken::generate();
+                                let info = new_peer_info(peer_id, addr, metadata);
+                                let existing =
+                                    admit_peer(room, code, info, token, peer_tx, &config);
+                                peer_rooms.insert(peer_id, code);
+
+                                if known.is_some() {
+                                    info!("Peer {} rejoined room {} by session key", peer_id, code);
+                                } else {
+                                    info!("Peer {} joined room {}", peer_id, code);
+                                }
+                                let _ = events.send(RoomEvent::PeerJoined { code, peer_id });
+                                (peer_id, token, existing)
+                            })
+                        }
+                    }
+                };
+
+                if let Err(e) = &result {
+                    let _ = events.send(RoomEvent::Error {
+                        message: e.to_string(),
+                    });
+                }
+
+                if result.is_ok() {
+                    totals.joins += 1;
+                }
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::QuickMatch {
+                addr,
+                peer_tx,
+                metadata,
+                reply,
+            } => {
+                while let Some(code) = open_rooms.front()
+                    && !rooms
+                        .get(code)
+                        .is_some_and(|room| has_open_seat(room, &config))
+                {
+                    open_rooms.pop_front();
+                }
+
+                let result = match open_rooms.front().copied() {
+                    Some(code) => {
+                        let room = rooms.get_mut(&code).expect("open rooms exist");
+                        check_admission(room, code, &config, at_capacity(&peer_rooms), false, None)
+                            .map(|()| {
+                                let peer_id = PeerId::generate();
+                                let token = SessionToken::generate();
+                                let info = new_peer_info(peer_id, addr, metadata);
+                                let existing =
+                                    admit_peer(room, code, info, token, peer_tx, &config);
+                                peer_rooms.insert(peer_id, code);
+                                if !has_open_seat(room, &config) {
+                                    open_rooms.pop_front();
+                                }
+
+                                info!("Peer {} quick-matched into room {}", peer_id, code);
+                                let _ = events.send(RoomEvent::PeerJoined { code, peer_id });
+                                QuickMatched::Joined(code, peer_id, token, existing)
+                            })
+                            .inspect_err(|e| {
+                                let _ = events.send(RoomEvent::Error {
+                                    message: e.to_string(),
+                                });
+                            })
+                    }
+                    None => {
+                        check_creation(at_capacity(&peer_rooms), creation_limiter.as_mut(), &events)
+                            .and_then(|()| new_room_code(None, &rooms, &aliases, &config))
+                            .map(|code| {
+                                let (mut room, peer_id, token) =
+                                    found_room(addr, peer_tx, metadata, &config);
+                                room.quick_match = true;
+                                reopen(code, &room, &mut open_rooms, &config);
+                                rooms.insert(code, room);
+                                peer_rooms.insert(peer_id, code);
+
+                                info!("Room created: {} by quick-matched peer {}", code, peer_id);
+                                let _ = events.send(RoomEvent::RoomCreated { code, peer_id });
+                                QuickMatched::Created(code, peer_id, token)
+                            })
+                    }
+                };
+                match &result {
+                    Ok(QuickMatched::Created(..)) => totals.rooms_created += 1,
+                    Ok(QuickMatched::Joined(..)) => totals.joins += 1,
+                    Err(_) => {}
+                }
+
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Rejoin {
+                code,
+                peer_id,
+                token,
+                addr,
+                peer_tx,
+                fallback,
+                reply,
+            } => {
+                let requested = code;
+                let code = aliases.get(&requested).copied().unwrap_or(requested);
+                let result = match rooms.get_mut(&code) {
+                    None => Err(SignalingError::RoomNotFound(requested)),
+                    Some(room) => {
+                        let returning = room.can_rejoin(&peer_id, &token, config.rejoin_grace);
+                        if !returning && !fallback {
+                            let _ = reply.send(Err(SignalingError::Unauthorized));
+                            continue;
+                        }
+                        check_admission(
+                            room,
+                            code,
+                            &config,
+                            at_capacity(&peer_rooms),
+                            returning,
+                            None,
+                        )
+                        .map(|()| {
+                            // Past the grace window the old identity is gone
+                            let (peer_id, token) = if returning {
+                                (peer_id, token)
+                            } else {
+                                (PeerId::generate(), SessionToken::generate())
+                            };
+                            let info = new_peer_info(peer_id, addr, None);
+                            let existing = admit_peer(room, code, info, token, peer_tx, &config);
+                            peer_rooms.insert(peer_id, code);
+
+                            if returning {
+                                info!("Peer {} rejoined room {}", peer_id, code);
+                            } else {
+                                info!("Peer {} joined room {}", peer_id, code);
+                            }
+                            let _ = events.send(RoomEvent::PeerJoined { code, peer_id });
+                            (peer_id, token, existing)
+                        })
+                    }
                 };
 
+                if let Err(e) = &result {
+                    let _ = events.send(RoomEvent::Error {
+                        message: e.to_string(),
+                    });
+                }
+
+                if result.is_ok() {
+                    totals.joins += 1;
+                }
                 let _ = reply.send(result);
             }
 
-            RoomCommand::Leave { peer_id } => {
+            RoomCommand::Leave { peer_id, conn } => {
+                // A connection whose session was handed off no longer speaks for the peer
+                if let Some(conn) = conn
+                    && let Some(room) = peer_rooms.get(&peer_id).and_then(|code| rooms.get(code))
+                    && room
+                        .peer(&peer_id)
+                        .is_some_and(|peer| !peer.tx.same_channel(&conn))
+                {
+                    continue;
+                }
+
                 if let Some(code) = peer_rooms.remove(&peer_id) {
                     if let Some(room) = rooms.get_mut(&code) {
-                        room.peers.remove(&peer_id);
+                        let was_owner = room.owner == peer_id;
+                        if let Some(left) = room.remove_peer(&peer_id) {
+                            room.record_departure(peer_id, left.token, config.rejoin_grace);
+                        }
+                        let _ = events.send(RoomEvent::PeerLeft { code, peer_id });
+                        totals.leaves += 1;
 
-                        if room.peers.is_empty() {
-                            rooms.remove(&code);
+                        if room.is_empty() {
+                            remove_room(&code, &mut rooms, &mut peer_rooms, &mut aliases);
                             info!("Room {} removed (empty)", code);
+                            let _ = events.send(RoomEvent::RoomRemoved { code });
+                        } else {
+                            room.broadcast(&bulk_message(&ServerMessage::PeerLeft { peer_id }));
+                            if was_owner {
+                                info!("Peer {} now owns room {}", room.owner, code);
+                            }
+                            reopen(code, room, &mut open_rooms, &config);
                         }
                     }
                     info!("Peer {} left room {}", peer_id, code);
                 }
             }
-        }
-    }
-}
 
-/// Handle to communicate with the room manager actor
-#[derive(Clone)]
-pub struct RoomManagerHandle {
-    pub(crate) tx: mpsc::Sender<RoomCommand>,
-}
+            RoomCommand::Rebind {
+                peer_id,
+                token,
+                new_tx,
+                reply,
+            } => {
+                let room = peer_rooms
+                    .get(&peer_id)
+                    .and_then(|code| rooms.get_mut(code).map(|room| (*code, room)));
 
-impl RoomManagerHandle {
-    /// Create a new room and become the first peer
-    pub async fn create_room(
-        &self,
-        addr: SocketAddr,
-        peer_tx: mpsc::UnboundedSender<OutboundMessage>,
-    ) -> Result<(RoomCode, PeerId), SignalingError> {
-        let (reply_tx, reply_rx) = oneshot::channel();
-        let _ = self
-            .tx
-            .send(RoomCommand::Create {
-                addr,
-                peer_tx,
-                reply: reply_tx,
-            })
-            .await;
-        reply_rx
-            .await
-            .map_err(|_| SignalingError::Internal("actor channel closed".to_string()))
-    }
+                let result = match room {
+                    None => Err(SignalingError::NotInRoom),
+                    Some((_, room))
+                        if !room
+                            .peer(&peer_id)
+                            .is_some_and(|peer| peer.token.verify(&token)) =>
+                    {
+                        Err(SignalingError::Unauthorized)
+                    }
+                    Some((code, room)) => {
+                        if let Some(old_tx) = room.rebind_peer(&peer_id, new_tx) {
+                            // Tell the old connection it has been superseded, and
+                            // have it close: it no longer speaks for the peer
+                            let _ = old_tx.send(direct_message(&ServerMessage::SessionMoved));
+                            old_tx.supersede();
+                        }
 
-    /// Join an existing room
-    pub async fn join_room(
-        &self,
-        code: RoomCode,
-        addr: SocketAddr,
-        peer_tx: mpsc::UnboundedSender<OutboundMessage>,
-    ) -> Result<(PeerId, Vec<PeerInfo>), SignalingError> {
-        let (reply_tx, reply_rx) = oneshot::channel();
-        let _ = self
-            .tx
-            .send(RoomCommand::Join {
-                code,
-                addr,
-                peer_tx,
-                reply: reply_tx,
-            })
-            .await;
-        reply_rx
-            .await
-            .map_err(|_| SignalingError::Internal("actor channel closed".to_string()))?
-    }
+                        info!(
+                            "Peer {} rebound to a new connection in room {}",
+                            peer_id, code
+                        );
+                        let _ = events.send(RoomEvent::PeerRebound { code, peer_id });
+                        Ok((code, room.peers().map(|p| p.info.clone()).collect()))
+                    }
+                };
 
-    /// Leave the current room
-    pub async fn leave_room(&self, peer_id: &PeerId) {
-        let _ = self.tx.send(RoomCommand::Leave { peer_id: *peer_id }).await;
+                if let Err(e) = &result {
+                    let _ = events.send(RoomEvent::Error {
+                        message: e.to_string(),
+                    });
+                }
+
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Stats { reply } => {
+                let _ = reply.send(Ok(ServerStats {
+                    rooms: rooms.len(),
+                    peers: peer_rooms.len(),
+                    ..totals
+                }));
+            }
+
+            RoomCommand::List { admin_token, reply } => {
+                let authorized = match (&config.admin_token, &admin_token) {
+                    (Some(expected), Some(presented)) => {
+                        constant_time_eq(expected.as_bytes(), presented.as_bytes())
+                    }
+                    _ => false,
+                };
+                let result = if authorized {
+                    let mut summaries: Vec<RoomSummary> = rooms
+                        .iter()
+                        .map(|(code, room)| RoomSummary {
+                            code: *code,
+                            peers: room.len(),
+                        })
+                        .collect();
+                    summaries.sort_by(|a, b| a.code.as_str().cmp(b.code.as_str()));
+                    Ok(summaries)
+                } else {
+                    warn!("Room list refused: bad or missing admin token");
+                    Err(SignalingError::Unauthorized)
+                };
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Resync { code, reply } => {
+                let code = aliases.get(&code).copied().unwrap_or(code);
+                let result = if let Some(room) = rooms.get_mut(&code) {
+                    room.broadcast(&bulk_message(&ServerMessage::RosterSync {
+                        peers: room.peers().map(|p| p.info.clone()).collect(),
+                    }));
+
+                    info!("Room {} resynced ({} peers)", code, room.len());
+                    Ok(())
+                } else {
+                    Err(SignalingError::RoomNotFound(code))
+                };
+
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::SetLocked {
+                peer_id,
+                locked,
+                reply,
+            } => {
+                let room = peer_rooms
+                    .get(&peer_id)
+                    .and_then(|code| rooms.get_mut(code).map(|room| (*code, room)));
+
+                let result = match room {
+                    None => Err(SignalingError::NotInRoom),
+                    Some((_, room)) if room.owner != peer_id => Err(SignalingError::Unauthorized),
+                    Some((code, room)) => {
+                        if room.locked != locked {
+                            room.locked = locked;
+                            room.broadcast(&bulk_message(&ServerMessage::RoomLockChanged {
+                                locked,
+                            }));
+                            info!(
+                                "Room {} {}",
+                                code,
+                                if locked { "locked" } else { "unlocked" }
+                            );
+                            reopen(code, room, &mut open_rooms, &config);
+                        }
+                        Ok(())
+                    }
+                };
+
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Broadcast {
+                from,
+                payload,
+                reply,
+            } => {
+                let room = peer_rooms.get(&from).and_then(|code| rooms.get_mut(code));
+
+                let result = match room {
+                    None => Err(SignalingError::NotInRoom),
+                    Some(room) => {
+                        let msg = bulk_message(&ServerMessage::Broadcast {
+                            from,
+                            payload: payload.clone(),
+                        });
+                        if let Err(e) = check_relay_size(&msg, &config) {
+                            Err(e)
+                        } else if room.charge_relay(&from) {
+                            room.record_broadcast(from, &payload);
+                            room.broadcast_from(from, &msg);
+                            Ok(())
+                        } else {
+                            Err(SignalingError::RoomRateLimited)
+                        }
+                    }
+                };
+
+                if result.is_ok() {
+                    totals.relays += 1;
+                }
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Kick {
+                peer_id,
+                target,
+                reply,
+            } => {
+                let room = peer_rooms
+                    .get(&peer_id)
+                    .and_then(|code| rooms.get_mut(code).map(|room| (*code, room)));
+
+                let result = match room {
+                    None => Err(SignalingError::NotInRoom),
+                    // Owners leave rather than kick themselves
+                    Some((_, room)) if room.owner != peer_id || target == peer_id => {
+                        Err(SignalingError::Unauthorized)
+                    }
+                    Some((code, room)) => match room.remove_peer(&target) {
+                        // No departure is recorded and its session key is
+                        // dropped, so the peer can't come back as itself
+                        Some(kicked) => {
+                            peer_rooms.remove(&target);
+                            room.session_keys.retain(|_, id| *id != target);
+                            let _ = kicked.tx.send(direct_message(&ServerMessage::Kicked));
+                            room.broadcast(&bulk_message(&ServerMessage::PeerLeft {
+                                peer_id: target,
+                            }));
+                            let _ = events.send(RoomEvent::PeerLeft {
+                                code,
+                                peer_id: target,
+                            });
+                            info!("Peer {} kicked from room {}", target, code);
+                            reopen(code, room, &mut open_rooms, &config);
+                            Ok(())
+                        }
+                        None => Err(SignalingError::PeerNotInRoom(target)),
+                    },
+                };
+
+                if result.is_ok() {
+                    totals.leaves += 1;
+                }
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Multicast {
+                from,
+                to,
+                payload,
+                reply,
+            } => {
+                let room = peer_rooms.get(&from).and_then(|code| rooms.get_mut(code));
+
+                let result = match room {
+                    None => Err(SignalingError::NotInRoom),
+                    Some(room) => {
+                        let msg = bulk_message(&ServerMessage::Broadcast { from, payload });
+                        if let Err(e) = check_relay_size(&msg, &config) {
+                            Err(e)
+                        } else if room.charge_relay(&from) {
+                            Ok(room.multicast(&to, &msg))
+                        } else {
+                            Err(SignalingError::RoomRateLimited)
+                        }
+                    }
+                };
+
+                if result.is_ok() {
+                    totals.relays += 1;
+                }
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Relay {
+                from,
+                to,
+                msg,
+                reply,
+            } => {
+                let room = peer_rooms.get(&from).and_then(|code| rooms.get_mut(code));
+
+                let result = match room {
+                    None => Err(SignalingError::NotInRoom),
+                    Some(room) if room.peer(&to).is_none() => {
+                        Err(SignalingError::PeerNotInRoom(to))
+                    }
+                    Some(room) => {
+                        let out = direct_message(&msg);
+                        if let Err(e) = check_negotiation(room, from, to, &msg)
+                            .and_then(|()| check_relay_size(&out, &config))
+                        {
+                            Err(e)
+                        } else if room.charge_relay(&from) {
+                            match msg {
+                                ServerMessage::Offer { .. } => room.record_offer(from, to),
+                                ServerMessage::Answer { .. } => room.settle_offer(to, from),
+                                _ => {}
+                            }
+                            room.multicast(&[to], &out);
+                            Ok(())
+                        } else {
+                            Err(SignalingError::RoomRateLimited)
+                        }
+                    }
+                };
+
+                if result.is_ok() {
+                    totals.relays += 1;
+                }
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Peers { peer_id, reply } => {
+                let result = match peer_rooms.get(&peer_id).and_then(|code| rooms.get(code)) {
+                    Some(room) => Ok(room
+                        .peers()
+                        .map(|p| p.info.clone())
+                        .filter(|info| info.id != peer_id)
+                        .collect()),
+                    None => Err(SignalingError::NotInRoom),
+                };
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::RequestRelay { peer_id, reply } => {
+                let room = peer_rooms
+                    .get(&peer_id)
+                    .and_then(|code| rooms.get_mut(code));
+                let result = match room {
+                    Some(room) => allocate_relay(room, &config),
+                    None => Err(SignalingError::NotInRoom),
+                };
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::SetReflexiveAddr {
+                peer_id,
+                addr,
+                reply,
+            } => {
+                let room = peer_rooms
+                    .get(&peer_id)
+                    .and_then(|code| rooms.get_mut(code));
+                let refused = config
+                    .require_global_peer_addrs
+                    .then(|| validate_global_peer_addr(addr.addr()).err())
+                    .flatten();
+                let result = match (room, refused) {
+                    (_, Some(reason)) => Err(SignalingError::InvalidAddr(reason)),
+                    (Some(room), None) => match room.set_reflexive_addr(&peer_id, addr) {
+                        // Peers already connected re-evaluate their candidates
+                        Some(peer) => {
+                            room.broadcast_from(
+                                peer_id,
+                                &bulk_message(&ServerMessage::PeerUpdated { peer }),
+                            );
+                            Ok(())
+                        }
+                        None => Err(SignalingError::NotInRoom),
+                    },
+                    (None, None) => Err(SignalingError::NotInRoom),
+                };
+
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::SetMetadata {
+                peer_id,
+                metadata,
+                reply,
+            } => {
+                let room = peer_rooms
+                    .get(&peer_id)
+                    .and_then(|code| rooms.get_mut(code));
+                let result = match room {
+                    Some(room) => match room.set_metadata(&peer_id, metadata) {
+                        Some(peer) => {
+                            room.broadcast_from(
+                                peer_id,
+                                &bulk_message(&ServerMessage::PeerUpdated { peer }),
+                            );
+                            Ok(())
+                        }
+                        None => Err(SignalingError::NotInRoom),
+                    },
+                    None => Err(SignalingError::NotInRoom),
+                };
+
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Migrate { code, url, reply } => {
+                let code = aliases.get(&code).copied().unwrap_or(code);
+                let result = match remove_room(&code, &mut rooms, &mut peer_rooms, &mut aliases) {
+                    Some(mut room) => {
+                        room.broadcast(&direct_message(&ServerMessage::RoomMigrating { url }));
+                        info!("Room {} migrated ({} peers notified)", code, room.len());
+                        let _ = events.send(RoomEvent::RoomRemoved { code });
+                        Ok(())
+                    }
+                    None => Err(SignalingError::RoomNotFound(code)),
+                };
+
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Shutdown { reply } => {
+                let codes: Vec<RoomCode> = rooms.keys().copied().collect();
+                let mut notified = 0;
+                for code in codes {
+                    if let Some(mut room) =
+                        remove_room(&code, &mut rooms, &mut peer_rooms, &mut aliases)
+                    {
+                        room.broadcast(&direct_message(&ServerMessage::ServerShutdown));
+                        notified += room.len();
+                        let _ = events.send(RoomEvent::RoomRemoved { code });
+                    }
+                }
+                open_rooms.clear();
+                info!("Shutting down ({} peers notified)", notified);
+                let _ = shutdown.send(true);
+                let _ = reply.send(Ok(notified));
+            }
+
+            RoomCommand::AddAlias {
+                peer_id,
+                alias,
+                reply,
+            } => {
+                let code = peer_rooms.get(&peer_id).copied();
+                let taken = rooms.contains_key(&alias) || aliases.contains_key(&alias);
+
+                let result = match code.and_then(|code| rooms.get_mut(&code).map(|r| (code, r))) {
+                    None => Err(SignalingError::NotInRoom),
+                    Some((_, room)) if room.owner != peer_id => Err(SignalingError::Unauthorized),
+                    Some(_) if taken => Err(SignalingError::CodeTaken(alias)),
+                    Some((code, room)) => {
+                        room.aliases.push(alias);
+                        aliases.insert(alias, code);
+                        info!("Room {} aliased as {}", code, alias);
+                        Ok(code)
+                    }
+                };
+
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+/// Handle to communicate with the room manager actor
+#[derive(Clone)]
+pub struct RoomManagerHandle {
+    tx: mpsc::Sender<RoomCommand>,
+    events: broadcast::Sender<RoomEvent>,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: Option<usize>,
+    /// Becomes `true` once the server is shutting down
+    shutdown: watch::Receiver<bool>,
+}
+
+/// Counts one outstanding request; decrements on drop so cancelled callers are released too
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RoomManagerHandle {
+    /// Spawn the room manager actor and return a handle to it
+    pub(crate) fn spawn(config: SignalingConfig) -> Self {
+        let (tx, rx) = mpsc::channel::<RoomCommand>(1024);
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let max_in_flight = config.max_in_flight_requests;
+        tokio::spawn(
+            room_manager_actor(rx, events.clone(), shutdown_tx, config)
+                .instrument(info_span!("room_manager")),
+        );
+
+        Self {
+            tx,
+            events,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight,
+            shutdown,
+        }
+    }
+
+    /// Number of requests currently awaiting a reply from the actor
+    pub fn in_flight_requests(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Send a command and await its reply, failing fast when the in-flight cap is reached
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T, SignalingError>>) -> RoomCommand,
+    ) -> Result<T, SignalingError> {
+        let outstanding = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _guard = InFlightGuard(&self.in_flight);
+        if self.max_in_flight.is_some_and(|max| outstanding >= max) {
+            return Err(SignalingError::Overloaded);
+        }
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let _ = self.tx.send(command(reply_tx)).await;
+        reply_rx
+            .await
+            .map_err(|_| SignalingError::Internal("actor channel closed".to_string()))?
+    }
+
+    /// Subscribe to the stream of room events published by the actor
+    pub fn subscribe_events(&self) -> broadcast::Receiver<RoomEvent> {
+        self.events.subscribe()
+    }
+
+    /// Create a new room and become the first peer
+    ///
+    /// Sending `RoomCreated` is up to the caller.
+    pub async fn create_room(
+        &self,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        options: CreateOptions,
+    ) -> Result<(RoomCode, PeerId, SessionToken), SignalingError> {
+        let CreateOptions {
+            code,
+            password,
+            metadata,
+            session_key,
+        } = options;
+        let password = password.as_deref().map(RoomPassword::new);
+        self.request(|reply| RoomCommand::Create {
+            addr,
+            peer_tx,
+            password,
+            code,
+            metadata,
+            session_key,
+            reply,
+        })
+        .await
+    }
+
+    /// Join an existing room
+    ///
+    /// The actor queues `RoomJoined` on `peer_tx` itself. Its roster, also
+    /// returned here, is a point-in-time view taken when the join was
+    /// processed; every later membership change reaches the new peer as a
+    /// `PeerJoined`/`PeerLeft` push queued behind it.
+    ///
+    /// Fails with `Unauthorized` if the room is protected and the password
+    /// is missing or wrong, and with `SessionKeyInUse` while the peer a
+    /// session key belongs to is still in the room.
+    pub async fn join_room(
+        &self,
+        code: RoomCode,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        options: JoinOptions,
+    ) -> Result<(PeerId, SessionToken, Vec<PeerInfo>), SignalingError> {
+        let JoinOptions {
+            password,
+            metadata,
+            session_key,
+        } = options;
+        self.request(|reply| RoomCommand::Join {
+            code,
+            addr,
+            peer_tx,
+            password,
+            metadata,
+            session_key,
+            reply,
+        })
+        .await
+    }
+
+    /// Join any quick-match room with a seat, or open a new one if none has
+    /// one, so clients never handle room codes. Rooms hold
+    /// `quick_match_size` matched peers.
+    pub async fn quick_match(
+        &self,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<QuickMatched, SignalingError> {
+        self.request(|reply| RoomCommand::QuickMatch {
+            addr,
+            peer_tx,
+            metadata,
+            reply,
+        })
+        .await
+    }
+
+    /// Rejoin a room under the id a peer left it with
+    ///
+    /// Within the configured grace window, and with the session token it was
+    /// given, the peer gets its old id back; otherwise this is an ordinary
+    /// join under a new id. Either way the actor queues `RoomJoined`.
+    pub async fn rejoin_room(
+        &self,
+        code: RoomCode,
+        peer_id: &PeerId,
+        token: &SessionToken,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+    ) -> Result<(PeerId, SessionToken, Vec<PeerInfo>), SignalingError> {
+        self.rejoin_room_with_fallback(code, peer_id, token, addr, peer_tx, true)
+            .await
+    }
+
+    /// Like `rejoin_room`, but with `fallback` false a peer that can't get
+    /// its old id back fails with `Unauthorized` instead of joining anew
+    ///
+    /// For a client that hasn't presented an access token: a valid session
+    /// token proves an earlier authenticated entry, but nothing else does.
+    pub async fn rejoin_room_with_fallback(
+        &self,
+        code: RoomCode,
+        peer_id: &PeerId,
+        token: &SessionToken,
+        addr: SignalingAddr,
+        peer_tx: OutboundSender,
+        fallback: bool,
+    ) -> Result<(PeerId, SessionToken, Vec<PeerInfo>), SignalingError> {
+        self.request(|reply| RoomCommand::Rejoin {
+            code,
+            peer_id: *peer_id,
+            token: *token,
+            addr,
+            peer_tx,
+            fallback,
+            reply,
+        })
+        .await
+    }
+
+    /// Leave the current room
+    pub async fn leave_room(&self, peer_id: &PeerId) {
+        let _ = self
+            .tx
+            .send(RoomCommand::Leave {
+                peer_id: *peer_id,
+                conn: None,
+            })
+            .await;
+    }
+
+    /// Leave on behalf of the connection feeding `conn`
+    ///
+    /// A no-op if the peer's session has since been rebound to another
+    /// connection, so a superseded connection closing doesn't evict the peer.
+    pub async fn disconnect(&self, peer_id: &PeerId, conn: &OutboundSender) {
+        let _ = self
+            .tx
+            .send(RoomCommand::Leave {
+                peer_id: *peer_id,
+                conn: Some(conn.clone()),
+            })
+            .await;
+    }
+
+    /// Send every peer in the room to another signaling server, then close it
+    ///
+    /// Peers receive `RoomMigrating { url }` and are removed from the room;
+    /// their connections stay open so they can reconnect at their own pace.
+    pub async fn migrate_room(&self, code: RoomCode, url: String) -> Result<(), SignalingError> {
+        self.request(|reply| RoomCommand::Migrate { code, url, reply })
+            .await
+    }
+
+    /// Tell every peer in every room the server is going away, then close
+    /// the rooms
+    ///
+    /// Peers receive `ServerShutdown`; the connections themselves are closed
+    /// by their own tasks, which learn of it from `shutdown_signal`. Returns
+    /// how many peers were notified.
+    pub async fn shutdown(&self) -> Result<usize, SignalingError> {
+        self.request(|reply| RoomCommand::Shutdown { reply }).await
+    }
+
+    /// Watch for the server shutting down: the value turns `true` once
+    /// `shutdown` has notified the rooms
+    ///
+    /// Every receiver starts out with the change unseen, so one taken after
+    /// the shutdown still sees it.
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.clone()
+    }
+
+    /// Register `alias` as an additional code for the peer's room (owner only)
+    ///
+    /// Joining via the alias reaches the room; the alias is released when
+    /// the room is removed. Returns the room's canonical code.
+    pub async fn add_alias(
+        &self,
+        peer_id: &PeerId,
+        alias: RoomCode,
+    ) -> Result<RoomCode, SignalingError> {
+        self.request(|reply| RoomCommand::AddAlias {
+            peer_id: *peer_id,
+            alias,
+            reply,
+        })
+        .await
+    }
+
+    /// Relay `payload` from a peer to everyone else in its room
+    ///
+    /// Subject to the per-peer relay budget; over-budget messages are
+    /// dropped with `RoomRateLimited`.
+    pub async fn broadcast(
+        &self,
+        from: &PeerId,
+        payload: serde_json::Value,
+    ) -> Result<(), SignalingError> {
+        self.request(|reply| RoomCommand::Broadcast {
+            from: *from,
+            payload,
+            reply,
+        })
+        .await
+    }
+
+    /// Relay `payload` from a peer to one other peer in its room
+    ///
+    /// Fails with `PeerNotInRoom` if `to` isn't in the sender's room. Subject
+    /// to the per-peer relay budget, like `broadcast`.
+    pub async fn relay(
+        &self,
+        from: &PeerId,
+        to: &PeerId,
+        payload: serde_json::Value,
+    ) -> Result<(), SignalingError> {
+        let msg = ServerMessage::Signal {
+            from: *from,
+            payload,
+        };
+        self.request(|reply| RoomCommand::Relay {
+            from: *from,
+            to: *to,
+            msg,
+            reply,
+        })
+        .await
+    }
+
+    /// Send an SDP offer from a peer to one other peer in its room
+    ///
+    /// Fails with `OfferPending` while an offer between the two, in either
+    /// direction, hasn't been answered. Otherwise as `relay`.
+    pub async fn relay_offer(
+        &self,
+        from: &PeerId,
+        to: &PeerId,
+        sdp: String,
+    ) -> Result<(), SignalingError> {
+        let msg = ServerMessage::Offer { from: *from, sdp };
+        self.request(|reply| RoomCommand::Relay {
+            from: *from,
+            to: *to,
+            msg,
+            reply,
+        })
+        .await
+    }
+
+    /// Answer the offer `to` sent `from`, closing that negotiation
+    ///
+    /// Fails with `NoOfferPending` unless `to` has an unanswered offer to
+    /// `from`. Otherwise as `relay`.
+    pub async fn relay_answer(
+        &self,
+        from: &PeerId,
+        to: &PeerId,
+        sdp: String,
+    ) -> Result<(), SignalingError> {
+        let msg = ServerMessage::Answer { from: *from, sdp };
+        self.request(|reply| RoomCommand::Relay {
+            from: *from,
+            to: *to,
+            msg,
+            reply,
+        })
+        .await
+    }
+
+    /// Relay an ICE candidate from a peer to one other peer in its room, with
+    /// the same checks and budget as `relay`
+    pub async fn relay_ice_candidate(
+        &self,
+        from: &PeerId,
+        to: &PeerId,
+        candidate: String,
+        sdp_mid: Option<String>,
+        sdp_mline_index: Option<u16>,
+    ) -> Result<(), SignalingError> {
+        let msg = ServerMessage::IceCandidate {
+            from: *from,
+            candidate,
+            sdp_mid,
+            sdp_mline_index,
+        };
+        self.request(|reply| RoomCommand::Relay {
+            from: *from,
+            to: *to,
+            msg,
+            reply,
+        })
+        .await
+    }
+
+    /// Move a peer's session onto a new connection without leaving the room
+    ///
+    /// Other peers are not notified. The old connection receives `SessionMoved`
+    /// and is told to close through `OutboundSender::superseded`; it is no
+    /// longer addressed by broadcasts. Returns the room code and the current
+    /// roster.
+    pub async fn rebind(
+        &self,
+        peer_id: &PeerId,
+        token: &SessionToken,
+        new_tx: OutboundSender,
+    ) -> Result<(RoomCode, Vec<PeerInfo>), SignalingError> {
+        self.request(|reply| RoomCommand::Rebind {
+            peer_id: *peer_id,
+            token: *token,
+            new_tx,
+            reply,
+        })
+        .await
+    }
+
+    /// Current room and peer counts, with totals of creates, joins, leaves
+    /// and relays since the room manager started, for a metrics task to poll
+    pub async fn stats(&self) -> Result<ServerStats, SignalingError> {
+        self.request(|reply| RoomCommand::Stats { reply }).await
+    }
+
+    /// Every room with its peer count, ordered by code
+    ///
+    /// Fails with `Unauthorized` unless `admin_token` matches the configured
+    /// one; with none configured, listing is disabled.
+    pub async fn list_rooms(
+        &self,
+        admin_token: Option<&str>,
+    ) -> Result<Vec<RoomSummary>, SignalingError> {
+        let admin_token = admin_token.map(str::to_string);
+        self.request(|reply| RoomCommand::List { admin_token, reply })
+            .await
+    }
+
+    /// Re-send every peer in the room the authoritative roster
+    ///
+    /// Heals clients that missed `PeerJoined`/`PeerLeft` pushes, all at once.
+    pub async fn resync_room(&self, code: RoomCode) -> Result<(), SignalingError> {
+        self.request(|reply| RoomCommand::Resync { code, reply })
+            .await
+    }
+
+    /// Relay `payload` from a peer to the listed peers in its room
+    ///
+    /// Returns `(delivered, missing)`: the targets that were in the room and
+    /// those that weren't. Costs one unit of the relay budget, however many
+    /// targets are listed.
+    pub async fn multicast(
+        &self,
+        from: &PeerId,
+        to: Vec<PeerId>,
+        payload: serde_json::Value,
+    ) -> Result<(Vec<PeerId>, Vec<PeerId>), SignalingError> {
+        self.request(|reply| RoomCommand::Multicast {
+            from: *from,
+            to,
+            payload,
+            reply,
+        })
+        .await
+    }
+
+    /// The other peers currently in a peer's room
+    pub async fn peers(&self, peer_id: &PeerId) -> Result<Vec<PeerInfo>, SignalingError> {
+        self.request(|reply| RoomCommand::Peers {
+            peer_id: *peer_id,
+            reply,
+        })
+        .await
+    }
+
+    /// Set up a UDP relay between the two peers of the peer's room, returning
+    /// its address; both peers are sent a `RelayAllocated`
+    pub async fn request_relay(&self, peer_id: &PeerId) -> Result<SocketAddr, SignalingError> {
+        self.request(|reply| RoomCommand::RequestRelay {
+            peer_id: *peer_id,
+            reply,
+        })
+        .await
+    }
+
+    /// Record the UDP address a peer reported, advertised to later joiners
+    pub async fn set_reflexive_addr(
+        &self,
+        peer_id: &PeerId,
+        addr: ReflexiveAddr,
+    ) -> Result<(), SignalingError> {
+        self.request(|reply| RoomCommand::SetReflexiveAddr {
+            peer_id: *peer_id,
+            addr,
+            reply,
+        })
+        .await
+    }
+
+    /// Replace the peer's metadata; the rest of its room gets a `PeerUpdated`
+    pub async fn set_metadata(
+        &self,
+        peer_id: &PeerId,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<(), SignalingError> {
+        self.request(|reply| RoomCommand::SetMetadata {
+            peer_id: *peer_id,
+            metadata,
+            reply,
+        })
+        .await
+    }
+
+    /// Remove `target` from the peer's room (owner only); it is sent a
+    /// `Kicked` and the rest of the room a `PeerLeft`
+    pub async fn kick(&self, peer_id: &PeerId, target: &PeerId) -> Result<(), SignalingError> {
+        self.request(|reply| RoomCommand::Kick {
+            peer_id: *peer_id,
+            target: *target,
+            reply,
+        })
+        .await
+    }
+
+    /// Lock or unlock the peer's room against new joins (owner only)
+    pub async fn set_locked(&self, peer_id: &PeerId, locked: bool) -> Result<(), SignalingError> {
+        self.request(|reply| RoomCommand::SetLocked {
+            peer_id: *peer_id,
+            locked,
+            reply,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::rate_limit::RateLimit;
+    use crate::signaling::outbound::{OutboundReceiver, outbound_channel};
+    use crate::signaling::types::RoomCodeAlphabet;
+
+    fn test_addr() -> SignalingAddr {
+        SignalingAddr::from("127.0.0.1:5000".parse::<SocketAddr>().unwrap())
+    }
+
+    async fn recv_json(rx: &mut OutboundReceiver) -> serde_json::Value {
+        let msg = rx.recv().await.expect("outbound channel closed");
+        serde_json::from_str(msg.into_inner().as_str()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn resync_sends_every_peer_the_roster() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (tx1, mut rx1) = outbound_channel();
+        let (tx2, mut rx2) = outbound_channel();
+
+        let (code, creator, _) = handle
+            .create_room(test_addr(), tx1, CreateOptions::default())
+            .await
+            .unwrap();
+        let (joiner, _, _) = handle
+            .join_room(code, test_addr(), tx2, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut rx1).await["type"], "peer_joined");
+        assert_eq!(recv_json(&mut rx2).await["type"], "room_joined");
+
+        handle.resync_room(code).await.unwrap();
+
+        for rx in [&mut rx1, &mut rx2] {
+            let msg = recv_json(rx).await;
+            assert_eq!(msg["type"], "roster_sync");
+            let mut ids: Vec<&str> = msg["peers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|p| p["id"].as_str().unwrap())
+                .collect();
+            ids.sort();
+            let mut expected = [creator.as_str(), joiner.as_str()];
+            expected.sort();
+            assert_eq!(ids, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn room_creation_is_rate_limited_but_joins_are_not() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            room_creation_rate: Some(RateLimit::new(0.001, 2)),
+            ..SignalingConfig::default()
+        });
+
+        let (code, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            let result = handle
+                .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+                .await;
+            assert!(matches!(result, Err(SignalingError::CreationRateLimited)));
+        }
+
+        for _ in 0..5 {
+            handle
+                .join_room(
+                    code,
+                    test_addr(),
+                    outbound_channel().0,
+                    JoinOptions::default(),
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn locking_blocks_joins_until_unlocked() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, owner, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+
+        handle.set_locked(&owner, true).await.unwrap();
+        let msg = recv_json(&mut owner_rx).await;
+        assert_eq!(msg["type"], "room_lock_changed");
+        assert_eq!(msg["locked"], true);
+
+        let result = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(SignalingError::RoomLocked(c)) if c == code));
+
+        handle.set_locked(&owner, false).await.unwrap();
+        assert_eq!(recv_json(&mut owner_rx).await["locked"], false);
+
+        handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn owner_kicks_peer() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, owner, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (member_tx, mut member_rx) = outbound_channel();
+        let (member, token, _) = handle
+            .join_room(code, test_addr(), member_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+        assert_eq!(recv_json(&mut member_rx).await["type"], "room_joined");
+
+        handle.kick(&owner, &member).await.unwrap();
+        assert_eq!(recv_json(&mut member_rx).await["type"], "kicked");
+        let left = recv_json(&mut owner_rx).await;
+        assert_eq!(left["type"], "peer_left");
+        assert_eq!(left["peer_id"], member.as_str());
+
+        assert!(handle.peers(&owner).await.unwrap().is_empty());
+        assert!(matches!(
+            handle.peers(&member).await,
+            Err(SignalingError::NotInRoom)
+        ));
+        // Kicked, not departed: the session can't be resumed
+        let (rejoined, _, _) = handle
+            .rejoin_room(code, &member, &token, test_addr(), outbound_channel().0)
+            .await
+            .unwrap();
+        assert_ne!(rejoined, member);
+    }
+
+    #[tokio::test]
+    async fn non_owner_cannot_kick() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (member, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let result = handle.kick(&member, &owner).await;
+        assert!(matches!(result, Err(SignalingError::Unauthorized)));
+        assert_eq!(handle.peers(&member).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn kicking_a_peer_outside_the_room_fails() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (_, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (_, stranger, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+
+        for target in [PeerId::from("peer_nobody00"), stranger] {
+            let result = handle.kick(&owner, &target).await;
+            assert!(matches!(result, Err(SignalingError::PeerNotInRoom(id)) if id == target));
+        }
+        assert!(handle.peers(&stranger).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn only_owner_can_lock() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, _owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (member, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let result = handle.set_locked(&member, true).await;
+        assert!(matches!(result, Err(SignalingError::Unauthorized)));
+
+        let result = handle
+            .set_locked(&PeerId::from("peer_nobody00"), true)
+            .await;
+        assert!(matches!(result, Err(SignalingError::NotInRoom)));
+
+        handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_flight_cap_fails_fast_when_actor_stalls() {
+        // An actor that never drains its queue
+        let (tx, _stalled_rx) = mpsc::channel(1024);
+        let handle = RoomManagerHandle {
+            tx,
+            events: broadcast::channel(1).0,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight: Some(2),
+            shutdown: watch::channel(false).1,
+        };
+
+        let pending: Vec<_> = (0..2)
+            .map(|_| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    handle
+                        .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+                        .await
+                })
+            })
+            .collect();
+        while handle.in_flight_requests() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let result = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            handle.join_room(
+                RoomCode::from("abc12345"),
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            ),
+        )
+        .await
+        .expect("capped request should fail fast");
+        assert!(matches!(result, Err(SignalingError::Overloaded)));
+        assert_eq!(handle.in_flight_requests(), 2);
+
+        for task in pending {
+            task.abort();
+            let _ = task.await;
+        }
+        assert_eq!(handle.in_flight_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn rebind_moves_session_without_notifying_room() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (old_tx, mut old_rx) = outbound_channel();
+        let (code, _, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (mobile, token, _) = handle
+            .join_room(code, test_addr(), old_tx.clone(), JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+        assert_eq!(recv_json(&mut old_rx).await["type"], "room_joined");
+
+        let (new_tx, mut new_rx) = outbound_channel();
+        let (rebound_code, roster) = handle.rebind(&mobile, &token, new_tx).await.unwrap();
+        assert_eq!(rebound_code, code);
+        assert_eq!(roster.len(), 2);
+        assert_eq!(recv_json(&mut old_rx).await["type"], "session_moved");
+        tokio::time::timeout(std::time::Duration::from_secs(1), old_tx.superseded())
+            .await
+            .expect("old connection should be told to close");
+
+        // The superseded connection closing must not evict the peer
+        handle.disconnect(&mobile, &old_tx).await;
+        handle.resync_room(code).await.unwrap();
+
+        let msg = recv_json(&mut owner_rx).await;
+        assert_eq!(msg["type"], "roster_sync", "owner saw no leave/join");
+        assert_eq!(msg["peers"].as_array().unwrap().len(), 2);
+        assert_eq!(recv_json(&mut new_rx).await["type"], "roster_sync");
+        let stale = tokio::time::timeout(std::time::Duration::from_millis(20), old_rx.recv()).await;
+        assert!(
+            stale.is_err(),
+            "old connection no longer receives broadcasts"
+        );
+    }
+
+    #[tokio::test]
+    async fn rebind_rejects_wrong_token() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (tx, mut rx) = outbound_channel();
+        let (_, peer, _) = handle
+            .create_room(test_addr(), tx, CreateOptions::default())
+            .await
+            .unwrap();
+
+        let result = handle
+            .rebind(&peer, &SessionToken::generate(), outbound_channel().0)
+            .await;
+        assert!(matches!(result, Err(SignalingError::Unauthorized)));
+
+        let result = handle
+            .rebind(
+                &PeerId::from("peer_nobody00"),
+                &SessionToken::generate(),
+                outbound_channel().0,
+            )
+            .await;
+        assert!(matches!(result, Err(SignalingError::NotInRoom)));
+
+        // The original connection still owns the session
+        handle.set_locked(&peer, true).await.unwrap();
+        assert_eq!(recv_json(&mut rx).await["type"], "room_lock_changed");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_rooms_expire_after_the_ttl() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            room_ttl: Some(std::time::Duration::from_secs(60)),
+            ..SignalingConfig::default()
+        });
+        let (idle_tx, mut idle_rx) = outbound_channel();
+        let (idle, _, _) = handle
+            .create_room(test_addr(), idle_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (busy_tx, _busy_rx) = outbound_channel();
+        let (busy, busy_peer, _) = handle
+            .create_room(test_addr(), busy_tx, CreateOptions::default())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_secs(45)).await;
+        handle
+            .broadcast(&busy_peer, serde_json::json!("still here"))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(45)).await;
+
+        let msg = recv_json(&mut idle_rx).await;
+        assert_eq!(msg["type"], "error");
+        assert_eq!(msg["message"], "room expired");
+        let result = handle
+            .join_room(
+                idle,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(SignalingError::RoomNotFound(_))));
+        handle
+            .join_room(
+                busy,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .expect("recently active room survives");
+    }
+
+    #[tokio::test]
+    async fn unambiguous_alphabet_avoids_confusable_characters() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            room_code_alphabet: RoomCodeAlphabet::Unambiguous,
+            ..SignalingConfig::default()
+        });
+        for _ in 0..200 {
+            let (code, _, _) = handle
+                .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+                .await
+                .unwrap();
+            assert!(
+                !code.as_str().contains(['i', 'l', 'o', '0', '1']),
+                "{}",
+                code
+            );
+            assert_eq!(code.as_str().parse::<RoomCode>().unwrap(), code);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_peers_returns_everyone_else_in_the_room() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (second, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        let (third, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let mut ids: Vec<PeerId> = handle
+            .peers(&second)
+            .await
+            .unwrap()
+            .iter()
+            .map(|p| p.id)
+            .collect();
+        ids.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        let mut expected = [owner, third];
+        expected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(ids, expected);
+
+        let result = handle.peers(&PeerId::generate()).await;
+        assert!(matches!(result, Err(SignalingError::NotInRoom)));
+    }
+
+    #[tokio::test]
+    async fn room_list_counts_peers_per_room() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..SignalingConfig::default()
+        });
+        let mut expected = Vec::new();
+        for size in [1, 3] {
+            let (code, _, _) = handle
+                .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+                .await
+                .unwrap();
+            for _ in 1..size {
+                handle
+                    .join_room(
+                        code,
+                        test_addr(),
+                        outbound_channel().0,
+                        JoinOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+            }
+            expected.push(RoomSummary { code, peers: size });
+        }
+        expected.sort_by(|a, b| a.code.as_str().cmp(b.code.as_str()));
+
+        assert_eq!(handle.list_rooms(Some("s3cret")).await.unwrap(), expected);
+        for wrong in [None, Some("guess")] {
+            let result = handle.list_rooms(wrong).await;
+            assert!(matches!(result, Err(SignalingError::Unauthorized)));
+        }
+    }
+
+    #[tokio::test]
+    async fn room_list_is_disabled_without_admin_token() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let result = handle.list_rooms(Some("")).await;
+        assert!(matches!(result, Err(SignalingError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn custom_code_is_used_only_while_free() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let wanted = RoomCode::from("gamenite");
+        let options = CreateOptions {
+            code: Some(wanted),
+            ..CreateOptions::default()
+        };
+        let (code, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, options.clone())
+            .await
+            .unwrap();
+        assert_eq!(code, wanted);
+
+        let result = handle
+            .create_room(test_addr(), outbound_channel().0, options)
+            .await;
+        assert!(matches!(result, Err(SignalingError::CodeTaken(c)) if c == wanted));
+        assert_eq!(handle.stats().await.unwrap().rooms, 1);
+    }
+
+    #[tokio::test]
+    async fn protected_room_admits_only_the_right_password() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let options = CreateOptions {
+            password: Some("hunter2".to_string()),
+            ..CreateOptions::default()
+        };
+        let (code, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, options)
+            .await
+            .unwrap();
+
+        let with_password = |password: Option<&str>| JoinOptions {
+            password: password.map(str::to_string),
+            ..JoinOptions::default()
+        };
+        for wrong in [None, Some("hunter3"), Some("")] {
+            let result = handle
+                .join_room(
+                    code,
+                    test_addr(),
+                    outbound_channel().0,
+                    with_password(wrong),
+                )
+                .await;
+            assert!(
+                matches!(result, Err(SignalingError::Unauthorized)),
+                "{:?}",
+                wrong
+            );
+        }
+
+        let (tx, mut rx) = outbound_channel();
+        let (_, _, roster) = handle
+            .join_room(code, test_addr(), tx, with_password(Some("hunter2")))
+            .await
+            .unwrap();
+        assert_eq!(roster.len(), 1);
+        assert_eq!(recv_json(&mut rx).await["type"], "room_joined");
+    }
+
+    #[tokio::test]
+    async fn open_room_ignores_a_presented_password() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+
+        handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        let options = JoinOptions {
+            password: Some("anything".to_string()),
+            ..JoinOptions::default()
+        };
+        handle
+            .join_room(code, test_addr(), outbound_channel().0, options)
+            .await
+            .unwrap();
+        assert_eq!(handle.stats().await.unwrap().peers, 3);
+    }
+
+    #[tokio::test]
+    async fn rejoin_within_grace_keeps_the_peer_id() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, _, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (old_tx, _old_rx) = outbound_channel();
+        let (peer, token, _) = handle
+            .join_room(code, test_addr(), old_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+
+        handle.leave_room(&peer).await;
+        assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_left");
+
+        let (new_tx, mut new_rx) = outbound_channel();
+        let (rejoined, rejoined_token, roster) = handle
+            .rejoin_room(code, &peer, &token, test_addr(), new_tx)
+            .await
+            .unwrap();
+        assert_eq!(rejoined, peer);
+        assert!(rejoined_token.verify(&token));
+        assert_eq!(roster.len(), 1);
+
+        let joined = recv_json(&mut new_rx).await;
+        assert_eq!(joined["type"], "room_joined");
+        assert_eq!(joined["your_id"], peer.to_string());
+        let announced = recv_json(&mut owner_rx).await;
+        assert_eq!(announced["type"], "peer_joined");
+        assert_eq!(announced["peer"]["id"], peer.to_string());
+
+        // The departure was used up; a second rejoin is an ordinary join
+        handle.leave_room(&peer).await;
+        let (again, _, _) = handle
+            .rejoin_room(
+                code,
+                &peer,
+                &SessionToken::generate(),
+                test_addr(),
+                outbound_channel().0,
+            )
+            .await
+            .unwrap();
+        assert_ne!(again, peer, "a wrong token doesn't reclaim the id");
+    }
+
+    #[tokio::test]
+    async fn rejoin_after_grace_joins_as_a_new_peer() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            rejoin_grace: std::time::Duration::from_millis(10),
+            ..SignalingConfig::default()
+        });
+        let (code, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (peer, token, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        handle.leave_room(&peer).await;
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let (new_tx, mut new_rx) = outbound_channel();
+        let (rejoined, rejoined_token, roster) = handle
+            .rejoin_room(code, &peer, &token, test_addr(), new_tx)
+            .await
+            .unwrap();
+        assert_ne!(rejoined, peer);
+        assert!(!rejoined_token.verify(&token));
+        assert_eq!(roster.len(), 1);
+        let joined = recv_json(&mut new_rx).await;
+        assert_eq!(joined["type"], "room_joined");
+        assert_eq!(joined["your_id"], rejoined.to_string());
+    }
+
+    #[tokio::test]
+    async fn join_snapshot_is_ordered_with_concurrent_leave() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (leaver, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        // The join is queued before the leave, so the leaver is in the snapshot
+        // and the newcomer must then be told it left, in that order
+        let (tx, mut rx) = outbound_channel();
+        let (joined, ()) = tokio::join!(
+            handle.join_room(code, test_addr(), tx, JoinOptions::default()),
+            handle.leave_room(&leaver)
+        );
+        let (_, _, snapshot) = joined.unwrap();
+        assert!(snapshot.iter().any(|p| p.id == leaver));
+
+        let msg = recv_json(&mut rx).await;
+        assert_eq!(msg["type"], "room_joined");
+        assert_eq!(msg["peers"].as_array().unwrap().len(), snapshot.len());
+        let msg = recv_json(&mut rx).await;
+        assert_eq!(msg["type"], "peer_left");
+        assert_eq!(msg["peer_id"], leaver.as_str());
+
+        // Queued after the leave: the leaver is not in the snapshot
+        let (tx, mut rx) = outbound_channel();
+        let (_, _, snapshot) = handle
+            .join_room(code, test_addr(), tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert!(snapshot.iter().all(|p| p.id != leaver));
+        assert_eq!(recv_json(&mut rx).await["type"], "room_joined");
+    }
+
+    #[tokio::test]
+    async fn alias_and_canonical_code_reach_the_same_room() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let alias = RoomCode::from("launch26");
+        assert_eq!(handle.add_alias(&owner, alias).await.unwrap(), code);
+
+        let (via_code, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        let (tx, mut rx) = outbound_channel();
+        let (_, _, peers) = handle
+            .join_room(alias, test_addr(), tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(peers.len(), 2);
+        assert!(peers.iter().any(|p| p.id == via_code));
+        let msg = recv_json(&mut rx).await;
+        assert_eq!(msg["code"], code.as_str());
+
+        let result = handle
+            .add_alias(&via_code, RoomCode::from("other123"))
+            .await;
+        assert!(matches!(result, Err(SignalingError::Unauthorized)));
+        let result = handle.add_alias(&owner, code).await;
+        assert!(matches!(result, Err(SignalingError::CodeTaken(_))));
+    }
+
+    #[tokio::test]
+    async fn removing_room_releases_aliases() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (_, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let alias = RoomCode::from("launch26");
+        handle.add_alias(&owner, alias).await.unwrap();
+        handle.leave_room(&owner).await;
+
+        let result = handle
+            .join_room(
+                alias,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(SignalingError::RoomNotFound(c)) if c == alias));
+
+        let (_, new_owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        handle.add_alias(&new_owner, alias).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn multicast_reaches_only_present_targets() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, owner, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (a_tx, mut a_rx) = outbound_channel();
+        let (a, _, _) = handle
+            .join_room(code, test_addr(), a_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        let (b_tx, mut b_rx) = outbound_channel();
+        handle
+            .join_room(code, test_addr(), b_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut a_rx).await["type"], "room_joined");
+        assert_eq!(recv_json(&mut a_rx).await["type"], "peer_joined");
+        assert_eq!(recv_json(&mut b_rx).await["type"], "room_joined");
+
+        let absent = PeerId::generate();
+        let (delivered, missing) = handle
+            .multicast(&owner, vec![a, absent, a], serde_json::json!("psst"))
+            .await
+            .unwrap();
+        assert_eq!(delivered, [a]);
+        assert_eq!(missing, [absent]);
+
+        let msg = recv_json(&mut a_rx).await;
+        assert_eq!(msg["type"], "broadcast");
+        assert_eq!(msg["from"], owner.as_str());
+        assert_eq!(msg["payload"], "psst");
+
+        // Neither the untargeted peer nor the sender got a copy
+        handle
+            .broadcast(&owner, serde_json::json!("everyone"))
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut b_rx).await["payload"], "everyone");
+        for _ in 0..2 {
+            assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+        }
+        let result =
+            tokio::time::timeout(std::time::Duration::from_millis(20), owner_rx.recv()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn relay_reaches_only_peers_in_the_senders_room() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (peer_tx, mut peer_rx) = outbound_channel();
+        let (peer, _, _) = handle
+            .join_room(code, test_addr(), peer_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut peer_rx).await["type"], "room_joined");
+
+        let offer = serde_json::json!({"sdp": "v=0", "kind": "offer"});
+        handle.relay(&owner, &peer, offer.clone()).await.unwrap();
+        let msg = recv_json(&mut peer_rx).await;
+        assert_eq!(msg["type"], "signal");
+        assert_eq!(msg["from"], owner.as_str());
+        assert_eq!(msg["payload"], offer);
+
+        let absent = PeerId::generate();
+        assert!(matches!(
+            handle.relay(&owner, &absent, offer.clone()).await,
+            Err(SignalingError::PeerNotInRoom(id)) if id == absent
+        ));
+
+        let (other_tx, mut other_rx) = outbound_channel();
+        let (_, elsewhere, _) = handle
+            .create_room(test_addr(), other_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            handle.relay(&owner, &elsewhere, offer).await,
+            Err(SignalingError::PeerNotInRoom(_))
+        ));
+        let result =
+            tokio::time::timeout(std::time::Duration::from_millis(20), other_rx.recv()).await;
+        assert!(result.is_err());
+    }
+
+    /// A room with two peers, returning each one's id and channel
+    async fn pair(
+        handle: &RoomManagerHandle,
+    ) -> ((PeerId, OutboundReceiver), (PeerId, OutboundReceiver)) {
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, owner, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (peer_tx, mut peer_rx) = outbound_channel();
+        let (peer, _, _) = handle
+            .join_room(code, test_addr(), peer_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut peer_rx).await["type"], "room_joined");
+        assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+        ((owner, owner_rx), (peer, peer_rx))
+    }
+
+    #[tokio::test]
+    async fn offer_then_answer_reaches_each_side() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let ((owner, mut owner_rx), (peer, mut peer_rx)) = pair(&handle).await;
+
+        // A round that completes leaves either side free to renegotiate
+        for round in 0..2 {
+            let offer = format!("v=0 offer {}", round);
+            handle
+                .relay_offer(&owner, &peer, offer.clone())
+                .await
+                .unwrap();
+            let msg = recv_json(&mut peer_rx).await;
+            assert_eq!(msg["type"], "offer");
+            assert_eq!(msg["from"], owner.as_str());
+            assert_eq!(msg["sdp"], offer);
+
+            handle
+                .relay_answer(&peer, &owner, "v=0 answer".into())
+                .await
+                .unwrap();
+            let msg = recv_json(&mut owner_rx).await;
+            assert_eq!(msg["type"], "answer");
+            assert_eq!(msg["from"], peer.as_str());
+            assert_eq!(msg["sdp"], "v=0 answer");
+        }
+
+        // Nothing is open now, so a further answer has nothing to close
+        assert!(matches!(
+            handle.relay_answer(&peer, &owner, "v=0".into()).await,
+            Err(SignalingError::NoOfferPending(id)) if id == owner
+        ));
+    }
+
+    #[tokio::test]
+    async fn second_offer_before_an_answer_is_refused() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let ((owner, _owner_rx), (peer, mut peer_rx)) = pair(&handle).await;
+
+        handle
+            .relay_offer(&owner, &peer, "v=0".into())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut peer_rx).await["type"], "offer");
+        assert!(matches!(
+            handle.relay_offer(&owner, &peer, "v=0".into()).await,
+            Err(SignalingError::OfferPending(id)) if id == peer
+        ));
+        // Glare: the other side offering back is refused too
+        assert!(matches!(
+            handle.relay_offer(&peer, &owner, "v=0".into()).await,
+            Err(SignalingError::OfferPending(id)) if id == owner
+        ));
+        // Only the offerer's counterpart can answer
+        assert!(matches!(
+            handle.relay_answer(&owner, &peer, "v=0".into()).await,
+            Err(SignalingError::NoOfferPending(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn ice_candidate_reaches_its_target() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (peer_tx, mut peer_rx) = outbound_channel();
+        let (peer, _, _) = handle
+            .join_room(code, test_addr(), peer_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut peer_rx).await["type"], "room_joined");
+
+        let candidate = "candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host";
+        handle
+            .relay_ice_candidate(&owner, &peer, candidate.into(), Some("0".into()), Some(0))
+            .await
+            .unwrap();
+        let msg = recv_json(&mut peer_rx).await;
+        assert_eq!(msg["type"], "ice_candidate");
+        assert_eq!(msg["from"], owner.as_str());
+        assert_eq!(msg["candidate"], candidate);
+        assert_eq!(msg["sdpMid"], "0");
+        assert_eq!(msg["sdpMLineIndex"], 0);
+    }
+
+    #[tokio::test]
+    async fn stats_count_creates_joins_relays_and_leaves() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (first, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        handle
+            .relay(&owner, &first, serde_json::json!({"sdp": "v=0"}))
+            .await
+            .unwrap();
+        // Refused relays aren't counted
+        assert!(
+            handle
+                .relay(&owner, &PeerId::generate(), serde_json::json!({}))
+                .await
+                .is_err()
+        );
+
+        assert_eq!(
+            handle.stats().await.unwrap(),
+            ServerStats {
+                rooms: 1,
+                peers: 3,
+                rooms_created: 1,
+                joins: 2,
+                leaves: 0,
+                relays: 1,
+            }
+        );
+
+        handle.leave_room(&first).await;
+        let stats = handle.stats().await.unwrap();
+        assert_eq!((stats.peers, stats.joins, stats.leaves), (2, 2, 1));
+    }
+
+    #[tokio::test]
+    async fn peer_ceiling_rejects_joins_until_a_peer_leaves() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            max_peers: Some(2),
+            ..SignalingConfig::default()
+        });
+        let (code, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (joiner, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            handle.stats().await.unwrap(),
+            ServerStats {
+                rooms: 1,
+                peers: 2,
+                rooms_created: 1,
+                joins: 1,
+                ..ServerStats::default()
+            }
+        );
+
+        let result = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(SignalingError::ServerAtCapacity)));
+        let result = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await;
+        assert!(matches!(result, Err(SignalingError::ServerAtCapacity)));
+
+        handle.leave_room(&joiner).await;
+        assert_eq!(handle.stats().await.unwrap().peers, 1);
+        handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn fresh_code_skips_taken_codes() {
+        let existing = RoomCode::from("aaaaaaaa");
+        let taken = |code: &RoomCode| *code == existing;
+
+        let mut draws = [existing, existing, RoomCode::from("bbbbbbbb")].into_iter();
+        let code = fresh_code(taken, || draws.next().unwrap());
+        assert_eq!(code, Some(RoomCode::from("bbbbbbbb")));
+
+        // A generator stuck on a taken code gives up instead of overwriting
+        let mut attempts = 0;
+        let code = fresh_code(taken, || {
+            attempts += 1;
+            existing
+        });
+        assert_eq!(code, None);
+        assert_eq!(attempts, MAX_CODE_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn full_room_rejects_joins_without_changing() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            max_peers_per_room: Some(3),
+            ..SignalingConfig::default()
+        });
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, _, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            handle
+                .join_room(
+                    code,
+                    test_addr(),
+                    outbound_channel().0,
+                    JoinOptions::default(),
+                )
+                .await
+                .unwrap();
+        }
+        for _ in 0..2 {
+            assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+        }
+
+        let result = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(SignalingError::RoomFull(c)) if c == code));
+        assert_eq!(
+            handle.stats().await.unwrap(),
+            // The refused join isn't counted either
+            ServerStats {
+                rooms: 1,
+                peers: 3,
+                rooms_created: 1,
+                joins: 2,
+                ..ServerStats::default()
+            }
+        );
+        // Nobody was told about the rejected peer
+        let result =
+            tokio::time::timeout(std::time::Duration::from_millis(20), owner_rx.recv()).await;
+        assert!(result.is_err());
+
+        // Other rooms are unaffected
+        let (other, _, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        handle
+            .join_room(
+                other,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn signaling_and_reflexive_addrs_stay_separate() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let tcp = |a: &str| SignalingAddr::from(a.parse::<SocketAddr>().unwrap());
+        let udp =
+            ReflexiveAddr::try_from("198.51.100.9:40000".parse::<SocketAddr>().unwrap()).unwrap();
+
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, owner, _) = handle
+            .create_room(tcp("203.0.113.7:51000"), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        handle.set_reflexive_addr(&owner, udp).await.unwrap();
+
+        let (joiner_tx, mut joiner_rx) = outbound_channel();
+        let (joiner, _, _) = handle
+            .join_room(
+                code,
+                tcp("192.0.2.4:52000"),
+                joiner_tx,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        // The roster carries the owner's UDP address next to, not instead of, its TCP one
+        let joined = recv_json(&mut joiner_rx).await;
+        assert_eq!(joined["type"], "room_joined");
+        assert_eq!(joined["peers"][0]["signaling_addr"], "203.0.113.7:51000");
+        assert_eq!(joined["peers"][0]["reflexive_addr"], "198.51.100.9:40000");
+
+        // A new peer hasn't reported a UDP address yet
+        let announced = recv_json(&mut owner_rx).await;
+        assert_eq!(announced["type"], "peer_joined");
+        assert_eq!(announced["peer"]["signaling_addr"], "192.0.2.4:52000");
+        assert!(announced["peer"]["reflexive_addr"].is_null());
+
+        handle.resync_room(code).await.unwrap();
+        let roster = recv_json(&mut owner_rx).await;
+        let entry = roster["peers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|p| p["id"] == joiner.as_str())
+            .unwrap();
+        assert!(entry["reflexive_addr"].is_null());
+    }
+
+    #[tokio::test]
+    async fn reflexive_addr_update_reaches_the_room() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, _, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (joiner_tx, mut joiner_rx) = outbound_channel();
+        let (joiner, _, _) = handle
+            .join_room(code, test_addr(), joiner_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+        assert_eq!(recv_json(&mut joiner_rx).await["type"], "room_joined");
+
+        let udp =
+            ReflexiveAddr::try_from("198.51.100.9:40000".parse::<SocketAddr>().unwrap()).unwrap();
+        handle.set_reflexive_addr(&joiner, udp).await.unwrap();
+
+        let updated = recv_json(&mut owner_rx).await;
+        assert_eq!(updated["type"], "peer_updated");
+        assert_eq!(updated["peer"]["id"], joiner.as_str());
+        assert_eq!(updated["peer"]["reflexive_addr"], "198.51.100.9:40000");
+        let echoed =
+            tokio::time::timeout(std::time::Duration::from_millis(20), joiner_rx.recv()).await;
+        assert!(echoed.is_err(), "sender was told of its own update");
+    }
+
+    #[tokio::test]
+    async fn non_global_reflexive_addr_is_refused_when_required() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            require_global_peer_addrs: true,
+            ..SignalingConfig::default()
+        });
+        let (owner_tx, _owner_rx) = outbound_channel();
+        let (_, owner, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+
+        let claim = |a: &str| ReflexiveAddr::try_from(a.parse::<SocketAddr>().unwrap()).unwrap();
+        let result = handle
+            .set_reflexive_addr(&owner, claim("192.168.1.20:40000"))
+            .await;
+        assert!(matches!(result, Err(SignalingError::InvalidAddr(_))));
+        handle
+            .set_reflexive_addr(&owner, claim("8.8.8.8:40000"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn metadata_set_at_join_and_updated_later() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let options = CreateOptions {
+            metadata: Some(serde_json::json!({ "name": "Host" })),
+            ..CreateOptions::default()
+        };
+        let (code, owner, _) = handle
+            .create_room(test_addr(), owner_tx, options)
+            .await
+            .unwrap();
+        let (joiner_tx, mut joiner_rx) = outbound_channel();
+        let options = JoinOptions {
+            metadata: Some(serde_json::json!({ "name": "Guest", "role": "viewer" })),
+            ..JoinOptions::default()
+        };
+        let (joiner, _, roster) = handle
+            .join_room(code, test_addr(), joiner_tx, options)
+            .await
+            .unwrap();
+        assert_eq!(
+            roster[0].metadata,
+            Some(serde_json::json!({ "name": "Host" }))
+        );
+        let joined = recv_json(&mut owner_rx).await;
+        assert_eq!(joined["type"], "peer_joined");
+        assert_eq!(joined["peer"]["metadata"]["name"], "Guest");
+        assert_eq!(recv_json(&mut joiner_rx).await["type"], "room_joined");
+
+        handle
+            .set_metadata(
+                &joiner,
+                Some(serde_json::json!({ "name": "Guest", "role": "player" })),
+            )
+            .await
+            .unwrap();
+        let updated = recv_json(&mut owner_rx).await;
+        assert_eq!(updated["type"], "peer_updated");
+        assert_eq!(updated["peer"]["id"], joiner.as_str());
+        assert_eq!(updated["peer"]["metadata"]["role"], "player");
+
+        // Cleared metadata is left out of the info altogether
+        handle.set_metadata(&owner, None).await.unwrap();
+        let updated = recv_json(&mut joiner_rx).await;
+        assert_eq!(updated["type"], "peer_updated");
+        assert!(updated["peer"].get("metadata").is_none());
+        assert_eq!(handle.peers(&joiner).await.unwrap()[0].metadata, None);
+    }
+
+    #[tokio::test]
+    async fn quick_match_fills_a_room_before_opening_another() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let quick_match = || handle.quick_match(test_addr(), outbound_channel().0, None);
+
+        let Ok(QuickMatched::Created(first, host, _)) = quick_match().await else {
+            panic!("first quick match should open a room");
+        };
+        let (tx, mut rx) = outbound_channel();
+        let Ok(QuickMatched::Joined(code, guest, _, peers)) =
+            handle.quick_match(test_addr(), tx, None).await
+        else {
+            panic!("second quick match should join the open room");
+        };
+        assert_eq!(code, first);
+        assert_eq!(peers.iter().map(|p| p.id).collect::<Vec<_>>(), [host]);
+        assert_eq!(recv_json(&mut rx).await["type"], "room_joined");
+
+        // Two is a full match, so the next peer waits in a room of its own
+        let Ok(QuickMatched::Created(second, _, _)) = quick_match().await else {
+            panic!("third quick match should open a new room");
+        };
+        assert_ne!(second, first);
+
+        // A seat freed by a leave is matched again, after older open rooms
+        handle.leave_room(&guest).await;
+        assert!(matches!(
+            quick_match().await,
+            Ok(QuickMatched::Joined(code, ..)) if code == second
+        ));
+        assert!(matches!(
+            quick_match().await,
+            Ok(QuickMatched::Joined(code, ..)) if code == first
+        ));
+    }
+
+    #[tokio::test]
+    async fn late_joiner_receives_buffered_broadcasts() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            broadcast_replay: 2,
+            ..SignalingConfig::default()
+        });
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        for state in ["lobby", "started", "round 2"] {
+            handle
+                .broadcast(&owner, serde_json::json!({ "state": state }))
+                .await
+                .unwrap();
+        }
+
+        let (tx, mut rx) = outbound_channel();
+        handle
+            .join_room(code, test_addr(), tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut rx).await["type"], "room_joined");
+
+        // Only the last two are kept
+        let replay = recv_json(&mut rx).await;
+        assert_eq!(replay["type"], "broadcast_replay");
+        let messages = replay["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["from"], owner.as_str());
+        assert_eq!(messages[0]["payload"]["state"], "started");
+        assert_eq!(messages[1]["payload"]["state"], "round 2");
+    }
+
+    #[tokio::test]
+    async fn oversized_relay_is_refused_without_charging_budget() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            max_message_size: 256,
+            relay_rate: Some(RateLimit::new(0.001, 1)),
+            ..SignalingConfig::default()
+        });
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (tx, mut rx) = outbound_channel();
+        handle
+            .join_room(code, test_addr(), tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut rx).await["type"], "room_joined");
+
+        let result = handle
+            .broadcast(&owner, serde_json::json!({ "blob": "x".repeat(512) }))
+            .await;
+        assert!(matches!(
+            result,
+            Err(SignalingError::PayloadTooLarge { limit: 256 })
+        ));
+        handle
+            .broadcast(&owner, serde_json::json!({ "blob": "small" }))
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut rx).await["payload"]["blob"], "small");
+    }
+
+    #[tokio::test]
+    async fn chatty_peer_is_throttled_without_affecting_others() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            relay_rate: Some(RateLimit::new(0.001, 3)),
+            ..SignalingConfig::default()
+        });
+        let (quiet_tx, mut quiet_rx) = outbound_channel();
+        let (code, quiet, _) = handle
+            .create_room(test_addr(), quiet_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (chatty_tx, mut chatty_rx) = outbound_channel();
+        let (chatty, _, _) = handle
+            .join_room(code, test_addr(), chatty_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut quiet_rx).await["type"], "peer_joined");
+        assert_eq!(recv_json(&mut chatty_rx).await["type"], "room_joined");
+
+        for i in 0..3 {
+            handle
+                .broadcast(&chatty, serde_json::json!(i))
+                .await
+                .unwrap();
+        }
+        let result = handle.broadcast(&chatty, serde_json::json!(3)).await;
+        assert!(matches!(result, Err(SignalingError::RoomRateLimited)));
+
+        handle
+            .broadcast(&quiet, serde_json::json!("hi"))
+            .await
+            .unwrap();
+
+        for i in 0..3 {
+            let msg = recv_json(&mut quiet_rx).await;
+            assert_eq!(msg["type"], "broadcast");
+            assert_eq!(msg["from"], chatty.as_str());
+            assert_eq!(msg["payload"], i);
+        }
+        let msg = recv_json(&mut chatty_rx).await;
+        assert_eq!(msg["from"], quiet.as_str());
+        assert_eq!(msg["payload"], "hi");
+    }
+
+    #[tokio::test]
+    async fn migrating_room_notifies_every_peer_and_closes_it() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, owner, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (member_tx, mut member_rx) = outbound_channel();
+        handle
+            .join_room(code, test_addr(), member_tx, JoinOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(recv_json(&mut owner_rx).await["type"], "peer_joined");
+        assert_eq!(recv_json(&mut member_rx).await["type"], "room_joined");
+
+        let url = "wss://eu-2.example.com/signal".to_string();
+        handle.migrate_room(code, url.clone()).await.unwrap();
+
+        for rx in [&mut owner_rx, &mut member_rx] {
+            let msg = recv_json(rx).await;
+            assert_eq!(msg["type"], "room_migrating");
+            assert_eq!(msg["url"], url.as_str());
+        }
+
+        let result = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(SignalingError::RoomNotFound(_))));
+        let result = handle.set_locked(&owner, true).await;
+        assert!(matches!(result, Err(SignalingError::NotInRoom)));
+    }
+
+    #[tokio::test]
+    async fn resync_unknown_room_fails() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let result = handle.resync_room(RoomCode::from("missing1")).await;
+        assert!(matches!(result, Err(SignalingError::RoomNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn relay_forwards_datagrams_between_the_two_peers() {
+        use tokio::net::UdpSocket;
+
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            relay_ip: Some("127.0.0.1".parse().unwrap()),
+            ..SignalingConfig::default()
+        });
+        let (owner_tx, mut owner_rx) = outbound_channel();
+        let (code, owner, _) = handle
+            .create_room(test_addr(), owner_tx, CreateOptions::default())
+            .await
+            .unwrap();
+        let (joiner_tx, mut joiner_rx) = outbound_channel();
+        let (joiner, _, _) = handle
+            .join_room(code, test_addr(), joiner_tx, JoinOptions::default())
+            .await
+            .unwrap();
+
+        // Refused until both peers have said where they can be reached
+        assert!(matches!(
+            handle.request_relay(&owner).await,
+            Err(SignalingError::RelayUnavailable(_))
+        ));
+
+        let owner_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let joiner_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for (peer, socket) in [(&owner, &owner_udp), (&joiner, &joiner_udp)] {
+            let addr = ReflexiveAddr::try_from(socket.local_addr().unwrap()).unwrap();
+            handle.set_reflexive_addr(peer, addr).await.unwrap();
+        }
+
+        let relay = handle.request_relay(&joiner).await.unwrap();
+        assert_eq!(handle.request_relay(&owner).await.unwrap(), relay);
+        for rx in [&mut owner_rx, &mut joiner_rx] {
+            let allocated = loop {
+                let msg = recv_json(rx).await;
+                if msg["type"] == "relay_allocated" {
+                    break msg;
+                }
+            };
+            assert_eq!(allocated["addr"], relay.to_string());
+        }
+
+        async fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+            let mut buf = [0u8; 64];
+            let (len, from) = tokio::time::timeout(
+                std::time::Duration::from_secs(1),
+                socket.recv_from(&mut buf),
+            )
+            .await
+            .expect("datagram wasn't forwarded")
+            .unwrap();
+            (buf[..len].to_vec(), from)
+        }
+        // The first datagram each way confirms its sender; traffic flows
+        // once both have
+        owner_udp.send_to(b"hello", relay).await.unwrap();
+        joiner_udp.send_to(b"pong", relay).await.unwrap();
+        assert_eq!(recv(&owner_udp).await, (b"pong".to_vec(), relay));
+        owner_udp.send_to(b"ping", relay).await.unwrap();
+        assert_eq!(recv(&joiner_udp).await, (b"ping".to_vec(), relay));
+
+        // Both peers share an IP here, so an unknown port is a stranger
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        stranger.send_to(b"spoof", relay).await.unwrap();
+        let mut buf = [0u8; 64];
+        let leaked = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            joiner_udp.recv_from(&mut buf),
+        )
+        .await;
+        assert!(leaked.is_err(), "relay forwarded a stranger's datagram");
+    }
+
+    #[tokio::test]
+    async fn public_relay_refuses_non_global_ends() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig {
+            // Never bound: the ends are refused first
+            relay_ip: Some("8.8.8.8".parse().unwrap()),
+            ..SignalingConfig::default()
+        });
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let (joiner, _, _) = handle
+            .join_room(
+                code,
+                test_addr(),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        for (peer, addr) in [(&owner, "127.0.0.1:40000"), (&joiner, "10.0.0.7:40000")] {
+            let addr = ReflexiveAddr::try_from(addr.parse::<SocketAddr>().unwrap()).unwrap();
+            handle.set_reflexive_addr(peer, addr).await.unwrap();
+        }
+
+        assert!(matches!(
+            handle.request_relay(&owner).await,
+            Err(SignalingError::RelayUnavailable(reason)) if reason.contains("non-global")
+        ));
+    }
+
+    #[tokio::test]
+    async fn session_key_brings_back_the_same_peer_id() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let key = |k: &str| Some(k.to_string());
+        let options = CreateOptions {
+            session_key: key("owner"),
+            ..CreateOptions::default()
+        };
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, options)
+            .await
+            .unwrap();
+        let join = |session_key| {
+            let options = JoinOptions {
+                session_key,
+                ..JoinOptions::default()
+            };
+            handle.join_room(code, test_addr(), outbound_channel().0, options)
+        };
+
+        let (first, _, _) = join(key("alice")).await.unwrap();
+        let (other, _, _) = join(key("bob")).await.unwrap();
+        assert_ne!(first, other);
+        assert_ne!(first, owner);
+        assert!(matches!(
+            join(key("alice")).await,
+            Err(SignalingError::SessionKeyInUse(c)) if c == code
+        ));
+
+        // Dropped connection, then back with the same key
+        handle.leave_room(&first).await;
+        let (again, _, peers) = join(key("alice")).await.unwrap();
+        assert_eq!(again, first);
+        assert!(peers.iter().all(|p| p.id != first));
+
+        // The creator's key works the same way
+        handle.leave_room(&owner).await;
+        assert_eq!(join(key("owner")).await.unwrap().0, owner);
+
+        // Without a key every join is a new peer
+        let (a, _, _) = join(None).await.unwrap();
+        let (b, _, _) = join(None).await.unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[tokio::test]
+    async fn session_key_gets_past_no_lock_password_or_kick() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let options = CreateOptions {
+            password: Some("hunter2".to_string()),
+            ..CreateOptions::default()
+        };
+        let (code, owner, _) = handle
+            .create_room(test_addr(), outbound_channel().0, options)
+            .await
+            .unwrap();
+        let join = |password: Option<&str>| {
+            let options = JoinOptions {
+                password: password.map(str::to_string),
+                session_key: Some("alice".to_string()),
+                ..JoinOptions::default()
+            };
+            handle.join_room(code, test_addr(), outbound_channel().0, options)
+        };
+
+        let (alice, _, _) = join(Some("hunter2")).await.unwrap();
+        handle.leave_room(&alice).await;
+        assert!(matches!(
+            join(None).await,
+            Err(SignalingError::Unauthorized)
+        ));
+
+        handle.set_locked(&owner, true).await.unwrap();
+        assert!(matches!(
+            join(Some("hunter2")).await,
+            Err(SignalingError::RoomLocked(c)) if c == code
+        ));
+        handle.set_locked(&owner, false).await.unwrap();
+        assert_eq!(join(Some("hunter2")).await.unwrap().0, alice);
+
+        // Kicked, the key no longer brings the id back
+        handle.kick(&owner, &alice).await.unwrap();
+        let (again, _, _) = join(Some("hunter2")).await.unwrap();
+        assert_ne!(again, alice);
     }
 }
@@ -0,0 +1,174 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+
+/// Wire encoding negotiated per connection via `Hello`
+///
+/// Text frames are always JSON; binary frames carry the negotiated encoding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "cbor")]
+    Cbor,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("CBOR error: {0}")]
+    Cbor(String),
+    #[error("MessagePack error: {0}")]
+    MessagePack(String),
+}
+
+/// Serialize a value into a WebSocket frame for `encoding`
+pub fn encode<T: Serialize>(value: &T, encoding: Encoding) -> Result<Message, CodecError> {
+    match encoding {
+        Encoding::Json => Ok(Message::text(serde_json::to_string(value)?)),
+        Encoding::Cbor => {
+            let mut buf = Vec::new();
+            ciborium::into_writer(value, &mut buf).map_err(|e| CodecError::Cbor(e.to_string()))?;
+            Ok(Message::binary(buf))
+        }
+        Encoding::MessagePack => rmp_serde::to_vec_named(value)
+            .map(Message::binary)
+            .map_err(|e| CodecError::MessagePack(e.to_string())),
+    }
+}
+
+/// Re-encode an already serialized JSON push for `encoding`
+///
+/// Pushes are serialized to JSON once and shared across peers, so JSON
+/// connections take them as-is and only other encodings pay for a transcode.
+pub fn encode_json(json: Utf8Bytes, encoding: Encoding) -> Result<Message, CodecError> {
+    match encoding {
+        Encoding::Json => Ok(Message::Text(json)),
+        _ => {
+            let value: serde_json::Value = serde_json::from_str(&json)?;
+            encode(&value, encoding)
+        }
+    }
+}
+
+/// Decode a binary frame in the negotiated encoding
+pub fn decode<T: DeserializeOwned>(data: &[u8], encoding: Encoding) -> Result<T, CodecError> {
+    match encoding {
+        Encoding::Json => Ok(serde_json::from_slice(data)?),
+        Encoding::Cbor => ciborium::from_reader(data).map_err(|e| CodecError::Cbor(e.to_string())),
+        Encoding::MessagePack => {
+            rmp_serde::from_slice(data).map_err(|e| CodecError::MessagePack(e.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::messages::{ClientMessage, ServerMessage};
+    use crate::signaling::types::{PeerId, PeerInfo, RoomCode, SessionToken, SignalingAddr};
+
+    const ALL: [Encoding; 3] = [Encoding::Json, Encoding::Cbor, Encoding::MessagePack];
+
+    fn frame_bytes(msg: Message) -> Vec<u8> {
+        match msg {
+            Message::Text(text) => text.as_bytes().to_vec(),
+            Message::Binary(data) => data.to_vec(),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        for encoding in ALL {
+            let msg = ClientMessage::JoinRoom {
+                code: "abc12345".to_string(),
+                password: None,
+                metadata: None,
+                session_key: None,
+            };
+            let bytes = frame_bytes(encode(&msg, encoding).unwrap());
+            let decoded: ClientMessage = decode(&bytes, encoding).unwrap();
+            assert!(
+                matches!(decoded, ClientMessage::JoinRoom { ref code, .. } if code == "abc12345"),
+                "{:?}",
+                encoding
+            );
+
+            let bytes = frame_bytes(
+                encode(
+                    &ClientMessage::CreateRoom {
+                        password: None,
+                        code: None,
+                        metadata: None,
+                        session_key: None,
+                    },
+                    encoding,
+                )
+                .unwrap(),
+            );
+            let decoded: ClientMessage = decode(&bytes, encoding).unwrap();
+            assert!(
+                matches!(
+                    decoded,
+                    ClientMessage::CreateRoom {
+                        password: None,
+                        code: None,
+                        metadata: None,
+                        session_key: None,
+                    }
+                ),
+                "{:?}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn json_push_transcodes_to_each_encoding() {
+        let msg = ServerMessage::RoomJoined {
+            code: RoomCode::from("test1234"),
+            your_id: PeerId::from("peer_new12345"),
+            session_token: SessionToken::generate(),
+            peers: vec![PeerInfo {
+                id: PeerId::from("peer_existing"),
+                signaling_addr: Some(SignalingAddr::from(
+                    "192.168.1.1:5000".parse::<std::net::SocketAddr>().unwrap(),
+                )),
+                reflexive_addr: None,
+                metadata: None,
+            }],
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+
+        for encoding in ALL {
+            let frame = encode_json(Utf8Bytes::from(json.clone()), encoding).unwrap();
+            assert_eq!(frame.is_text(), encoding == Encoding::Json);
+            let decoded: ServerMessage = decode(&frame_bytes(frame), encoding).unwrap();
+            match decoded {
+                ServerMessage::RoomJoined { code, peers, .. } => {
+                    assert_eq!(code, RoomCode::from("test1234"));
+                    assert_eq!(peers[0].id, PeerId::from("peer_existing"));
+                }
+                other => panic!("{:?}: unexpected {:?}", encoding, other),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_encoding_is_rejected() {
+        let json = r#"{"type": "hello", "encoding": "xml"}"#;
+        assert!(serde_json::from_str::<ClientMessage>(json).is_err());
+    }
+
+    #[test]
+    fn garbage_binary_frame_is_an_error() {
+        assert!(decode::<ClientMessage>(&[0xff, 0x00, 0x13], Encoding::Cbor).is_err());
+        assert!(decode::<ClientMessage>(&[0xc1], Encoding::MessagePack).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use semver::Version;
+
+use crate::rate_limit::RateLimit;
+use crate::redact::AddrRedaction;
+
+use super::outbound::DEFAULT_OUTBOUND_CAPACITY;
+use super::types::RoomCodeAlphabet;
+
+/// Room size at which broadcasts are offloaded from the actor by default
+pub const DEFAULT_FANOUT_OFFLOAD_THRESHOLD: usize = 128;
+
+/// Time a new connection gets to complete the WebSocket upgrade by default
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time between keepalive pings by default
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time a client gets to answer a ping by default
+pub const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest inbound WebSocket message accepted by default
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Idle time after which a room is closed by default
+pub const DEFAULT_ROOM_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a departed peer can reclaim its id with `Rejoin` by default
+pub const DEFAULT_REJOIN_GRACE: Duration = Duration::from_secs(30);
+
+/// Peers matched into one room by `QuickMatch` by default
+pub const DEFAULT_QUICK_MATCH_SIZE: usize = 2;
+
+/// Signaling server configuration
+#[derive(Debug, Clone)]
+pub struct SignalingConfig {
+    /// Global limit on room creation across all clients (`None` = unlimited).
+    /// Joins to existing rooms are never limited by this.
+    pub room_creation_rate: Option<RateLimit>,
+    /// Per-peer budget for messages relayed to the rest of a room (`None` =
+    /// unlimited). Each peer gets its own bucket, so one chatty peer can't
+    /// starve the others.
+    pub relay_rate: Option<RateLimit>,
+    /// Number of recent room-wide broadcasts each room keeps and replays to
+    /// peers that join later (0 = disabled)
+    pub broadcast_replay: usize,
+    /// Stamp every server push with a per-connection `seq` field so clients
+    /// can detect gaps.
+    pub sequence_numbers: bool,
+    /// Rooms with at least this many peers broadcast from a dedicated fan-out
+    /// task instead of the room manager actor (`usize::MAX` disables it).
+    pub fanout_offload_threshold: usize,
+    /// Cap on requests awaiting a reply from the room manager; beyond it new
+    /// requests fail fast with `Overloaded` (`None` = unbounded).
+    pub max_in_flight_requests: Option<usize>,
+    /// Ceiling on peers across all rooms; creates and joins beyond it fail
+    /// with `ServerAtCapacity` (`None` = unlimited)
+    pub max_peers: Option<usize>,
+    /// Ceiling on peers in one room; joins beyond it fail with `RoomFull`
+    /// (`None` = unlimited). A full mesh needs a connection per pair of
+    /// peers, so mesh deployments want this small.
+    pub max_peers_per_room: Option<usize>,
+    /// How client addresses appear in logs (full addresses by default)
+    pub addr_redaction: AddrRedaction,
+    /// Clients must announce at least this version in `Hello` before anything
+    /// else is served; others get an error and a close frame (`None` = no gate)
+    pub min_client_version: Option<Version>,
+    /// Connections that haven't completed the WebSocket upgrade within this
+    /// long are dropped, so stalled clients can't pin a task each
+    pub handshake_timeout: Duration,
+    /// Time between keepalive pings on each connection
+    pub ping_interval: Duration,
+    /// Connections that don't answer a ping within this long are dropped
+    pub pong_timeout: Duration,
+    /// A peer that left can `Rejoin` its room under the same id for this long;
+    /// after that it joins as a new peer (`Duration::ZERO` disables it)
+    pub rejoin_grace: Duration,
+    /// Rooms with no joins or relays for this long are closed, so a room whose
+    /// peers all hung silently doesn't linger (`None` = never)
+    pub room_ttl: Option<Duration>,
+    /// Characters new room codes are drawn from; `Unambiguous` suits codes
+    /// that are read aloud or typed from a screen
+    pub room_code_alphabet: RoomCodeAlphabet,
+    /// Largest WebSocket message or frame a client may send, in bytes; larger
+    /// ones close the connection with 1009. Relayed pushes are held to the
+    /// same limit, so a payload can't be amplified across a room.
+    pub max_message_size: usize,
+    /// Token a client must present to `ListRooms`; room codes are what let
+    /// peers join, so listing is refused to everyone while unset
+    pub admin_token: Option<String>,
+    /// Bearer tokens that unlock creating and joining rooms, presented as a
+    /// WebSocket subprotocol or in `Authenticate` (empty = no authentication)
+    pub access_tokens: Vec<String>,
+    /// Local IP relay sockets are bound on for `RequestRelay`; it must be
+    /// reachable by clients (`None` = relaying disabled)
+    pub relay_ip: Option<IpAddr>,
+    /// Refuse a `SetReflexiveAddr` naming a private, loopback or otherwise
+    /// non-global address. Leave off when peers share a LAN with the server.
+    pub require_global_peer_addrs: bool,
+    /// Peers `QuickMatch` puts in one room before opening another. Joining
+    /// by code isn't held to it; `max_peers_per_room` still applies.
+    pub quick_match_size: usize,
+    /// Messages a connection's outbound queue holds per tier. A client that
+    /// lets it fill is too slow to keep up and is closed with 1011.
+    pub outbound_capacity: usize,
+}
+
+impl Default for SignalingConfig {
+    fn default() -> Self {
+        Self {
+            room_creation_rate: None,
+            relay_rate: None,
+            broadcast_replay: 0,
+            sequence_numbers: false,
+            fanout_offload_threshold: DEFAULT_FANOUT_OFFLOAD_THRESHOLD,
+            max_in_flight_requests: None,
+            max_peers: None,
+            max_peers_per_room: None,
+            addr_redaction: AddrRedaction::default(),
+            min_client_version: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            pong_timeout: DEFAULT_PONG_TIMEOUT,
+            rejoin_grace: DEFAULT_REJOIN_GRACE,
+            room_ttl: Some(DEFAULT_ROOM_TTL),
+            room_code_alphabet: RoomCodeAlphabet::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            admin_token: None,
+            access_tokens: Vec::new(),
+            relay_ip: None,
+            require_global_peer_addrs: false,
+            quick_match_size: DEFAULT_QUICK_MATCH_SIZE,
+            outbound_capacity: DEFAULT_OUTBOUND_CAPACITY,
+        }
+    }
+}
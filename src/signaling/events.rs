@@ -0,0 +1,170 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use super::types::{PeerId, RoomCode};
+
+/// Capacity of the room event broadcast channel
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Significant signaling activity, published by the room manager actor
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RoomEvent {
+    RoomCreated { code: RoomCode, peer_id: PeerId },
+    PeerJoined { code: RoomCode, peer_id: PeerId },
+    PeerLeft { code: RoomCode, peer_id: PeerId },
+    PeerRebound { code: RoomCode, peer_id: PeerId },
+    RoomRemoved { code: RoomCode },
+    Error { message: String },
+}
+
+/// A single line of the event log
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    /// milliseconds since the Unix epoch
+    ts: u64,
+    #[serde(flatten)]
+    event: &'a RoomEvent,
+}
+
+/// Write every event received on `rx` to `sink` as one JSON object per line
+///
+/// Runs on a blocking thread so a slow file never stalls the runtime. The
+/// task ends once the event channel closes (the server was dropped) or the
+/// sink fails.
+pub fn spawn_event_log<W>(mut rx: broadcast::Receiver<RoomEvent>, mut sink: W) -> JoinHandle<()>
+where
+    W: Write + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        loop {
+            let event = match rx.blocking_recv() {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event log lagging, skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            let record = EventRecord { ts, event: &event };
+
+            let written = serde_json::to_writer(&mut sink, &record)
+                .map_err(std::io::Error::from)
+                .and_then(|_| sink.write_all(b"\n"))
+                .and_then(|_| sink.flush());
+            if let Err(e) = written {
+                warn!("Event log write failed, stopping: {}", e);
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::signaling::{
+        CreateOptions, JoinOptions, SignalingAddr, SignalingServer, outbound_channel,
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn event_serializes_with_tag() {
+        let event = RoomEvent::PeerJoined {
+            code: RoomCode::from("abc12345"),
+            peer_id: PeerId::from("peer_12345678"),
+        };
+        let record = EventRecord {
+            ts: 7,
+            event: &event,
+        };
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            json,
+            r#"{"ts":7,"event":"peer_joined","code":"abc12345","peer_id":"peer_12345678"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn captures_room_lifecycle_to_sink() {
+        let server = SignalingServer::new();
+        let handle = server.handle();
+        let sink = SharedBuf::default();
+        let log_task = spawn_event_log(server.subscribe_events(), sink.clone());
+
+        let addr = SignalingAddr::from("127.0.0.1:5000".parse::<std::net::SocketAddr>().unwrap());
+        let (tx1, _rx1) = outbound_channel();
+        let (tx2, _rx2) = outbound_channel();
+        let (code, creator, _) = handle
+            .create_room(addr, tx1, CreateOptions::default())
+            .await
+            .unwrap();
+        let (joiner, _, _) = handle
+            .join_room(code, addr, tx2, JoinOptions::default())
+            .await
+            .unwrap();
+        let _ = handle
+            .join_room(
+                RoomCode::from("missing1"),
+                addr,
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await;
+        handle.leave_room(&joiner).await;
+        handle.leave_room(&creator).await;
+
+        drop(handle);
+        drop(server);
+        log_task.await.unwrap();
+
+        let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "room_created",
+                "peer_joined",
+                "error",
+                "peer_left",
+                "peer_left",
+                "room_removed"
+            ]
+        );
+        assert!(events.iter().all(|e| e["ts"].is_u64()));
+        assert_eq!(events[1]["peer_id"], joiner.as_str());
+        assert_eq!(events[5]["code"], code.as_str());
+    }
+}
@@ -0,0 +1,149 @@
+//! Plain HTTP liveness probe on the signaling port, so orchestrators can
+//! check the server without speaking WebSocket
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Path a plain `GET` is answered `200 OK` on
+const HEALTH_PATH: &str = "/healthz";
+
+/// Most of a request head read while deciding what a connection is; the
+/// WebSocket handshake applies its own limits to whatever follows
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+const HEALTHY_RESPONSE: &[u8] =
+    b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\nok\n";
+
+/// What a new connection turned out to be
+pub(crate) enum Opening<S> {
+    /// A health probe, already answered
+    Probe,
+    /// Anything else, with the bytes read so far put back in front
+    Upgrade(Replay<S>),
+}
+
+/// Read a connection's request head and answer it if it's a health probe
+pub(crate) async fn sniff<S>(mut stream: S) -> io::Result<Opening<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD_SIZE {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+
+    if is_probe(&head) {
+        stream.write_all(HEALTHY_RESPONSE).await?;
+        stream.shutdown().await?;
+        return Ok(Opening::Probe);
+    }
+    Ok(Opening::Upgrade(Replay {
+        prefix: head,
+        pos: 0,
+        inner: stream,
+    }))
+}
+
+/// A complete `GET /healthz` that doesn't ask for an upgrade
+fn is_probe(head: &[u8]) -> bool {
+    let Ok(head) = std::str::from_utf8(head) else {
+        return false;
+    };
+    let Some((head, _)) = head.split_once("\r\n\r\n") else {
+        return false;
+    };
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let is_get = parts.next() == Some("GET") && parts.next() == Some(HEALTH_PATH);
+    is_get
+        && !lines.any(|line| {
+            line.split_once(':')
+                .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("upgrade"))
+        })
+}
+
+/// A stream that yields `prefix` before reading on from `inner`
+pub(crate) struct Replay<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_plain_gets_of_the_health_path_are_probes() {
+        assert!(is_probe(b"GET /healthz HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(is_probe(b"GET /healthz HTTP/1.0\r\n\r\n"));
+        assert!(!is_probe(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(!is_probe(b"POST /healthz HTTP/1.1\r\n\r\n"));
+        assert!(!is_probe(
+            b"GET /healthz HTTP/1.1\r\nupgrade: websocket\r\nConnection: Upgrade\r\n\r\n"
+        ));
+        // Not a whole head yet
+        assert!(!is_probe(b"GET /healthz HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn other_requests_are_replayed_in_full() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nafter the head")
+            .await
+            .unwrap();
+        drop(client);
+
+        let Opening::Upgrade(mut replay) = sniff(server).await.unwrap() else {
+            panic!("not a probe");
+        };
+        let mut read = String::new();
+        replay.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, "GET / HTTP/1.1\r\n\r\nafter the head");
+    }
+}
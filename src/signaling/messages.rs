@@ -1,44 +1,334 @@
+use std::net::SocketAddr;
+
 use serde::{Deserialize, Serialize};
 
-use super::types::{PeerId, PeerInfo, RoomCode};
+use super::codec::Encoding;
+use super::types::{
+    PeerId, PeerInfo, ReflexiveAddr, RoomCode, RoomSummary, SessionToken, SignalingAddr,
+};
 
 /// Messages sent from client to server
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
+    /// Announce the client version and negotiate the encoding of binary
+    /// frames for this connection
+    #[serde(rename = "hello")]
+    Hello {
+        #[serde(default)]
+        encoding: Encoding,
+        /// Client version (semver), checked against the server's minimum
+        #[serde(default)]
+        version: Option<String>,
+    },
+
+    /// Present an access token; required before creating or joining a room
+    /// when the server has any configured
+    #[serde(rename = "authenticate")]
+    Authenticate { token: String },
+
     /// Create a new room (becomes the first peer)
     #[serde(rename = "create_room")]
-    CreateRoom,
+    CreateRoom {
+        /// Secret joiners must present (`None` = open room)
+        #[serde(default)]
+        password: Option<String>,
+        /// Code to create the room under, e.g. one the host picked to be
+        /// memorable (`None` = generate one)
+        #[serde(default)]
+        code: Option<String>,
+        /// Shown to the room in this peer's `PeerInfo`
+        #[serde(default)]
+        metadata: Option<serde_json::Value>,
+        /// Secret the client picks to keep its peer id if it reconnects and
+        /// joins the room again
+        #[serde(default)]
+        session_key: Option<String>,
+    },
 
     /// Join an existing room by code
     #[serde(rename = "join_room")]
-    JoinRoom { code: String },
+    JoinRoom {
+        code: String,
+        /// Required if the room was created with a password
+        #[serde(default)]
+        password: Option<String>,
+        /// Shown to the room in this peer's `PeerInfo`
+        #[serde(default)]
+        metadata: Option<serde_json::Value>,
+        /// Joining again with the key a peer used before brings back its
+        /// peer id (`None` = a fresh id every time)
+        #[serde(default)]
+        session_key: Option<String>,
+    },
+
+    /// Join whichever quick-match room has a seat, or open a new one; answered
+    /// with `RoomJoined` or `RoomCreated` like a join or create
+    #[serde(rename = "quick_match")]
+    QuickMatch {
+        /// Shown to the room in this peer's `PeerInfo`
+        #[serde(default)]
+        metadata: Option<serde_json::Value>,
+    },
+
+    /// Join a room again under the id this peer left it with, if it left
+    /// recently enough; otherwise join it as a new peer
+    #[serde(rename = "rejoin")]
+    Rejoin {
+        code: String,
+        peer_id: PeerId,
+        token: SessionToken,
+    },
 
     /// Leave the current room
     #[serde(rename = "leave_room")]
     LeaveRoom,
+
+    /// Take over an existing session from another connection
+    #[serde(rename = "rebind")]
+    Rebind {
+        peer_id: PeerId,
+        token: SessionToken,
+    },
+
+    /// Lock or unlock the current room against new joins (owner only)
+    #[serde(rename = "set_locked")]
+    SetLocked { locked: bool },
+
+    /// Replace this peer's metadata (`null` clears it); the rest of the room
+    /// gets a `PeerUpdated`
+    #[serde(rename = "set_metadata")]
+    SetMetadata {
+        #[serde(default)]
+        metadata: Option<serde_json::Value>,
+    },
+
+    /// Remove another peer from the current room (owner only)
+    #[serde(rename = "kick")]
+    Kick { peer_id: PeerId },
+
+    /// Relay a payload to every other peer in the current room
+    #[serde(rename = "broadcast")]
+    Broadcast { payload: serde_json::Value },
+
+    /// Relay a payload to the listed peers in the current room
+    #[serde(rename = "multicast")]
+    Multicast {
+        to: Vec<PeerId>,
+        payload: serde_json::Value,
+    },
+
+    /// Relay a payload (an SDP offer or answer, an ICE candidate) to one
+    /// peer in the current room
+    #[serde(rename = "signal")]
+    Signal {
+        to: PeerId,
+        payload: serde_json::Value,
+    },
+
+    /// Send an SDP offer to one peer in the current room. Refused while an
+    /// offer between the two is still unanswered, so both sides can't offer
+    /// at once.
+    #[serde(rename = "offer")]
+    Offer { to: PeerId, sdp: String },
+
+    /// Answer the offer a peer in the current room sent this one
+    #[serde(rename = "answer")]
+    Answer { to: PeerId, sdp: String },
+
+    /// Relay an ICE candidate to one peer in the current room. Field names
+    /// follow the browser's `RTCIceCandidateInit`, so a candidate from
+    /// `onicecandidate` can be sent as is.
+    #[serde(rename = "ice_candidate")]
+    IceCandidate {
+        to: PeerId,
+        candidate: String,
+        #[serde(default, rename = "sdpMid")]
+        sdp_mid: Option<String>,
+        #[serde(default, rename = "sdpMLineIndex")]
+        sdp_mline_index: Option<u16>,
+    },
+
+    /// Add another code that reaches the current room (owner only)
+    #[serde(rename = "add_alias")]
+    AddAlias { alias: String },
+
+    /// Report the server-reflexive address the client learned over STUN;
+    /// peers in the room get a `PeerUpdated`, and later joiners see it in
+    /// the roster
+    #[serde(rename = "set_reflexive_addr")]
+    SetReflexiveAddr { addr: ReflexiveAddr },
+
+    /// Fetch the other peers currently in the room, e.g. after reconnecting
+    #[serde(rename = "get_peers")]
+    GetPeers,
+
+    /// Ask for a UDP relay to the other peer when a direct connection can't
+    /// be made; both peers must have reported their reflexive address
+    #[serde(rename = "request_relay")]
+    RequestRelay,
+
+    /// List every room and its size, for monitoring; needs the server's
+    /// admin token
+    #[serde(rename = "list_rooms")]
+    ListRooms {
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+}
+
+/// A relayed room-wide broadcast, as kept for replay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayedBroadcast {
+    pub from: PeerId,
+    pub payload: serde_json::Value,
 }
 
 /// Messages sent from server to client
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
+    /// Acknowledges `Hello`; every later push uses `encoding`
+    #[serde(rename = "welcome")]
+    Welcome { encoding: Encoding },
+
+    /// Acknowledges `Authenticate`
+    #[serde(rename = "authenticated")]
+    Authenticated,
+
     /// Room created successfully
     #[serde(rename = "room_created")]
-    RoomCreated { code: RoomCode, your_id: PeerId },
+    RoomCreated {
+        code: RoomCode,
+        your_id: PeerId,
+        session_token: SessionToken,
+    },
 
     /// Joined room successfully (includes existing peers with their addresses)
     #[serde(rename = "room_joined")]
     RoomJoined {
         code: RoomCode,
         your_id: PeerId,
+        session_token: SessionToken,
         peers: Vec<PeerInfo>,
     },
 
+    /// Session moved onto this connection (includes the current roster)
+    #[serde(rename = "session_resumed")]
+    SessionResumed {
+        code: RoomCode,
+        your_id: PeerId,
+        peers: Vec<PeerInfo>,
+    },
+
+    /// This connection's session was taken over by another connection
+    #[serde(rename = "session_moved")]
+    SessionMoved,
+
     /// A new peer joined the room (use their address for P2P connection)
     #[serde(rename = "peer_joined")]
     PeerJoined { peer: PeerInfo },
 
+    /// A peer's info changed, e.g. it reported its reflexive address
+    #[serde(rename = "peer_updated")]
+    PeerUpdated { peer: PeerInfo },
+
+    /// A peer left the room
+    #[serde(rename = "peer_left")]
+    PeerLeft { peer_id: PeerId },
+
+    /// A payload relayed by another peer in the room
+    #[serde(rename = "broadcast")]
+    Broadcast {
+        from: PeerId,
+        payload: serde_json::Value,
+    },
+
+    /// A payload another peer in the room addressed to this one
+    #[serde(rename = "signal")]
+    Signal {
+        from: PeerId,
+        payload: serde_json::Value,
+    },
+
+    /// An SDP offer from another peer in the room; reply with `Answer`
+    #[serde(rename = "offer")]
+    Offer { from: PeerId, sdp: String },
+
+    /// The answer to an offer this peer sent
+    #[serde(rename = "answer")]
+    Answer { from: PeerId, sdp: String },
+
+    /// An ICE candidate another peer in the room addressed to this one; the
+    /// fields other than `from` can be passed to `addIceCandidate`
+    #[serde(rename = "ice_candidate")]
+    IceCandidate {
+        from: PeerId,
+        candidate: String,
+        #[serde(rename = "sdpMid")]
+        sdp_mid: Option<String>,
+        #[serde(rename = "sdpMLineIndex")]
+        sdp_mline_index: Option<u16>,
+    },
+
+    /// The room's most recent broadcasts, oldest first, sent to a new peer
+    /// right after `RoomJoined`
+    #[serde(rename = "broadcast_replay")]
+    BroadcastReplay { messages: Vec<RelayedBroadcast> },
+
+    /// Outcome of a `Multicast`: who it reached, and who wasn't in the room
+    #[serde(rename = "multicast_result")]
+    MulticastResult {
+        delivered: Vec<PeerId>,
+        missing: Vec<PeerId>,
+    },
+
+    /// Authoritative room roster, pushed to every peer when the room is resynced
+    #[serde(rename = "roster_sync")]
+    RosterSync { peers: Vec<PeerInfo> },
+
+    /// The room was locked or unlocked by its owner
+    #[serde(rename = "room_lock_changed")]
+    RoomLockChanged { locked: bool },
+
+    /// The room's owner removed this peer; the rest of the room gets a
+    /// `PeerLeft`
+    #[serde(rename = "kicked")]
+    Kicked,
+
+    /// An alias was registered for the room
+    #[serde(rename = "alias_added")]
+    AliasAdded { code: RoomCode, alias: RoomCode },
+
+    /// The room is moving to another signaling server; reconnect to `url`
+    #[serde(rename = "room_migrating")]
+    RoomMigrating { url: String },
+
+    /// The server is going away; the connection closes right after
+    #[serde(rename = "server_shutdown")]
+    ServerShutdown,
+
+    /// The reported reflexive address doesn't match the connection's source
+    /// IP: a symmetric NAT, a proxy in the path, or a spoofed claim
+    #[serde(rename = "address_mismatch")]
+    AddressMismatch {
+        claimed: ReflexiveAddr,
+        observed: SignalingAddr,
+    },
+
+    /// Answer to `GetPeers`: everyone in the room but the requester
+    #[serde(rename = "peer_list")]
+    PeerList { peers: Vec<PeerInfo> },
+
+    /// Answer to `ListRooms`, ordered by code
+    #[serde(rename = "room_list")]
+    RoomList { rooms: Vec<RoomSummary> },
+
+    /// A relay was set up for the room's two peers: send to `addr` to reach
+    /// the other one. Both peers get this.
+    #[serde(rename = "relay_allocated")]
+    RelayAllocated { addr: SocketAddr },
+
     /// Error response
     #[serde(rename = "error")]
     Error { message: String },
@@ -52,15 +342,67 @@ mod tests {
     fn parse_create_room() {
         let json = r#"{"type": "create_room"}"#;
         let msg: ClientMessage = serde_json::from_str(json).unwrap();
-        matches!(msg, ClientMessage::CreateRoom);
+        matches!(
+            msg,
+            ClientMessage::CreateRoom {
+                password: None,
+                code: None,
+                metadata: None,
+                session_key: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_create_room_with_code() {
+        let json = r#"{"type": "create_room", "code": "gamenite"}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(
+            matches!(msg, ClientMessage::CreateRoom { code: Some(ref c), .. } if c == "gamenite")
+        );
+    }
+
+    #[test]
+    fn parse_room_passwords() {
+        let json = r#"{"type": "create_room", "password": "hunter2"}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(
+            matches!(msg, ClientMessage::CreateRoom { password: Some(ref p), .. } if p == "hunter2")
+        );
+
+        let json = r#"{"type": "join_room", "code": "abc12345", "password": "hunter2"}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(
+            matches!(msg, ClientMessage::JoinRoom { password: Some(ref p), .. } if p == "hunter2")
+        );
+    }
+
+    #[test]
+    fn parse_session_keys() {
+        let json = r#"{"type": "create_room", "session_key": "k-1"}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(
+            matches!(msg, ClientMessage::CreateRoom { session_key: Some(ref k), .. } if k == "k-1")
+        );
+
+        let json = r#"{"type": "join_room", "code": "abc12345"}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::JoinRoom {
+                session_key: None,
+                ..
+            }
+        ));
     }
 
     #[test]
     fn parse_join_room() {
         let json = r#"{"type": "join_room", "code": "abc12345"}"#;
         let msg: ClientMessage = serde_json::from_str(json).unwrap();
-        if let ClientMessage::JoinRoom { code } = msg {
+        if let ClientMessage::JoinRoom { code, password, .. } = msg {
             assert_eq!(code, "abc12345");
+            assert_eq!(password, None);
         } else {
             panic!("Expected JoinRoom");
         }
@@ -73,11 +415,93 @@ mod tests {
         matches!(msg, ClientMessage::LeaveRoom);
     }
 
+    #[test]
+    fn parse_set_locked() {
+        let json = r#"{"type": "set_locked", "locked": true}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(msg, ClientMessage::SetLocked { locked: true }));
+    }
+
+    #[test]
+    fn parse_signal() {
+        let json = r#"{"type": "signal", "to": "peer_abc12345", "payload": {"sdp": "v=0"}}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        let ClientMessage::Signal { to, payload } = msg else {
+            panic!("Expected Signal");
+        };
+        assert_eq!(to, PeerId::from("peer_abc12345"));
+        assert_eq!(payload["sdp"], "v=0");
+    }
+
+    #[test]
+    fn ice_candidate_uses_browser_field_names() {
+        let json = r#"{"type": "ice_candidate", "to": "peer_abc12345",
+            "candidate": "candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host",
+            "sdpMid": "0", "sdpMLineIndex": 0}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        let ClientMessage::IceCandidate {
+            to,
+            candidate,
+            sdp_mid,
+            sdp_mline_index,
+        } = msg
+        else {
+            panic!("Expected IceCandidate");
+        };
+        assert_eq!(to, PeerId::from("peer_abc12345"));
+        assert!(candidate.starts_with("candidate:1 "));
+        assert_eq!(sdp_mid.as_deref(), Some("0"));
+        assert_eq!(sdp_mline_index, Some(0));
+
+        // The end-of-candidates marker carries neither
+        let json = r#"{"type": "ice_candidate", "to": "peer_abc12345", "candidate": ""}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::IceCandidate {
+                sdp_mid: None,
+                sdp_mline_index: None,
+                ..
+            }
+        ));
+
+        let msg = ServerMessage::IceCandidate {
+            from: PeerId::from("peer_abc12345"),
+            candidate: "candidate:1".to_string(),
+            sdp_mid: Some("audio".to_string()),
+            sdp_mline_index: Some(1),
+        };
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap(),
+            serde_json::json!({
+                "type": "ice_candidate",
+                "from": "peer_abc12345",
+                "candidate": "candidate:1",
+                "sdpMid": "audio",
+                "sdpMLineIndex": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_hello() {
+        let json = r#"{"type": "hello", "encoding": "msgpack"}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::Hello {
+                encoding: Encoding::MessagePack,
+                version: None,
+            }
+        ));
+    }
+
     #[test]
     fn serialize_room_created() {
         let msg = ServerMessage::RoomCreated {
             code: RoomCode::from("test1234"),
             your_id: PeerId::from("peer_abc12345"),
+            session_token: SessionToken::generate(),
         };
         let json = serde_json::to_string(&msg).unwrap();
         assert!(json.contains("room_created"));
@@ -90,9 +514,12 @@ mod tests {
         let msg = ServerMessage::RoomJoined {
             code: RoomCode::from("test1234"),
             your_id: PeerId::from("peer_new12345"),
+            session_token: SessionToken::generate(),
             peers: vec![PeerInfo {
                 id: PeerId::from("peer_existing"),
-                public_addr: None,
+                signaling_addr: None,
+                reflexive_addr: None,
+                metadata: None,
             }],
         };
         let json = serde_json::to_string(&msg).unwrap();
@@ -105,7 +532,11 @@ mod tests {
         let msg = ServerMessage::PeerJoined {
             peer: PeerInfo {
                 id: PeerId::from("peer_new12345"),
-                public_addr: Some("192.168.1.1:5000".parse().unwrap()),
+                signaling_addr: Some(SignalingAddr::from(
+                    "192.168.1.1:5000".parse::<std::net::SocketAddr>().unwrap(),
+                )),
+                reflexive_addr: None,
+                metadata: None,
             },
         };
         let json = serde_json::to_string(&msg).unwrap();
@@ -114,6 +545,21 @@ mod tests {
         assert!(json.contains("192.168.1.1:5000"));
     }
 
+    #[test]
+    fn serialize_roster_sync() {
+        let msg = ServerMessage::RosterSync {
+            peers: vec![PeerInfo {
+                id: PeerId::from("peer_abc12345"),
+                signaling_addr: None,
+                reflexive_addr: None,
+                metadata: None,
+            }],
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("roster_sync"));
+        assert!(json.contains("peer_abc12345"));
+    }
+
     #[test]
     fn serialize_error() {
         let msg = ServerMessage::Error {
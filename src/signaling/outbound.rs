@@ -0,0 +1,279 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{Notify, mpsc};
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+
+/// Messages each tier of a connection's outbound queue holds by default
+pub const DEFAULT_OUTBOUND_CAPACITY: usize = 1024;
+
+/// Delivery tier of an outbound message
+///
+/// Critical messages (direct replies, targeted relays) are drained before bulk
+/// ones (room-wide broadcasts), so a burst of broadcasts can't starve them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Critical,
+    Bulk,
+}
+
+/// Wrapper for outbound WebSocket messages using tungstenite's Utf8Bytes.
+#[derive(Debug, Clone)]
+pub struct OutboundMessage {
+    payload: Utf8Bytes,
+    priority: Priority,
+}
+
+impl OutboundMessage {
+    /// Create a new critical outbound message from any string type
+    pub fn new(s: impl Into<Utf8Bytes>) -> Self {
+        Self {
+            payload: s.into(),
+            priority: Priority::Critical,
+        }
+    }
+
+    /// Move the message into a different delivery tier
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Size of the serialized message in bytes
+    pub fn payload_len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Get the inner Utf8Bytes for tungstenite Message::Text
+    pub fn into_inner(self) -> Utf8Bytes {
+        self.payload
+    }
+}
+
+impl From<String> for OutboundMessage {
+    fn from(s: String) -> Self {
+        Self::new(s)
+    }
+}
+
+/// Create a two-tier outbound queue for a single connection, holding
+/// `DEFAULT_OUTBOUND_CAPACITY` messages per tier
+pub fn outbound_channel() -> (OutboundSender, OutboundReceiver) {
+    outbound_channel_with_capacity(DEFAULT_OUTBOUND_CAPACITY)
+}
+
+/// Create a two-tier outbound queue holding up to `capacity` messages per
+/// tier (at least one)
+pub fn outbound_channel_with_capacity(capacity: usize) -> (OutboundSender, OutboundReceiver) {
+    let (critical_tx, critical_rx) = mpsc::channel(capacity.max(1));
+    let (bulk_tx, bulk_rx) = mpsc::channel(capacity.max(1));
+
+    (
+        OutboundSender {
+            critical: critical_tx,
+            bulk: bulk_tx,
+            overflow: Arc::new(Notify::new()),
+            superseded: Arc::new(Notify::new()),
+        },
+        OutboundReceiver {
+            critical: critical_rx,
+            bulk: bulk_rx,
+        },
+    )
+}
+
+/// Sending half of a connection's outbound queue
+#[derive(Debug, Clone)]
+pub struct OutboundSender {
+    critical: mpsc::Sender<OutboundMessage>,
+    bulk: mpsc::Sender<OutboundMessage>,
+    /// Signalled when a send finds its tier full
+    overflow: Arc<Notify>,
+    /// Signalled when the session moves to another connection
+    superseded: Arc<Notify>,
+}
+
+impl OutboundSender {
+    /// Enqueue a message on the tier it carries, without waiting
+    ///
+    /// A full tier means the client isn't reading fast enough: the message is
+    /// dropped and `overflowed` fires, so the connection can cut it off
+    /// rather than buffer without limit.
+    pub fn send(&self, msg: OutboundMessage) -> Result<(), TrySendError<OutboundMessage>> {
+        let result = match msg.priority {
+            Priority::Critical => self.critical.try_send(msg),
+            Priority::Bulk => self.bulk.try_send(msg),
+        };
+        if let Err(TrySendError::Full(_)) = result {
+            self.overflow.notify_one();
+        }
+        result
+    }
+
+    /// Resolves once a send has found the queue full, including one that
+    /// happened before the call
+    pub async fn overflowed(&self) {
+        self.overflow.notified().await
+    }
+
+    /// Mark the connection as replaced by another, so it closes
+    pub fn supersede(&self) {
+        self.superseded.notify_one();
+    }
+
+    /// Resolves once `supersede` has been called, including before this call
+    pub async fn superseded(&self) {
+        self.superseded.notified().await
+    }
+
+    /// Whether the receiving half is gone, so nothing sent would be delivered
+    pub fn is_closed(&self) -> bool {
+        self.critical.is_closed()
+    }
+
+    /// Whether both senders feed the same connection
+    pub fn same_channel(&self, other: &OutboundSender) -> bool {
+        self.critical.same_channel(&other.critical)
+    }
+}
+
+/// Receiving half of a connection's outbound queue, drained by the send task
+#[derive(Debug)]
+pub struct OutboundReceiver {
+    critical: mpsc::Receiver<OutboundMessage>,
+    bulk: mpsc::Receiver<OutboundMessage>,
+}
+
+impl OutboundReceiver {
+    /// Receive the next message, preferring the critical tier
+    ///
+    /// Messages within a tier keep FIFO order. Returns `None` once every
+    /// sender is dropped and both tiers are empty.
+    pub async fn recv(&mut self) -> Option<OutboundMessage> {
+        tokio::select! {
+            biased;
+            Some(msg) = self.critical.recv() => Some(msg),
+            Some(msg) = self.bulk.recv() => Some(msg),
+            else => None,
+        }
+    }
+
+    /// Take the next queued message without waiting, preferring the critical
+    /// tier
+    pub fn try_recv(&mut self) -> Option<OutboundMessage> {
+        self.critical
+            .try_recv()
+            .or_else(|_| self.bulk.try_recv())
+            .ok()
+    }
+}
+
+/// Stamps a connection-local, monotonically increasing `seq` on every push
+///
+/// Payloads are serialized once and shared across a room, so the sequence
+/// number is spliced into the JSON object on the send path rather than being
+/// part of `ServerMessage`. Clients seeing a gap know they missed a push and
+/// can ask for a resync.
+#[derive(Debug)]
+pub struct PushSequencer {
+    next: Option<u64>,
+}
+
+impl PushSequencer {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            next: enabled.then_some(0),
+        }
+    }
+
+    /// Produce the wire payload, with `seq` as the first field when enabled
+    pub fn stamp(&mut self, msg: OutboundMessage) -> Utf8Bytes {
+        let Some(seq) = self.next else {
+            return msg.into_inner();
+        };
+        self.next = Some(seq + 1);
+
+        let payload = msg.into_inner();
+        match payload.as_str().strip_prefix('{') {
+            Some("}") => format!("{{\"seq\":{}}}", seq).into(),
+            Some(rest) => format!("{{\"seq\":{},{}", seq, rest).into(),
+            None => payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn critical_message_jumps_ahead_of_bulk() {
+        let (tx, mut rx) = outbound_channel();
+
+        for i in 0..100 {
+            let msg = OutboundMessage::from(format!("bulk {}", i)).with_priority(Priority::Bulk);
+            tx.send(msg).unwrap();
+        }
+        tx.send(OutboundMessage::from("critical".to_string()))
+            .unwrap();
+
+        let first = rx.recv().await.unwrap();
+        assert_eq!(first.priority(), Priority::Critical);
+        assert_eq!(first.into_inner().as_str(), "critical");
+    }
+
+    #[tokio::test]
+    async fn bulk_messages_keep_fifo_order() {
+        let (tx, mut rx) = outbound_channel();
+
+        for i in 0..3 {
+            let msg = OutboundMessage::from(format!("bulk {}", i)).with_priority(Priority::Bulk);
+            tx.send(msg).unwrap();
+        }
+        drop(tx);
+
+        for i in 0..3 {
+            let msg = rx.recv().await.unwrap();
+            assert_eq!(msg.into_inner().as_str(), format!("bulk {}", i));
+        }
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn full_queue_refuses_and_signals_overflow() {
+        let (tx, _rx) = outbound_channel_with_capacity(1);
+
+        tx.send(OutboundMessage::from("first".to_string())).unwrap();
+        let second = tx.send(OutboundMessage::from("second".to_string()));
+        assert!(matches!(second, Err(TrySendError::Full(_))));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), tx.overflowed())
+            .await
+            .expect("overflow should be signaled");
+    }
+
+    #[test]
+    fn sequence_numbers_increment_across_pushes() {
+        let mut sequencer = PushSequencer::new(true);
+
+        for expected in 0..3u64 {
+            let msg = OutboundMessage::from(r#"{"type":"peer_joined","peer":{}}"#.to_string());
+            let stamped = sequencer.stamp(msg);
+            let value: serde_json::Value = serde_json::from_str(stamped.as_str()).unwrap();
+            assert_eq!(value["seq"], expected);
+            assert_eq!(value["type"], "peer_joined");
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_absent_when_disabled() {
+        let mut sequencer = PushSequencer::new(false);
+        let msg = OutboundMessage::from(r#"{"type":"error","message":"x"}"#.to_string());
+        let stamped = sequencer.stamp(msg);
+        assert_eq!(stamped.as_str(), r#"{"type":"error","message":"x"}"#);
+    }
+}
@@ -0,0 +1,171 @@
+//! UDP relay for peer pairs whose NATs won't let a hole punch through: each
+//! peer sends to the relay and the relay forwards to the other peer
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use super::types::PeerId;
+
+/// Largest datagram forwarded; anything bigger is truncated by the socket
+const MAX_DATAGRAM: usize = 65_535;
+
+/// A relay socket serving the two peers of a room. Dropping it closes the
+/// socket.
+#[derive(Debug)]
+pub(crate) struct Relay {
+    addr: SocketAddr,
+    peers: [PeerId; 2],
+    task: JoinHandle<()>,
+}
+
+impl Relay {
+    /// Bind a socket on `ip` and start forwarding between two peers, given
+    /// each one's reflexive address
+    ///
+    /// Nothing is sent to a peer until a datagram has come from it: the
+    /// addresses are only what the peers claimed, and the relay must not be
+    /// aimed at a host that never asked for its traffic.
+    pub fn bind(ip: IpAddr, a: (PeerId, SocketAddr), b: (PeerId, SocketAddr)) -> io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(ip, 0))?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        let addr = socket.local_addr()?;
+        let task = tokio::spawn(forward(socket, [a.1, b.1]));
+        Ok(Self {
+            addr,
+            peers: [a.0, b.0],
+            task,
+        })
+    }
+
+    /// Address the peers send to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn serves(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains(peer_id)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Which side a datagram came from, if either
+///
+/// A peer's NAT may map its relay traffic to a different port than its STUN
+/// traffic, so a source that only matches a peer's IP is taken as that peer,
+/// unless both peers share the IP.
+fn side_of(from: SocketAddr, known: &[SocketAddr; 2]) -> Option<usize> {
+    if let Some(side) = known.iter().position(|addr| *addr == from) {
+        return Some(side);
+    }
+    let ip_of = |addr: &SocketAddr| canonical(addr.ip());
+    match known.map(|addr| ip_of(&addr) == canonical(from.ip())) {
+        [true, false] => Some(0),
+        [false, true] => Some(1),
+        _ => None,
+    }
+}
+
+/// An IPv4-mapped IPv6 address as plain IPv4, so dual-stack sockets compare
+/// equal to reported addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+async fn forward(socket: UdpSocket, mut known: [SocketAddr; 2]) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    // Whether each side has sent from its address, confirming it
+    let mut confirmed = [false; 2];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // ICMP errors from an earlier send surface here on some
+                // platforms; they don't stop the relay
+                debug!("Relay receive error: {}", e);
+                continue;
+            }
+        };
+        let Some(side) = side_of(from, &known) else {
+            continue;
+        };
+        // Follow the peer to wherever its NAT maps it now
+        known[side] = from;
+        confirmed[side] = true;
+        if confirmed[1 - side] {
+            let _ = socket.send_to(&buf[..len], known[1 - side]).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn sources_match_by_address_then_by_ip() {
+        let a: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let b: SocketAddr = "198.51.100.7:5000".parse().unwrap();
+        assert_eq!(side_of(a, &[a, b]), Some(0));
+        assert_eq!(
+            side_of("198.51.100.7:6000".parse().unwrap(), &[a, b]),
+            Some(1)
+        );
+        assert_eq!(
+            side_of("[::ffff:192.0.2.1]:4001".parse().unwrap(), &[a, b]),
+            Some(0)
+        );
+        assert_eq!(side_of("203.0.113.9:4000".parse().unwrap(), &[a, b]), None);
+
+        // Behind the same NAT only exact matches count
+        let c = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 4100);
+        assert_eq!(side_of(c, &[a, c]), Some(1));
+        assert_eq!(side_of("192.0.2.1:4200".parse().unwrap(), &[a, c]), None);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_sent_to_a_side_before_it_has_sent() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay = Relay::bind(
+            "127.0.0.1".parse().unwrap(),
+            (PeerId::generate(), a.local_addr().unwrap()),
+            (PeerId::generate(), b.local_addr().unwrap()),
+        )
+        .unwrap();
+        let mut buf = [0u8; 16];
+
+        // `b` is only a claim so far
+        a.send_to(b"early", relay.addr()).await.unwrap();
+        let leaked = tokio::time::timeout(Duration::from_millis(50), b.recv_from(&mut buf)).await;
+        assert!(leaked.is_err(), "relay sent to an unconfirmed address");
+
+        // Once `b` has sent, both ends are confirmed
+        b.send_to(b"hello", relay.addr()).await.unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), a.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        a.send_to(b"again", relay.addr()).await.unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), b.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"again");
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::rate_limit::TokenBucket;
+
+use super::messages::RelayedBroadcast;
+use super::outbound::{OutboundMessage, OutboundSender};
+use super::relay::Relay;
+use super::types::{PeerId, PeerInfo, ReflexiveAddr, RoomCode, RoomPassword, SessionToken};
+
+#[derive(Debug)]
+pub(crate) struct PeerState {
+    pub info: PeerInfo,
+    /// Channel for outbound messages to this peer.
+    /// Uses OutboundMessage (Arc<str>) for O(1) broadcast cloning.
+    pub tx: OutboundSender,
+    /// Presented by a new connection to take over this peer's session
+    pub token: SessionToken,
+    /// Budget for messages this peer relays to the room (`None` = unlimited)
+    pub relay_limiter: Option<TokenBucket>,
+}
+
+/// Snapshot of a room's recipients, shared between broadcasts until membership changes
+type Targets = Arc<[(PeerId, OutboundSender)]>;
+
+/// A broadcast handed to a room's fan-out task
+struct FanOut {
+    msg: OutboundMessage,
+    targets: Targets,
+    /// The sender of a relayed message, which doesn't get its own copy
+    skip: Option<PeerId>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Room {
+    peers: HashMap<PeerId, PeerState>,
+    /// Peer with moderation privileges: the creator, or a remaining peer once
+    /// the owner leaves
+    pub owner: PeerId,
+    /// Locked rooms reject new joins
+    pub locked: bool,
+    /// Secret joiners must present, set by the creator
+    pub password: Option<RoomPassword>,
+    /// Extra codes that also reach this room
+    pub aliases: Vec<RoomCode>,
+    /// Opened by `QuickMatch`, so matchmaking may put peers in it
+    pub quick_match: bool,
+    /// Room size at which broadcasts move off the actor onto a fan-out task
+    offload_threshold: usize,
+    /// Cached recipient snapshot, rebuilt lazily after joins and leaves
+    targets: Option<Targets>,
+    /// Most recent room-wide relays, oldest first, replayed to late joiners
+    recent: VecDeque<RelayedBroadcast>,
+    /// How many relays `recent` keeps (0 = none)
+    replay_len: usize,
+    /// Fan-out task queue. Once a room has one it keeps it, so broadcasts stay
+    /// in order even if the room shrinks back under the threshold.
+    fanout: Option<mpsc::UnboundedSender<FanOut>>,
+    /// Peers that left recently, with their session token and when they left
+    departed: HashMap<PeerId, (SessionToken, Instant)>,
+    /// Last join or relay, for closing rooms nobody is using
+    last_activity: Instant,
+    /// UDP relay between two of the peers, once one asked for it
+    pub relay: Option<Relay>,
+    /// SDP offers still awaiting an answer, as (offerer, answerer)
+    offers: HashSet<(PeerId, PeerId)>,
+    /// Client-chosen session keys and the peer id each was first given, so a
+    /// reconnecting client gets its id back
+    pub session_keys: HashMap<String, PeerId>,
+}
+
+impl Room {
+    pub fn new(owner: PeerId, owner_state: PeerState, offload_threshold: usize) -> Self {
+        Self {
+            peers: HashMap::from([(owner, owner_state)]),
+            owner,
+            locked: false,
+            password: None,
+            aliases: Vec::new(),
+            quick_match: false,
+            offload_threshold,
+            targets: None,
+            fanout: None,
+            recent: VecDeque::new(),
+            replay_len: 0,
+            departed: HashMap::new(),
+            last_activity: Instant::now(),
+            relay: None,
+            offers: HashSet::new(),
+            session_keys: HashMap::new(),
+        }
+    }
+
+    /// Keep the last `len` room-wide relays for replay to new peers
+    pub fn with_replay(mut self, len: usize) -> Self {
+        self.replay_len = len;
+        self
+    }
+
+    /// Require joiners to present this password
+    pub fn with_password(mut self, password: Option<RoomPassword>) -> Self {
+        self.password = password;
+        self
+    }
+
+    /// Remember a room-wide relay, evicting the oldest once full
+    pub fn record_broadcast(&mut self, from: PeerId, payload: &serde_json::Value) {
+        if self.replay_len == 0 {
+            return;
+        }
+        if self.recent.len() == self.replay_len {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(RelayedBroadcast {
+            from,
+            payload: payload.clone(),
+        });
+    }
+
+    /// Buffered relays, oldest first
+    pub fn recent_broadcasts(&self) -> impl ExactSizeIterator<Item = &RelayedBroadcast> {
+        self.recent.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerState> {
+        self.peers.values()
+    }
+
+    pub fn peer(&self, peer_id: &PeerId) -> Option<&PeerState> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn insert_peer(&mut self, peer_id: PeerId, state: PeerState) {
+        self.departed.remove(&peer_id);
+        self.peers.insert(peer_id, state);
+        self.targets = None;
+        self.last_activity = Instant::now();
+    }
+
+    /// Time since the last join or relay
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Remember a peer that left so it can rejoin within `grace`, forgetting
+    /// any whose window has already closed
+    pub fn record_departure(&mut self, peer_id: PeerId, token: SessionToken, grace: Duration) {
+        self.departed.retain(|_, (_, left)| left.elapsed() < grace);
+        if !grace.is_zero() {
+            self.departed.insert(peer_id, (token, Instant::now()));
+        }
+    }
+
+    /// Whether `peer_id` left within `grace` and `token` was its session token
+    pub fn can_rejoin(&self, peer_id: &PeerId, token: &SessionToken, grace: Duration) -> bool {
+        self.departed
+            .get(peer_id)
+            .is_some_and(|(known, left)| known.verify(token) && left.elapsed() < grace)
+    }
+
+    /// Remove a peer, handing ownership to a remaining peer if it was the owner
+    pub fn remove_peer(&mut self, peer_id: &PeerId) -> Option<PeerState> {
+        let removed = self.peers.remove(peer_id)?;
+        self.targets = None;
+        if self
+            .relay
+            .as_ref()
+            .is_some_and(|relay| relay.serves(peer_id))
+        {
+            self.relay = None;
+        }
+        self.offers
+            .retain(|(offerer, answerer)| offerer != peer_id && answerer != peer_id);
+
+        if self.owner == *peer_id
+            && let Some(&next) = self.peers.keys().next()
+        {
+            self.owner = next;
+        }
+        Some(removed)
+    }
+
+    /// Whether an offer between the two peers, in either direction, awaits
+    /// an answer
+    pub fn negotiating(&self, a: PeerId, b: PeerId) -> bool {
+        self.offers.contains(&(a, b)) || self.offers.contains(&(b, a))
+    }
+
+    pub fn offer_pending(&self, offerer: PeerId, answerer: PeerId) -> bool {
+        self.offers.contains(&(offerer, answerer))
+    }
+
+    pub fn record_offer(&mut self, offerer: PeerId, answerer: PeerId) {
+        self.offers.insert((offerer, answerer));
+    }
+
+    /// Close the negotiation an answer completes
+    pub fn settle_offer(&mut self, offerer: PeerId, answerer: PeerId) {
+        self.offers.remove(&(offerer, answerer));
+    }
+
+    /// Point a peer at a new connection, returning the old one's sender
+    pub fn rebind_peer(&mut self, peer_id: &PeerId, tx: OutboundSender) -> Option<OutboundSender> {
+        let peer = self.peers.get_mut(peer_id)?;
+        self.targets = None;
+        Some(std::mem::replace(&mut peer.tx, tx))
+    }
+
+    /// Record the UDP address a peer discovered for itself, returning its
+    /// updated info, or `None` if it isn't in the room
+    pub fn set_reflexive_addr(
+        &mut self,
+        peer_id: &PeerId,
+        addr: ReflexiveAddr,
+    ) -> Option<PeerInfo> {
+        let peer = self.peers.get_mut(peer_id)?;
+        peer.info.reflexive_addr = Some(addr);
+        Some(peer.info.clone())
+    }
+
+    /// Replace a peer's metadata, returning its updated info, or `None` if it
+    /// isn't in the room
+    pub fn set_metadata(
+        &mut self,
+        peer_id: &PeerId,
+        metadata: Option<serde_json::Value>,
+    ) -> Option<PeerInfo> {
+        let peer = self.peers.get_mut(peer_id)?;
+        peer.info.metadata = metadata;
+        Some(peer.info.clone())
+    }
+
+    /// Take a token from the peer's relay budget
+    ///
+    /// Returns `false` if the peer is over budget or not in the room.
+    pub fn charge_relay(&mut self, peer_id: &PeerId) -> bool {
+        self.last_activity = Instant::now();
+        match self.peers.get_mut(peer_id) {
+            Some(peer) => peer
+                .relay_limiter
+                .as_mut()
+                .is_none_or(|limiter| limiter.try_acquire()),
+            None => false,
+        }
+    }
+
+    /// Send a message to every peer in the room
+    ///
+    /// Small rooms are served inline. Large rooms hand the message and a shared
+    /// recipient snapshot to a per-room fan-out task, so the actor isn't
+    /// blocked pushing into hundreds of channels.
+    pub fn broadcast(&mut self, msg: &OutboundMessage) {
+        self.send_all(msg, None);
+    }
+
+    /// Send a message relayed by `sender` to every other peer in the room
+    pub fn broadcast_from(&mut self, sender: PeerId, msg: &OutboundMessage) {
+        self.send_all(msg, Some(sender));
+    }
+
+    /// Send a message to the listed peers only
+    ///
+    /// Returns the peers it was sent to and those not in the room, each
+    /// listed once. Goes through the fan-out task when the room has one, so
+    /// it can't overtake broadcasts still queued there.
+    pub fn multicast(
+        &mut self,
+        to: &[PeerId],
+        msg: &OutboundMessage,
+    ) -> (Vec<PeerId>, Vec<PeerId>) {
+        let mut delivered = Vec::new();
+        let mut missing = Vec::new();
+        let mut targets = Vec::new();
+        for id in to {
+            if delivered.contains(id) || missing.contains(id) {
+                continue;
+            }
+            match self.peers.get(id) {
+                Some(peer) => {
+                    delivered.push(*id);
+                    targets.push((*id, peer.tx.clone()));
+                }
+                None => missing.push(*id),
+            }
+        }
+
+        match &self.fanout {
+            Some(fanout) => {
+                let _ = fanout.send(FanOut {
+                    msg: msg.clone(),
+                    targets: targets.into(),
+                    skip: None,
+                });
+            }
+            None => {
+                for (_, tx) in &targets {
+                    let _ = tx.send(msg.clone());
+                }
+            }
+        }
+        (delivered, missing)
+    }
+
+    fn send_all(&mut self, msg: &OutboundMessage, skip: Option<PeerId>) {
+        if self.fanout.is_none() && self.peers.len() >= self.offload_threshold {
+            self.fanout = Some(spawn_fanout());
+        }
+
+        match &self.fanout {
+            Some(fanout) => {
+                let targets = self
+                    .targets
+                    .get_or_insert_with(|| {
+                        self.peers
+                            .iter()
+                            .map(|(id, peer)| (*id, peer.tx.clone()))
+                            .collect()
+                    })
+                    .clone();
+                let _ = fanout.send(FanOut {
+                    msg: msg.clone(),
+                    targets,
+                    skip,
+                });
+            }
+            None => {
+                for (id, peer) in &self.peers {
+                    if skip != Some(*id) {
+                        let _ = peer.tx.send(msg.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Spawn a room's fan-out task; it exits when the room is dropped
+fn spawn_fanout() -> mpsc::UnboundedSender<FanOut> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<FanOut>();
+    tokio::spawn(async move {
+        while let Some(FanOut { msg, targets, skip }) = rx.recv().await {
+            for (id, peer_tx) in targets.iter() {
+                if skip != Some(*id) {
+                    let _ = peer_tx.send(msg.clone());
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::outbound::{OutboundReceiver, outbound_channel};
+
+    fn peer() -> (PeerId, PeerState, OutboundReceiver) {
+        let id = PeerId::generate();
+        let (tx, rx) = outbound_channel();
+        let state = PeerState {
+            info: PeerInfo {
+                id,
+                signaling_addr: None,
+                reflexive_addr: None,
+                metadata: None,
+            },
+            tx,
+            token: SessionToken::generate(),
+            relay_limiter: None,
+        };
+        (id, state, rx)
+    }
+
+    async fn broadcast_reaches_everyone(offload_threshold: usize) {
+        let (owner, owner_state, owner_rx) = peer();
+        let mut room = Room::new(owner, owner_state, offload_threshold);
+        let mut receivers = vec![owner_rx];
+        for _ in 0..9 {
+            let (id, state, rx) = peer();
+            room.insert_peer(id, state);
+            receivers.push(rx);
+        }
+
+        for i in 0..3 {
+            room.broadcast(&OutboundMessage::from(format!("msg {}", i)));
+        }
+
+        for rx in &mut receivers {
+            for i in 0..3 {
+                let msg = rx.recv().await.unwrap();
+                assert_eq!(msg.into_inner().as_str(), format!("msg {}", i));
+            }
+        }
+    }
+
+    async fn relayed_broadcast_skips_sender(offload_threshold: usize) {
+        let (owner, owner_state, mut owner_rx) = peer();
+        let mut room = Room::new(owner, owner_state, offload_threshold);
+        let (other, other_state, mut other_rx) = peer();
+        room.insert_peer(other, other_state);
+
+        room.broadcast_from(owner, &OutboundMessage::from("relayed".to_string()));
+        room.broadcast(&OutboundMessage::from("everyone".to_string()));
+
+        assert_eq!(
+            other_rx.recv().await.unwrap().into_inner().as_str(),
+            "relayed"
+        );
+        assert_eq!(
+            owner_rx.recv().await.unwrap().into_inner().as_str(),
+            "everyone"
+        );
+    }
+
+    #[tokio::test]
+    async fn relayed_broadcast_skips_sender_inline_and_offloaded() {
+        relayed_broadcast_skips_sender(usize::MAX).await;
+        relayed_broadcast_skips_sender(1).await;
+    }
+
+    #[tokio::test]
+    async fn inline_broadcast_reaches_everyone() {
+        broadcast_reaches_everyone(usize::MAX).await;
+    }
+
+    #[tokio::test]
+    async fn offloaded_broadcast_reaches_everyone_in_order() {
+        broadcast_reaches_everyone(1).await;
+    }
+
+    #[tokio::test]
+    async fn offloaded_broadcast_tracks_membership() {
+        let (owner, owner_state, mut owner_rx) = peer();
+        let mut room = Room::new(owner, owner_state, 1);
+        let (left, left_state, mut left_rx) = peer();
+        room.insert_peer(left, left_state);
+
+        room.broadcast(&OutboundMessage::from("before".to_string()));
+        room.remove_peer(&left);
+        room.broadcast(&OutboundMessage::from("after".to_string()));
+
+        assert_eq!(
+            owner_rx.recv().await.unwrap().into_inner().as_str(),
+            "before"
+        );
+        assert_eq!(
+            owner_rx.recv().await.unwrap().into_inner().as_str(),
+            "after"
+        );
+        assert_eq!(
+            left_rx.recv().await.unwrap().into_inner().as_str(),
+            "before"
+        );
+        drop(room);
+        assert!(left_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn leaving_peer_drops_its_open_offers() {
+        let (owner, owner_state, _owner_rx) = peer();
+        let mut room = Room::new(owner, owner_state, usize::MAX);
+        let (guest, guest_state, _guest_rx) = peer();
+        room.insert_peer(guest, guest_state);
+
+        room.record_offer(owner, guest);
+        assert!(room.negotiating(guest, owner));
+        assert!(!room.offer_pending(guest, owner));
+
+        // Back under the same id, the guest starts from a clean slate
+        let (_, returning, _returning_rx) = peer();
+        room.remove_peer(&guest);
+        room.insert_peer(guest, returning);
+        assert!(!room.negotiating(owner, guest));
+    }
+}
@@ -1,22 +1,46 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::{Bytes, Message};
-use tracing::{debug, error, info, warn};
+use semver::Version;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Bytes, Error as WsError, Message};
+use tracing::{Instrument, Span, debug, error, info, warn};
 
-use super::actor::{RoomCommand, RoomManagerHandle, room_manager_actor};
+use crate::rate_limit::RateLimit;
+use crate::redact::{AddrRedaction, Redacted};
+
+use super::actor::{CreateOptions, JoinOptions, QuickMatched, RoomManagerHandle};
+use super::codec::{self, CodecError, Encoding};
+use super::config::SignalingConfig;
+use super::events::RoomEvent;
+use super::health::{self, Opening};
 use super::messages::{ClientMessage, ServerMessage};
-use super::types::{OutboundMessage, PeerId, RoomCode};
+use super::outbound::{
+    OutboundMessage, OutboundSender, PushSequencer, outbound_channel_with_capacity,
+};
+use super::transport::Transport;
+use super::types::{
+    PeerId, RoomCode, RoomCodeAlphabet, SignalingAddr, SignalingError, constant_time_eq,
+};
 
 pub const DEFAULT_SIGNALING_PORT: u16 = 3479;
-const PING_INTERVAL: Duration = Duration::from_secs(30);
-const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a closing connection waits for its last frames to go out
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct SignalingServer {
     handle: RoomManagerHandle,
+    config: Arc<SignalingConfig>,
 }
 
 impl Default for SignalingServer {
@@ -27,59 +51,523 @@ impl Default for SignalingServer {
 
 impl SignalingServer {
     pub fn new() -> Self {
-        let (tx, rx) = mpsc::channel::<RoomCommand>(1024);
-        tokio::spawn(room_manager_actor(rx));
+        Self::with_config(SignalingConfig::default())
+    }
 
+    pub fn with_config(config: SignalingConfig) -> Self {
         Self {
-            handle: RoomManagerHandle { tx },
+            handle: RoomManagerHandle::spawn(config.clone()),
+            config: Arc::new(config),
         }
     }
 
+    /// Start configuring a server; unset options keep their defaults
+    pub fn builder() -> SignalingServerBuilder {
+        SignalingServerBuilder::default()
+    }
+
+    /// The configuration the server was built with
+    pub fn config(&self) -> &SignalingConfig {
+        &self.config
+    }
+
+    /// Handle to the room manager, for driving rooms outside a WebSocket connection
+    pub fn handle(&self) -> RoomManagerHandle {
+        self.handle.clone()
+    }
+
+    /// Subscribe to room events (created, joined, left, ...)
+    pub fn subscribe_events(&self) -> broadcast::Receiver<RoomEvent> {
+        self.handle.subscribe_events()
+    }
+
     pub async fn run(&self, addr: &str) -> std::io::Result<()> {
+        self.run_until(addr, std::future::pending()).await
+    }
+
+    /// Like `run`, but shut down gracefully once `shutdown` completes
+    ///
+    /// The server stops accepting, every peer in a room is sent
+    /// `ServerShutdown`, every connection is closed with 1001 (going away),
+    /// and only once they have all finished does this return.
+    pub async fn run_until(
+        &self,
+        addr: &str,
+        shutdown: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
         let listener = TcpListener::bind(addr).await?;
         info!("Signaling server listening on {}", addr);
+        self.serve(listener, shutdown).await
+    }
+
+    async fn serve(
+        &self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
 
         loop {
-            let (stream, addr) = listener.accept().await?;
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                Some(_) = connections.join_next() => continue,
+                () = &mut shutdown => break,
+            };
             let handle = self.handle.clone();
+            let config = self.config.clone();
 
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, addr, handle).await {
-                    error!("Connection error from {}: {}", addr, e);
-                }
+            connections.spawn(async move {
+                let shown = config.addr_redaction.redact(addr);
+                let result = handle_connection(stream, addr, handle, config).await;
+                log_disconnect(&shown, result);
+            });
+        }
+
+        self.drain(connections).await;
+        Ok(())
+    }
+
+    /// Notify the rooms and wait for every connection to close
+    ///
+    /// Bounded without a timeout of its own: a connection closes within
+    /// `CLOSE_FLUSH_TIMEOUT` of seeing the shutdown, or of finishing a
+    /// handshake already under way.
+    async fn drain(&self, mut connections: JoinSet<()>) {
+        info!(
+            "Signaling server shutting down, {} connections open",
+            connections.len()
+        );
+        if let Err(e) = self.handle.shutdown().await {
+            warn!("Failed to notify rooms of shutdown: {}", e);
+        }
+        while connections.join_next().await.is_some() {}
+        info!("Signaling server stopped");
+    }
+
+    /// Like `run`, but every connection is TLS (wss://)
+    ///
+    /// The certificate and key are read once, up front; a bad file fails
+    /// here rather than on the first connection. The TLS handshake gets the
+    /// same `handshake_timeout` as the WebSocket upgrade that follows it.
+    #[cfg(feature = "tls")]
+    pub async fn run_tls(&self, addr: &str, tls: &super::tls::TlsConfig) -> std::io::Result<()> {
+        self.run_tls_until(addr, tls, std::future::pending()).await
+    }
+
+    /// Like `run_tls`, but shut down gracefully once `shutdown` completes,
+    /// as `run_until` does
+    #[cfg(feature = "tls")]
+    pub async fn run_tls_until(
+        &self,
+        addr: &str,
+        tls: &super::tls::TlsConfig,
+        shutdown: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        let acceptor = tls.acceptor()?;
+        let listener = TcpListener::bind(addr).await?;
+        info!("Signaling server listening on {} (TLS)", addr);
+        self.serve_tls(listener, acceptor, shutdown).await
+    }
+
+    #[cfg(feature = "tls")]
+    async fn serve_tls(
+        &self,
+        listener: TcpListener,
+        acceptor: tokio_rustls::TlsAcceptor,
+        shutdown: impl Future<Output = ()>,
+    ) -> std::io::Result<()> {
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                Some(_) = connections.join_next() => continue,
+                () = &mut shutdown => break,
+            };
+            let handle = self.handle.clone();
+            let config = self.config.clone();
+            let acceptor = acceptor.clone();
+
+            connections.spawn(async move {
+                let shown = config.addr_redaction.redact(addr);
+                let stream =
+                    match tokio::time::timeout(config.handshake_timeout, acceptor.accept(stream))
+                        .await
+                    {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            warn!("TLS handshake with {} failed: {}", shown, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("TLS handshake with {} timed out", shown);
+                            return;
+                        }
+                    };
+                let result = handle_connection(stream, addr, handle, config).await;
+                log_disconnect(&shown, result);
             });
         }
+
+        self.drain(connections).await;
+        Ok(())
+    }
+}
+
+/// Fluent construction of a [`SignalingServer`]
+///
+/// Each setter corresponds to a [`SignalingConfig`] field.
+#[derive(Debug, Clone, Default)]
+pub struct SignalingServerBuilder {
+    config: SignalingConfig,
+}
+
+impl SignalingServerBuilder {
+    pub fn room_creation_rate(mut self, limit: RateLimit) -> Self {
+        self.config.room_creation_rate = Some(limit);
+        self
+    }
+
+    pub fn relay_rate(mut self, limit: RateLimit) -> Self {
+        self.config.relay_rate = Some(limit);
+        self
+    }
+
+    pub fn broadcast_replay(mut self, len: usize) -> Self {
+        self.config.broadcast_replay = len;
+        self
+    }
+
+    pub fn sequence_numbers(mut self, enabled: bool) -> Self {
+        self.config.sequence_numbers = enabled;
+        self
+    }
+
+    pub fn fanout_offload_threshold(mut self, peers: usize) -> Self {
+        self.config.fanout_offload_threshold = peers;
+        self
+    }
+
+    pub fn max_in_flight_requests(mut self, max: usize) -> Self {
+        self.config.max_in_flight_requests = Some(max);
+        self
+    }
+
+    pub fn max_peers(mut self, max: usize) -> Self {
+        self.config.max_peers = Some(max);
+        self
+    }
+
+    pub fn max_peers_per_room(mut self, max: usize) -> Self {
+        self.config.max_peers_per_room = Some(max);
+        self
+    }
+
+    pub fn addr_redaction(mut self, redaction: AddrRedaction) -> Self {
+        self.config.addr_redaction = redaction;
+        self
+    }
+
+    pub fn min_client_version(mut self, version: Version) -> Self {
+        self.config.min_client_version = Some(version);
+        self
+    }
+
+    /// Require clients to present `token` (or another added this way) before
+    /// creating or joining rooms
+    pub fn access_token(mut self, token: impl Into<String>) -> Self {
+        self.config.access_tokens.push(token.into());
+        self
+    }
+
+    /// Offer `RequestRelay`, binding relay sockets on `ip`
+    pub fn relay_ip(mut self, ip: IpAddr) -> Self {
+        self.config.relay_ip = Some(ip);
+        self
+    }
+
+    /// Refuse reflexive addresses that aren't globally routable
+    pub fn require_global_peer_addrs(mut self) -> Self {
+        self.config.require_global_peer_addrs = true;
+        self
+    }
+
+    pub fn quick_match_size(mut self, peers: usize) -> Self {
+        self.config.quick_match_size = peers;
+        self
+    }
+
+    pub fn outbound_capacity(mut self, messages: usize) -> Self {
+        self.config.outbound_capacity = messages;
+        self
+    }
+
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.config.admin_token = Some(token.into());
+        self
+    }
+
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.config.max_message_size = bytes;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.config.ping_interval = interval;
+        self
+    }
+
+    pub fn pong_timeout(mut self, timeout: Duration) -> Self {
+        self.config.pong_timeout = timeout;
+        self
+    }
+
+    pub fn room_code_alphabet(mut self, alphabet: RoomCodeAlphabet) -> Self {
+        self.config.room_code_alphabet = alphabet;
+        self
+    }
+
+    pub fn rejoin_grace(mut self, grace: Duration) -> Self {
+        self.config.rejoin_grace = grace;
+        self
+    }
+
+    pub fn room_ttl(mut self, ttl: Duration) -> Self {
+        self.config.room_ttl = Some(ttl);
+        self
+    }
+
+    /// Spawn the room manager and return the configured server
+    pub fn build(self) -> SignalingServer {
+        SignalingServer::with_config(self.config)
+    }
+}
+
+/// Why an established connection ended
+#[derive(Debug)]
+enum DisconnectReason {
+    /// The client sent a close frame, its stream ended cleanly, or it could
+    /// no longer be written to; a client that drops the connection without a
+    /// close frame is otherwise a `WsError`
+    ClientClose,
+    /// The client didn't answer a ping in time
+    PongTimeout,
+    /// The WebSocket stream failed: a protocol violation, an oversized
+    /// message or an I/O error
+    WsError(WsError),
+    /// The server closed the connection after refusing a message, e.g. from
+    /// a client below the minimum version
+    ServerClose(SignalingError),
+    /// It was never a WebSocket: a `GET /healthz`, answered and closed
+    HealthProbe,
+    /// The client read too slowly and its outbound queue filled up
+    Lagging,
+    /// The server is shutting down
+    ServerShutdown,
+    /// The session was rebound to another connection
+    SessionMoved,
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ClientClose => write!(f, "closed by client"),
+            Self::PongTimeout => write!(f, "pong timeout"),
+            Self::WsError(e) => write!(f, "WebSocket error: {}", e),
+            Self::ServerClose(e) => write!(f, "closed by server: {}", e),
+            Self::HealthProbe => write!(f, "health probe"),
+            Self::Lagging => write!(f, "outbound queue full"),
+            Self::ServerShutdown => write!(f, "server shutting down"),
+            Self::SessionMoved => write!(f, "session moved"),
+        }
+    }
+}
+
+/// Log how a connection ended; failures get a louder level than hang-ups
+fn log_disconnect(
+    shown: &Redacted,
+    result: Result<DisconnectReason, Box<dyn std::error::Error + Send + Sync>>,
+) {
+    match result {
+        // Probes arrive every few seconds; they'd drown out everything else
+        Ok(DisconnectReason::HealthProbe) => debug!("Health probe from {}", shown),
+        Ok(reason @ DisconnectReason::WsError(_)) => {
+            warn!("WebSocket disconnected: {} ({})", shown, reason)
+        }
+        Ok(reason) => info!("WebSocket disconnected: {} ({})", shown, reason),
+        Err(e) => error!("Connection error from {}: {}", shown, e),
     }
 }
 
-async fn handle_connection(
-    stream: TcpStream,
+/// Serve one client until it disconnects, or answer it if it's a health
+/// probe
+///
+/// # Errors
+/// Only for a WebSocket upgrade that fails or times out; once the connection
+/// is established, however it ends is the `DisconnectReason`.
+///
+/// Runs in a `connection` span tagged with the client address, and with the
+/// peer id and room code once the client is in a room.
+#[tracing::instrument(
+    name = "connection",
+    skip_all,
+    fields(
+        client_addr = %config.addr_redaction.redact(addr),
+        peer_id = tracing::field::Empty,
+        room = tracing::field::Empty,
+    )
+)]
+async fn handle_connection<S>(
+    stream: S,
     addr: SocketAddr,
     handle: RoomManagerHandle,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let ws_stream = tokio_tungstenite::accept_async(stream).await?;
-    let (mut ws_tx, mut ws_rx) = ws_stream.split();
+    config: Arc<SignalingConfig>,
+) -> Result<DisconnectReason, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Client address as it may appear in logs
+    let shown = config.addr_redaction.redact(addr);
+
+    // tungstenite implements no extensions, so a permessage-deflate offer is
+    // declined and the connection carries uncompressed frames
+    let ws_config = WebSocketConfig::default()
+        .max_message_size(Some(config.max_message_size))
+        .max_frame_size(Some(config.max_message_size));
+    // Browsers can't set headers on a WebSocket, but they can offer
+    // subprotocols, so an access token may arrive as one. It is echoed back,
+    // as a client that offers subprotocols expects one to be selected.
+    let mut authenticated = config.access_tokens.is_empty();
+    // The error type is tungstenite's, fixed by its `Callback` trait
+    #[allow(clippy::result_large_err)]
+    let check_subprotocols = |request: &Request, mut response: Response| {
+        let offered = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok());
+        if let Some(token) = offered.and_then(|offered| {
+            offered
+                .split(',')
+                .map(str::trim)
+                .find(|t| token_accepted(&config.access_tokens, t))
+        }) && let Ok(value) = HeaderValue::from_str(token)
+        {
+            response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+            authenticated = true;
+        }
+        Ok(response)
+    };
+    // A probe gets the same time as an upgrade; dropping the stream on
+    // timeout closes the socket
+    let upgrade = async {
+        let stream = match health::sniff(stream).await? {
+            Opening::Probe => return Ok(None),
+            Opening::Upgrade(stream) => stream,
+        };
+        let ws_stream = tokio_tungstenite::accept_hdr_async_with_config(
+            stream,
+            check_subprotocols,
+            Some(ws_config),
+        )
+        .await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Some(ws_stream))
+    };
+    let ws_stream = tokio::time::timeout(config.handshake_timeout, upgrade)
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "WebSocket handshake not completed within {:?}",
+                    config.handshake_timeout
+                ),
+            )
+        })??;
+    let Some(ws_stream) = ws_stream else {
+        return Ok(DisconnectReason::HealthProbe);
+    };
+
+    info!("WebSocket connection from {}", shown);
+
+    Ok(serve(ws_stream, addr, authenticated, handle, config).await)
+}
 
-    info!("WebSocket connection from {}", addr);
+/// Run the message loop for a client whose connection is established
+///
+/// `authenticated` says whether the client already presented an access token
+/// during the handshake. Returns once the connection is over, having told the
+/// room manager the peer is gone.
+async fn serve<T: Transport>(
+    transport: T,
+    addr: SocketAddr,
+    authenticated: bool,
+    handle: RoomManagerHandle,
+    config: Arc<SignalingConfig>,
+) -> DisconnectReason {
+    let shown = config.addr_redaction.redact(addr);
+    let (mut ws_tx, mut ws_rx) = transport.split();
 
-    let (tx, mut rx) = mpsc::unbounded_channel::<OutboundMessage>();
+    let (tx, mut rx) = outbound_channel_with_capacity(config.outbound_capacity);
     let (ctrl_tx, mut ctrl_rx) = mpsc::unbounded_channel::<Message>();
 
-    let mut peer_id: Option<PeerId> = None;
-    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+    let (encoding_tx, encoding_rx) = watch::channel(Encoding::default());
+    let mut conn = Connection {
+        addr,
+        peer_id: None,
+        encoding: encoding_tx,
+        min_version: config.min_client_version.clone(),
+        access_tokens: (!authenticated).then(|| config.access_tokens.clone()),
+    };
+    // The first ping waits a full interval: one racing a fresh client's close
+    // would arrive after the echo and turn the teardown into a reset
+    let mut ping_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + config.ping_interval,
+        config.ping_interval,
+    );
     let mut waiting_for_pong = false;
     let mut pong_deadline: Option<tokio::time::Instant> = None;
 
-    let send_task = tokio::spawn(async move {
+    let mut sequencer = PushSequencer::new(config.sequence_numbers);
+    // Sent once the loop ends, so the client learns why it was disconnected
+    let mut close: Option<CloseFrame> = None;
+    let reason;
+    let sending = async move {
         loop {
             tokio::select! {
                 Some(msg) = rx.recv() => {
-                    let ws_msg = Message::Text(msg.into_inner());
+                    let encoding = *encoding_rx.borrow();
+                    let Some(ws_msg) = encode_push(&mut sequencer, msg, encoding) else {
+                        continue;
+                    };
                     if ws_tx.send(ws_msg).await.is_err() {
                         break;
                     }
                 }
                 Some(ctrl_msg) = ctrl_rx.recv() => {
+                    if let Message::Close(_) = ctrl_msg {
+                        // Pushes already queued, such as a shutdown notice, go
+                        // out ahead of the close
+                        while let Some(msg) = rx.try_recv() {
+                            let encoding = *encoding_rx.borrow();
+                            if let Some(ws_msg) = encode_push(&mut sequencer, msg, encoding)
+                                && ws_tx.send(ws_msg).await.is_err()
+                            {
+                                break;
+                            }
+                        }
+                        // If the client closed first the frame is refused, and
+                        // closing the sink flushes the echo of theirs instead
+                        let _ = ws_tx.send(ctrl_msg).await;
+                        let _ = ws_tx.close().await;
+                        break;
+                    }
                     if ws_tx.send(ctrl_msg).await.is_err() {
                         break;
                     }
@@ -87,7 +575,9 @@ async fn handle_connection(
                 else => break,
             }
         }
-    });
+    };
+    let mut send_task = tokio::spawn(sending.in_current_span());
+    let mut shutdown = handle.shutdown_signal();
 
     loop {
         let pong_timeout = async {
@@ -100,19 +590,50 @@ async fn handle_connection(
         tokio::select! {
             _ = ping_interval.tick() => {
                 if waiting_for_pong {
-                    warn!("No Pong received, disconnecting {}", addr);
+                    warn!("No Pong received, disconnecting {}", shown);
+                    close = Some(close_frame(CloseCode::Away, "pong timeout"));
+                    reason = DisconnectReason::PongTimeout;
                     break;
                 }
+                // The send task only stops once writing to the client fails
                 if ctrl_tx.send(Message::Ping(Bytes::new())).is_err() {
+                    reason = DisconnectReason::ClientClose;
                     break;
                 }
                 waiting_for_pong = true;
-                pong_deadline = Some(tokio::time::Instant::now() + PONG_TIMEOUT);
-                debug!("Ping sent to {}", addr);
+                pong_deadline = Some(tokio::time::Instant::now() + config.pong_timeout);
+                debug!("Ping sent to {}", shown);
+            }
+
+            Ok(()) = shutdown.changed() => {
+                info!("Server shutting down, disconnecting {}", shown);
+                close = Some(close_frame(CloseCode::Away, "server shutting down"));
+                reason = DisconnectReason::ServerShutdown;
+                break;
+            }
+
+            () = tx.superseded() => {
+                info!("Session moved off {}, closing", shown);
+                // The peer lives on elsewhere, so closing mustn't evict it
+                conn.peer_id = None;
+                close = Some(close_frame(CloseCode::Normal, "session moved"));
+                reason = DisconnectReason::SessionMoved;
+                break;
+            }
+
+            () = tx.overflowed() => {
+                warn!("Outbound queue full, disconnecting {}", shown);
+                close = Some(close_frame(CloseCode::Error, "outbound queue full"));
+                reason = DisconnectReason::Lagging;
+                break;
             }
 
             _ = pong_timeout => {
-                warn!("Pong timeout, disconnecting {}", addr);
+                warn!("Pong timeout, disconnecting {}", shown);
+                // Going away rather than a violation: the client may just be
+                // on a dead network path
+                close = Some(close_frame(CloseCode::Away, "pong timeout"));
+                reason = DisconnectReason::PongTimeout;
                 break;
             }
 
@@ -121,108 +642,2007 @@ async fn handle_connection(
                     Some(Ok(m)) => m,
                     Some(Err(e)) => {
                         warn!("WebSocket error: {}", e);
+                        let code = match e {
+                            WsError::Capacity(_) => CloseCode::Size,
+                            _ => CloseCode::Protocol,
+                        };
+                        close = Some(close_frame(code, &e.to_string()));
+                        reason = DisconnectReason::WsError(e);
+                        break;
+                    }
+                    None => {
+                        reason = DisconnectReason::ClientClose;
                         break;
                     }
-                    None => break,
                 };
 
-                match msg {
-                    Message::Text(text) => {
-                        if let Err(e) = handle_text_message(&text, &tx, &handle, addr, &mut peer_id).await {
-                            warn!("Message handling error: {}", e);
-                        }
-                    }
+                let decoded = match msg {
+                    Message::Text(text) => serde_json::from_str(&text).map_err(CodecError::from),
+                    Message::Binary(data) => codec::decode(&data, *conn.encoding.borrow()),
                     Message::Pong(_) => {
                         waiting_for_pong = false;
                         pong_deadline = None;
-                        debug!("Pong received from {}", addr);
+                        debug!("Pong received from {}", shown);
+                        continue;
                     }
                     Message::Close(_) => {
-                        info!("Close received from {}", addr);
+                        info!("Close received from {}", shown);
+                        close = Some(close_frame(CloseCode::Normal, ""));
+                        reason = DisconnectReason::ClientClose;
+                        break;
+                    }
+                    _ => continue,
+                };
+
+                match handle_client_message(decoded, &tx, &handle, &mut conn).await {
+                    Ok(Flow::Continue) => {}
+                    Ok(Flow::Close(err)) => {
+                        info!("Closing {}: {}", shown, err);
+                        // Through the control queue, like the close, so the error precedes it
+                        let msg = ServerMessage::Error {
+                            message: err.to_string(),
+                        };
+                        if let Ok(frame) = codec::encode(&msg, *conn.encoding.borrow()) {
+                            let _ = ctrl_tx.send(frame);
+                        }
+                        close = Some(close_frame(close_code(&err), &err.to_string()));
+                        reason = DisconnectReason::ServerClose(err);
+                        break;
+                    }
+                    Ok(Flow::Disconnected) => {
+                        reason = DisconnectReason::ClientClose;
                         break;
                     }
-                    _ => {}
+                    Err(e) => warn!("Message handling error: {}", e),
                 }
             }
         }
     }
 
-    if let Some(ref pid) = peer_id {
-        handle.leave_room(pid).await;
+    if let Some(ref pid) = conn.peer_id {
+        handle.disconnect(pid, &tx).await;
     }
 
+    if let Some(frame) = close
+        && ctrl_tx.send(Message::Close(Some(frame))).is_ok()
+    {
+        let _ = tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, &mut send_task).await;
+    }
     send_task.abort();
-    info!("WebSocket disconnected: {}", addr);
 
+    reason
+}
+
+/// Stamp and encode a push for the wire, or log why it can't be
+fn encode_push(
+    sequencer: &mut PushSequencer,
+    msg: OutboundMessage,
+    encoding: Encoding,
+) -> Option<Message> {
+    match codec::encode_json(sequencer.stamp(msg), encoding) {
+        Ok(m) => Some(m),
+        Err(e) => {
+            warn!("Failed to encode push as {:?}: {}", encoding, e);
+            None
+        }
+    }
+}
+
+/// Per-connection state owned by the receive loop
+struct Connection {
+    addr: SocketAddr,
+    peer_id: Option<PeerId>,
+    /// Negotiated encoding, shared with the send task
+    encoding: watch::Sender<Encoding>,
+    /// Version the client must announce in `Hello`; cleared once it has
+    min_version: Option<Version>,
+    /// Tokens the client must present one of before creating or joining a
+    /// room; cleared once it has
+    access_tokens: Option<Vec<String>>,
+}
+
+/// Whether `presented` is one of the configured access tokens
+fn token_accepted(tokens: &[String], presented: &str) -> bool {
+    tokens
+        .iter()
+        .any(|token| constant_time_eq(token.as_bytes(), presented.as_bytes()))
+}
+
+/// What the receive loop does after a message
+enum Flow {
+    Continue,
+    /// Send the error to the client, then close the connection
+    Close(SignalingError),
+    /// The client can no longer be written to; stop serving it
+    Disconnected,
+}
+
+/// RFC 6455 close code for a connection ended by `err`
+fn close_code(err: &SignalingError) -> CloseCode {
+    match err {
+        SignalingError::Overloaded
+        | SignalingError::ServerAtCapacity
+        | SignalingError::CreationRateLimited
+        | SignalingError::RoomRateLimited => CloseCode::Again,
+        SignalingError::Internal(_) => CloseCode::Error,
+        _ => CloseCode::Policy,
+    }
+}
+
+/// Build a close frame, truncating `reason` to fit a control frame
+fn close_frame(code: CloseCode, reason: &str) -> CloseFrame {
+    // 125 byte control payload, two of which carry the code
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    CloseFrame {
+        code,
+        reason: reason[..end].into(),
+    }
+}
+
+/// Check a client's announced version against the configured minimum
+///
+/// Versions compare as semver, so `1.10.0` is newer than `1.9.0`. A client
+/// that announces no version is treated as older than any minimum.
+fn check_client_version(version: Option<&str>, minimum: &Version) -> Result<(), SignalingError> {
+    let too_old = || SignalingError::ClientTooOld {
+        minimum: minimum.clone(),
+    };
+    let version = version.ok_or_else(too_old)?;
+    let version = Version::parse(version.trim())
+        .map_err(|e| SignalingError::InvalidVersion(format!("{:?}: {}", version, e)))?;
+    if version < *minimum {
+        return Err(too_old());
+    }
     Ok(())
 }
 
-async fn handle_text_message(
-    text: &str,
-    tx: &mpsc::UnboundedSender<OutboundMessage>,
+/// Act on one message from the client
+///
+/// Once the send task has stopped nothing can reach the client, so this
+/// returns `Flow::Disconnected` rather than doing work for it: up front if
+/// the queue is already closed, or as soon as a response can't be queued.
+async fn handle_client_message(
+    decoded: Result<ClientMessage, CodecError>,
+    tx: &OutboundSender,
     handle: &RoomManagerHandle,
-    addr: SocketAddr,
-    peer_id: &mut Option<PeerId>,
+    conn: &mut Connection,
+) -> Result<Flow, Box<dyn std::error::Error + Send + Sync>> {
+    if tx.is_closed() {
+        return Ok(Flow::Disconnected);
+    }
+    match dispatch_client_message(decoded, tx, handle, conn).await {
+        Err(e) if e.is::<ClientGone>() => Ok(Flow::Disconnected),
+        result => result,
+    }
+}
+
+/// The client's outbound queue is closed
+#[derive(Debug, thiserror::Error)]
+#[error("client connection closed")]
+struct ClientGone;
+
+/// Queue a response for the client
+///
+/// # Errors
+/// `ClientGone` if the send task has stopped
+fn reply(
+    tx: &OutboundSender,
+    msg: &ServerMessage,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client_msg: ClientMessage = match serde_json::from_str(text) {
+    // A full queue is not an error here: the connection loop sees the
+    // overflow and disconnects the client
+    match tx.send(OutboundMessage::from(serde_json::to_string(msg)?)) {
+        Err(TrySendError::Closed(_)) => Err(ClientGone.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Bind the connection to the peer it now is, and tag the connection span
+/// with it
+fn enter_room(peer_id: &mut Option<PeerId>, id: PeerId, code: RoomCode) {
+    *peer_id = Some(id);
+    let span = Span::current();
+    span.record("peer_id", tracing::field::display(id));
+    span.record("room", tracing::field::display(code));
+}
+
+async fn dispatch_client_message(
+    decoded: Result<ClientMessage, CodecError>,
+    tx: &OutboundSender,
+    handle: &RoomManagerHandle,
+    conn: &mut Connection,
+) -> Result<Flow, Box<dyn std::error::Error + Send + Sync>> {
+    let client_msg = match decoded {
         Ok(m) => m,
         Err(e) => {
+            // The connection span says whose it was
+            debug!("Invalid message: {}", e);
             let err = ServerMessage::Error {
                 message: format!("Invalid message: {}", e),
             };
-            let _ = tx.send(OutboundMessage::from(serde_json::to_string(&err)?));
-            return Ok(());
+            reply(tx, &err)?;
+            return Ok(Flow::Continue);
         }
     };
 
+    // With a minimum configured, nothing but Hello is served until the client
+    // has announced a recent enough version
+    if let Some(minimum) = &conn.min_version {
+        let version = match &client_msg {
+            ClientMessage::Hello { version, .. } => version.as_deref(),
+            _ => None,
+        };
+        if let Err(e) = check_client_version(version, minimum) {
+            return Ok(Flow::Close(e));
+        }
+        conn.min_version = None;
+    }
+
+    // Until a token is presented, everything but entering a room is served.
+    // Rejoin and Rebind are left open: their session token is itself proof
+    // of an earlier, authenticated entry. A Rejoin without a valid one is
+    // refused by the actor rather than falling back to a fresh join.
+    if let Some(tokens) = &conn.access_tokens {
+        let refusal = match &client_msg {
+            ClientMessage::Authenticate { token } if token_accepted(tokens, token) => {
+                conn.access_tokens = None;
+                None
+            }
+            ClientMessage::Authenticate { .. }
+            | ClientMessage::CreateRoom { .. }
+            | ClientMessage::JoinRoom { .. }
+            | ClientMessage::QuickMatch { .. } => Some(SignalingError::Unauthorized),
+            _ => None,
+        };
+        if let Some(e) = refusal {
+            let err = ServerMessage::Error {
+                message: e.to_string(),
+            };
+            reply(tx, &err)?;
+            return Ok(Flow::Continue);
+        }
+    }
+
+    let addr = SignalingAddr::from(conn.addr);
+    let authenticated = conn.access_tokens.is_none();
+    let peer_id = &mut conn.peer_id;
+
     match client_msg {
-        ClientMessage::CreateRoom => match handle.create_room(addr, tx.clone()).await {
-            Ok((code, new_peer_id)) => {
-                *peer_id = Some(new_peer_id);
+        ClientMessage::Hello { encoding, .. } => {
+            // Swap before queueing the ack so the ack itself goes out in the new encoding
+            conn.encoding.send_replace(encoding);
+            let response = ServerMessage::Welcome { encoding };
+            reply(tx, &response)?;
+        }
 
-                let response = ServerMessage::RoomCreated {
-                    code,
-                    your_id: new_peer_id,
-                };
-                let _ = tx.send(OutboundMessage::from(serde_json::to_string(&response)?));
+        ClientMessage::Authenticate { .. } => {
+            let response = ServerMessage::Authenticated;
+            reply(tx, &response)?;
+        }
+
+        ClientMessage::CreateRoom {
+            password,
+            code,
+            metadata,
+            session_key,
+        } => {
+            let created = match code.map(|c| c.parse::<RoomCode>()).transpose() {
+                Ok(room_code) => {
+                    let options = CreateOptions {
+                        code: room_code,
+                        password,
+                        metadata,
+                        session_key,
+                    };
+                    handle.create_room(addr, tx.clone(), options).await
+                }
+                Err(e) => Err(e),
+            };
+            match created {
+                Ok((code, new_peer_id, session_token)) => {
+                    enter_room(peer_id, new_peer_id, code);
+
+                    let response = ServerMessage::RoomCreated {
+                        code,
+                        your_id: new_peer_id,
+                        session_token,
+                    };
+                    reply(tx, &response)?;
+                }
+                Err(e) => {
+                    let err = ServerMessage::Error {
+                        message: e.to_string(),
+                    };
+                    reply(tx, &err)?;
+                }
             }
-            Err(e) => {
-                let err = ServerMessage::Error {
-                    message: e.to_string(),
-                };
-                let _ = tx.send(OutboundMessage::from(serde_json::to_string(&err)?));
+        }
+
+        ClientMessage::JoinRoom {
+            code,
+            password,
+            metadata,
+            session_key,
+        } => {
+            let joined = match code.parse::<RoomCode>() {
+                Ok(room_code) => {
+                    let options = JoinOptions {
+                        password,
+                        metadata,
+                        session_key,
+                    };
+                    handle
+                        .join_room(room_code, addr, tx.clone(), options)
+                        .await
+                        .map(|joined| (room_code, joined))
+                }
+                Err(e) => Err(e),
+            };
+            match joined {
+                // The actor has already queued `RoomJoined`
+                Ok((room_code, (new_peer_id, _, _))) => {
+                    enter_room(peer_id, new_peer_id, room_code);
+                }
+                Err(e) => {
+                    let err = ServerMessage::Error {
+                        message: e.to_string(),
+                    };
+                    reply(tx, &err)?;
+                }
             }
-        },
+        }
 
-        ClientMessage::JoinRoom { code } => {
-            let room_code = RoomCode::from(code.as_str());
-            match handle.join_room(room_code, addr, tx.clone()).await {
-                Ok((new_peer_id, peers)) => {
-                    *peer_id = Some(new_peer_id);
+        ClientMessage::QuickMatch { metadata } => {
+            match handle.quick_match(addr, tx.clone(), metadata).await {
+                Ok(QuickMatched::Created(code, new_peer_id, session_token)) => {
+                    enter_room(peer_id, new_peer_id, code);
 
-                    let response = ServerMessage::RoomJoined {
-                        code: room_code,
+                    let response = ServerMessage::RoomCreated {
+                        code,
                         your_id: new_peer_id,
-                        peers,
+                        session_token,
+                    };
+                    reply(tx, &response)?;
+                }
+                // The actor has already queued `RoomJoined`
+                Ok(QuickMatched::Joined(code, new_peer_id, _, _)) => {
+                    enter_room(peer_id, new_peer_id, code);
+                }
+                Err(e) => {
+                    let err = ServerMessage::Error {
+                        message: e.to_string(),
                     };
-                    let _ = tx.send(OutboundMessage::from(serde_json::to_string(&response)?));
+                    reply(tx, &err)?;
+                }
+            }
+        }
+
+        ClientMessage::Rejoin {
+            code,
+            peer_id: previous,
+            token,
+        } => {
+            let rejoined = match code.parse::<RoomCode>() {
+                Ok(room_code) => handle
+                    .rejoin_room_with_fallback(
+                        room_code,
+                        &previous,
+                        &token,
+                        addr,
+                        tx.clone(),
+                        authenticated,
+                    )
+                    .await
+                    .map(|rejoined| (room_code, rejoined)),
+                Err(e) => Err(e),
+            };
+            match rejoined {
+                // The actor has already queued `RoomJoined`
+                Ok((room_code, (new_peer_id, _, _))) => {
+                    enter_room(peer_id, new_peer_id, room_code);
                 }
                 Err(e) => {
                     let err = ServerMessage::Error {
                         message: e.to_string(),
                     };
-                    let _ = tx.send(OutboundMessage::from(serde_json::to_string(&err)?));
+                    reply(tx, &err)?;
                 }
             }
         }
 
         ClientMessage::LeaveRoom => {
             if let Some(pid) = peer_id.as_ref() {
-                handle.leave_room(pid).await;
+                handle.disconnect(pid, tx).await;
             }
             *peer_id = None;
         }
-    }
 
-    Ok(())
+        ClientMessage::Rebind {
+            peer_id: target,
+            token,
+        } => match handle.rebind(&target, &token, tx.clone()).await {
+            Ok((code, peers)) => {
+                // Whatever this connection was before, it is the rebound peer now
+                if let Some(previous) = peer_id.take()
+                    && previous != target
+                {
+                    handle.disconnect(&previous, tx).await;
+                }
+                enter_room(peer_id, target, code);
+
+                let response = ServerMessage::SessionResumed {
+                    code,
+                    your_id: target,
+                    peers,
+                };
+                reply(tx, &response)?;
+            }
+            Err(e) => {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        },
+
+        ClientMessage::Broadcast { payload } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.broadcast(pid, payload).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::Signal { to, payload } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.relay(pid, &to, payload).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::Offer { to, sdp } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.relay_offer(pid, &to, sdp).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::Answer { to, sdp } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.relay_answer(pid, &to, sdp).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::IceCandidate {
+            to,
+            candidate,
+            sdp_mid,
+            sdp_mline_index,
+        } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => {
+                    handle
+                        .relay_ice_candidate(pid, &to, candidate, sdp_mid, sdp_mline_index)
+                        .await
+                }
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::Multicast { to, payload } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.multicast(pid, to, payload).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            let response = match result {
+                Ok((delivered, missing)) => ServerMessage::MulticastResult { delivered, missing },
+                Err(e) => ServerMessage::Error {
+                    message: e.to_string(),
+                },
+            };
+            reply(tx, &response)?;
+        }
+
+        ClientMessage::AddAlias { alias } => {
+            let result = match (peer_id.as_ref(), alias.parse::<RoomCode>()) {
+                (None, _) => Err(SignalingError::NotInRoom),
+                (Some(_), Err(e)) => Err(e),
+                (Some(pid), Ok(alias)) => {
+                    handle.add_alias(pid, alias).await.map(|code| (code, alias))
+                }
+            };
+            let response = match result {
+                Ok((code, alias)) => ServerMessage::AliasAdded { code, alias },
+                Err(e) => ServerMessage::Error {
+                    message: e.to_string(),
+                },
+            };
+            reply(tx, &response)?;
+        }
+
+        ClientMessage::SetReflexiveAddr { addr: claimed } => {
+            // Only the IP is compared: the claim is a UDP mapping, the
+            // observed source a TCP one, so ports never line up
+            if claimed.addr().ip().to_canonical() != addr.addr().ip().to_canonical() {
+                let warning = ServerMessage::AddressMismatch {
+                    claimed,
+                    observed: addr,
+                };
+                reply(tx, &warning)?;
+            }
+
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.set_reflexive_addr(pid, claimed).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::GetPeers => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.peers(pid).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            let response = match result {
+                Ok(peers) => ServerMessage::PeerList { peers },
+                Err(e) => ServerMessage::Error {
+                    message: e.to_string(),
+                },
+            };
+            reply(tx, &response)?;
+        }
+
+        ClientMessage::RequestRelay => {
+            // Both peers hear about the relay from the room manager
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.request_relay(pid).await.map(|_| ()),
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::ListRooms { admin_token } => {
+            let response = match handle.list_rooms(admin_token.as_deref()).await {
+                Ok(rooms) => ServerMessage::RoomList { rooms },
+                Err(e) => ServerMessage::Error {
+                    message: e.to_string(),
+                },
+            };
+            reply(tx, &response)?;
+        }
+
+        ClientMessage::SetLocked { locked } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.set_locked(pid, locked).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::SetMetadata { metadata } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.set_metadata(pid, metadata).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+
+        ClientMessage::Kick { peer_id: target } => {
+            let result = match peer_id.as_ref() {
+                Some(pid) => handle.kick(pid, &target).await,
+                None => Err(SignalingError::NotInRoom),
+            };
+            if let Err(e) = result {
+                let err = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                reply(tx, &err)?;
+            }
+        }
+    }
+
+    Ok(Flow::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_tungstenite::tungstenite::error::ProtocolError;
+
+    use super::*;
+    use crate::signaling::outbound::{OutboundReceiver, outbound_channel};
+    use crate::signaling::transport::{MockClient, mock_transport};
+    use crate::signaling::types::ReflexiveAddr;
+
+    #[tokio::test]
+    async fn stalled_handshake_is_aborted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+
+        let config = Arc::new(SignalingConfig {
+            handshake_timeout: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handle_connection(stream, addr, handle, config),
+        )
+        .await
+        .expect("handshake timeout should fire");
+        let err = result.unwrap_err();
+        let io = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), std::io::ErrorKind::TimedOut);
+
+        // The server side hung up without sending anything
+        let mut buf = [0u8; 16];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    async fn recv_json(rx: &mut OutboundReceiver) -> serde_json::Value {
+        let msg = rx.recv().await.unwrap();
+        serde_json::from_str(msg.into_inner().as_str()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn malformed_join_and_rejoin_codes_are_rejected_before_lookup() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (tx, mut rx) = outbound_channel();
+        let (encoding, _) = watch::channel(Encoding::default());
+        let mut conn = Connection {
+            addr: "203.0.113.7:51000".parse().unwrap(),
+            peer_id: None,
+            encoding,
+            min_version: None,
+            access_tokens: None,
+        };
+
+        let join = ClientMessage::JoinRoom {
+            code: "HELLO WORLD!!".to_string(),
+            password: None,
+            metadata: None,
+            session_key: None,
+        };
+        handle_client_message(Ok(join), &tx, &handle, &mut conn)
+            .await
+            .unwrap();
+        let reply = recv_json(&mut rx).await;
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["message"], "invalid room code \"HELLO WORLD!!\"");
+
+        let rejoin = serde_json::from_str(
+            r#"{"type": "rejoin", "code": "abc12345garbage", "peer_id": "peer_abc12345", "token": "t"}"#,
+        );
+        handle_client_message(Ok(rejoin.unwrap()), &tx, &handle, &mut conn)
+            .await
+            .unwrap();
+        let reply = recv_json(&mut rx).await;
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["message"], "invalid room code \"abc12345garbage\"");
+    }
+
+    #[tokio::test]
+    async fn closed_outbound_queue_tears_the_connection_down() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (code, owner, _) = handle
+            .create_room(
+                SignalingAddr::from("127.0.0.1:5000".parse::<SocketAddr>().unwrap()),
+                outbound_channel().0,
+                CreateOptions::default(),
+            )
+            .await
+            .unwrap();
+        let (tx, rx) = outbound_channel();
+        drop(rx);
+        let (encoding, _) = watch::channel(Encoding::default());
+        let mut conn = Connection {
+            addr: "203.0.113.7:51000".parse().unwrap(),
+            peer_id: None,
+            encoding,
+            min_version: None,
+            access_tokens: None,
+        };
+
+        // The first response that can't be queued ends the handling
+        let hello = || ClientMessage::Hello {
+            encoding: Encoding::Json,
+            version: None,
+        };
+        let result = dispatch_client_message(Ok(hello()), &tx, &handle, &mut conn).await;
+        assert!(matches!(result, Err(e) if e.is::<ClientGone>()));
+        let flow = handle_client_message(Ok(hello()), &tx, &handle, &mut conn).await;
+        assert!(matches!(flow, Ok(Flow::Disconnected)));
+
+        // And nothing is done on the client's behalf
+        let join = ClientMessage::JoinRoom {
+            code: code.to_string(),
+            password: None,
+            metadata: None,
+            session_key: None,
+        };
+        let flow = handle_client_message(Ok(join), &tx, &handle, &mut conn).await;
+        assert!(matches!(flow, Ok(Flow::Disconnected)));
+        assert!(conn.peer_id.is_none());
+        assert!(handle.peers(&owner).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_custom_code_is_rejected() {
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (tx, mut rx) = outbound_channel();
+        let (encoding, _) = watch::channel(Encoding::default());
+        let mut conn = Connection {
+            addr: "203.0.113.7:51000".parse().unwrap(),
+            peer_id: None,
+            encoding,
+            min_version: None,
+            access_tokens: None,
+        };
+
+        let create = ClientMessage::CreateRoom {
+            password: None,
+            code: Some("Game Night".to_string()),
+            metadata: None,
+            session_key: None,
+        };
+        handle_client_message(Ok(create), &tx, &handle, &mut conn)
+            .await
+            .unwrap();
+        let reply = recv_json(&mut rx).await;
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["message"], "invalid room code \"Game Night\"");
+        assert!(conn.peer_id.is_none());
+        assert_eq!(handle.stats().await.unwrap().rooms, 0);
+    }
+
+    #[tokio::test]
+    async fn differing_reflexive_addr_triggers_mismatch_warning() {
+        let observed: SocketAddr = "203.0.113.7:51000".parse().unwrap();
+        let handle = RoomManagerHandle::spawn(SignalingConfig::default());
+        let (tx, mut rx) = outbound_channel();
+        let (encoding, _) = watch::channel(Encoding::default());
+        let mut conn = Connection {
+            addr: observed,
+            peer_id: None,
+            encoding,
+            min_version: None,
+            access_tokens: None,
+        };
+
+        handle_client_message(
+            Ok(ClientMessage::CreateRoom {
+                password: None,
+                code: None,
+                metadata: None,
+                session_key: None,
+            }),
+            &tx,
+            &handle,
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        let created = recv_json(&mut rx).await;
+        assert_eq!(created["type"], "room_created");
+
+        // Same IP, different port: a normal NAT mapping, no warning
+        let claim = |addr: &str| {
+            Ok(ClientMessage::SetReflexiveAddr {
+                addr: ReflexiveAddr::try_from(addr.parse::<SocketAddr>().unwrap()).unwrap(),
+            })
+        };
+        handle_client_message(claim("203.0.113.7:40000"), &tx, &handle, &mut conn)
+            .await
+            .unwrap();
+        handle_client_message(claim("198.51.100.9:40000"), &tx, &handle, &mut conn)
+            .await
+            .unwrap();
+
+        let warning = recv_json(&mut rx).await;
+        assert_eq!(warning["type"], "address_mismatch");
+        assert_eq!(warning["claimed"], "198.51.100.9:40000");
+        assert_eq!(warning["observed"], "203.0.113.7:51000");
+
+        // The claim is still what later joiners see
+        let code = RoomCode::from(created["code"].as_str().unwrap());
+        let (_, _, peers) = handle
+            .join_room(
+                code,
+                SignalingAddr::from(observed),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        let reflexive = peers[0].reflexive_addr.unwrap();
+        assert_eq!(reflexive.addr(), "198.51.100.9:40000".parse().unwrap());
+        assert_eq!(peers[0].signaling_addr.unwrap().addr(), observed);
+    }
+
+    #[tokio::test]
+    async fn builder_applies_options() {
+        let server = SignalingServer::builder()
+            .room_creation_rate(RateLimit::new(0.001, 1))
+            .sequence_numbers(true)
+            .addr_redaction(AddrRedaction::Hash)
+            .handshake_timeout(Duration::from_secs(3))
+            .build();
+
+        let config = server.config();
+        assert!(config.sequence_numbers);
+        assert_eq!(config.addr_redaction, AddrRedaction::Hash);
+        assert_eq!(config.handshake_timeout, Duration::from_secs(3));
+        // Untouched options keep their defaults
+        assert!(config.min_client_version.is_none());
+
+        // The room manager was spawned with the same config
+        let handle = server.handle();
+        let addr = SignalingAddr::from("127.0.0.1:5000".parse::<SocketAddr>().unwrap());
+        handle
+            .create_room(addr, outbound_channel().0, CreateOptions::default())
+            .await
+            .unwrap();
+        let result = handle
+            .create_room(addr, outbound_channel().0, CreateOptions::default())
+            .await;
+        assert!(matches!(result, Err(SignalingError::CreationRateLimited)));
+    }
+
+    /// Serve connections sharing one room manager; returns the address
+    async fn listen(config: SignalingConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(config);
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                tokio::spawn(handle_connection(
+                    stream,
+                    peer,
+                    handle.clone(),
+                    config.clone(),
+                ));
+            }
+        });
+        addr
+    }
+
+    async fn dial(addr: SocketAddr) -> WebSocketStream<TcpStream> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream)
+            .await
+            .unwrap();
+        ws
+    }
+
+    /// Serve `config` and connect a client to it
+    async fn connect(config: SignalingConfig) -> WebSocketStream<TcpStream> {
+        dial(listen(config).await).await
+    }
+
+    /// Next JSON push, skipping control frames
+    async fn next_json(ws: &mut WebSocketStream<TcpStream>) -> serde_json::Value {
+        let read = async {
+            loop {
+                match ws.next().await.expect("connection ended").unwrap() {
+                    Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                    Message::Close(_) => panic!("connection closed"),
+                    _ => continue,
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("no message")
+    }
+
+    /// Next binary push decoded from `encoding`, skipping control frames
+    async fn next_binary(
+        ws: &mut WebSocketStream<TcpStream>,
+        encoding: Encoding,
+    ) -> serde_json::Value {
+        let read = async {
+            loop {
+                match ws.next().await.expect("connection ended").unwrap() {
+                    Message::Binary(data) => return codec::decode(&data, encoding).unwrap(),
+                    Message::Text(text) => panic!("expected a binary frame, got {}", text),
+                    Message::Close(_) => panic!("connection closed"),
+                    _ => continue,
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("no message")
+    }
+
+    /// Read until the server's close frame and return its code
+    async fn close_code_from(ws: &mut WebSocketStream<TcpStream>) -> CloseCode {
+        let read = async {
+            while let Some(msg) = ws.next().await {
+                if let Message::Close(frame) = msg.unwrap() {
+                    return frame.expect("close frame without a code").code;
+                }
+            }
+            panic!("connection ended without a close frame");
+        };
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("no close frame")
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_peers_and_closes_with_going_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(async move {
+            SignalingServer::new()
+                .serve(listener, async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+
+        let mut host = dial(addr).await;
+        host.send(Message::text(r#"{"type":"create_room"}"#))
+            .await
+            .unwrap();
+        let created = next_json(&mut host).await;
+        let mut guest = dial(addr).await;
+        let join = serde_json::json!({"type": "join_room", "code": created["code"]});
+        guest.send(Message::text(join.to_string())).await.unwrap();
+        assert_eq!(next_json(&mut guest).await["type"], "room_joined");
+        assert_eq!(next_json(&mut host).await["type"], "peer_joined");
+
+        stop.send(()).unwrap();
+        for ws in [&mut host, &mut guest] {
+            assert_eq!(next_json(ws).await["type"], "server_shutdown");
+            assert_eq!(close_code_from(ws).await, CloseCode::Away);
+        }
+        drop((host, guest));
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("connections should drain")
+            .unwrap()
+            .unwrap();
+    }
+
+    /// Create a room over `ws`; returns the `room_created` push
+    async fn create_on(ws: &mut WebSocketStream<TcpStream>) -> serde_json::Value {
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        next_json(ws).await
+    }
+
+    fn rebind_to(created: &serde_json::Value) -> Message {
+        let rebind = serde_json::json!({
+            "type": "rebind",
+            "peer_id": created["your_id"],
+            "token": created["session_token"],
+        });
+        Message::text(rebind.to_string())
+    }
+
+    #[tokio::test]
+    async fn rebind_closes_the_superseded_connection() {
+        let addr = listen(SignalingConfig::default()).await;
+        let mut old = dial(addr).await;
+        let created = create_on(&mut old).await;
+
+        let mut new = dial(addr).await;
+        new.send(rebind_to(&created)).await.unwrap();
+        assert_eq!(next_json(&mut new).await["type"], "session_resumed");
+
+        assert_eq!(next_json(&mut old).await["type"], "session_moved");
+        assert_eq!(close_code_from(&mut old).await, CloseCode::Normal);
+
+        // The peer lives on at the new connection
+        new.send(Message::text(r#"{"type": "get_peers"}"#))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut new).await["type"], "peer_list");
+    }
+
+    #[tokio::test]
+    async fn rebind_leaves_the_connection_previous_room() {
+        let addr = listen(SignalingConfig::default()).await;
+        let mut mobile = dial(addr).await;
+        let wanted = create_on(&mut mobile).await;
+
+        let mut host = dial(addr).await;
+        let other = create_on(&mut host).await;
+        let mut switcher = dial(addr).await;
+        let join = serde_json::json!({"type": "join_room", "code": other["code"]});
+        switcher
+            .send(Message::text(join.to_string()))
+            .await
+            .unwrap();
+        let joined = next_json(&mut switcher).await;
+        assert_eq!(next_json(&mut host).await["type"], "peer_joined");
+
+        switcher.send(rebind_to(&wanted)).await.unwrap();
+        assert_eq!(next_json(&mut switcher).await["type"], "session_resumed");
+
+        // No ghost of the switcher stays behind in the room it was in
+        let left = next_json(&mut host).await;
+        assert_eq!(left["type"], "peer_left");
+        assert_eq!(left["peer_id"], joined["your_id"]);
+        host.send(Message::text(r#"{"type": "get_peers"}"#))
+            .await
+            .unwrap();
+        let listed = next_json(&mut host).await;
+        assert_eq!(listed["peers"].as_array().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn version_gate_closes_with_policy_violation() {
+        let mut ws = connect(SignalingConfig {
+            min_client_version: Some(minimum()),
+            ..Default::default()
+        })
+        .await;
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        assert_eq!(close_code_from(&mut ws).await, CloseCode::Policy);
+    }
+
+    #[tokio::test]
+    async fn oversized_message_closes_with_1009() {
+        let mut ws = connect(SignalingConfig {
+            max_message_size: 1024,
+            ..Default::default()
+        })
+        .await;
+        let payload = "x".repeat(2048);
+        let broadcast = serde_json::json!({"type": "broadcast", "payload": payload});
+        ws.send(Message::text(broadcast.to_string())).await.unwrap();
+        assert_eq!(close_code_from(&mut ws).await, CloseCode::Size);
+    }
+
+    #[tokio::test]
+    async fn deflate_offer_is_declined_and_connection_works() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let addr = listen(SignalingConfig::default()).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Extensions",
+            "permessage-deflate; client_max_window_bits"
+                .parse()
+                .unwrap(),
+        );
+        let (mut ws, response) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .unwrap();
+        assert!(response.headers().get("Sec-WebSocket-Extensions").is_none());
+
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "room_created");
+    }
+
+    #[tokio::test]
+    async fn rooms_are_refused_until_a_valid_token_is_presented() {
+        let mut ws = connect(SignalingConfig {
+            access_tokens: vec!["let-me-in".to_string()],
+            ..Default::default()
+        })
+        .await;
+        let create = Message::text(r#"{"type": "create_room"}"#);
+
+        ws.send(create.clone()).await.unwrap();
+        let refused = next_json(&mut ws).await;
+        assert_eq!(refused["type"], "error");
+        assert_eq!(refused["message"], "unauthorized");
+
+        ws.send(Message::text(
+            r#"{"type": "authenticate", "token": "let-me-out"}"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(next_json(&mut ws).await["message"], "unauthorized");
+
+        ws.send(Message::text(
+            r#"{"type": "authenticate", "token": "let-me-in"}"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "authenticated");
+        ws.send(create).await.unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "room_created");
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_rejoin_needs_a_valid_session() {
+        let addr = listen(SignalingConfig {
+            access_tokens: vec!["let-me-in".to_string()],
+            ..Default::default()
+        })
+        .await;
+        let authenticate = Message::text(r#"{"type": "authenticate", "token": "let-me-in"}"#);
+
+        let mut host = dial(addr).await;
+        host.send(authenticate.clone()).await.unwrap();
+        assert_eq!(next_json(&mut host).await["type"], "authenticated");
+        host.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        let code = next_json(&mut host).await["code"].clone();
+
+        let mut guest = dial(addr).await;
+        guest.send(authenticate).await.unwrap();
+        assert_eq!(next_json(&mut guest).await["type"], "authenticated");
+        let join = serde_json::json!({"type": "join_room", "code": code});
+        guest.send(Message::text(join.to_string())).await.unwrap();
+        let joined = next_json(&mut guest).await;
+        assert_eq!(next_json(&mut host).await["type"], "peer_joined");
+        guest.close(None).await.unwrap();
+        assert_eq!(next_json(&mut host).await["type"], "peer_left");
+
+        // A made-up session gets no fresh join in its place
+        let mut intruder = dial(addr).await;
+        let bogus = serde_json::json!({
+            "type": "rejoin",
+            "code": code,
+            "peer_id": joined["your_id"],
+            "token": "00000000000000000000000000000000",
+        });
+        intruder
+            .send(Message::text(bogus.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut intruder).await["message"], "unauthorized");
+
+        // The real one still brings the guest back
+        let rejoin = serde_json::json!({
+            "type": "rejoin",
+            "code": code,
+            "peer_id": joined["your_id"],
+            "token": joined["session_token"],
+        });
+        intruder
+            .send(Message::text(rejoin.to_string()))
+            .await
+            .unwrap();
+        let rejoined = next_json(&mut intruder).await;
+        assert_eq!(rejoined["type"], "room_joined");
+        assert_eq!(rejoined["your_id"], joined["your_id"]);
+    }
+
+    #[tokio::test]
+    async fn token_offered_as_subprotocol_authenticates() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let addr = listen(SignalingConfig {
+            access_tokens: vec!["let-me-in".to_string()],
+            ..Default::default()
+        })
+        .await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("carapace, let-me-in"),
+        );
+        let (mut ws, response) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(),
+            "let-me-in"
+        );
+
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "room_created");
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn wss_round_trip_with_self_signed_cert() {
+        use tokio_rustls::TlsConnector;
+        use tokio_rustls::rustls::crypto::ring;
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+        use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let fixture =
+            |name: &str| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let tls =
+            crate::signaling::TlsConfig::new(fixture("localhost.crt"), fixture("localhost.key"));
+        let server = SignalingServer::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tls.acceptor().unwrap();
+        tokio::spawn(async move {
+            server
+                .serve_tls(listener, acceptor, std::future::pending())
+                .await
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(fixture("localhost.crt")).unwrap())
+            .unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async("wss://localhost/", stream)
+            .await
+            .unwrap();
+
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        let read = async {
+            loop {
+                if let Message::Text(text) = ws.next().await.unwrap().unwrap() {
+                    return serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                }
+            }
+        };
+        let created = tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("no message");
+        assert_eq!(created["type"], "room_created");
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_config_rejects_missing_files() {
+        let tls = crate::signaling::TlsConfig::new("/nonexistent.crt", "/nonexistent.key");
+        assert!(tls.acceptor().is_err());
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_notifies_remaining_peers() {
+        let addr = listen(SignalingConfig::default()).await;
+        let mut owner = dial(addr).await;
+        owner
+            .send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        let created = next_json(&mut owner).await;
+        assert_eq!(created["type"], "room_created");
+
+        let mut joiner = dial(addr).await;
+        let join = serde_json::json!({"type": "join_room", "code": created["code"]});
+        joiner.send(Message::text(join.to_string())).await.unwrap();
+        let joined = next_json(&mut joiner).await;
+        assert_eq!(joined["type"], "room_joined");
+        assert_eq!(next_json(&mut owner).await["type"], "peer_joined");
+
+        // No close handshake: the socket just goes away
+        drop(joiner);
+        let left = next_json(&mut owner).await;
+        assert_eq!(left["type"], "peer_left");
+        assert_eq!(left["peer_id"], joined["your_id"]);
+    }
+
+    #[tokio::test]
+    async fn room_joined_precedes_signals_from_existing_peers() {
+        let addr = listen(SignalingConfig {
+            sequence_numbers: true,
+            ..Default::default()
+        })
+        .await;
+        let mut owner = dial(addr).await;
+        owner
+            .send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        let created = next_json(&mut owner).await;
+
+        let mut joiner = dial(addr).await;
+        let join = serde_json::json!({"type": "join_room", "code": created["code"]});
+        joiner.send(Message::text(join.to_string())).await.unwrap();
+
+        // The owner signals the newcomer as soon as it learns of it, before
+        // the newcomer has read anything
+        let joined_peer = next_json(&mut owner).await;
+        assert_eq!(joined_peer["type"], "peer_joined");
+        let signal = serde_json::json!({
+            "type": "signal",
+            "to": joined_peer["peer"]["id"],
+            "payload": {"sdp": "offer"},
+        });
+        owner.send(Message::text(signal.to_string())).await.unwrap();
+
+        let joined = next_json(&mut joiner).await;
+        assert_eq!(joined["type"], "room_joined");
+        let relayed = next_json(&mut joiner).await;
+        assert_eq!(relayed["type"], "signal");
+        assert!(joined["seq"].as_u64().unwrap() < relayed["seq"].as_u64().unwrap());
+    }
+
+    #[tokio::test]
+    async fn binary_signals_reach_json_peers_and_back() {
+        let addr = listen(SignalingConfig::default()).await;
+        let mut owner = dial(addr).await;
+        owner
+            .send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        let created = next_json(&mut owner).await;
+
+        let encoding = Encoding::MessagePack;
+        let mut joiner = dial(addr).await;
+        let hello = ClientMessage::Hello {
+            encoding,
+            version: None,
+        };
+        joiner
+            .send(codec::encode(&hello, Encoding::Json).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut joiner, encoding).await["type"], "welcome");
+        let join = ClientMessage::JoinRoom {
+            code: created["code"].as_str().unwrap().to_string(),
+            password: None,
+            metadata: None,
+            session_key: None,
+        };
+        joiner
+            .send(codec::encode(&join, encoding).unwrap())
+            .await
+            .unwrap();
+        let joined = next_binary(&mut joiner, encoding).await;
+        assert_eq!(joined["type"], "room_joined");
+        assert_eq!(next_json(&mut owner).await["type"], "peer_joined");
+
+        let offer = ClientMessage::Signal {
+            to: serde_json::from_value(created["your_id"].clone()).unwrap(),
+            payload: serde_json::json!({"sdp": "offer"}),
+        };
+        joiner
+            .send(codec::encode(&offer, encoding).unwrap())
+            .await
+            .unwrap();
+        let relayed = next_json(&mut owner).await;
+        assert_eq!(relayed["type"], "signal");
+        assert_eq!(relayed["payload"]["sdp"], "offer");
+
+        let answer = serde_json::json!({
+            "type": "signal",
+            "to": joined["your_id"],
+            "payload": {"sdp": "answer"},
+        });
+        owner.send(Message::text(answer.to_string())).await.unwrap();
+        let relayed = next_binary(&mut joiner, encoding).await;
+        assert_eq!(relayed["type"], "signal");
+        assert_eq!(relayed["from"], created["your_id"]);
+        assert_eq!(relayed["payload"]["sdp"], "answer");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client_is_dropped_after_one_missed_pong() {
+        let config = SignalingConfig {
+            ping_interval: Duration::from_secs(1),
+            pong_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = RoomManagerHandle::spawn(config.clone());
+        let mut events = handle.subscribe_events();
+        let config = Arc::new(config);
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, peer, handle, config).await
+        });
+
+        let mut ws = dial(addr).await;
+        let start = tokio::time::Instant::now();
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "room_created");
+
+        // Not reading means the ping is never answered
+        while !matches!(events.recv().await.unwrap(), RoomEvent::PeerLeft { .. }) {}
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        let mut pings = 0;
+        let frame = loop {
+            match ws.next().await.expect("connection ended").unwrap() {
+                Message::Ping(_) => pings += 1,
+                Message::Close(frame) => break frame.expect("close frame without a code"),
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        assert_eq!(pings, 1);
+        assert_eq!(frame.code, CloseCode::Away);
+        assert_eq!(frame.reason, "pong timeout");
+    }
+
+    #[tokio::test]
+    async fn client_close_is_answered_normally() {
+        let mut ws = connect(SignalingConfig::default()).await;
+        ws.send(Message::Close(Some(close_frame(CloseCode::Normal, "bye"))))
+            .await
+            .unwrap();
+        assert_eq!(close_code_from(&mut ws).await, CloseCode::Normal);
+    }
+
+    #[test]
+    fn close_codes_follow_error_kind() {
+        assert_eq!(close_code(&SignalingError::Overloaded), CloseCode::Again);
+        assert_eq!(
+            close_code(&SignalingError::Internal("actor gone".into())),
+            CloseCode::Error
+        );
+        assert_eq!(
+            close_code(&SignalingError::ClientTooOld { minimum: minimum() }),
+            CloseCode::Policy
+        );
+
+        let long = "é".repeat(100);
+        let frame = close_frame(CloseCode::Policy, &long);
+        assert!(frame.reason.len() <= 123);
+    }
+
+    fn minimum() -> Version {
+        Version::new(1, 9, 0)
+    }
+
+    #[test]
+    fn accepts_versions_at_or_above_minimum() {
+        assert!(check_client_version(Some("1.9.0"), &minimum()).is_ok());
+        // Semantic, not lexical: "1.10.0" < "1.9.0" as strings
+        assert!(check_client_version(Some("1.10.0"), &minimum()).is_ok());
+        assert!(check_client_version(Some("2.0.0"), &minimum()).is_ok());
+    }
+
+    #[test]
+    fn rejects_old_or_missing_versions_with_minimum() {
+        for version in [Some("1.8.12"), Some("1.9.0-beta.1"), None] {
+            let err = check_client_version(version, &minimum()).unwrap_err();
+            assert!(matches!(&err, SignalingError::ClientTooOld { minimum: m } if *m == minimum()));
+            assert!(err.to_string().contains("1.9.0"));
+        }
+    }
+
+    #[test]
+    fn rejects_unparseable_versions() {
+        for version in ["1.9", "latest", ""] {
+            assert!(matches!(
+                check_client_version(Some(version), &minimum()),
+                Err(SignalingError::InvalidVersion(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_every_peer_but_the_sender() {
+        let addr = listen(SignalingConfig::default()).await;
+        let mut owner = dial(addr).await;
+        owner
+            .send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        let code = next_json(&mut owner).await["code"].clone();
+
+        let join = serde_json::json!({ "type": "join_room", "code": code }).to_string();
+        let mut sender = dial(addr).await;
+        sender.send(Message::text(join.clone())).await.unwrap();
+        let sender_id = next_json(&mut sender).await["your_id"].clone();
+        let mut other = dial(addr).await;
+        other.send(Message::text(join)).await.unwrap();
+        assert_eq!(next_json(&mut other).await["type"], "room_joined");
+        // Everyone hears about the joiners after them
+        assert_eq!(next_json(&mut owner).await["type"], "peer_joined");
+        assert_eq!(next_json(&mut owner).await["type"], "peer_joined");
+        assert_eq!(next_json(&mut sender).await["type"], "peer_joined");
+
+        sender
+            .send(Message::text(
+                r#"{"type": "broadcast", "payload": {"event": "host started"}}"#,
+            ))
+            .await
+            .unwrap();
+        for ws in [&mut owner, &mut other] {
+            let msg = next_json(ws).await;
+            assert_eq!(msg["type"], "broadcast");
+            assert_eq!(msg["from"], sender_id);
+            assert_eq!(msg["payload"]["event"], "host started");
+        }
+
+        // Had the sender got a copy, it would arrive ahead of this answer
+        sender
+            .send(Message::text(r#"{"type": "get_peers"}"#))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut sender).await["type"], "peer_list");
+    }
+
+    #[tokio::test]
+    async fn health_probe_and_websocket_share_the_port() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let addr = listen(SignalingConfig::default()).await;
+        let mut probe = TcpStream::connect(addr).await.unwrap();
+        probe
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), probe.read_to_string(&mut response))
+            .await
+            .expect("probe not answered")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let mut ws = dial(addr).await;
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "room_created");
+    }
+
+    #[tokio::test]
+    async fn concurrent_rooms_introduce_only_their_own_peers() {
+        let addr = listen(SignalingConfig::default()).await;
+        let report = |port: u16| {
+            serde_json::json!({ "type": "set_reflexive_addr", "addr": format!("127.0.0.1:{}", port) })
+                .to_string()
+        };
+        let get_peers = || Message::text(r#"{"type": "get_peers"}"#);
+
+        // Two hosts open rooms and report their addresses before anyone joins
+        let mut pairs = Vec::new();
+        for host_port in [41000, 42000] {
+            let mut host = dial(addr).await;
+            host.send(Message::text(r#"{"type": "create_room"}"#))
+                .await
+                .unwrap();
+            let code = next_json(&mut host).await["code"].clone();
+            host.send(Message::text(report(host_port))).await.unwrap();
+            // Answered only once the report has been applied
+            host.send(get_peers()).await.unwrap();
+            assert_eq!(next_json(&mut host).await["type"], "peer_list");
+            pairs.push((host, host_port, code));
+        }
+
+        for (host, host_port, code) in &mut pairs {
+            let guest_port = *host_port + 1;
+            let mut guest = dial(addr).await;
+            let join = serde_json::json!({ "type": "join_room", "code": code }).to_string();
+            guest.send(Message::text(join)).await.unwrap();
+            let joined = next_json(&mut guest).await;
+            assert_eq!(joined["type"], "room_joined");
+            let peers = joined["peers"].as_array().unwrap();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(
+                peers[0]["reflexive_addr"],
+                format!("127.0.0.1:{}", host_port)
+            );
+
+            guest.send(Message::text(report(guest_port))).await.unwrap();
+            assert_eq!(next_json(host).await["type"], "peer_joined");
+            let updated = next_json(host).await;
+            assert_eq!(updated["type"], "peer_updated");
+            assert_eq!(
+                updated["peer"]["reflexive_addr"],
+                format!("127.0.0.1:{}", guest_port)
+            );
+
+            // Neither side sees anyone from the other room
+            host.send(get_peers()).await.unwrap();
+            let listed = next_json(host).await;
+            assert_eq!(listed["peers"].as_array().unwrap().len(), 1);
+            assert_eq!(listed["peers"][0]["id"], joined["your_id"]);
+        }
+    }
+
+    /// Serve one client over an in-memory stream; returns the client's end
+    /// and how the server's end of the connection finished
+    async fn mock_connection(
+        config: SignalingConfig,
+    ) -> (
+        WebSocketStream<DuplexStream>,
+        tokio::task::JoinHandle<DisconnectReason>,
+    ) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = Arc::new(config);
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let served = tokio::spawn(async move {
+            handle_connection(server, addr, handle, config)
+                .await
+                .unwrap()
+        });
+        let (ws, _) = tokio_tungstenite::client_async("ws://localhost/", client)
+            .await
+            .unwrap();
+        (ws, served)
+    }
+
+    #[tokio::test]
+    async fn close_frame_is_a_client_close_but_hang_up_is_not() {
+        let (mut ws, served) = mock_connection(SignalingConfig::default()).await;
+        ws.close(None).await.unwrap();
+        assert!(matches!(
+            served.await.unwrap(),
+            DisconnectReason::ClientClose
+        ));
+
+        // Dropping the stream skips the closing handshake
+        let (ws, served) = mock_connection(SignalingConfig::default()).await;
+        drop(ws);
+        assert!(matches!(
+            served.await.unwrap(),
+            DisconnectReason::WsError(WsError::Protocol(
+                ProtocolError::ResetWithoutClosingHandshake
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_peer_is_disconnected_when_its_queue_fills() {
+        let (client, server) = tokio::io::duplex(1024);
+        let config = Arc::new(SignalingConfig {
+            outbound_capacity: 8,
+            ..Default::default()
+        });
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let served = tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                handle_connection(server, addr, handle, config)
+                    .await
+                    .unwrap()
+            }
+        });
+        let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/", client)
+            .await
+            .unwrap();
+        let created = create_over(&mut ws).await;
+
+        // The client stops reading, so its end of the stream fills up and
+        // then the queue behind it does
+        let code = RoomCode::from(created["code"].as_str().unwrap());
+        let (sender, _, _) = handle
+            .join_room(
+                code,
+                SignalingAddr::from(addr),
+                outbound_channel().0,
+                JoinOptions::default(),
+            )
+            .await
+            .unwrap();
+        let payload = serde_json::json!("x".repeat(1024));
+        for _ in 0..64 {
+            if handle.broadcast(&sender, payload.clone()).await.is_err() {
+                break;
+            }
+        }
+
+        let reason = tokio::time::timeout(Duration::from_secs(5), served)
+            .await
+            .expect("slow peer should be dropped")
+            .unwrap();
+        assert!(matches!(reason, DisconnectReason::Lagging));
+    }
+
+    /// Serve one client over an in-memory transport; no socket, no handshake
+    fn serve_mock(
+        handle: &RoomManagerHandle,
+        config: &Arc<SignalingConfig>,
+    ) -> (MockClient, tokio::task::JoinHandle<DisconnectReason>) {
+        let (transport, client) = mock_transport();
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let served = tokio::spawn(serve(
+            transport,
+            addr,
+            true,
+            handle.clone(),
+            Arc::clone(config),
+        ));
+        (client, served)
+    }
+
+    /// A host and a guest in one room, with the host's `peer_joined` read
+    async fn mock_room(
+        handle: &RoomManagerHandle,
+        config: &Arc<SignalingConfig>,
+    ) -> ((MockClient, PeerId), (MockClient, PeerId)) {
+        let (mut host, _) = serve_mock(handle, config);
+        host.send(&ClientMessage::CreateRoom {
+            password: None,
+            code: None,
+            metadata: None,
+            session_key: None,
+        });
+        let ServerMessage::RoomCreated { code, your_id, .. } = host.recv().await else {
+            panic!("room not created");
+        };
+        let host_id = your_id;
+
+        let (mut guest, _) = serve_mock(handle, config);
+        guest.send(&ClientMessage::JoinRoom {
+            code: code.to_string(),
+            password: None,
+            metadata: None,
+            session_key: None,
+        });
+        let ServerMessage::RoomJoined { your_id, peers, .. } = guest.recv().await else {
+            panic!("room not joined");
+        };
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, host_id);
+
+        let ServerMessage::PeerJoined { peer } = host.recv().await else {
+            panic!("host not told of the guest");
+        };
+        assert_eq!(peer.id, your_id);
+        ((host, host_id), (guest, your_id))
+    }
+
+    #[tokio::test]
+    async fn mock_clients_create_and_join_a_room() {
+        let config = Arc::new(SignalingConfig::default());
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        let ((mut host, host_id), (mut guest, guest_id)) = mock_room(&handle, &config).await;
+
+        guest.send(&ClientMessage::GetPeers);
+        let ServerMessage::PeerList { peers } = guest.recv().await else {
+            panic!("no peer list");
+        };
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, host_id);
+
+        host.send(&ClientMessage::GetPeers);
+        let ServerMessage::PeerList { peers } = host.recv().await else {
+            panic!("no peer list");
+        };
+        assert_eq!(peers[0].id, guest_id);
+    }
+
+    #[tokio::test]
+    async fn mock_clients_relay_signals() {
+        let config = Arc::new(SignalingConfig::default());
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        let ((mut host, host_id), (guest, guest_id)) = mock_room(&handle, &config).await;
+
+        let payload =
+            serde_json::json!({"candidate": "candidate:1 1 UDP 1 192.0.2.1 5000 typ host"});
+        guest.send(&ClientMessage::Signal {
+            to: host_id,
+            payload: payload.clone(),
+        });
+        let ServerMessage::Signal { from, payload: got } = host.recv().await else {
+            panic!("signal not relayed");
+        };
+        assert_eq!(from, guest_id);
+        assert_eq!(got, payload);
+    }
+
+    #[tokio::test]
+    async fn mock_client_leaving_is_announced() {
+        let config = Arc::new(SignalingConfig::default());
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        let ((mut host, _), (guest, guest_id)) = mock_room(&handle, &config).await;
+
+        guest.send(&ClientMessage::LeaveRoom);
+        let ServerMessage::PeerLeft { peer_id } = host.recv().await else {
+            panic!("leave not announced");
+        };
+        assert_eq!(peer_id, guest_id);
+    }
+
+    #[tokio::test]
+    async fn mock_client_close_ends_the_connection() {
+        let config = Arc::new(SignalingConfig::default());
+        let handle = RoomManagerHandle::spawn((*config).clone());
+        let (client, served) = serve_mock(&handle, &config);
+
+        client.close(None);
+        assert!(matches!(
+            served.await.unwrap(),
+            DisconnectReason::ClientClose
+        ));
+    }
+
+    /// Log output collected in memory
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        /// Collect this thread's logs as JSON lines until the guard drops;
+        /// test runtimes are single-threaded, so that covers every task
+        fn json() -> (Self, tracing::subscriber::DefaultGuard) {
+            let logs = Self::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .json()
+                .with_current_span(true)
+                .with_max_level(tracing::Level::DEBUG)
+                .with_writer(move || writer.clone())
+                .finish();
+            (logs, tracing::subscriber::set_default(subscriber))
+        }
+
+        /// The first line whose message starts with `prefix`
+        fn find(&self, prefix: &str) -> serde_json::Value {
+            let logs = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            logs.lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .find(|line: &serde_json::Value| {
+                    line["fields"]["message"]
+                        .as_str()
+                        .is_some_and(|m| m.starts_with(prefix))
+                })
+                .unwrap_or_else(|| panic!("nothing logged starting {:?}", prefix))
+        }
+    }
+
+    /// Create a room over a mock connection and return `room_created`
+    async fn create_over(ws: &mut WebSocketStream<DuplexStream>) -> serde_json::Value {
+        ws.send(Message::text(r#"{"type":"create_room"}"#))
+            .await
+            .unwrap();
+        let Some(Ok(Message::Text(text))) = ws.next().await else {
+            panic!("no reply");
+        };
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn json_logs_carry_the_connection_fields() {
+        let (logs, _guard) = Captured::json();
+        let (mut ws, served) = mock_connection(SignalingConfig::default()).await;
+        let created = create_over(&mut ws).await;
+        ws.close(None).await.unwrap();
+        served.await.unwrap();
+
+        let span = &logs.find("Close received")["span"];
+        assert_eq!(span["name"], "connection");
+        assert_eq!(span["client_addr"], "127.0.0.1:5000");
+        assert_eq!(span["peer_id"], created["your_id"]);
+        assert_eq!(span["room"], created["code"]);
+    }
+
+    #[tokio::test]
+    async fn message_handling_logs_carry_the_peer_id() {
+        let (logs, _guard) = Captured::json();
+        let (mut ws, served) = mock_connection(SignalingConfig::default()).await;
+        let created = create_over(&mut ws).await;
+        ws.send(Message::text("not json")).await.unwrap();
+        ws.next().await.unwrap().unwrap();
+        ws.close(None).await.unwrap();
+        served.await.unwrap();
+
+        let invalid = logs.find("Invalid message");
+        assert_eq!(invalid["level"], "DEBUG");
+        assert_eq!(invalid["span"]["peer_id"], created["your_id"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_ping_is_a_pong_timeout() {
+        let (_ws, served) = mock_connection(SignalingConfig {
+            ping_interval: Duration::from_secs(1),
+            pong_timeout: Duration::from_millis(500),
+            ..Default::default()
+        })
+        .await;
+        assert!(matches!(
+            served.await.unwrap(),
+            DisconnectReason::PongTimeout
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_message_is_a_ws_error() {
+        let (mut ws, served) = mock_connection(SignalingConfig {
+            max_message_size: 1024,
+            ..Default::default()
+        })
+        .await;
+        ws.send(Message::text("x".repeat(2048))).await.unwrap();
+        assert!(matches!(
+            served.await.unwrap(),
+            DisconnectReason::WsError(WsError::Capacity(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refused_client_is_a_server_close() {
+        let (mut ws, served) = mock_connection(SignalingConfig {
+            min_client_version: Some(minimum()),
+            ..Default::default()
+        })
+        .await;
+        ws.send(Message::text(r#"{"type": "create_room"}"#))
+            .await
+            .unwrap();
+        assert!(matches!(
+            served.await.unwrap(),
+            DisconnectReason::ServerClose(SignalingError::ClientTooOld { .. })
+        ));
+    }
 }
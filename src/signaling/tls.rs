@@ -0,0 +1,59 @@
+//! TLS for the signaling server, so browsers on https pages can reach it as
+//! wss://
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Certificate and private key for `SignalingServer::run_tls`, as PEM files
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// Private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+
+    /// Read the PEM files and build an acceptor from them
+    pub(crate) fn acceptor(&self) -> std::io::Result<TlsAcceptor> {
+        let invalid = |what: &str, path: &PathBuf, e: &dyn std::fmt::Display| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} {}: {}", what, path.display(), e),
+            )
+        };
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid("bad certificate file", &self.cert_path, &e))?;
+        if certs.is_empty() {
+            return Err(invalid(
+                "bad certificate file",
+                &self.cert_path,
+                &"no certificates",
+            ));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| invalid("bad key file", &self.key_path, &e))?;
+
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
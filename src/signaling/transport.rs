@@ -0,0 +1,135 @@
+//! What a connection's message loop reads frames from and writes them to:
+//! the client's WebSocket, or in tests an in-memory pair of channels
+
+use futures_util::{Sink, Stream};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+/// A duplex stream of WebSocket messages
+///
+/// Implemented for anything that reads and writes `Message`s the way
+/// `WebSocketStream` does, so the message loop never needs a socket.
+pub(crate) trait Transport:
+    Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Send + Unpin + 'static
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<Message, WsError>>
+        + Sink<Message, Error = WsError>
+        + Send
+        + Unpin
+        + 'static
+{
+}
+
+#[cfg(test)]
+pub(crate) use mock::{MockClient, mock_transport};
+
+#[cfg(test)]
+mod mock {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use futures_util::{Sink, Stream};
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+    use crate::signaling::messages::{ClientMessage, ServerMessage};
+
+    /// A connected pair: the server's end and the test's end
+    pub(crate) fn mock_transport() -> (MockTransport, MockClient) {
+        let (to_server, incoming) = mpsc::unbounded_channel();
+        let (outgoing, from_server) = mpsc::unbounded_channel();
+        let transport = MockTransport {
+            incoming,
+            outgoing: Some(outgoing),
+        };
+        let client = MockClient {
+            tx: to_server,
+            rx: from_server,
+        };
+        (transport, client)
+    }
+
+    /// The server's end of an in-memory connection
+    pub(crate) struct MockTransport {
+        incoming: mpsc::UnboundedReceiver<Message>,
+        /// Dropped when the server closes its end
+        outgoing: Option<mpsc::UnboundedSender<Message>>,
+    }
+
+    impl Stream for MockTransport {
+        type Item = Result<Message, WsError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.incoming.poll_recv(cx).map(|msg| msg.map(Ok))
+        }
+    }
+
+    impl Sink<Message> for MockTransport {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), WsError> {
+            self.outgoing
+                .as_ref()
+                .and_then(|tx| tx.send(msg).ok())
+                .ok_or(WsError::ConnectionClosed)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), WsError>> {
+            self.outgoing = None;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The test's end of an in-memory connection, speaking in protocol
+    /// messages rather than frames
+    pub(crate) struct MockClient {
+        tx: mpsc::UnboundedSender<Message>,
+        rx: mpsc::UnboundedReceiver<Message>,
+    }
+
+    impl MockClient {
+        pub(crate) fn send(&self, msg: &ClientMessage) {
+            let text = serde_json::to_string(msg).unwrap();
+            self.tx.send(Message::text(text)).unwrap();
+        }
+
+        /// Next message the server pushed; pings are answered on the way
+        pub(crate) async fn recv(&mut self) -> ServerMessage {
+            let read = async {
+                loop {
+                    match self.rx.recv().await.expect("connection ended") {
+                        Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                        Message::Ping(data) => {
+                            let _ = self.tx.send(Message::Pong(data));
+                        }
+                        Message::Close(frame) => panic!("connection closed: {:?}", frame),
+                        _ => continue,
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), read)
+                .await
+                .expect("no message")
+        }
+
+        /// Send a close frame, as a client hanging up cleanly would
+        pub(crate) fn close(&self, frame: Option<CloseFrame>) {
+            let _ = self.tx.send(Message::Close(frame));
+        }
+    }
+}
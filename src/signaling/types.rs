@@ -1,12 +1,14 @@
-use std::collections::HashMap;
 use std::fmt;
-use std::net::SocketAddr;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
+use hmac::{Hmac, Mac};
 use rand::Rng;
+use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha1::Sha1;
 use thiserror::Error;
-use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::Utf8Bytes;
 
 /// Signaling server errors
 #[derive(Debug, Error)]
@@ -14,15 +16,127 @@ pub enum SignalingError {
     #[error("room not found: {0}")]
     RoomNotFound(RoomCode),
 
+    #[error("room creation rate limited, try again later")]
+    CreationRateLimited,
+
+    #[error("room is locked: {0}")]
+    RoomLocked(RoomCode),
+
+    #[error("room is full: {0}")]
+    RoomFull(RoomCode),
+
+    #[error("room code already in use: {0}")]
+    CodeTaken(RoomCode),
+
+    #[error("not in a room")]
+    NotInRoom,
+
+    #[error("peer not in room: {0}")]
+    PeerNotInRoom(PeerId),
+
+    #[error("room rate limited")]
+    RoomRateLimited,
+
+    #[error("unauthorized")]
+    Unauthorized,
+
+    #[error("server overloaded, try again later")]
+    Overloaded,
+
+    #[error("server at capacity, try again later")]
+    ServerAtCapacity,
+
+    #[error("client too old, minimum version is {minimum}")]
+    ClientTooOld { minimum: semver::Version },
+
+    #[error("invalid client version {0}")]
+    InvalidVersion(String),
+
+    #[error("invalid room code {0:?}")]
+    InvalidRoomCode(String),
+
+    #[error("payload too large, limit is {limit} bytes")]
+    PayloadTooLarge { limit: usize },
+
+    #[error("invalid address: {0}")]
+    InvalidAddr(String),
+
+    #[error("relay unavailable: {0}")]
+    RelayUnavailable(String),
+
+    #[error("an offer between you and {0} is already awaiting an answer")]
+    OfferPending(PeerId),
+
+    #[error("no offer from {0} is awaiting an answer")]
+    NoOfferPending(PeerId),
+
+    #[error("the peer with this session key is still in room {0}")]
+    SessionKeyInUse(RoomCode),
+
     #[error("internal error: {0}")]
     Internal(String),
 }
 
+/// Counts from the room manager: current rooms and peers, plus running
+/// totals since it started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ServerStats {
+    pub rooms: usize,
+    /// Peers across all rooms, the figure `max_peers` caps
+    pub peers: usize,
+    /// Rooms opened, by `CreateRoom` or `QuickMatch`
+    pub rooms_created: u64,
+    /// Peers admitted to an existing room, rejoins included
+    pub joins: u64,
+    /// Peers that left their room or were kicked; peers of a room that
+    /// expired or migrated aren't counted
+    pub leaves: u64,
+    /// Messages relayed between peers: signals, offers, answers, ICE
+    /// candidates, broadcasts and multicasts
+    pub relays: u64,
+}
+
+/// One room in a `RoomList`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomSummary {
+    pub code: RoomCode,
+    pub peers: usize,
+}
+
+/// Compare secrets in constant time, so a guess leaks nothing through timing
+/// (beyond its length)
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 const ROOM_CODE_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+/// `ROOM_CODE_CHARS` without `i`, `l`, `o`, `0` and `1`, which are easily
+/// confused when read aloud or off a screen
+const UNAMBIGUOUS_ROOM_CODE_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
 const ROOM_CODE_LEN: usize = 8;
 const PEER_ID_LEN: usize = 13;
+const SESSION_TOKEN_LEN: usize = 32;
 const HEX_CHARS: &[u8] = b"0123456789abcdef";
 
+/// Characters new room codes are drawn from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoomCodeAlphabet {
+    /// Lowercase letters and digits
+    #[default]
+    Full,
+    /// Lowercase letters and digits except `i`, `l`, `o`, `0` and `1`
+    Unambiguous,
+}
+
+impl RoomCodeAlphabet {
+    pub fn chars(&self) -> &'static [u8] {
+        match self {
+            Self::Full => ROOM_CODE_CHARS,
+            Self::Unambiguous => UNAMBIGUOUS_ROOM_CODE_CHARS,
+        }
+    }
+}
+
 /// Room code: 8-byte fixed array
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct RoomCode {
@@ -32,10 +146,16 @@ pub struct RoomCode {
 
 impl RoomCode {
     pub fn generate() -> Self {
+        Self::generate_from(RoomCodeAlphabet::Full)
+    }
+
+    /// A random code drawn from `alphabet`
+    pub fn generate_from(alphabet: RoomCodeAlphabet) -> Self {
+        let chars = alphabet.chars();
         let mut rng = rand::rng();
         let mut bytes = [0u8; ROOM_CODE_LEN];
         for byte in &mut bytes {
-            *byte = ROOM_CODE_CHARS[rng.random_range(0..ROOM_CODE_CHARS.len())];
+            *byte = chars[rng.random_range(0..chars.len())];
         }
         Self {
             bytes,
@@ -67,6 +187,19 @@ impl From<&str> for RoomCode {
     }
 }
 
+/// Strict parsing for codes typed by users: exactly 8 lowercase letters or
+/// digits. The lossy `From` is for codes the server produced itself.
+impl FromStr for RoomCode {
+    type Err = SignalingError;
+
+    fn from_str(s: &str) -> Result<Self, SignalingError> {
+        if s.len() != ROOM_CODE_LEN || !s.bytes().all(|b| ROOM_CODE_CHARS.contains(&b)) {
+            return Err(SignalingError::InvalidRoomCode(s.to_string()));
+        }
+        Ok(Self::from(s))
+    }
+}
+
 impl Serialize for RoomCode {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         serializer.serialize_str(self.as_str())
@@ -75,8 +208,31 @@ impl Serialize for RoomCode {
 
 impl<'de> Deserialize<'de> for RoomCode {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        let s = <&str>::deserialize(deserializer)?;
-        Ok(RoomCode::from(s))
+        deserializer.deserialize_str(FixedStrVisitor::<RoomCode>::new())
+    }
+}
+
+/// Visitor for the fixed-array string types
+///
+/// Accepts transient strings as well as borrowed ones, so decoders that read
+/// from a buffer (CBOR) work without an intermediate `String`.
+struct FixedStrVisitor<T>(PhantomData<T>);
+
+impl<T> FixedStrVisitor<T> {
+    fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T: for<'a> From<&'a str>> Visitor<'_> for FixedStrVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+        Ok(T::from(v))
     }
 }
 
@@ -137,50 +293,228 @@ impl Serialize for PeerId {
 
 impl<'de> Deserialize<'de> for PeerId {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        let s = <&str>::deserialize(deserializer)?;
-        Ok(PeerId::from(s))
+        deserializer.deserialize_str(FixedStrVisitor::<PeerId>::new())
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Secret minted for each peer on create/join, presented to move the
+/// session onto a new connection. 32 hex chars (128 bits).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SessionToken {
+    bytes: [u8; SESSION_TOKEN_LEN],
+}
+
+impl SessionToken {
+    pub fn generate() -> Self {
+        let mut rng = rand::rng();
+        let mut bytes = [0u8; SESSION_TOKEN_LEN];
+        for byte in &mut bytes {
+            *byte = HEX_CHARS[rng.random_range(0..HEX_CHARS.len())];
+        }
+        Self { bytes }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes).unwrap_or("")
+    }
+
+    /// Compare in constant time, so a guessed token leaks nothing through timing
+    pub fn verify(&self, presented: &SessionToken) -> bool {
+        constant_time_eq(&self.bytes, &presented.bytes)
+    }
+}
+
+/// Redacted: tokens are credentials and must not end up in logs
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionToken(..)")
+    }
+}
+
+impl From<&str> for SessionToken {
+    fn from(s: &str) -> Self {
+        let mut bytes = [0u8; SESSION_TOKEN_LEN];
+        let src = s.as_bytes();
+        let len = src.len().min(SESSION_TOKEN_LEN);
+        bytes[..len].copy_from_slice(&src[..len]);
+        Self { bytes }
+    }
+}
+
+impl Serialize for SessionToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SessionToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(FixedStrVisitor::<SessionToken>::new())
+    }
+}
+
+/// Salted digest of a room password; the password itself is never kept
+#[derive(Clone)]
+pub(crate) struct RoomPassword {
+    salt: [u8; 16],
+    digest: [u8; 20],
+}
+
+impl RoomPassword {
+    pub fn new(password: &str) -> Self {
+        let salt = rand::rng().random();
+        Self {
+            salt,
+            digest: Self::digest(&salt, password),
+        }
+    }
+
+    fn digest(salt: &[u8; 16], password: &str) -> [u8; 20] {
+        let mut mac = Hmac::<Sha1>::new_from_slice(salt).expect("HMAC accepts any key length");
+        mac.update(password.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Compare in constant time, so a guessed password leaks nothing through timing
+    pub fn verify(&self, presented: &str) -> bool {
+        constant_time_eq(&Self::digest(&self.salt, presented), &self.digest)
+    }
+}
+
+/// Redacted, like [`SessionToken`]
+impl fmt::Debug for RoomPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RoomPassword(..)")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub id: PeerId,
-    pub public_addr: Option<SocketAddr>,
+    /// Source of the peer's signaling connection. A TCP mapping: never a
+    /// target for UDP hole punching.
+    pub signaling_addr: Option<SignalingAddr>,
+    /// UDP address the peer learned over STUN and reported, if it has yet
+    pub reflexive_addr: Option<ReflexiveAddr>,
+    /// Whatever the peer chose to tell the room about itself, e.g. a display
+    /// name or role; opaque to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
 }
 
-/// Wrapper for outbound WebSocket messages using tungstenite's Utf8Bytes.
-#[derive(Debug, Clone)]
-pub struct OutboundMessage(Utf8Bytes);
+/// Address a signaling (TCP/WebSocket) connection was observed coming from
+///
+/// Behind a NAT this is a TCP mapping, unrelated to any UDP mapping the same
+/// client gets; see [`ReflexiveAddr`] for the address to punch towards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SignalingAddr(SocketAddr);
+
+impl SignalingAddr {
+    pub fn addr(&self) -> SocketAddr {
+        self.0
+    }
+}
+
+impl From<SocketAddr> for SignalingAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self(addr)
+    }
+}
 
-impl OutboundMessage {
-    /// Create a new outbound message from any string type
-    pub fn new(s: impl Into<Utf8Bytes>) -> Self {
-        Self(s.into())
+impl fmt::Display for SignalingAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
     }
+}
 
-    /// Get the inner Utf8Bytes for tungstenite Message::Text
-    pub fn into_inner(self) -> Utf8Bytes {
+/// Server-reflexive UDP address a client discovered over STUN
+///
+/// The only address usable for hole punching. It comes from the client, so
+/// it must pass [`validate_peer_addr`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
+pub struct ReflexiveAddr(SocketAddr);
+
+impl ReflexiveAddr {
+    pub fn addr(&self) -> SocketAddr {
         self.0
     }
 }
 
-impl From<String> for OutboundMessage {
-    fn from(s: String) -> Self {
-        Self(Utf8Bytes::from(s))
+impl TryFrom<SocketAddr> for ReflexiveAddr {
+    type Error = String;
+
+    fn try_from(addr: SocketAddr) -> Result<Self, Self::Error> {
+        validate_peer_addr(addr)?;
+        Ok(Self(addr))
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct PeerState {
-    pub info: PeerInfo,
-    /// Channel for outbound messages to this peer.
-    /// Uses OutboundMessage (Arc<str>) for O(1) broadcast cloning.
-    pub tx: mpsc::UnboundedSender<OutboundMessage>,
+impl<'de> Deserialize<'de> for ReflexiveAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let addr = SocketAddr::deserialize(deserializer)?;
+        ReflexiveAddr::try_from(addr).map_err(serde::de::Error::custom)
+    }
 }
 
-#[derive(Debug)]
-pub(crate) struct Room {
-    pub peers: HashMap<PeerId, PeerState>,
+impl fmt::Display for ReflexiveAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Check that a client-supplied address is usable as a P2P endpoint
+///
+/// Rejects the unspecified address (`0.0.0.0`, `::`) and port 0, which serde's
+/// `SocketAddr` parsing happily accepts. Every path that takes an address from
+/// a client should go through this.
+pub fn validate_peer_addr(addr: SocketAddr) -> Result<(), String> {
+    if addr.ip().is_unspecified() {
+        return Err(format!("unspecified address: {}", addr));
+    }
+    if addr.port() == 0 {
+        return Err(format!("port 0 is not a valid endpoint: {}", addr));
+    }
+    Ok(())
+}
+
+/// [`validate_peer_addr`], additionally rejecting non-global addresses
+/// (private, loopback, link-local, multicast, documentation ranges)
+pub fn validate_global_peer_addr(addr: SocketAddr) -> Result<(), String> {
+    validate_peer_addr(addr)?;
+    if !is_global(addr.ip()) {
+        return Err(format!("non-global address: {}", addr));
+    }
+    Ok(())
+}
+
+/// Whether `ip` is routable on the public internet
+pub(crate) fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                // shared address space (RFC 6598)
+                || (a == 100 && (b & 0xC0) == 64))
+        }
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_multicast()
+                // unique local fc00::/7
+                || (first & 0xFE00) == 0xFC00
+                // link-local fe80::/10
+                || (first & 0xFFC0) == 0xFE80
+                // documentation 2001:db8::/32
+                || (first == 0x2001 && v6.segments()[1] == 0x0DB8))
+        }
+    }
 }
 
 #[cfg(test)]
@@ -215,6 +549,36 @@ mod tests {
         assert_eq!(code.as_str(), "test1234");
     }
 
+    #[test]
+    fn room_code_parse_accepts_generated_codes() {
+        let code = RoomCode::generate();
+        assert_eq!(code.as_str().parse::<RoomCode>().unwrap(), code);
+        assert_eq!("abc12345".parse::<RoomCode>().unwrap().as_str(), "abc12345");
+    }
+
+    #[test]
+    fn room_code_parse_rejects_malformed_input() {
+        for input in [
+            "abc123456",
+            "HELLO WORLD!!",
+            "abc1234",
+            "",
+            "ABC12345",
+            "abc-1234",
+            "abc 1234",
+            "abcdéfg",
+        ] {
+            assert!(
+                matches!(
+                    input.parse::<RoomCode>(),
+                    Err(SignalingError::InvalidRoomCode(ref s)) if s == input
+                ),
+                "{:?}",
+                input
+            );
+        }
+    }
+
     #[test]
     fn peer_id_from_str() {
         let peer_id = PeerId::from("peer_12345678");
@@ -263,11 +627,64 @@ mod tests {
     fn peer_info_serialization() {
         let peer_info = PeerInfo {
             id: PeerId::from("peer_abc12345"),
-            public_addr: Some("127.0.0.1:8080".parse().unwrap()),
+            signaling_addr: Some(SignalingAddr::from(
+                "127.0.0.1:8080".parse::<SocketAddr>().unwrap(),
+            )),
+            reflexive_addr: Some(
+                ReflexiveAddr::try_from("203.0.113.5:40000".parse::<SocketAddr>().unwrap())
+                    .unwrap(),
+            ),
+            metadata: None,
         };
         let json = serde_json::to_string(&peer_info).unwrap();
-        assert!(json.contains("peer_abc12345"));
-        assert!(json.contains("127.0.0.1:8080"));
+        assert_eq!(
+            json,
+            r#"{"id":"peer_abc12345","signaling_addr":"127.0.0.1:8080","reflexive_addr":"203.0.113.5:40000"}"#
+        );
+    }
+
+    #[test]
+    fn reflexive_addr_is_validated() {
+        assert!(serde_json::from_str::<ReflexiveAddr>(r#""0.0.0.0:5000""#).is_err());
+        let addr: ReflexiveAddr = serde_json::from_str(r#""203.0.113.5:40000""#).unwrap();
+        assert_eq!(addr.addr(), "203.0.113.5:40000".parse().unwrap());
+    }
+
+    #[test]
+    fn validate_rejects_unspecified_address() {
+        assert!(validate_peer_addr("0.0.0.0:5000".parse().unwrap()).is_err());
+        assert!(validate_peer_addr("[::]:5000".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_port_zero() {
+        assert!(validate_peer_addr("203.0.113.7:0".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn validate_accepts_valid_address() {
+        assert!(validate_peer_addr("192.168.1.10:5000".parse().unwrap()).is_ok());
+        assert!(validate_peer_addr("8.8.8.8:3478".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn validate_global_rejects_private_ranges() {
+        for addr in [
+            "192.168.1.10:5000",
+            "10.0.0.1:5000",
+            "127.0.0.1:5000",
+            "100.64.0.1:5000",
+            "[fe80::1]:5000",
+            "[fd00::1]:5000",
+        ] {
+            assert!(
+                validate_global_peer_addr(addr.parse().unwrap()).is_err(),
+                "{}",
+                addr
+            );
+        }
+        assert!(validate_global_peer_addr("8.8.8.8:3478".parse().unwrap()).is_ok());
+        assert!(validate_global_peer_addr("[2606:4700::1111]:3478".parse().unwrap()).is_ok());
     }
 
     #[test]